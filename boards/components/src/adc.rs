@@ -1,7 +1,7 @@
 //! Components for using ADC capsules.
 
 use capsules_core::adc::AdcDedicated;
-use capsules_core::adc::AdcVirtualized;
+use capsules_core::adc::{AdcVirtualized, SampleClock};
 use capsules_core::virtualizers::virtual_adc::{AdcDevice, MuxAdc};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
@@ -106,6 +106,7 @@ impl<A: 'static + adc::Adc> Component for AdcComponent<A> {
 pub struct AdcVirtualComponent {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
+    rate_limit: Option<(&'static dyn SampleClock, u32)>,
 }
 
 impl AdcVirtualComponent {
@@ -113,8 +114,20 @@ impl AdcVirtualComponent {
         AdcVirtualComponent {
             board_kernel: board_kernel,
             driver_num: driver_num,
+            rate_limit: None,
         }
     }
+
+    /// Require at least `min_interval_us` microseconds, as measured by
+    /// `clock`, between two sample requests from the same app.
+    pub fn with_min_sample_interval(
+        mut self,
+        clock: &'static dyn SampleClock,
+        min_interval_us: u32,
+    ) -> AdcVirtualComponent {
+        self.rate_limit = Some((clock, min_interval_us));
+        self
+    }
 }
 
 impl Component for AdcVirtualComponent {
@@ -133,6 +146,7 @@ impl Component for AdcVirtualComponent {
             .write(capsules_core::adc::AdcVirtualized::new(
                 static_buffer.1,
                 grant_adc,
+                self.rate_limit,
             ));
 
         for driver in static_buffer.1 {
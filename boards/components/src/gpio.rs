@@ -43,14 +43,31 @@
 //!     ),
 //! ).finalize(components::gpio_component_static!(nrf52840::gpio::GPIOPin));
 //! ```
+//!
+//! Interrupt coalescing (command 13) needs a `GpioCoalescer`, which runs on
+//! its own virtual alarm. Interrupt timestamps (command 16) only read a
+//! clock, so any `Time` can be shared. With pin ownership, the first process
+//! to configure a pin keeps it until it releases the pin:
+//!
+//! ```rust
+//! let coalescer = components::gpio::GpioCoalescerComponent::new(mux_alarm)
+//!     .finalize(components::gpio_coalescer_component_static!(nrf52840::rtc::Rtc));
+//! let gpio = components::gpio::GpioComponent::new(board_kernel, DRIVER_NUM, pins)
+//!     .with_coalescer(coalescer)
+//!     .with_edge_clock(&base_peripherals.rtc)
+//!     .with_pin_ownership()
+//!     .finalize(components::gpio_component_static!(nrf52840::gpio::GPIOPin));
+//! ```
 
-use capsules_core::gpio::GPIO;
+use capsules_core::gpio::{Coalesce, EdgeClock, GpioCoalescer, GPIO};
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil::gpio;
 use kernel::hil::gpio::InterruptWithValue;
+use kernel::hil::time::{self, Alarm};
 
 #[macro_export]
 macro_rules! gpio_component_helper_max_pin {
@@ -115,10 +132,30 @@ macro_rules! gpio_component_static {
     };};
 }
 
+#[macro_export]
+macro_rules! gpio_coalescer_component_static {
+    ($A:ty $(,)?) => {{
+        let alarm = kernel::static_buf!(
+            capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>
+        );
+        let coalescer = kernel::static_buf!(
+            capsules_core::gpio::GpioCoalescer<
+                'static,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+
+        (alarm, coalescer)
+    };};
+}
+
 pub struct GpioComponent<IP: 'static + gpio::InterruptPin<'static>> {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
     gpio_pins: &'static [Option<&'static gpio::InterruptValueWrapper<'static, IP>>],
+    coalescer: Option<&'static dyn Coalesce<'static>>,
+    edge_clock: Option<&'static dyn EdgeClock>,
+    pin_ownership: bool,
 }
 
 impl<IP: 'static + gpio::InterruptPin<'static>> GpioComponent<IP> {
@@ -131,8 +168,29 @@ impl<IP: 'static + gpio::InterruptPin<'static>> GpioComponent<IP> {
             board_kernel: board_kernel,
             driver_num,
             gpio_pins,
+            coalescer: None,
+            edge_clock: None,
+            pin_ownership: false,
         }
     }
+
+    /// Let apps coalesce pin interrupts with `coalescer`.
+    pub fn with_coalescer(mut self, coalescer: &'static dyn Coalesce<'static>) -> Self {
+        self.coalescer = Some(coalescer);
+        self
+    }
+
+    /// Let apps timestamp pin interrupts with ticks of `clock`.
+    pub fn with_edge_clock(mut self, clock: &'static dyn EdgeClock) -> Self {
+        self.edge_clock = Some(clock);
+        self
+    }
+
+    /// Stop processes from configuring or writing pins another process owns.
+    pub fn with_pin_ownership(mut self) -> Self {
+        self.pin_ownership = true;
+        self
+    }
 }
 
 impl<IP: 'static + gpio::InterruptPin<'static>> Component for GpioComponent<IP> {
@@ -150,7 +208,42 @@ impl<IP: 'static + gpio::InterruptPin<'static>> Component for GpioComponent<IP>
                 pin.set_client(gpio);
             }
         }
+        if let Some(coalescer) = self.coalescer {
+            gpio.set_coalescer(coalescer);
+        }
+        if let Some(clock) = self.edge_clock {
+            gpio.set_edge_clock(clock);
+        }
+        gpio.set_pin_ownership(self.pin_ownership);
 
         gpio
     }
 }
+
+pub struct GpioCoalescerComponent<A: 'static + time::Alarm<'static>> {
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: 'static + time::Alarm<'static>> GpioCoalescerComponent<A> {
+    pub fn new(alarm_mux: &'static MuxAlarm<'static, A>) -> Self {
+        Self { alarm_mux }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>> Component for GpioCoalescerComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<GpioCoalescer<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static GpioCoalescer<'static, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let alarm = static_buffer.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        alarm.setup();
+
+        let coalescer = static_buffer.1.write(GpioCoalescer::new(alarm));
+        alarm.set_alarm_client(coalescer);
+
+        coalescer
+    }
+}
@@ -1 +1,2 @@
 pub mod multi_alarm_test;
+pub mod virtual_alarm_order_test;
@@ -0,0 +1,80 @@
+//! Component for the virtual alarm mux ordering self-test.
+//!
+//! The test runs on its own mock alarm and mux, so it needs no hardware
+//! alarm from the board.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let test = components::test::virtual_alarm_order_test::VirtualAlarmOrderTestComponent::new()
+//!     .finalize(components::virtual_alarm_order_test_component_static!());
+//! test.run();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_core::test::virtual_alarm_order::{
+    FireLog, FireRecorder, MockAlarm, TestVirtualAlarmOrder, NUM_ALARMS,
+};
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::component::Component;
+use kernel::hil::time::Alarm;
+
+#[macro_export]
+macro_rules! virtual_alarm_order_test_component_static {
+    () => {{
+        use capsules_core::test::virtual_alarm_order::{
+            FireLog, FireRecorder, MockAlarm, TestVirtualAlarmOrder, NUM_ALARMS,
+        };
+        use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+
+        let mock = kernel::static_buf!(MockAlarm<'static>);
+        let mux = kernel::static_buf!(MuxAlarm<'static, MockAlarm<'static>>);
+        let log = kernel::static_buf!(FireLog);
+        let recorders = kernel::static_buf!([FireRecorder<'static>; NUM_ALARMS]);
+        let alarms =
+            kernel::static_buf!([VirtualMuxAlarm<'static, MockAlarm<'static>>; NUM_ALARMS]);
+        let test = kernel::static_buf!(TestVirtualAlarmOrder<'static>);
+
+        (mock, mux, log, recorders, alarms, test)
+    };};
+}
+
+pub struct VirtualAlarmOrderTestComponent;
+
+impl VirtualAlarmOrderTestComponent {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Component for VirtualAlarmOrderTestComponent {
+    type StaticInput = (
+        &'static mut MaybeUninit<MockAlarm<'static>>,
+        &'static mut MaybeUninit<MuxAlarm<'static, MockAlarm<'static>>>,
+        &'static mut MaybeUninit<FireLog>,
+        &'static mut MaybeUninit<[FireRecorder<'static>; NUM_ALARMS]>,
+        &'static mut MaybeUninit<[VirtualMuxAlarm<'static, MockAlarm<'static>>; NUM_ALARMS]>,
+        &'static mut MaybeUninit<TestVirtualAlarmOrder<'static>>,
+    );
+    type Output = &'static TestVirtualAlarmOrder<'static>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let (mock, mux, log, recorders, alarms, test) = static_buffer;
+
+        let mock = mock.write(MockAlarm::new());
+        let mux = mux.write(MuxAlarm::new(mock));
+        mock.set_alarm_client(mux);
+
+        let log = log.write(FireLog::new());
+        let recorders =
+            recorders.write(core::array::from_fn(|id| FireRecorder::new(id as u8, log)));
+        let alarms = alarms.write(core::array::from_fn(|_| VirtualMuxAlarm::new(mux)));
+        for (alarm, recorder) in alarms.iter().zip(recorders.iter()) {
+            alarm.setup();
+            alarm.set_alarm_client(recorder);
+        }
+
+        test.write(TestVirtualAlarmOrder::new(mock, alarms, log))
+    }
+}
@@ -774,6 +774,10 @@ pub unsafe fn main() {
     .finalize(components::multi_alarm_test_component_buf!(sam4l::ast::Ast))
     .run();*/
 
+    /*components::test::virtual_alarm_order_test::VirtualAlarmOrderTestComponent::new()
+    .finalize(components::virtual_alarm_order_test_component_static!())
+    .run();*/
+
     debug!("Initialization complete. Entering main loop");
 
     // These symbols are defined in the linker script.
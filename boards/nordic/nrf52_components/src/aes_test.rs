@@ -1,15 +1,23 @@
+//! Known-answer test for the nRF52 AES-128 CTR driver, shared by the nRF52
+//! boards.
+//!
+//! Encrypts the NIST SP 800-38A CTR vectors, checks the ciphertext and then
+//! decrypts it again, driving each step from `crypt_done`.
+
 use capsules_extra::test::aes::TestAes128Ctr;
 use kernel::hil::symmetric_encryption::{AES128, AES128_BLOCK_SIZE, AES128_KEY_SIZE};
 use kernel::static_init;
-use nrf52832::aes::AesECB;
+use nrf52::aes::AesECB;
 
 /// To run the tests add the following `main.rs::main` somewhere after that the AES
 /// peripheral has been initialized:
 ///
 /// ```rustc
-///     aes::run(base_peripherals.ecb);
+///     nrf52_components::aes_test::run(&base_peripherals.ecb);
 /// ```
 ///
+/// The test takes over the ECB client, so call it in place of setting up the
+/// `MuxAES128CCM` (or after the last CCM user is done with it).
 pub unsafe fn run(aesecb: &'static AesECB) {
     let t = static_init_test(aesecb);
     aesecb.set_client(t);
@@ -1,5 +1,6 @@
 #![no_std]
 
+pub mod aes_test;
 pub mod startup;
 
 pub use self::startup::{
@@ -115,21 +115,33 @@ impl<'a> NrfClockComponent<'a> {
     }
 }
 
+/// How long to wait for the 32.768 kHz crystal to start before falling back to
+/// the RC oscillator. The crystal typically starts in 250 ms.
+const LFXO_START_TIMEOUT_US: u32 = 1_000_000;
+
 impl<'a> Component for NrfClockComponent<'a> {
     type StaticInput = ();
-    type Output = ();
+    /// The source the low frequency clock runs from: the crystal, or the RC
+    /// oscillator if the crystal did not start.
+    type Output = nrf52::clock::LowClockSource;
     fn finalize(self, _s: Self::StaticInput) -> Self::Output {
         // Start all of the clocks. Low power operation will require a better
         // approach than this.
         self.clock.low_stop();
         self.clock.high_stop();
 
-        self.clock
-            .low_set_source(nrf52::clock::LowClockSource::XTAL);
-        self.clock.low_start();
         self.clock.high_start();
-        while !self.clock.low_started() {}
+        // The SysTick runs from the 64 MHz CPU clock whichever oscillator
+        // drives it, so it can time the crystal start-up. It is reset again
+        // before the scheduler uses it.
+        let systick = unsafe { cortexm4::systick::SysTick::new_with_calibration(64_000_000) };
+        let source = self.clock.low_start_or_rc(
+            nrf52::clock::LowClockSource::XTAL,
+            &systick,
+            LFXO_START_TIMEOUT_US,
+        );
         while !self.clock.high_started() {}
+        source
     }
 }
 
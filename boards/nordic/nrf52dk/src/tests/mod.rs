@@ -1,2 +1 @@
-pub mod aes;
 pub mod uart;
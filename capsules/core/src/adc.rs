@@ -54,6 +54,7 @@ use core::convert::TryFrom;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil;
+use kernel::hil::time::{ConvertTicks, Ticks};
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
@@ -71,6 +72,81 @@ pub struct AdcVirtualized<'a> {
     drivers: &'a [&'a dyn hil::adc::AdcChannel],
     apps: Grant<AppSys, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
     current_process: OptionalCell<ProcessId>,
+    /// Optional per-app rate limit: the clock used to timestamp requests and
+    /// the minimum number of microseconds between two samples from one app.
+    rate_limit: Option<(&'a dyn SampleClock, u32)>,
+}
+
+/// Time source used by `AdcVirtualized` to enforce a minimum interval between
+/// samples requested by the same app.
+///
+/// Requests are only compared against the time of the app's last one, so
+/// nothing is ever scheduled on it and the board can share a clock that
+/// other capsules also read from.
+pub trait SampleClock {
+    /// Returns the current tick value.
+    fn now_ticks(&self) -> u64;
+
+    /// Returns whether fewer than `us` microseconds have passed since `start`,
+    /// which must be a value previously returned by `now_ticks()`.
+    fn within_us(&self, start: u64, us: u32) -> bool;
+}
+
+impl<T: hil::time::Time> SampleClock for T {
+    fn now_ticks(&self) -> u64 {
+        self.now().into_u64()
+    }
+
+    fn within_us(&self, start: u64, us: u32) -> bool {
+        // `start` came from `into_u64()`, so it fits in `T::Ticks`.
+        let start = T::Ticks::from_or_max(start);
+        let end = start.wrapping_add(self.ticks_from_us(us));
+        self.now().within_range(start, end)
+    }
+}
+
+/// Channel numbers used for the chip's internal channels, which are not in
+/// the board's channel list. They are reported as the channel in callbacks for
+/// commands 9 and 10.
+pub mod internal_channel {
+    pub const TEMPERATURE: usize = 0x100;
+    pub const REFERENCE: usize = 0x101;
+}
+
+/// Look up the ADC channel for a channel number: an index into `channels`, or
+/// one of the `internal_channel` numbers if the ADC exposes that channel.
+fn lookup_channel<'c, A: hil::adc::Adc>(
+    adc: &'c A,
+    channels: &'c [A::Channel],
+    channel: usize,
+) -> Option<&'c A::Channel> {
+    match channel {
+        internal_channel::TEMPERATURE => adc.internal_temperature_channel(),
+        internal_channel::REFERENCE => adc.internal_reference_channel(),
+        _ => channels.get(channel),
+    }
+}
+
+/// Arguments of the upcall reporting a finished calibration: the mode, the
+/// status, and the offset the calibration measured in sample LSBs (0 if the
+/// ADC does not report one).
+fn calibration_upcall(result: Result<Option<i32>, ErrorCode>) -> (usize, usize, usize) {
+    (
+        AdcMode::Calibration as usize,
+        kernel::errorcode::into_statuscode(result.map(|_| ())),
+        result.ok().flatten().unwrap_or(0) as usize,
+    )
+}
+
+/// Arguments of the upcall reporting a differential sample: the mode, the
+/// positive channel, and the signed sample, sign-extended so that
+/// applications can read it as an `int`.
+fn differential_upcall(channel: usize, sample: i16) -> (usize, usize, usize) {
+    (
+        AdcMode::DifferentialSample as usize,
+        channel,
+        sample as isize as usize,
+    )
 }
 
 /// ADC syscall driver, used by applications to interact with ADC.
@@ -91,6 +167,14 @@ pub struct AdcDedicated<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> {
     processid: OptionalCell<ProcessId>,
     channel: Cell<usize>,
 
+    // Threshold window state: the `(low, high)` thresholds set with command 6,
+    // and which side of the window the last sample fell on.
+    window: Cell<(u16, u16)>,
+    outside: Cell<Option<Crossing>>,
+
+    // Single sample averaging, set with command 8.
+    averaging: Averaging,
+
     // ADC buffers
     adc_buf1: TakeCell<'static, [u16]>,
     adc_buf2: TakeCell<'static, [u16]>,
@@ -106,6 +190,107 @@ pub(crate) enum AdcMode {
     ContinuousSample = 1,
     SingleBuffer = 2,
     ContinuousBuffer = 3,
+    WindowSample = 4,
+    Calibration = 5,
+    DifferentialSample = 6,
+}
+
+/// The side of the threshold window a sample fell on. Reported to applications
+/// when continuous window sampling sees a sample leave the window.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Crossing {
+    Below = 0,
+    Above = 1,
+}
+
+/// Compare a sample against the `(low, high)` threshold window and record
+/// which side of it the sample fell on in `outside`. Returns the crossing to
+/// report: only the first sample outside the window (or on the other side of
+/// it) is reported, and samples back inside the window re-arm the check.
+///
+/// None of the ADC HILs expose a hardware window comparator, so this is done
+/// in software for every sample.
+fn window_crossing(
+    window: (u16, u16),
+    outside: &Cell<Option<Crossing>>,
+    sample: u16,
+) -> Option<Crossing> {
+    let (low, high) = window;
+    let now = if sample < low {
+        Some(Crossing::Below)
+    } else if sample > high {
+        Some(Crossing::Above)
+    } else {
+        None
+    };
+    let previous = outside.replace(now);
+    now.filter(|_| now != previous)
+}
+
+/// The largest number of samples that can be averaged for a single sample
+/// request.
+pub const MAX_AVERAGE_SAMPLES: usize = 256;
+
+/// Averaging state for single samples, configured with command 8.
+///
+/// If the ADC oversamples in hardware every sample it returns is already
+/// averaged, so a request completes after one sample. Otherwise `samples`
+/// samples are collected and averaged in software.
+struct Averaging {
+    samples: Cell<usize>,
+    hardware: Cell<bool>,
+    taken: Cell<usize>,
+    sum: Cell<u32>,
+}
+
+impl Averaging {
+    fn new() -> Averaging {
+        Averaging {
+            samples: Cell::new(1),
+            hardware: Cell::new(false),
+            taken: Cell::new(0),
+            sum: Cell::new(0),
+        }
+    }
+
+    /// Check that `samples` is a power of two no larger than
+    /// `MAX_AVERAGE_SAMPLES`.
+    fn validate(samples: usize) -> Result<(), ErrorCode> {
+        if samples.is_power_of_two() && samples <= MAX_AVERAGE_SAMPLES {
+            Ok(())
+        } else {
+            Err(ErrorCode::INVAL)
+        }
+    }
+
+    fn configure(&self, samples: usize, hardware: bool) {
+        self.samples.set(samples);
+        self.hardware.set(hardware);
+    }
+
+    /// Reset the running sum at the start of a sample request.
+    fn start(&self) {
+        self.taken.set(0);
+        self.sum.set(0);
+    }
+
+    /// Add a sample to the running sum. Returns `true` once enough samples
+    /// have been collected for this request.
+    fn add(&self, sample: u16) -> bool {
+        self.taken.set(self.taken.get() + 1);
+        self.sum.set(self.sum.get() + sample as u32);
+        let needed = if self.hardware.get() {
+            1
+        } else {
+            self.samples.get()
+        };
+        self.taken.get() >= needed
+    }
+
+    /// The average of the samples collected so far.
+    fn average(&self) -> u16 {
+        (self.sum.get() / self.taken.get().max(1) as u32) as u16
+    }
 }
 
 // Datas passed by the application to us
@@ -113,6 +298,9 @@ pub struct AppSys {
     pending_command: bool,
     command: OptionalCell<Operation>,
     channel: usize,
+    /// Clock value when this app last had a sample request accepted, only
+    /// tracked when a rate limit is configured.
+    last_request: Option<u64>,
 }
 
 /// Holds buffers that the application has passed us
@@ -142,6 +330,7 @@ impl Default for AppSys {
             pending_command: false,
             command: OptionalCell::empty(),
             channel: 0,
+            last_request: None,
         }
     }
 }
@@ -180,6 +369,13 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
             processid: OptionalCell::empty(),
             channel: Cell::new(0),
 
+            // Threshold window state
+            window: Cell::new((0, u16::MAX)),
+            outside: Cell::new(None),
+
+            // Single sample averaging
+            averaging: Averaging::new(),
+
             // ADC buffers
             adc_buf1: TakeCell::new(adc_buf1),
             adc_buf2: TakeCell::new(adc_buf2),
@@ -244,7 +440,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
 
     /// Collect a single analog sample on a channel.
     ///
-    /// - `channel` - index into `channels` array, which channel to sample
+    /// - `channel` - index into `channels` array, or an `internal_channel`
+    ///   number, which channel to sample
     fn sample(&self, channel: usize) -> Result<(), ErrorCode> {
         // only one sample at a time
         if self.active.get() {
@@ -252,15 +449,13 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
         }
 
         // convert channel index
-        if channel >= self.channels.len() {
-            return Err(ErrorCode::INVAL);
-        }
-        let chan = &self.channels[channel];
+        let chan = lookup_channel(self.adc, self.channels, channel).ok_or(ErrorCode::INVAL)?;
 
         // save state for callback
         self.active.set(true);
         self.mode.set(AdcMode::SingleSample);
         self.channel.set(channel);
+        self.averaging.start();
 
         // start a single sample
         let res = self.adc.sample(chan);
@@ -275,6 +470,58 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
         Ok(())
     }
 
+    /// Set how many samples are averaged for each single sample request.
+    /// Hardware oversampling is used if the ADC supports it, otherwise the
+    /// samples are averaged in software. Returns whether the ADC oversamples
+    /// in hardware.
+    ///
+    /// - `samples` - number of samples to average, a power of two
+    fn set_averaging(&self, samples: usize) -> Result<bool, ErrorCode> {
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        Averaging::validate(samples)?;
+
+        let hardware = match self.adc.set_oversampling(samples) {
+            Ok(()) => true,
+            Err(ErrorCode::NOSUPPORT) => false,
+            Err(e) => return Err(e),
+        };
+        self.averaging.configure(samples, hardware);
+        Ok(hardware)
+    }
+
+    /// Run the ADC's self-calibration. The application is called back when
+    /// it finishes.
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.adc.calibrate()?;
+        self.active.set(true);
+        self.mode.set(AdcMode::Calibration);
+        Ok(())
+    }
+
+    /// Collect a single sample of the difference between two channels.
+    ///
+    /// - `positive` - index into `channels` array, the channel measured
+    /// - `negative` - index into `channels` array, the channel it is measured
+    ///   against
+    fn sample_differential(&self, positive: usize, negative: usize) -> Result<(), ErrorCode> {
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        let pos = self.channels.get(positive).ok_or(ErrorCode::INVAL)?;
+        let neg = self.channels.get(negative).ok_or(ErrorCode::INVAL)?;
+
+        self.adc.sample_differential(pos, neg)?;
+        self.active.set(true);
+        self.mode.set(AdcMode::DifferentialSample);
+        self.channel.set(positive);
+        Ok(())
+    }
+
     /// Collect repeated single analog samples on a channel.
     ///
     /// - `channel` - index into `channels` array, which channel to sample
@@ -309,6 +556,59 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed> AdcDedicated<'a, A> {
         Ok(())
     }
 
+    /// Set the thresholds used by `sample_window`. A sample below `low` or
+    /// above `high` is outside the window; a `low` of 0 or a `high` of
+    /// `u16::MAX` disables that side of the window.
+    ///
+    /// - `low` - lowest sample value inside the window
+    /// - `high` - highest sample value inside the window
+    fn set_window(&self, low: usize, high: usize) -> Result<(), ErrorCode> {
+        let (Ok(low), Ok(high)) = (u16::try_from(low), u16::try_from(high)) else {
+            return Err(ErrorCode::INVAL);
+        };
+        if low > high {
+            return Err(ErrorCode::INVAL);
+        }
+        self.window.set((low, high));
+        self.outside.set(None);
+        Ok(())
+    }
+
+    /// Collect repeated single analog samples on a channel, but only notify
+    /// the application when a sample leaves the window set by `set_window`.
+    ///
+    /// - `channel` - index into `channels` array, which channel to sample
+    /// - `frequency` - number of samples per second to collect
+    fn sample_window(&self, channel: usize, frequency: u32) -> Result<(), ErrorCode> {
+        // only one sample at a time
+        if self.active.get() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // convert channel index
+        if channel >= self.channels.len() {
+            return Err(ErrorCode::INVAL);
+        }
+        let chan = &self.channels[channel];
+
+        // save state for callback, starting inside the window
+        self.active.set(true);
+        self.mode.set(AdcMode::WindowSample);
+        self.channel.set(channel);
+        self.outside.set(None);
+
+        let res = self.adc.sample_continuous(chan, frequency);
+        if res != Ok(()) {
+            // failure, clear state
+            self.active.set(false);
+            self.mode.set(AdcMode::NoMode);
+
+            return res;
+        }
+
+        Ok(())
+    }
+
     /// Collect a buffer-full of analog samples.
     ///
     /// Samples are collected into the first app buffer provided. The number of
@@ -634,17 +934,42 @@ impl<'a> AdcVirtualized<'a> {
     /// Create a new `Adc` application interface.
     ///
     /// - `drivers` - Virtual ADC drivers to provide application access to
+    /// - `rate_limit` - Optionally, a clock and the minimum number of
+    ///   microseconds it must measure between two sample requests from the
+    ///   same app. Requests arriving faster are rejected with `BUSY`.
     pub fn new(
         drivers: &'a [&'a dyn hil::adc::AdcChannel],
         grant: Grant<AppSys, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+        rate_limit: Option<(&'a dyn SampleClock, u32)>,
     ) -> AdcVirtualized<'a> {
         AdcVirtualized {
             drivers: drivers,
             apps: grant,
             current_process: OptionalCell::empty(),
+            rate_limit: rate_limit,
         }
     }
 
+    /// Check the app against the configured rate limit, and if it is allowed
+    /// to sample, record the time of this request.
+    fn check_rate_limit(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        self.rate_limit.map_or(Ok(()), |(clock, min_interval_us)| {
+            self.apps
+                .enter(processid, |app, _| {
+                    let too_soon = app
+                        .last_request
+                        .map_or(false, |last| clock.within_us(last, min_interval_us));
+                    if too_soon {
+                        Err(ErrorCode::BUSY)
+                    } else {
+                        app.last_request = Some(clock.now_ticks());
+                        Ok(())
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
     /// Enqueue the command to be executed when the ADC is available.
     fn enqueue_command(
         &self,
@@ -653,6 +978,7 @@ impl<'a> AdcVirtualized<'a> {
         processid: ProcessId,
     ) -> Result<(), ErrorCode> {
         if channel < self.drivers.len() {
+            self.check_rate_limit(processid)?;
             if self.current_process.is_none() {
                 self.current_process.set(processid);
                 let r = self.call_driver(command, channel);
@@ -735,6 +1061,16 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for AdcDedicate
     fn sample_ready(&self, sample: u16) {
         let mut calledback = false;
         if self.active.get() && self.mode.get() == AdcMode::SingleSample {
+            // keep sampling until enough samples have been collected to
+            // average, reporting what we have if the ADC refuses
+            if !self.averaging.add(sample)
+                && lookup_channel(self.adc, self.channels, self.channel.get())
+                    .map_or(false, |chan| self.adc.sample(chan).is_ok())
+            {
+                return;
+            }
+            let sample = self.averaging.average();
+
             // single sample complete, clean up state
             self.active.set(false);
             self.mode.set(AdcMode::NoMode);
@@ -791,6 +1127,36 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for AdcDedicate
                         }
                     })
             });
+        } else if self.active.get() && self.mode.get() == AdcMode::WindowSample {
+            // sample ready in window sampling operation, keep state and only
+            // call back if the sample just left the window
+            let crossing = window_crossing(self.window.get(), &self.outside, sample);
+
+            self.processid.map(|id| {
+                self.apps
+                    .enter(*id, |_app, upcalls| {
+                        calledback = true;
+                        crossing.map(|direction| {
+                            upcalls
+                                .schedule_upcall(
+                                    0,
+                                    (
+                                        AdcMode::WindowSample as usize,
+                                        self.channel.get(),
+                                        sample as usize | (direction as usize) << 16,
+                                    ),
+                                )
+                                .ok();
+                        });
+                    })
+                    .map_err(|err| {
+                        if err == kernel::process::Error::NoSuchApp
+                            || err == kernel::process::Error::InactiveApp
+                        {
+                            self.processid.clear();
+                        }
+                    })
+            });
         }
         if !calledback {
             // operation probably canceled. Make sure state is consistent. No
@@ -803,6 +1169,64 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> hil::adc::Client for AdcDedicate
             let _ = self.adc.stop_sampling();
         }
     }
+
+    /// Calibration complete.
+    ///
+    /// Reports the result, and the offset if the ADC measured one, to the
+    /// application. Calibrations the application did not ask for, such as
+    /// one a board runs at boot, are ignored.
+    ///
+    /// - `result` - whether calibration succeeded, and the offset
+    fn calibration_done(&self, result: Result<Option<i32>, ErrorCode>) {
+        if !(self.active.get() && self.mode.get() == AdcMode::Calibration) {
+            return;
+        }
+        self.active.set(false);
+        self.mode.set(AdcMode::NoMode);
+
+        self.processid.map(|id| {
+            self.apps
+                .enter(*id, |_app, upcalls| {
+                    upcalls.schedule_upcall(0, calibration_upcall(result)).ok();
+                })
+                .map_err(|err| {
+                    if err == kernel::process::Error::NoSuchApp
+                        || err == kernel::process::Error::InactiveApp
+                    {
+                        self.processid.clear();
+                    }
+                })
+        });
+    }
+
+    /// Differential sample complete.
+    ///
+    /// Provides the signed sample to the application.
+    ///
+    /// - `sample` - signed analog sample value
+    fn differential_sample_ready(&self, sample: i16) {
+        if !(self.active.get() && self.mode.get() == AdcMode::DifferentialSample) {
+            return;
+        }
+        self.active.set(false);
+        self.mode.set(AdcMode::NoMode);
+
+        self.processid.map(|id| {
+            self.apps
+                .enter(*id, |_app, upcalls| {
+                    upcalls
+                        .schedule_upcall(0, differential_upcall(self.channel.get(), sample))
+                        .ok();
+                })
+                .map_err(|err| {
+                    if err == kernel::process::Error::NoSuchApp
+                        || err == kernel::process::Error::InactiveApp
+                    {
+                        self.processid.clear();
+                    }
+                })
+        });
+    }
 }
 
 /// Callbacks from the High Speed ADC driver
@@ -1187,6 +1611,7 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> SyscallDriver for AdcDedicated<'
             0 => CommandReturn::success_u32(self.channels.len() as u32),
 
             // Single sample on channel
+            1 if channel >= self.channels.len() => CommandReturn::failure(ErrorCode::INVAL),
             1 => match self.sample(channel) {
                 Ok(()) => CommandReturn::success(),
                 e => CommandReturn::failure(if let Ok(err) = ErrorCode::try_from(e) {
@@ -1236,6 +1661,52 @@ impl<A: hil::adc::Adc + hil::adc::AdcHighSpeed> SyscallDriver for AdcDedicated<'
                 }),
             },
 
+            // Set the threshold window, low in the first argument and high in
+            // the second
+            6 => match self.set_window(channel, frequency) {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // Repeated single samples on a channel, calling back only when a
+            // sample leaves the threshold window
+            7 => match self.sample_window(channel, frequency as u32) {
+                Ok(()) => CommandReturn::success(),
+                e => CommandReturn::failure(if let Ok(err) = ErrorCode::try_from(e) {
+                    err
+                } else {
+                    panic!("ADC: invalid return code")
+                }),
+            },
+
+            // Set the number of samples averaged for each single sample, and
+            // return whether the ADC oversamples in hardware
+            8 => match self.set_averaging(channel) {
+                Ok(hardware) => CommandReturn::success_u32(hardware as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // Single sample of the internal temperature sensor or the
+            // internal voltage reference
+            9 | 10 => {
+                let internal = if command_num == 9 {
+                    internal_channel::TEMPERATURE
+                } else {
+                    internal_channel::REFERENCE
+                };
+                if lookup_channel(self.adc, self.channels, internal).is_none() {
+                    return CommandReturn::failure(ErrorCode::NOSUPPORT);
+                }
+                self.sample(internal).into()
+            }
+
+            // Run the ADC's self-calibration
+            11 => self.calibrate().into(),
+
+            // Single sample of the positive channel in the first argument
+            // against the negative channel in the second
+            12 => self.sample_differential(channel, frequency).into(),
+
             // Get resolution bits
             101 => CommandReturn::success_u32(self.get_resolution_bits() as u32),
             // Get voltage reference mV
@@ -1337,3 +1808,424 @@ impl<'a> hil::adc::Client for AdcVirtualized<'a> {
         self.run_next_command();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak, QueuedUpcall, TestKernel};
+    use kernel::syscall::SyscallReturn;
+
+    #[test]
+    fn window_ramp_crosses_once() {
+        let outside = Cell::new(None);
+        let window = (100, 500);
+
+        // A ramp from 200 up to 1000 leaves the window once, at 510.
+        let mut crossings = (200..=1000)
+            .step_by(10)
+            .filter_map(|sample| window_crossing(window, &outside, sample).map(|c| (sample, c)));
+        assert_eq!(crossings.next(), Some((510, Crossing::Above)));
+        assert_eq!(crossings.next(), None);
+
+        // Falling back through the window re-arms the check, and leaving the
+        // other side is reported.
+        let mut crossings = (0..=1000)
+            .rev()
+            .step_by(10)
+            .filter_map(|sample| window_crossing(window, &outside, sample).map(|c| (sample, c)));
+        assert_eq!(crossings.next(), Some((90, Crossing::Below)));
+        assert_eq!(crossings.next(), None);
+    }
+
+    #[test]
+    fn software_average_of_eight_samples() {
+        let averaging = Averaging::new();
+        assert_eq!(Averaging::validate(8), Ok(()));
+        averaging.configure(8, false);
+        averaging.start();
+
+        // A mock ADC producing a noisy reading around 1000.
+        let mut samples = [1000u16, 1004, 996, 1001, 999, 1010, 990, 1008].into_iter();
+        let done: [bool; 8] = core::array::from_fn(|_| averaging.add(samples.next().unwrap()));
+        assert_eq!(
+            done,
+            [false, false, false, false, false, false, false, true]
+        );
+        assert_eq!(averaging.average(), 1001);
+
+        // A new request starts a fresh sum.
+        averaging.start();
+        assert!(!averaging.add(8));
+        assert_eq!(averaging.average(), 8);
+    }
+
+    #[test]
+    fn hardware_oversampling_takes_one_sample() {
+        let averaging = Averaging::new();
+        averaging.configure(16, true);
+        averaging.start();
+        assert!(averaging.add(2048));
+        assert_eq!(averaging.average(), 2048);
+    }
+
+    #[test]
+    fn averaging_must_be_power_of_two() {
+        assert_eq!(Averaging::validate(1), Ok(()));
+        assert_eq!(Averaging::validate(MAX_AVERAGE_SAMPLES), Ok(()));
+        assert_eq!(Averaging::validate(0), Err(ErrorCode::INVAL));
+        assert_eq!(Averaging::validate(6), Err(ErrorCode::INVAL));
+        assert_eq!(
+            Averaging::validate(MAX_AVERAGE_SAMPLES * 2),
+            Err(ErrorCode::INVAL)
+        );
+    }
+
+    /// 12-bit ADC with a 3300 mV reference, a temperature sensor on channel
+    /// 16 and no internal reference channel, reporting a fixed reading for
+    /// each channel. Calibration finishes when the test calls
+    /// `finish_calibration`.
+    struct MockAdc {
+        sampled: Cell<Option<u8>>,
+        calibrating: Cell<bool>,
+        differential: Cell<Option<(u8, u8)>>,
+        client: OptionalCell<&'static dyn hil::adc::Client>,
+    }
+
+    impl hil::adc::Adc for MockAdc {
+        type Channel = u8;
+
+        fn sample(&self, channel: &u8) -> Result<(), ErrorCode> {
+            self.sampled.set(Some(*channel));
+            Ok(())
+        }
+        fn sample_continuous(&self, _channel: &u8, _frequency: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+        fn stop_sampling(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn get_resolution_bits(&self) -> usize {
+            12
+        }
+        fn get_voltage_reference_mv(&self) -> Option<usize> {
+            Some(3300)
+        }
+        fn internal_temperature_channel(&self) -> Option<&u8> {
+            Some(&16)
+        }
+        fn calibrate(&self) -> Result<(), ErrorCode> {
+            self.calibrating.set(true);
+            Ok(())
+        }
+        fn sample_differential(&self, positive: &u8, negative: &u8) -> Result<(), ErrorCode> {
+            self.differential.set(Some((*positive, *negative)));
+            Ok(())
+        }
+        fn set_client(&self, client: &'static dyn hil::adc::Client) {
+            self.client.set(client);
+        }
+    }
+
+    impl hil::adc::AdcHighSpeed for MockAdc {
+        fn sample_highspeed(
+            &self,
+            _channel: &u8,
+            _frequency: u32,
+            buffer1: &'static mut [u16],
+            _length1: usize,
+            buffer2: &'static mut [u16],
+            _length2: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u16], &'static mut [u16])> {
+            Err((ErrorCode::NOSUPPORT, buffer1, buffer2))
+        }
+        fn provide_buffer(
+            &self,
+            buf: &'static mut [u16],
+            _length: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u16])> {
+            Err((ErrorCode::NOSUPPORT, buf))
+        }
+        fn retrieve_buffers(
+            &self,
+        ) -> Result<(Option<&'static mut [u16]>, Option<&'static mut [u16]>), ErrorCode> {
+            Ok((None, None))
+        }
+        fn set_highspeed_client(&self, _client: &'static dyn hil::adc::HighSpeedClient) {}
+    }
+
+    impl MockAdc {
+        fn new() -> MockAdc {
+            MockAdc {
+                sampled: Cell::new(None),
+                calibrating: Cell::new(false),
+                differential: Cell::new(None),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        // Complete a calibration, reporting an offset of -3 LSBs.
+        fn finish_calibration(&self) {
+            if self.calibrating.replace(false) {
+                self.client
+                    .map(|client| client.calibration_done(Ok(Some(-3))));
+            }
+        }
+
+        // Complete a single sample, reporting the reading of its channel.
+        fn finish_sample(&self) {
+            if let Some(channel) = self.sampled.take() {
+                self.client
+                    .map(|client| client.sample_ready(Self::level(channel)));
+            }
+        }
+
+        // The reading the ADC reports for the channel last sampled.
+        fn reading(&self) -> Option<u16> {
+            self.sampled.get().map(Self::level)
+        }
+
+        fn level(channel: u8) -> u16 {
+            match channel {
+                16 => 0x5d0,
+                channel => channel as u16 * 0x100,
+            }
+        }
+
+        // Complete a differential sample, reporting the difference of the
+        // readings of its channels.
+        fn finish_differential(&self) {
+            if let Some((positive, negative)) = self.differential.take() {
+                let sample = Self::level(positive) as i16 - Self::level(negative) as i16;
+                self.client
+                    .map(|client| client.differential_sample_ready(sample));
+            }
+        }
+    }
+
+    type MockDriver = AdcDedicated<'static, MockAdc>;
+
+    /// A dedicated ADC driver over a mock ADC with board channels 0 to 3,
+    /// registered as the ADC's client.
+    fn dedicated_adc(kernel: &TestKernel) -> (&'static MockDriver, &'static MockAdc) {
+        let adc = leak(MockAdc::new());
+        let driver = leak(AdcDedicated::new(
+            adc,
+            kernel.create_grant(DRIVER_NUM),
+            leak([0, 1, 2, 3]),
+            leak([0; 128]),
+            leak([0; 128]),
+            leak([0; 128]),
+        ));
+        hil::adc::Adc::set_client(adc, driver);
+        (driver, adc)
+    }
+
+    #[test]
+    fn resolution_and_reference_commands() {
+        let kernel = TestKernel::new(&["app"]);
+        let (driver, _) = dedicated_adc(kernel);
+        let app = kernel.process(0);
+        assert!(matches!(
+            app.command(driver, DRIVER_NUM, 101, 0, 0),
+            SyscallReturn::SuccessU32(12)
+        ));
+        assert!(matches!(
+            app.command(driver, DRIVER_NUM, 102, 0, 0),
+            SyscallReturn::SuccessU32(3300)
+        ));
+    }
+
+    #[test]
+    fn internal_temperature_channel() {
+        let adc = MockAdc::new();
+        let channels = [0, 1, 2];
+
+        let chan = lookup_channel(&adc, &channels, internal_channel::TEMPERATURE);
+        assert_eq!(chan, Some(&16));
+        assert_eq!(
+            chan.map(|chan| hil::adc::Adc::sample(&adc, chan)),
+            Some(Ok(()))
+        );
+        assert_eq!(adc.reading(), Some(0x5d0));
+
+        // No internal reference, and board channels are still indexed.
+        assert_eq!(
+            lookup_channel(&adc, &channels, internal_channel::REFERENCE),
+            None
+        );
+        assert_eq!(lookup_channel(&adc, &channels, 2), Some(&2));
+        assert_eq!(lookup_channel(&adc, &channels, 3), None);
+    }
+
+    #[test]
+    fn temperature_command_samples_the_sensor() {
+        let kernel = TestKernel::new(&["app"]);
+        let (driver, adc) = dedicated_adc(kernel);
+        let app = kernel.process(0);
+        app.subscribe(driver, DRIVER_NUM, 0);
+
+        assert!(matches!(
+            app.command(driver, DRIVER_NUM, 9, 0, 0),
+            SyscallReturn::Success
+        ));
+        adc.finish_sample();
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(
+                DRIVER_NUM,
+                0,
+                (0, internal_channel::TEMPERATURE, 0x5d0)
+            )]
+        );
+
+        // There is no internal reference to sample.
+        assert!(matches!(
+            app.command(driver, DRIVER_NUM, 10, 0, 0),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ));
+    }
+
+    #[test]
+    fn calibration_calls_back() {
+        let kernel = TestKernel::new(&["app"]);
+        let (driver, adc) = dedicated_adc(kernel);
+        let app = kernel.process(0);
+        app.subscribe(driver, DRIVER_NUM, 0);
+
+        assert!(matches!(
+            app.command(driver, DRIVER_NUM, 11, 0, 0),
+            SyscallReturn::Success
+        ));
+        assert!(adc.calibrating.get());
+        assert_eq!(app.take_upcalls(), []);
+
+        // The application sees the offset as the third upcall argument.
+        adc.finish_calibration();
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (5, 0, -3i32 as usize))]
+        );
+
+        // A calibration the application did not ask for is not reported.
+        hil::adc::Client::calibration_done(driver, Ok(None));
+        assert_eq!(app.take_upcalls(), []);
+
+        assert_eq!(calibration_upcall(Ok(None)), (5, 0, 0));
+        assert_eq!(
+            calibration_upcall(Err(ErrorCode::FAIL)),
+            (5, ErrorCode::FAIL as usize, 0)
+        );
+    }
+
+    #[test]
+    fn differential_sample_is_signed() {
+        let kernel = TestKernel::new(&["app"]);
+        let (driver, adc) = dedicated_adc(kernel);
+        let app = kernel.process(0);
+        app.subscribe(driver, DRIVER_NUM, 0);
+        let differential = |positive, negative| {
+            let ret = app.command(driver, DRIVER_NUM, 12, positive, negative);
+            adc.finish_differential();
+            ret
+        };
+
+        // Channel 1 reads below channel 3, and the application reads the
+        // sample as a signed value.
+        assert!(matches!(differential(1, 3), SyscallReturn::Success));
+        assert_eq!(adc.differential.get(), None);
+        let upcalls = app.take_upcalls();
+        assert_eq!(
+            upcalls,
+            [QueuedUpcall::new(
+                DRIVER_NUM,
+                0,
+                (6, 1, -0x200isize as usize)
+            )]
+        );
+        assert_eq!(upcalls[0].args.2 as i32, -0x200);
+
+        assert!(matches!(differential(3, 1), SyscallReturn::Success));
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (6, 3, 0x200))]
+        );
+
+        assert!(matches!(
+            differential(1, 4),
+            SyscallReturn::Failure(ErrorCode::INVAL)
+        ));
+        assert_eq!(app.take_upcalls(), []);
+    }
+
+    #[test]
+    fn window_sides_can_be_disabled() {
+        let outside = Cell::new(None);
+        let window = (0, u16::MAX);
+        assert!((0..=u16::MAX).all(|sample| window_crossing(window, &outside, sample).is_none()));
+    }
+
+    struct MockClock(Cell<u64>);
+
+    impl hil::time::Time for MockClock {
+        type Ticks = hil::time::Ticks64;
+        type Frequency = hil::time::Freq1MHz;
+
+        fn now(&self) -> Self::Ticks {
+            self.0.get().into()
+        }
+    }
+
+    /// A channel that starts every sample it is asked for.
+    struct MockChannel;
+
+    impl hil::adc::AdcChannel for MockChannel {
+        fn sample(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn sample_continuous(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn stop_sampling(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_resolution_bits(&self) -> usize {
+            12
+        }
+
+        fn get_voltage_reference_mv(&self) -> Option<usize> {
+            None
+        }
+
+        fn set_client(&self, _client: &'static dyn hil::adc::Client) {}
+    }
+
+    #[test]
+    fn rate_limit_rejects_rapid_samples() {
+        let kernel = TestKernel::new(&["app"]);
+        // A time that does not fit in 32 bits.
+        let clock = MockClock(Cell::new((1 << 32) + 50));
+        let channels: [&dyn hil::adc::AdcChannel; 1] = [&MockChannel];
+        let adc = AdcVirtualized::new(
+            &channels,
+            kernel.create_grant(DRIVER_NUM),
+            Some((&clock, 100)),
+        );
+        let app = kernel.process(0);
+        app.subscribe(&adc, DRIVER_NUM, 0);
+
+        let sample = || app.command(&adc, DRIVER_NUM, 1, 0, 0);
+        assert!(matches!(sample(), SyscallReturn::Success));
+        hil::adc::Client::sample_ready(&adc, 7);
+        assert_eq!(app.take_upcalls().len(), 1);
+
+        // The ADC is idle again, but the app asked too soon.
+        clock.0.set(clock.0.get() + 99);
+        assert!(matches!(sample(), SyscallReturn::Failure(ErrorCode::BUSY)));
+
+        clock.0.set(clock.0.get() + 1);
+        assert!(matches!(sample(), SyscallReturn::Success));
+    }
+}
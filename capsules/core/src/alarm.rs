@@ -1,7 +1,12 @@
 //! Tock syscall driver capsule for Alarms, which issue callbacks when
 //! a point in time has been reached.
+//!
+//! Each process has `NUM_SLOTS` independent alarms. Commands that arm or stop
+//! an alarm take its slot id in their second argument, and the upcall reports
+//! which slot fired.
 
 use core::cell::Cell;
+use core::cmp;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::time::{self, Alarm, Frequency, Ticks, Ticks32};
@@ -18,33 +23,102 @@ enum Expiration {
     Enabled { reference: u32, dt: u32 },
 }
 
+/// Number of independent alarms each process can have armed.
+pub const NUM_SLOTS: usize = 4;
+
 #[derive(Copy, Clone)]
-pub struct AlarmData {
+struct Slot {
     expiration: Expiration,
+    /// Period of a repeating alarm in ticks, or 0 for a one-shot alarm.
+    period: u32,
+    /// Whether fires of a repeating alarm that happen before userspace
+    /// handled the previous upcall are merged into a single upcall.
+    coalesce: bool,
+    /// Number of missed fires reported in the most recently scheduled upcall.
+    missed: u32,
+}
+
+/// The slot id is held in the low bits of the second argument of commands 3,
+/// 5, 7, 9 and 10, and of the third argument of the upcall.
+const SLOT_BITS: usize = 8;
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+
+/// Flag for command 7, above the slot id, requesting that pending upcalls be
+/// coalesced.
+const PERIODIC_FLAG_COALESCE: usize = 1 << SLOT_BITS;
+
+/// Returns the `(reference, dt)` of an alarm expiring at the absolute tick
+/// `target`. A target up to half the counter range ahead of `now` is in the
+/// future; any other target has already passed, and expires immediately
+/// rather than a whole counter wrap later.
+fn absolute_expiration(now: u32, target: u32) -> (u32, u32) {
+    let dt = target.wrapping_sub(now);
+    if dt <= u32::MAX / 2 {
+        (now, dt)
+    } else {
+        (target, 0)
+    }
 }
 
-const ALARM_CALLBACK_NUM: usize = 0;
-const NUM_UPCALLS: u8 = 1;
+/// Converts `us` microseconds to ticks of a `frequency` Hz clock, rounding
+/// to the nearest tick. A non-zero duration shorter than half a tick still
+/// waits one tick rather than firing immediately.
+fn us_to_ticks(us: u32, frequency: u32) -> u32 {
+    let ticks = (us as u64 * frequency as u64 + 500_000) / 1_000_000;
+    match ticks {
+        0 if us > 0 => 1,
+        ticks => u32::try_from(ticks).unwrap_or(u32::MAX),
+    }
+}
 
-impl Default for AlarmData {
-    fn default() -> AlarmData {
-        AlarmData {
+/// The upcall arguments for the alarm in `slot` that expired at
+/// `reference + dt`: the tick `now` at which the expiration was handled, the
+/// tick it was scheduled for, and the slot id together with the number of
+/// `missed` fires merged into the upcall. The handling tick is later than
+/// the scheduled one when the alarm interrupt was delayed by other work, so
+/// apps can tell how late the callback is.
+fn expired_upcall(
+    now: Ticks32,
+    reference: u32,
+    dt: u32,
+    slot: usize,
+    missed: u32,
+) -> (usize, usize, usize) {
+    let missed = cmp::min(missed as usize, usize::MAX >> SLOT_BITS);
+    (
+        now.into_u32() as usize,
+        reference.wrapping_add(dt) as usize,
+        missed << SLOT_BITS | slot,
+    )
+}
+
+impl Default for Slot {
+    fn default() -> Slot {
+        Slot {
             expiration: Expiration::Disabled,
+            period: 0,
+            coalesce: false,
+            missed: 0,
         }
     }
 }
 
+#[derive(Default)]
+pub struct AlarmData {
+    slots: [Slot; NUM_SLOTS],
+}
+
 pub struct AlarmDriver<'a, A: Alarm<'a>> {
     alarm: &'a A,
     num_armed: Cell<usize>,
-    app_alarms: Grant<AlarmData, UpcallCount<NUM_UPCALLS>, AllowRoCount<0>, AllowRwCount<0>>,
+    app_alarms: Grant<AlarmData, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
     next_alarm: Cell<Expiration>,
 }
 
 impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
     pub const fn new(
         alarm: &'a A,
-        grant: Grant<AlarmData, UpcallCount<NUM_UPCALLS>, AllowRoCount<0>, AllowRwCount<0>>,
+        grant: Grant<AlarmData, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
     ) -> AlarmDriver<'a, A> {
         AlarmDriver {
             alarm: alarm,
@@ -68,61 +142,66 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
         // its counter value at earliest_end. In the case that there
         // are multiple alarms in the past, just store one of them
         // and resolve ordering later, when we fire.
-        for alarm in self.app_alarms.iter() {
-            alarm.enter(|alarm, _upcalls| match alarm.expiration {
-                Expiration::Enabled { reference, dt } => {
-                    // Do this because `reference` shadowed below
-                    let current_reference = reference;
-                    let current_reference_ticks = A::Ticks::from(current_reference);
-                    let current_dt = dt;
-                    let current_dt_ticks = A::Ticks::from(current_dt);
-                    let current_end_ticks = current_reference_ticks.wrapping_add(current_dt_ticks);
-
-                    earliest_alarm = match earliest_alarm {
-                        Expiration::Disabled => {
-                            earliest_end = current_end_ticks;
-                            alarm.expiration
-                        }
+        for app in self.app_alarms.iter() {
+            app.enter(|app, _upcalls| {
+                for alarm in app.slots.iter() {
+                    match alarm.expiration {
                         Expiration::Enabled { reference, dt } => {
-                            // There are two cases when current might be
-                            // an earlier alarm.  The first is if it
-                            // fires inside the interval (reference,
-                            // reference+dt) of the existing earliest.
-                            // The second is if now is not within the
-                            // interval: this means that it has
-                            // passed. It could be the earliest has passed
-                            // too, but at this point we don't need to track
-                            // which is earlier: the key point is that
-                            // the alarm must fire immediately, and then when
-                            // we handle the alarm callback the userspace
-                            // callbacks will all be pushed onto processes.
-                            // Because there is at most a single callback per
-                            // process and they must go through the scheduler
-                            // we don't care about the order in which we push
-                            // their callbacks, as their order of execution is
-                            // determined by the scheduler not push order. -pal
-                            let temp_earliest_reference = A::Ticks::from(reference);
-                            let temp_earliest_dt = A::Ticks::from(dt);
-                            let temp_earliest_end =
-                                temp_earliest_reference.wrapping_add(temp_earliest_dt);
-
-                            if current_end_ticks
-                                .within_range(temp_earliest_reference, temp_earliest_end)
-                            {
-                                earliest_end = current_end_ticks;
-                                alarm.expiration
-                            } else if !now_lower_bits
-                                .within_range(temp_earliest_reference, temp_earliest_end)
-                            {
-                                earliest_end = temp_earliest_end;
-                                alarm.expiration
-                            } else {
-                                earliest_alarm
+                            // Do this because `reference` shadowed below
+                            let current_reference = reference;
+                            let current_reference_ticks = A::Ticks::from(current_reference);
+                            let current_dt = dt;
+                            let current_dt_ticks = A::Ticks::from(current_dt);
+                            let current_end_ticks =
+                                current_reference_ticks.wrapping_add(current_dt_ticks);
+
+                            earliest_alarm = match earliest_alarm {
+                                Expiration::Disabled => {
+                                    earliest_end = current_end_ticks;
+                                    alarm.expiration
+                                }
+                                Expiration::Enabled { reference, dt } => {
+                                    // There are two cases when current might be
+                                    // an earlier alarm.  The first is if it
+                                    // fires inside the interval (reference,
+                                    // reference+dt) of the existing earliest.
+                                    // The second is if now is not within the
+                                    // interval: this means that it has
+                                    // passed. It could be the earliest has passed
+                                    // too, but at this point we don't need to track
+                                    // which is earlier: the key point is that
+                                    // the alarm must fire immediately, and then when
+                                    // we handle the alarm callback the userspace
+                                    // callbacks will all be pushed onto processes.
+                                    // Because there is at most a single callback per
+                                    // process and they must go through the scheduler
+                                    // we don't care about the order in which we push
+                                    // their callbacks, as their order of execution is
+                                    // determined by the scheduler not push order. -pal
+                                    let temp_earliest_reference = A::Ticks::from(reference);
+                                    let temp_earliest_dt = A::Ticks::from(dt);
+                                    let temp_earliest_end =
+                                        temp_earliest_reference.wrapping_add(temp_earliest_dt);
+
+                                    if current_end_ticks
+                                        .within_range(temp_earliest_reference, temp_earliest_end)
+                                    {
+                                        earliest_end = current_end_ticks;
+                                        alarm.expiration
+                                    } else if !now_lower_bits
+                                        .within_range(temp_earliest_reference, temp_earliest_end)
+                                    {
+                                        earliest_end = temp_earliest_end;
+                                        alarm.expiration
+                                    } else {
+                                        earliest_alarm
+                                    }
+                                }
                             }
                         }
+                        Expiration::Disabled => {}
                     }
                 }
-                Expiration::Disabled => {}
             });
         }
         self.next_alarm.set(earliest_alarm);
@@ -161,6 +240,15 @@ impl<'a, A: Alarm<'a>> SyscallDriver for AlarmDriver<'a, A> {
     /// - `3`: Stop the alarm if it is outstanding
     /// - `4`: Set an alarm to fire at a given clock value `time`.
     /// - `5`: Set an alarm to fire at a given clock value `time` relative to `now` (EXPERIMENTAL).
+    /// - `6`: Set an alarm to fire at `reference + dt`.
+    /// - `7`: Set a repeating alarm with period `dt`, optionally coalescing
+    ///   fires that userspace has not handled yet.
+    /// - `9`: Set an alarm to fire at the absolute clock value `time`.
+    /// - `10`: Set an alarm to fire `us` microseconds from now.
+    ///
+    /// Commands `3`, `5`, `7`, `9` and `10` act on the slot whose id is in the
+    /// low bits of `data2`. Command `6` uses both arguments, and always acts on
+    /// slot 0.
     fn command(
         &self,
         cmd_type: usize,
@@ -174,7 +262,16 @@ impl<'a, A: Alarm<'a>> SyscallDriver for AlarmDriver<'a, A> {
         //   - the underlying alarm is currently disabled and we're enabling the first alarm, or
         //   - on an error (i.e. no change to the alarms).
         self.app_alarms
-            .enter(caller_id, |td, _upcalls| {
+            .enter(caller_id, |app, _upcalls| {
+                let slot = match cmd_type {
+                    3 | 5 | 9 | 10 => data2,
+                    7 => data2 & SLOT_MASK,
+                    _ => 0,
+                };
+                let td = match app.slots.get_mut(slot) {
+                    Some(td) => td,
+                    None => return (CommandReturn::failure(ErrorCode::INVAL), false),
+                };
                 // helper function to rearm alarm
                 let mut rearm = |reference: usize, dt: usize| {
                     if let Expiration::Disabled = td.expiration {
@@ -184,6 +281,7 @@ impl<'a, A: Alarm<'a>> SyscallDriver for AlarmDriver<'a, A> {
                         reference: reference as u32,
                         dt: dt as u32,
                     };
+                    td.period = 0;
                     (
                         CommandReturn::success_u32(reference.wrapping_add(dt) as u32),
                         true,
@@ -227,6 +325,28 @@ impl<'a, A: Alarm<'a>> SyscallDriver for AlarmDriver<'a, A> {
                         let dt = data2;
                         rearm(reference, dt)
                     }
+                    7 /* Set repeating alarm */ => {
+                        let period = data;
+                        if period == 0 {
+                            (CommandReturn::failure(ErrorCode::INVAL), false)
+                        } else {
+                            let reference = now.into_u32() as usize;
+                            let ret = rearm(reference, period);
+                            td.period = period as u32;
+                            td.coalesce = data2 & PERIODIC_FLAG_COALESCE != 0;
+                            td.missed = 0;
+                            ret
+                        }
+                    }
+                    9 /* Set absolute expiration */ => {
+                        let (reference, dt) = absolute_expiration(now.into_u32(), data as u32);
+                        rearm(reference as usize, dt as usize)
+                    }
+                    10 /* Set relative expiration in microseconds */ => {
+                        let reference = now.into_u32() as usize;
+                        let dt = us_to_ticks(data as u32, <A::Frequency>::frequency());
+                        rearm(reference, dt as usize)
+                    }
                     _ => (CommandReturn::failure(ErrorCode::NOSUPPORT), false)
                 }
             })
@@ -249,26 +369,45 @@ impl<'a, A: Alarm<'a>> SyscallDriver for AlarmDriver<'a, A> {
 impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
     fn alarm(&self) {
         let now: Ticks32 = Ticks32::from(self.alarm.now().into_u32());
-        self.app_alarms.each(|_processid, alarm, upcalls| {
-            if let Expiration::Enabled { reference, dt } = alarm.expiration {
-                // Now is not within reference, reference + ticks; this timer
-                // as passed (since reference must be in the past)
-                if !now.within_range(
-                    Ticks32::from(reference),
-                    Ticks32::from(reference.wrapping_add(dt)),
-                ) {
-                    alarm.expiration = Expiration::Disabled;
-                    self.num_armed.set(self.num_armed.get() - 1);
-                    upcalls
-                        .schedule_upcall(
-                            ALARM_CALLBACK_NUM,
-                            (
-                                now.into_u32() as usize,
-                                reference.wrapping_add(dt) as usize,
-                                0,
-                            ),
-                        )
-                        .ok();
+        self.app_alarms.each(|_processid, app, upcalls| {
+            for (slot, alarm) in app.slots.iter_mut().enumerate() {
+                if let Expiration::Enabled { reference, dt } = alarm.expiration {
+                    // Now is not within reference, reference + ticks; this timer
+                    // as passed (since reference must be in the past)
+                    if !now.within_range(
+                        Ticks32::from(reference),
+                        Ticks32::from(reference.wrapping_add(dt)),
+                    ) {
+                        let mut missed = 0;
+                        if alarm.period == 0 {
+                            alarm.expiration = Expiration::Disabled;
+                            self.num_armed.set(self.num_armed.get() - 1);
+                        } else {
+                            // Re-arm relative to this expiration rather than
+                            // `now` so the period does not drift.
+                            alarm.expiration = Expiration::Enabled {
+                                reference: reference.wrapping_add(dt),
+                                dt: alarm.period,
+                            };
+                            if alarm.coalesce {
+                                // If the upcall for the previous fire has not
+                                // been delivered yet, replace it with this one
+                                // and count the fire it stood for as missed.
+                                let removed = upcalls
+                                    .remove_pending_upcalls(0, &|(_, _, arg2)| {
+                                        arg2 & SLOT_MASK == slot
+                                    })
+                                    .unwrap_or(0);
+                                if removed > 0 {
+                                    missed = alarm.missed.saturating_add(removed as u32);
+                                }
+                                alarm.missed = missed;
+                            }
+                        }
+                        upcalls
+                            .schedule_upcall(0, expired_upcall(now, reference, dt, slot, missed))
+                            .ok();
+                    }
                 }
             }
         });
@@ -283,3 +422,213 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether an alarm with this `(reference, dt)` has fired at `now`, as
+    /// checked in `alarm()`.
+    fn fired((reference, dt): (u32, u32), now: u32) -> bool {
+        !Ticks32::from(now).within_range(
+            Ticks32::from(reference),
+            Ticks32::from(reference.wrapping_add(dt)),
+        )
+    }
+
+    #[test]
+    fn absolute_expiration_fires_at_target() {
+        let expiration = absolute_expiration(1_000, 1_500);
+        assert_eq!(expiration, (1_000, 500));
+        assert!(!fired(expiration, 1_499));
+        assert!(fired(expiration, 1_500));
+
+        // Targets are reached across a counter wrap, too.
+        let expiration = absolute_expiration(u32::MAX - 10, 20);
+        assert!(!fired(expiration, u32::MAX));
+        assert!(!fired(expiration, 19));
+        assert!(fired(expiration, 20));
+    }
+
+    #[test]
+    fn us_to_ticks_rounds_to_nearest() {
+        let hz = <time::Freq32KHz as Frequency>::frequency();
+        // 500us is 16.384 ticks at 32768Hz.
+        assert_eq!(us_to_ticks(500, hz), 16);
+        // 1000us is 32.768 ticks, which rounds up.
+        assert_eq!(us_to_ticks(1_000, hz), 33);
+        // Sub-tick requests still wait for one tick.
+        assert_eq!(us_to_ticks(1, hz), 1);
+        assert_eq!(us_to_ticks(0, hz), 0);
+        // Durations too long for the counter are clamped.
+        assert_eq!(us_to_ticks(u32::MAX, 16_000_000), u32::MAX);
+    }
+
+    /// Clock whose value only moves when the test advances it.
+    struct MockClock(Cell<u32>);
+
+    impl time::Time for MockClock {
+        type Frequency = time::Freq32KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(self.0.get())
+        }
+    }
+
+    impl MockClock {
+        fn advance(&self, ticks: u32) {
+            self.0.set(self.0.get().wrapping_add(ticks));
+        }
+    }
+
+    /// Only the clock matters to the driver: tests call `alarm()`
+    /// themselves.
+    impl<'a> Alarm<'a> for MockClock {
+        fn set_alarm_client(&self, _client: &'a dyn time::AlarmClient) {}
+
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {}
+
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            false
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    #[test]
+    fn delayed_fire_reports_handling_time() {
+        let clock = MockClock(Cell::new(u32::MAX - 100));
+        let now = || time::Time::now(&clock);
+
+        // Arm an alarm 300 ticks out, across a counter wrap.
+        let (reference, dt) = (now().into_u32(), 300);
+        clock.advance(299);
+        assert!(!fired((reference, dt), now().into_u32()));
+
+        // The interrupt is handled 45 ticks after the alarm expired.
+        clock.advance(46);
+        assert!(fired((reference, dt), now().into_u32()));
+        let (fire_time, scheduled, slot) = expired_upcall(now(), reference, dt, 2, 0);
+        assert_eq!(scheduled, 199);
+        assert_eq!(fire_time, 244);
+        assert_eq!((fire_time as u32).wrapping_sub(scheduled as u32), 45);
+        assert_eq!(slot, 2);
+    }
+
+    #[test]
+    fn absolute_expiration_in_past_fires_now() {
+        let expiration = absolute_expiration(1_000, 990);
+        assert_eq!(expiration, (990, 0));
+        assert!(fired(expiration, 1_000));
+        // More than half the range ahead counts as the past.
+        assert_eq!(absolute_expiration(0, u32::MAX / 2 + 1).1, 0);
+    }
+
+    #[test]
+    fn slots_fire_independently() {
+        use capsules_testing::{QueuedUpcall, TestKernel};
+        use kernel::syscall::SyscallReturn;
+        use time::AlarmClient;
+
+        let kernel = TestKernel::new(&["app"]);
+        let clock = MockClock(Cell::new(1_000));
+        let driver = AlarmDriver::new(&clock, kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+
+        // Slot 1 fires in 100 ticks, slot 3 in 50.
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 5, 100, 1),
+            SyscallReturn::SuccessU32(1_100)
+        ));
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 5, 50, 3),
+            SyscallReturn::SuccessU32(1_050)
+        ));
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 5, 50, NUM_SLOTS),
+            SyscallReturn::Failure(ErrorCode::INVAL)
+        ));
+
+        clock.advance(50);
+        driver.alarm();
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (1_050, 1_050, 3))]
+        );
+
+        clock.advance(60);
+        driver.alarm();
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (1_110, 1_100, 1))]
+        );
+
+        // Both alarms were one-shot.
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 3, 0, 1),
+            SyscallReturn::Failure(ErrorCode::ALREADY)
+        ));
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 3, 0, 3),
+            SyscallReturn::Failure(ErrorCode::ALREADY)
+        ));
+    }
+
+    #[test]
+    fn coalesced_fires_report_missed_count() {
+        use capsules_testing::{QueuedUpcall, TestKernel};
+        use kernel::syscall::SyscallReturn;
+        use time::AlarmClient;
+
+        let kernel = TestKernel::new(&["app"]);
+        let clock = MockClock(Cell::new(0));
+        let driver = AlarmDriver::new(&clock, kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+
+        // Slot 2 repeats every 10 ticks, coalescing. Slot 0 repeats every 20
+        // ticks without, so its queued upcalls must be left alone.
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 7, 10, PERIODIC_FLAG_COALESCE | 2),
+            SyscallReturn::SuccessU32(10)
+        ));
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 7, 20, 0),
+            SyscallReturn::SuccessU32(20)
+        ));
+
+        // Four fires of slot 2 and two of slot 0 before the app runs.
+        for _ in 0..4 {
+            clock.advance(10);
+            driver.alarm();
+        }
+        assert_eq!(
+            app.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, 0, (20, 20, 0)),
+                QueuedUpcall::new(DRIVER_NUM, 0, (40, 40, 0)),
+                QueuedUpcall::new(DRIVER_NUM, 0, (40, 40, 3 << SLOT_BITS | 2)),
+            ]
+        );
+
+        // Once the app handled the upcall, the count starts over.
+        clock.advance(10);
+        driver.alarm();
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (50, 50, 2))]
+        );
+    }
+}
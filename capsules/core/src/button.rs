@@ -38,6 +38,7 @@
 //! - `2`: Disable interrupts for a button. No affect or reliance on
 //!   registered callback.
 //! - `3`: Read the current state of the button.
+//! - `4`: Read the current state of all buttons as a bitfield.
 //!
 //! ### Subscribe
 //!
@@ -140,6 +141,8 @@ impl<'a, P: gpio::InterruptPin<'a>> SyscallDriver for Button<'a, P> {
     /// - `2`: Disable interrupts for a button. No affect or reliance on
     ///   registered callback.
     /// - `3`: Read the current state of the button.
+    /// - `4`: Read the current state of all buttons at once. Bit `i` of the
+    ///   result is set if button `i` is pressed.
     fn command(
         &self,
         command_num: usize,
@@ -157,7 +160,7 @@ impl<'a, P: gpio::InterruptPin<'a>> SyscallDriver for Button<'a, P> {
                 if data < pins.len() {
                     self.apps
                         .enter(processid, |cntr, _| {
-                            cntr.subscribe_map |= 1 << data;
+                            cntr.subscribe_map = subscribe(cntr.subscribe_map, data);
                             let _ = pins[data]
                                 .0
                                 .enable_interrupts(gpio::InterruptEdge::EitherEdge);
@@ -177,7 +180,7 @@ impl<'a, P: gpio::InterruptPin<'a>> SyscallDriver for Button<'a, P> {
                     let res = self
                         .apps
                         .enter(processid, |cntr, _| {
-                            cntr.subscribe_map &= !(1 << data);
+                            cntr.subscribe_map = unsubscribe(cntr.subscribe_map, data);
                             CommandReturn::success()
                         })
                         .unwrap_or_else(|err| CommandReturn::failure(err.into()));
@@ -185,12 +188,13 @@ impl<'a, P: gpio::InterruptPin<'a>> SyscallDriver for Button<'a, P> {
                     // are any processes waiting for this button?
                     let interrupt_count = Cell::new(0);
                     self.apps.each(|_, cntr, _| {
-                        if cntr.subscribe_map & (1 << data) != 0 {
+                        if wants(cntr.subscribe_map, data) {
                             interrupt_count.set(interrupt_count.get() + 1);
                         }
                     });
 
-                    // if not, disable the interrupt
+                    // if not, disable the interrupt so the button no longer
+                    // wakes the chip
                     if interrupt_count.get() == 0 {
                         self.pins[data].0.disable_interrupts();
                     }
@@ -209,6 +213,11 @@ impl<'a, P: gpio::InterruptPin<'a>> SyscallDriver for Button<'a, P> {
                 }
             }
 
+            // read all inputs
+            4 => CommandReturn::success_u32(pressed_mask(
+                pins.iter().map(|&(pin, mode, _)| (pin, mode)),
+            )),
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
@@ -227,7 +236,7 @@ impl<'a, P: gpio::InterruptPin<'a>> gpio::ClientWithValue for Button<'a, P> {
 
         // schedule callback with the pin number and value
         self.apps.each(|_, cntr, upcalls| {
-            if cntr.subscribe_map & (1 << pin_num) != 0 {
+            if wants(cntr.subscribe_map, pin_num as usize) {
                 interrupt_count.set(interrupt_count.get() + 1);
                 upcalls
                     .schedule_upcall(UPCALL_NUM, (pin_num as usize, button_state as usize, 0))
@@ -243,3 +252,200 @@ impl<'a, P: gpio::InterruptPin<'a>> gpio::ClientWithValue for Button<'a, P> {
         }
     }
 }
+
+/// Add button `index` to an app's subscriptions.
+fn subscribe(map: SubscribeMap, index: usize) -> SubscribeMap {
+    map | (1 << index)
+}
+
+/// Remove button `index` from an app's subscriptions.
+fn unsubscribe(map: SubscribeMap, index: usize) -> SubscribeMap {
+    map & !(1 << index)
+}
+
+/// Whether an app should get a callback for an edge on button `index`.
+fn wants(map: SubscribeMap, index: usize) -> bool {
+    map & (1 << index) != 0
+}
+
+/// Reads each button back-to-back and returns a bitfield where bit `i` is set
+/// if button `i` is pressed, taking its activation mode into account.
+fn pressed_mask<'b, I: Input + 'b>(
+    buttons: impl Iterator<Item = (&'b I, gpio::ActivationMode)>,
+) -> u32 {
+    buttons.take(SubscribeMap::BITS as usize).enumerate().fold(
+        0,
+        |mask, (i, (pin, mode))| match pin.read_activation(mode) {
+            gpio::ActivationState::Active => mask | (1 << i),
+            gpio::ActivationState::Inactive => mask,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak, QueuedUpcall, TestKernel};
+    use gpio::ActivationMode::{ActiveHigh, ActiveLow};
+    use kernel::syscall::SyscallReturn;
+
+    struct MockInput(bool);
+
+    impl Input for MockInput {
+        fn read(&self) -> bool {
+            self.0
+        }
+    }
+
+    /// A button pin, pressed high, that tracks whether its interrupts are
+    /// enabled.
+    struct MockButtonPin {
+        pressed: Cell<bool>,
+        interrupts: Cell<bool>,
+    }
+
+    impl gpio::Output for MockButtonPin {
+        fn set(&self) {}
+        fn clear(&self) {}
+        fn toggle(&self) -> bool {
+            false
+        }
+    }
+
+    impl Input for MockButtonPin {
+        fn read(&self) -> bool {
+            self.pressed.get()
+        }
+    }
+
+    impl Configure for MockButtonPin {
+        fn configuration(&self) -> gpio::Configuration {
+            gpio::Configuration::Input
+        }
+        fn make_output(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn disable_output(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn make_input(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn disable_input(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn deactivate_to_low_power(&self) {}
+        fn set_floating_state(&self, _state: gpio::FloatingState) {}
+        fn floating_state(&self) -> gpio::FloatingState {
+            gpio::FloatingState::PullNone
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockButtonPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+        fn enable_interrupts(&self, _mode: gpio::InterruptEdge) {
+            self.interrupts.set(true);
+        }
+        fn disable_interrupts(&self) {
+            self.interrupts.set(false);
+        }
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn pressed_mask_accounts_for_activation_mode() {
+        let levels = [
+            MockInput(true),
+            MockInput(false),
+            MockInput(false),
+            MockInput(true),
+            MockInput(true),
+        ];
+        let modes = [ActiveHigh, ActiveHigh, ActiveLow, ActiveLow, ActiveHigh];
+
+        // Buttons 0 and 4 are pressed high, button 2 is pressed low.
+        let mask = pressed_mask(levels.iter().zip(modes));
+        assert_eq!(mask, 0b10101);
+    }
+
+    #[test]
+    fn disabled_button_gets_no_callback() {
+        // One app listens to buttons 0 and 2, then mutes button 2; another
+        // app never enabled any.
+        let mut first = subscribe(subscribe(0, 0), 2);
+        first = unsubscribe(first, 2);
+        let apps = [first, 0];
+
+        // An edge on button 2 reaches no app, so its interrupt is turned off,
+        // while button 0 still reports to the first app.
+        assert!(!apps.iter().any(|&map| wants(map, 2)));
+        assert_eq!(apps.map(|map| wants(map, 0)), [true, false]);
+
+        // Unsubscribing a button the app never enabled changes nothing.
+        assert_eq!(unsubscribe(first, 1), first);
+    }
+
+    type MockButtons = Button<'static, MockButtonPin>;
+
+    /// A button driver over `N` pressed, active-high mock pins, and the pins
+    /// themselves.
+    fn mock_buttons<const N: usize>(
+        kernel: &TestKernel,
+    ) -> (MockButtons, [&'static MockButtonPin; N]) {
+        let pins: [&'static MockButtonPin; N] = core::array::from_fn(|_| {
+            &*leak(MockButtonPin {
+                pressed: Cell::new(true),
+                interrupts: Cell::new(false),
+            })
+        });
+        let buttons = leak(pins.map(|pin| {
+            (
+                &*leak(gpio::InterruptValueWrapper::new(pin)),
+                ActiveHigh,
+                gpio::FloatingState::PullNone,
+            )
+        }));
+        (Button::new(buttons, kernel.create_grant(DRIVER_NUM)), pins)
+    }
+
+    #[test]
+    fn command_0_counts_buttons() {
+        let kernel = TestKernel::new(&["app"]);
+        let (button, _) = mock_buttons::<5>(kernel);
+        assert!(matches!(
+            kernel.process(0).command(&button, DRIVER_NUM, 0, 0, 0),
+            SyscallReturn::SuccessU32(5)
+        ));
+    }
+
+    #[test]
+    fn edge_on_disabled_button_is_not_reported() {
+        let kernel = TestKernel::new(&["listener", "idle"]);
+        let (button, pins) = mock_buttons::<2>(kernel);
+        let (listener, idle) = (kernel.process(0), kernel.process(1));
+        listener.subscribe(&button, DRIVER_NUM, UPCALL_NUM);
+        idle.subscribe(&button, DRIVER_NUM, UPCALL_NUM);
+
+        // The listener enables both buttons, then disables button 1.
+        listener.command(&button, DRIVER_NUM, 1, 0, 0);
+        listener.command(&button, DRIVER_NUM, 1, 1, 0);
+        listener.command(&button, DRIVER_NUM, 2, 1, 0);
+        assert!(!pins[1].interrupts.get());
+
+        // An edge already latched on button 1 reaches no app.
+        gpio::ClientWithValue::fired(&button, 1);
+        assert!(listener.take_upcalls().is_empty());
+        assert!(idle.take_upcalls().is_empty());
+
+        // Button 0 still reports to the listener alone.
+        gpio::ClientWithValue::fired(&button, 0);
+        assert_eq!(
+            listener.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (0, 1, 0))]
+        );
+        assert!(idle.take_upcalls().is_empty());
+        assert!(pins[0].interrupts.get());
+    }
+}
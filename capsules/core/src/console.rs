@@ -19,6 +19,15 @@
 //! hil::uart::UART::set_client(&usart::USART0, console);
 //! ```
 //!
+//! Output is passed to the UART unchanged. A board that forwards untrusted
+//! data to a terminal can call `console.set_sanitize(true)` so that control
+//! bytes, including the ESC that starts ANSI escape sequences, are printed in
+//! caret notation (`^[`) instead.
+//!
+//! To let apps change the parity, stop bits and data width of the line, a
+//! board passes the UART's `Configure` interface and its current parameters
+//! with `console.set_configure(&uart, params)`.
+//!
 //! Usage
 //! -----
 //!
@@ -37,9 +46,13 @@
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
 
+use core::cell::Cell;
+
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
 use kernel::hil::uart;
-use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::processbuffer::{
+    ReadableProcessBuffer, ReadableProcessSlice, WriteableProcessBuffer, WriteableProcessSlice,
+};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
@@ -72,26 +85,87 @@ mod rw_allow {
     pub const COUNT: u8 = 2;
 }
 
+/// Receive error kinds passed as the third argument of the read callback
+mod rx_error {
+    pub const NONE: usize = 0;
+    pub const PARITY: usize = 1;
+    pub const FRAMING: usize = 2;
+    pub const OVERRUN: usize = 3;
+    /// Any other error reported by the UART
+    pub const OTHER: usize = 4;
+}
+
+/// Characters that erase the previous byte of a line in line mode.
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// The byte that ends a line in line mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Terminator {
+    byte: u8,
+    /// Store the terminator in the read buffer at the end of the line.
+    keep: bool,
+}
+
+impl Default for Terminator {
+    fn default() -> Terminator {
+        Terminator {
+            byte: b'\n',
+            keep: true,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct App {
     write_len: usize,
     write_remaining: usize, // How many bytes didn't fit in the buffer and still need to be printed.
     pending_write: bool,
+    pending_flush: bool,
     read_len: usize,
+    /// Deliver reads a line at a time, with in-kernel backspace editing.
+    line_mode: bool,
+    /// End of line for line mode, set with command `10`.
+    terminator: Terminator,
+    /// Number of bytes of the current line, or of a broadcast read, already
+    /// in the read buffer.
+    read_pos: usize,
+    /// Waiting for bytes in broadcast mode.
+    read_pending: bool,
+    /// The write in flight is the request of a command `9` exchange, so its
+    /// completion is reported through the read callback.
+    exchange: bool,
 }
 
 pub struct Console<'a> {
     uart: &'a dyn uart::UartData<'a>,
     apps: Grant<
         App,
-        UpcallCount<3>,
+        UpcallCount<5>,
         AllowRoCount<{ ro_allow::COUNT }>,
         AllowRwCount<{ rw_allow::COUNT }>,
     >,
     tx_in_progress: OptionalCell<ProcessId>,
     tx_buffer: TakeCell<'static, [u8]>,
+    // Number of app bytes in the transmission in flight
+    tx_len: Cell<usize>,
+    // Number of bytes handed to the UART for those app bytes, which is larger
+    // than `tx_len` if some were escaped
+    tx_out_len: Cell<usize>,
+    // Escape control bytes in outbound writes
+    sanitize: Cell<bool>,
+    // Interface for reconfiguring the line, and its current parameters
+    configure: OptionalCell<&'a dyn uart::Configure>,
+    params: OptionalCell<uart::Parameters>,
     rx_in_progress: OptionalCell<ProcessId>,
     rx_buffer: TakeCell<'static, [u8]>,
+    // Deliver received bytes to every app with a pending read
+    broadcast: Cell<bool>,
+    // One app cancelled its broadcast read, so the receive that was aborted
+    // for it should continue for the others
+    rx_aborting: Cell<bool>,
+    // Peak bytes buffered for transmission and reception
+    high_water: HighWater,
 }
 
 impl<'a> Console<'a> {
@@ -101,7 +175,7 @@ impl<'a> Console<'a> {
         rx_buffer: &'static mut [u8],
         grant: Grant<
             App,
-            UpcallCount<3>,
+            UpcallCount<5>,
             AllowRoCount<{ ro_allow::COUNT }>,
             AllowRwCount<{ rw_allow::COUNT }>,
         >,
@@ -111,9 +185,50 @@ impl<'a> Console<'a> {
             apps: grant,
             tx_in_progress: OptionalCell::empty(),
             tx_buffer: TakeCell::new(tx_buffer),
+            tx_len: Cell::new(0),
+            tx_out_len: Cell::new(0),
+            sanitize: Cell::new(false),
+            configure: OptionalCell::empty(),
+            params: OptionalCell::empty(),
             rx_in_progress: OptionalCell::empty(),
             rx_buffer: TakeCell::new(rx_buffer),
+            broadcast: Cell::new(false),
+            rx_aborting: Cell::new(false),
+            high_water: HighWater::default(),
+        }
+    }
+
+    /// Print control bytes in outbound writes in caret notation rather than
+    /// passing them to the UART. Off by default so that writes are
+    /// transparent to binary data.
+    pub fn set_sanitize(&self, sanitize: bool) {
+        self.sanitize.set(sanitize);
+    }
+
+    /// Deliver received bytes to every app with a read in progress, each
+    /// getting a copy, instead of one app receiving the stream at a time.
+    /// Off by default. Must be set before apps start reading.
+    pub fn set_broadcast(&self, broadcast: bool) {
+        self.broadcast.set(broadcast);
+    }
+
+    /// Allow apps to set the parity, stop bits and data width of the UART.
+    /// `params` must be the configuration the UART currently has.
+    pub fn set_configure(&self, configure: &'a dyn uart::Configure, params: uart::Parameters) {
+        self.configure.set(configure);
+        self.params.set(params);
+    }
+
+    /// Internal helper function for changing the line settings.
+    fn set_line(&self, arg1: usize, arg2: usize) -> Result<(), ErrorCode> {
+        if self.tx_in_progress.is_some() || self.rx_buffer.is_none() {
+            return Err(ErrorCode::BUSY);
         }
+        let configure = self.configure.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        let params = self.params.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        let params = apply_line_settings(configure, params, arg1, arg2)?;
+        self.params.set(params);
+        Ok(())
     }
 
     /// Internal helper function for setting up a new send transaction
@@ -128,11 +243,44 @@ impl<'a> Console<'a> {
             .get_readonly_processbuffer(ro_allow::WRITE)
             .map_or(0, |write| write.len())
             .min(len);
+        if app.write_len == 0 && !self.tx_in_progress.contains(&processid) {
+            // Nothing to send, so complete right away without involving
+            // the UART. If this process has a write in flight, it is
+            // cancelled instead and completes when the UART returns.
+            kernel_data.schedule_upcall(1, (0, 0, 0)).ok();
+            return Ok(());
+        }
         app.write_remaining = app.write_len;
         self.send(processid, app, kernel_data);
         Ok(())
     }
 
+    /// Internal helper function for starting a flush. Completes right away if
+    /// this process has no write queued or in flight, otherwise completes once
+    /// its write has been fully transmitted by the UART.
+    fn flush(
+        &self,
+        processid: ProcessId,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+    ) -> Result<(), ErrorCode> {
+        if app.write_len == 0 && !self.tx_in_progress.contains(&processid) {
+            kernel_data.schedule_upcall(3, (0, 0, 0)).ok();
+        } else {
+            app.pending_flush = true;
+        }
+        Ok(())
+    }
+
+    /// Internal helper function for signalling a pending flush once the
+    /// process's write has completed.
+    fn flush_complete(&self, app: &mut App, kernel_data: &GrantKernelData) {
+        if app.pending_flush {
+            app.pending_flush = false;
+            kernel_data.schedule_upcall(3, (0, 0, 0)).ok();
+        }
+    }
+
     /// Internal helper function for continuing a previously set up transaction.
     /// Returns `true` if this send is still active, or `false` if it has
     /// completed.
@@ -156,7 +304,8 @@ impl<'a> Console<'a> {
         if self.tx_in_progress.is_none() {
             self.tx_in_progress.set(processid);
             self.tx_buffer.take().map(|buffer| {
-                let transaction_len = kernel_data
+                let sanitize = self.sanitize.get();
+                let (transaction_len, out_len) = kernel_data
                     .get_readonly_processbuffer(ro_allow::WRITE)
                     .and_then(|write| {
                         write.enter(|data| {
@@ -175,27 +324,90 @@ impl<'a> Console<'a> {
                                     // to the write done upcall) is correct.
                                     app.write_len -= app.write_remaining;
                                     app.write_remaining = 0;
-                                    return 0;
+                                    return (0, 0);
                                 }
                             };
-                            for (i, c) in remaining_data.iter().enumerate() {
-                                if buffer.len() <= i {
-                                    return i; // Short circuit on partial send
-                                }
-                                buffer[i] = c.get();
-                            }
-                            app.write_remaining
+                            fill_tx(buffer, remaining_data, sanitize)
                         })
                     })
-                    .unwrap_or(0);
+                    .unwrap_or((0, 0));
                 app.write_remaining -= transaction_len;
-                let _ = self.uart.transmit_buffer(buffer, transaction_len);
+                self.tx_len.set(transaction_len);
+                self.tx_out_len.set(out_len);
+                self.high_water.record_tx(out_len);
+                let _ = self.uart.transmit_buffer(buffer, out_len);
             });
         } else {
             app.pending_write = true;
         }
     }
 
+    /// Internal helper function for a request/response exchange. The read is
+    /// armed before the request is written, so that the start of the reply
+    /// cannot be missed, and only the read callback is delivered.
+    fn exchange(
+        &self,
+        processid: ProcessId,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+        write_len: usize,
+        read_len: usize,
+    ) -> Result<(), ErrorCode> {
+        if app.write_len != 0 || self.tx_in_progress.contains(&processid) {
+            return Err(ErrorCode::BUSY);
+        }
+        let writable = kernel_data
+            .get_readonly_processbuffer(ro_allow::WRITE)
+            .map_or(0, |write| write.len())
+            .min(write_len);
+        let readable = kernel_data
+            .get_readwrite_processbuffer(rw_allow::READ)
+            .map_or(0, |read| read.len())
+            .min(read_len);
+        if writable == 0 || readable == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        self.receive_new(processid, app, kernel_data, read_len)?;
+        app.exchange = true;
+        self.send_new(processid, app, kernel_data, write_len)
+    }
+
+    /// Internal helper function for starting a break. The break occupies the
+    /// transmitter like a write, so it waits for no other write and any write
+    /// started during it is sent afterwards.
+    fn send_break(&self, processid: ProcessId, duration_us: usize) -> Result<(), ErrorCode> {
+        if self.tx_in_progress.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let duration_us = match u32::try_from(duration_us) {
+            Ok(0) | Err(_) => return Err(ErrorCode::INVAL),
+            Ok(duration_us) => duration_us,
+        };
+        self.uart.transmit_break(duration_us)?;
+        self.tx_in_progress.set(processid);
+        Ok(())
+    }
+
+    /// Internal helper function for starting the write of the next process
+    /// with one pending, once the UART is free.
+    fn send_pending(&self) {
+        for cntr in self.apps.iter() {
+            let processid = cntr.processid();
+            let started_tx = cntr.enter(|app, kernel_data| {
+                if app.pending_write {
+                    app.pending_write = false;
+                    self.send_continue(processid, app, kernel_data)
+                } else {
+                    false
+                }
+            });
+            if started_tx {
+                break;
+            }
+        }
+    }
+
     /// Internal helper function for starting a receive operation
     fn receive_new(
         &self,
@@ -204,17 +416,52 @@ impl<'a> Console<'a> {
         kernel_data: &GrantKernelData,
         len: usize,
     ) -> Result<(), ErrorCode> {
+        let read_len = kernel_data
+            .get_readwrite_processbuffer(rw_allow::READ)
+            .map_or(0, |read| read.len())
+            .min(len);
+        if read_len == 0 {
+            // Nothing to receive, so complete right away without involving
+            // the UART.
+            kernel_data
+                .schedule_upcall(2, (kernel::errorcode::into_statuscode(Ok(())), 0, 0))
+                .ok();
+            return Ok(());
+        }
+
+        if self.broadcast.get() {
+            if app.read_pending {
+                return Err(ErrorCode::BUSY);
+            }
+            // Bytes are received one at a time and copied to every waiting
+            // app, so each read completes at its own length.
+            app.read_len = read_len;
+            app.read_pos = 0;
+            app.read_pending = true;
+            // Start receiving unless other apps are already waiting.
+            self.rx_buffer.take().map(|buffer| {
+                let _ = self.uart.receive_buffer(buffer, 1);
+            });
+            return Ok(());
+        }
+
         if self.rx_buffer.is_none() {
             // For now, we tolerate only one concurrent receive operation on this console.
             // Competing apps will have to retry until success.
             return Err(ErrorCode::BUSY);
         }
 
-        let read_len = kernel_data
-            .get_readwrite_processbuffer(rw_allow::READ)
-            .map_or(0, |read| read.len())
-            .min(len);
-        if read_len > self.rx_buffer.map_or(0, |buf| buf.len()) {
+        if app.line_mode {
+            // Lines are received a byte at a time so that editing and the
+            // end of the line can be handled as the bytes arrive.
+            app.read_len = read_len;
+            app.read_pos = 0;
+            self.rx_buffer.take().map(|buffer| {
+                self.rx_in_progress.set(processid);
+                let _ = self.uart.receive_buffer(buffer, 1);
+            });
+            Ok(())
+        } else if read_len > self.rx_buffer.map_or(0, |buf| buf.len()) {
             // For simplicity, impose a small maximum receive length
             // instead of doing incremental reads
             Err(ErrorCode::INVAL)
@@ -228,6 +475,138 @@ impl<'a> Console<'a> {
             Ok(())
         }
     }
+
+    /// Handle the bytes received for a line-mode read. Returns `true` if the
+    /// line is not complete and another byte should be received, otherwise
+    /// signals the read callback.
+    fn received_line_byte(
+        &self,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+        received: &[u8],
+        rcode: Result<(), ErrorCode>,
+        error: uart::Error,
+    ) -> bool {
+        let kind = rx_error_kind(error);
+        let res = kernel_data
+            .get_readwrite_processbuffer(rw_allow::READ)
+            .and_then(|read| {
+                read.mut_enter(|data| {
+                    line_input(
+                        data,
+                        app.read_pos,
+                        app.read_len.min(data.len()),
+                        app.terminator,
+                        received,
+                    )
+                })
+            });
+        let (ret, len) = match res {
+            Ok((pos, done)) => {
+                app.read_pos = pos;
+                if !done && kind == rx_error::NONE && rcode.is_ok() {
+                    return true;
+                }
+                // An aborted read still delivers the partial line.
+                let ret = if kind != rx_error::NONE {
+                    Err(ErrorCode::FAIL)
+                } else {
+                    rcode
+                };
+                (ret, pos)
+            }
+            // The buffer disappeared: return NOMEM.
+            Err(_) => (Err(ErrorCode::NOMEM), 0),
+        };
+        kernel_data
+            .schedule_upcall(2, (kernel::errorcode::into_statuscode(ret), len, kind))
+            .ok();
+        false
+    }
+
+    /// Cancel the read of an exchange whose request could not be written. The
+    /// read callback then reports `CANCEL`.
+    fn cancel_exchange_read(&self, app: &mut App, kernel_data: &GrantKernelData) {
+        if self.broadcast.get() {
+            self.receive_abort_broadcast(app, kernel_data);
+        } else {
+            let _ = self.uart.receive_abort();
+        }
+    }
+
+    /// Cancel this app's read in broadcast mode, delivering what it has
+    /// received so far. Reads by other apps continue.
+    fn receive_abort_broadcast(&self, app: &mut App, kernel_data: &GrantKernelData) {
+        if !app.read_pending {
+            return;
+        }
+        app.read_pending = false;
+        kernel_data
+            .schedule_upcall(
+                2,
+                (
+                    kernel::errorcode::into_statuscode(Err(ErrorCode::CANCEL)),
+                    app.read_pos,
+                    rx_error::NONE,
+                ),
+            )
+            .ok();
+
+        // The receive is restarted for the other apps once it has been
+        // aborted, or just stopped if there are none.
+        self.rx_aborting.set(true);
+        if self.uart.receive_abort().is_ok() {
+            self.rx_aborting.set(false);
+        }
+    }
+
+    /// Copy bytes received in broadcast mode into the read buffer of every
+    /// app waiting for them, and keep receiving while any read is pending.
+    fn received_broadcast(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rcode: Result<(), ErrorCode>,
+        error: uart::Error,
+    ) {
+        // A receive aborted for one app is not an error for the others.
+        let rcode = if self.rx_aborting.replace(false) {
+            Ok(())
+        } else {
+            rcode
+        };
+        let kind = rx_error_kind(error);
+        let received = &buffer[..rx_len.min(buffer.len())];
+        let mut waiting = false;
+        self.apps.each(|_, app, kernel_data| {
+            if !app.read_pending {
+                return;
+            }
+            let res = kernel_data
+                .get_readwrite_processbuffer(rw_allow::READ)
+                .and_then(|read| read.mut_enter(|data| broadcast_input(app, data, received)));
+            let (ret, len) = match res {
+                Ok((_, false)) if kind == rx_error::NONE && rcode.is_ok() => {
+                    waiting = true;
+                    return;
+                }
+                Ok((pos, _)) if kind != rx_error::NONE => (Err(ErrorCode::FAIL), pos),
+                Ok((pos, _)) => (rcode, pos),
+                // The buffer disappeared: return NOMEM.
+                Err(_) => (Err(ErrorCode::NOMEM), 0),
+            };
+            app.read_pending = false;
+            kernel_data
+                .schedule_upcall(2, (kernel::errorcode::into_statuscode(ret), len, kind))
+                .ok();
+        });
+
+        if waiting {
+            let _ = self.uart.receive_buffer(buffer, 1);
+        } else {
+            self.rx_buffer.replace(buffer);
+        }
+    }
 }
 
 impl SyscallDriver for Console<'_> {
@@ -247,8 +626,11 @@ impl SyscallDriver for Console<'_> {
     //
     // ### `subscribe_num`
     //
-    // - `1`: Write buffer completed callback
+    // - `1`: Write buffer completed callback, with the number of bytes
+    //        written and a statuscode
     // - `2`: Read buffer completed callback
+    // - `3`: Flush completed callback
+    // - `4`: Break transmitted callback, with a statuscode
 
     /// Initiate serial transfers
     ///
@@ -260,14 +642,37 @@ impl SyscallDriver for Console<'_> {
     /// - `2`: Receives into a buffer passed via `allow`, up to the length
     ///        passed in `arg1`
     /// - `3`: Cancel any in progress receives and return (via callback)
-    ///        what has been received so far.
+    ///        what has been received so far. In broadcast mode only this
+    ///        process's receive is cancelled.
+    /// - `4`: Flush. Completes (via callback) once this process's pending
+    ///        write has been fully transmitted.
+    /// - `5`: Select line-buffered (`arg1` = 1) or raw (`arg1` = 0) input
+    ///        for later receives.
+    /// - `6`: Set the parity (`arg1`: 0 none, 1 odd, 2 even), the stop bits
+    ///        (low byte of `arg2`: 1 or 2) and the data width (second byte of
+    ///        `arg2`: 6, 7 or 8, or 0 to keep the current width).
+    /// - `7`: Get the most bytes buffered for transmission and reception
+    ///        since boot, resetting them afterwards if `arg1` is 1.
+    /// - `8`: Send a break, holding the line low for `arg1` microseconds.
+    ///        Completes (via callback) once the line is idle again. No chip
+    ///        UART implements breaks yet, so this returns NOSUPPORT on
+    ///        hardware, except for a first break that waits behind another
+    ///        user of a shared UART, which reports it in the callback.
+    /// - `9`: Write up to `arg1` bytes and read up to `arg2` bytes of reply,
+    ///        arming the read before the write starts. Completes (via the
+    ///        read callback) once the reply has been received.
+    /// - `10`: Set the byte `arg1` that ends a line in line mode, and select
+    ///         line mode. The terminator is stored at the end of the line if
+    ///         `arg2` is 1, or dropped if it is 0. The default is a newline,
+    ///         stored.
     fn command(
         &self,
         cmd_num: usize,
         arg1: usize,
-        _: usize,
+        arg2: usize,
         processid: ProcessId,
     ) -> CommandReturn {
+        let mut high_water = None;
         let res = self
             .apps
             .enter(processid, |app, kernel_data| {
@@ -285,15 +690,56 @@ impl SyscallDriver for Console<'_> {
                     }
                     3 => {
                         // Abort RX
-                        let _ = self.uart.receive_abort();
+                        if self.broadcast.get() {
+                            self.receive_abort_broadcast(app, kernel_data);
+                        } else {
+                            let _ = self.uart.receive_abort();
+                        }
                         Ok(())
                     }
+                    4 => {
+                        // Flush TX
+                        self.flush(processid, app, kernel_data)
+                    }
+                    5 => {
+                        // Select line-buffered (1) or raw (0) input
+                        match arg1 {
+                            0 | 1 => {
+                                app.line_mode = arg1 == 1;
+                                Ok(())
+                            }
+                            _ => Err(ErrorCode::INVAL),
+                        }
+                    }
+                    6 => {
+                        // Set parity, stop bits and width
+                        self.set_line(arg1, arg2)
+                    }
+                    7 => {
+                        // Buffer high-water marks
+                        high_water = Some(self.high_water.read(arg1 == 1));
+                        Ok(())
+                    }
+                    8 => {
+                        // Send break
+                        self.send_break(processid, arg1)
+                    }
+                    9 => {
+                        // Write then read
+                        self.exchange(processid, app, kernel_data, arg1, arg2)
+                    }
+                    10 => {
+                        // Set the line terminator
+                        set_terminator(app, arg1, arg2)
+                    }
                     _ => Err(ErrorCode::NOSUPPORT),
                 }
             })
             .map_err(ErrorCode::from);
         match res {
-            Ok(Ok(())) => CommandReturn::success(),
+            Ok(Ok(())) => high_water.map_or(CommandReturn::success(), |(tx, rx)| {
+                CommandReturn::success_u32_u32(tx as u32, rx as u32)
+            }),
             Ok(Err(e)) => CommandReturn::failure(e),
             Err(e) => CommandReturn::failure(e),
         }
@@ -308,23 +754,62 @@ impl uart::TransmitClient for Console<'_> {
     fn transmitted_buffer(
         &self,
         buffer: &'static mut [u8],
-        _tx_len: usize,
-        _rcode: Result<(), ErrorCode>,
+        tx_len: usize,
+        rcode: Result<(), ErrorCode>,
     ) {
         // Either print more from the AppSlice or send a callback to the
         // application.
         self.tx_buffer.replace(buffer);
         self.tx_in_progress.take().map(|processid| {
             self.apps.enter(processid, |app, kernel_data| {
+                if let Err(e) = rcode {
+                    // The UART aborted the transmission, so give up on the
+                    // rest of the write and report how far it got.
+                    let unsent = if tx_len >= self.tx_out_len.get() {
+                        0
+                    } else if self.tx_out_len.get() == self.tx_len.get() {
+                        self.tx_len.get() - tx_len
+                    } else {
+                        // Escaped bytes make the UART count differ from the
+                        // app's, so count the whole chunk as unsent.
+                        self.tx_len.get()
+                    };
+                    let written = app
+                        .write_len
+                        .saturating_sub(app.write_remaining)
+                        .saturating_sub(unsent);
+                    app.write_len = 0;
+                    app.write_remaining = 0;
+                    match write_done(core::mem::take(&mut app.exchange), Err(e)) {
+                        WriteDone::Report => {
+                            kernel_data
+                                .schedule_upcall(
+                                    1,
+                                    (written, kernel::errorcode::into_statuscode(Err(e)), 0),
+                                )
+                                .ok();
+                        }
+                        WriteDone::CancelRead => self.cancel_exchange_read(app, kernel_data),
+                        WriteDone::Silent => {}
+                    }
+                    self.flush_complete(app, kernel_data);
+                    return;
+                }
                 match self.send_continue(processid, app, kernel_data) {
                     true => {
                         // Still more to send. Wait to notify the process.
                     }
                     false => {
-                        // Go ahead and signal the application
+                        // Go ahead and signal the application, unless the
+                        // reply to this write is what it is waiting for
                         let written = app.write_len;
                         app.write_len = 0;
-                        kernel_data.schedule_upcall(1, (written, 0, 0)).ok();
+                        if write_done(core::mem::take(&mut app.exchange), Ok(()))
+                            == WriteDone::Report
+                        {
+                            kernel_data.schedule_upcall(1, (written, 0, 0)).ok();
+                        }
+                        self.flush_complete(app, kernel_data);
                     }
                 }
             })
@@ -333,22 +818,21 @@ impl uart::TransmitClient for Console<'_> {
         // If we are not printing more from the current AppSlice,
         // see if any other applications have pending messages.
         if self.tx_in_progress.is_none() {
-            for cntr in self.apps.iter() {
-                let processid = cntr.processid();
-                let started_tx = cntr.enter(|app, kernel_data| {
-                    if app.pending_write {
-                        app.pending_write = false;
-                        self.send_continue(processid, app, kernel_data)
-                    } else {
-                        false
-                    }
-                });
-                if started_tx {
-                    break;
-                }
-            }
+            self.send_pending();
         }
     }
+
+    fn transmitted_break(&self, rcode: Result<(), ErrorCode>) {
+        self.tx_in_progress.take().map(|processid| {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                kernel_data
+                    .schedule_upcall(4, (kernel::errorcode::into_statuscode(rcode), 0, 0))
+                    .ok();
+                self.flush_complete(app, kernel_data);
+            });
+        });
+        self.send_pending();
+    }
 }
 
 impl uart::ReceiveClient for Console<'_> {
@@ -359,100 +843,918 @@ impl uart::ReceiveClient for Console<'_> {
         rcode: Result<(), ErrorCode>,
         error: uart::Error,
     ) {
-        self.rx_in_progress
-            .take()
-            .map(|processid| {
-                self.apps
-                    .enter(processid, |_, kernel_data| {
-                        // An iterator over the returned buffer yielding only the first `rx_len`
-                        // bytes
-                        let rx_buffer = buffer.iter().take(rx_len);
-                        match error {
-                            uart::Error::None | uart::Error::Aborted => {
-                                // Receive some bytes, signal error type and return bytes to process buffer
-                                let count = kernel_data
-                                    .get_readwrite_processbuffer(rw_allow::READ)
-                                    .and_then(|read| {
-                                        read.mut_enter(|data| {
-                                            let mut c = 0;
-                                            for (a, b) in data.iter().zip(rx_buffer) {
-                                                c = c + 1;
-                                                a.set(*b);
-                                            }
-                                            c
-                                        })
-                                    })
-                                    .unwrap_or(-1);
-
-                                // Make sure we report the same number
-                                // of bytes that we actually copied into
-                                // the app's buffer. This is defensive:
-                                // we shouldn't ever receive more bytes
-                                // than will fit in the app buffer since
-                                // we use the app_buffer's length when
-                                // calling `receive()`. However, a buggy
-                                // lower layer could return more bytes
-                                // than we asked for, and we don't want
-                                // to propagate that length error to
-                                // userspace. However, we do return an
-                                // error code so that userspace knows
-                                // something went wrong.
-                                //
-                                // If count < 0 this means the buffer
-                                // disappeared: return NOMEM.
-                                let read_buffer_len = kernel_data
-                                    .get_readwrite_processbuffer(rw_allow::READ)
-                                    .map_or(0, |read| read.len());
-                                let (ret, received_length) = if count < 0 {
-                                    (Err(ErrorCode::NOMEM), 0)
-                                } else if rx_len > read_buffer_len {
-                                    // Return `SIZE` indicating that
-                                    // some received bytes were dropped.
-                                    // We report the length that we
-                                    // actually copied into the buffer,
-                                    // but also indicate that there was
-                                    // an issue in the kernel with the
-                                    // receive.
-                                    (Err(ErrorCode::SIZE), read_buffer_len)
-                                } else {
-                                    // This is the normal and expected
-                                    // case.
-                                    (rcode, rx_len)
-                                };
-
-                                kernel_data
-                                    .schedule_upcall(
-                                        2,
-                                        (
-                                            kernel::errorcode::into_statuscode(ret),
-                                            received_length,
-                                            0,
-                                        ),
-                                    )
-                                    .ok();
-                            }
-                            _ => {
-                                // Some UART error occurred
-                                kernel_data
-                                    .schedule_upcall(
-                                        2,
-                                        (
-                                            kernel::errorcode::into_statuscode(Err(
-                                                ErrorCode::FAIL,
-                                            )),
-                                            0,
-                                            0,
-                                        ),
-                                    )
-                                    .ok();
-                            }
-                        }
-                    })
-                    .unwrap_or_default();
-            })
-            .unwrap_or_default();
+        self.high_water.record_rx(rx_len.min(buffer.len()));
+        if self.broadcast.get() {
+            self.received_broadcast(buffer, rx_len, rcode, error);
+            return;
+        }
+        let continue_line = self.rx_in_progress.take().and_then(|processid| {
+            self.apps
+                .enter(processid, |app, kernel_data| {
+                    if app.line_mode {
+                        return self
+                            .received_line_byte(
+                                app,
+                                kernel_data,
+                                &buffer[..rx_len.min(buffer.len())],
+                                rcode,
+                                error,
+                            )
+                            .then_some(processid);
+                    }
+                    // Receive errors still deliver whatever arrived
+                    // before the error, so copy out the received bytes in
+                    // every case and report the error alongside them.
+                    let received = &buffer[..rx_len.min(buffer.len())];
+                    let (ret, received_length, kind) = kernel_data
+                        .get_readwrite_processbuffer(rw_allow::READ)
+                        .and_then(|read| {
+                            read.mut_enter(|data| copy_received(data, received, rcode, error))
+                        })
+                        // The buffer disappeared: return NOMEM.
+                        .unwrap_or((Err(ErrorCode::NOMEM), 0, rx_error::NONE));
+
+                    kernel_data
+                        .schedule_upcall(
+                            2,
+                            (
+                                kernel::errorcode::into_statuscode(ret),
+                                received_length,
+                                kind,
+                            ),
+                        )
+                        .ok();
+                    None
+                })
+                .unwrap_or_default()
+        });
+
+        match continue_line {
+            Some(processid) => {
+                // The line is not complete yet, so wait for the next byte.
+                self.rx_in_progress.set(processid);
+                let _ = self.uart.receive_buffer(buffer, 1);
+            }
+            None => {
+                // Whatever happens, we want to make sure to replace the
+                // rx_buffer for future transactions
+                self.rx_buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+/// What to report when a write completes.
+#[derive(Debug, PartialEq)]
+enum WriteDone {
+    /// Call the write callback.
+    Report,
+    /// The write was the request of an exchange: the read callback reports
+    /// the reply.
+    Silent,
+    /// The request of an exchange failed, so cancel the read that was
+    /// waiting for its reply.
+    CancelRead,
+}
+
+fn write_done(exchange: bool, rcode: Result<(), ErrorCode>) -> WriteDone {
+    match (exchange, rcode) {
+        (false, _) => WriteDone::Report,
+        (true, Ok(())) => WriteDone::Silent,
+        (true, Err(_)) => WriteDone::CancelRead,
+    }
+}
+
+/// The most bytes the console has held in its transmit and receive buffers,
+/// reported by command `7`.
+#[derive(Default)]
+struct HighWater {
+    tx: Cell<usize>,
+    rx: Cell<usize>,
+}
+
+impl HighWater {
+    fn record_tx(&self, len: usize) {
+        self.tx.set(self.tx.get().max(len));
+    }
+
+    fn record_rx(&self, len: usize) {
+        self.rx.set(self.rx.get().max(len));
+    }
+
+    /// Returns the transmit and receive marks, starting both again from zero
+    /// if `reset` is set.
+    fn read(&self, reset: bool) -> (usize, usize) {
+        let marks = (self.tx.get(), self.rx.get());
+        if reset {
+            self.tx.set(0);
+            self.rx.set(0);
+        }
+        marks
+    }
+}
+
+/// Decode the parity, stop bits and width of command `6` and apply them on top
+/// of `params`. Returns the new parameters if `uart` accepted them; encodings
+/// that do not name a setting are rejected with `INVAL`, and settings the UART
+/// cannot satisfy with its error (usually `NOSUPPORT`).
+fn apply_line_settings(
+    uart: &dyn uart::Configure,
+    mut params: uart::Parameters,
+    arg1: usize,
+    arg2: usize,
+) -> Result<uart::Parameters, ErrorCode> {
+    params.parity = match arg1 {
+        0 => uart::Parity::None,
+        1 => uart::Parity::Odd,
+        2 => uart::Parity::Even,
+        _ => return Err(ErrorCode::INVAL),
+    };
+    params.stop_bits = match arg2 & 0xff {
+        1 => uart::StopBits::One,
+        2 => uart::StopBits::Two,
+        _ => return Err(ErrorCode::INVAL),
+    };
+    params.width = match arg2 >> 8 {
+        0 => params.width,
+        6 => uart::Width::Six,
+        7 => uart::Width::Seven,
+        8 => uart::Width::Eight,
+        _ => return Err(ErrorCode::INVAL),
+    };
+    uart.configure(params)?;
+    Ok(params)
+}
+
+/// Copy bytes to transmit from an app's write buffer into `out`. With
+/// `sanitize` set, control bytes other than tab, newline and carriage return
+/// are written in caret notation (ESC becomes `^[`, DEL becomes `^?`), and an
+/// escaped byte is never split across two transmissions. Returns the number of
+/// app bytes consumed and the number of bytes written to `out`.
+fn fill_tx(out: &mut [u8], data: &ReadableProcessSlice, sanitize: bool) -> (usize, usize) {
+    let mut produced = 0;
+    for (consumed, c) in data.iter().enumerate() {
+        let b = c.get();
+        let escaped = sanitize && (b < 0x20 || b == 0x7f) && !matches!(b, b'\t' | b'\n' | b'\r');
+        let width = if escaped { 2 } else { 1 };
+        if out.len() < produced + width {
+            // Short circuit on partial send
+            return (consumed, produced);
+        }
+        if escaped {
+            out[produced] = b'^';
+            out[produced + 1] = b ^ 0x40;
+        } else {
+            out[produced] = b;
+        }
+        produced += width;
+    }
+    (data.len(), produced)
+}
+
+/// Copy bytes returned by the UART into an app's read buffer, returning the
+/// status, the number of bytes copied and the `rx_error` kind to report in the
+/// read callback.
+fn copy_received(
+    data: &WriteableProcessSlice,
+    received: &[u8],
+    rcode: Result<(), ErrorCode>,
+    error: uart::Error,
+) -> (Result<(), ErrorCode>, usize, usize) {
+    let mut count = 0;
+    for (a, b) in data.iter().zip(received) {
+        count += 1;
+        a.set(*b);
+    }
+
+    let kind = rx_error_kind(error);
+    let ret = if kind != rx_error::NONE {
+        Err(ErrorCode::FAIL)
+    } else if received.len() > count {
+        // Make sure we report the same number of bytes that we actually
+        // copied into the app's buffer. This is defensive: we shouldn't ever
+        // receive more bytes than will fit in the app buffer since we use the
+        // app_buffer's length when calling `receive()`. However, a buggy lower
+        // layer could return more bytes than we asked for, and we don't want
+        // to propagate that length error to userspace. Return `SIZE`
+        // indicating that some received bytes were dropped.
+        Err(ErrorCode::SIZE)
+    } else {
+        // This is the normal and expected case.
+        rcode
+    };
+
+    (ret, count, kind)
+}
+
+/// Map a UART receive error to the `rx_error` kind reported to apps.
+fn rx_error_kind(error: uart::Error) -> usize {
+    match error {
+        uart::Error::None | uart::Error::Aborted => rx_error::NONE,
+        uart::Error::ParityError => rx_error::PARITY,
+        uart::Error::FramingError => rx_error::FRAMING,
+        uart::Error::OverrunError => rx_error::OVERRUN,
+        _ => rx_error::OTHER,
+    }
+}
+
+/// Set the terminator of an app's line-mode reads to the byte `byte`,
+/// stored at the end of the line if `keep` is 1, and select line mode.
+fn set_terminator(app: &mut App, byte: usize, keep: usize) -> Result<(), ErrorCode> {
+    let byte = u8::try_from(byte).map_err(|_| ErrorCode::INVAL)?;
+    let keep = match keep {
+        0 => false,
+        1 => true,
+        _ => return Err(ErrorCode::INVAL),
+    };
+    app.terminator = Terminator { byte, keep };
+    app.line_mode = true;
+    Ok(())
+}
+
+/// Add bytes received in broadcast mode to an app's pending read, in line
+/// mode or raw. Returns the length of the read so far and whether it is
+/// complete.
+fn broadcast_input(app: &mut App, data: &WriteableProcessSlice, received: &[u8]) -> (usize, bool) {
+    let limit = app.read_len.min(data.len());
+    let (pos, done) = if app.line_mode {
+        line_input(data, app.read_pos, limit, app.terminator, received)
+    } else {
+        let mut pos = app.read_pos;
+        for (a, b) in data[pos.min(limit)..limit].iter().zip(received) {
+            a.set(*b);
+            pos += 1;
+        }
+        (pos, pos >= limit)
+    };
+    app.read_pos = pos;
+    (pos, done)
+}
+
+/// Add received bytes to a line being edited in an app's read buffer, starting
+/// at `pos`. Backspace and delete remove the previous byte. Returns the new
+/// length of the line and whether it is complete, which is when the
+/// terminator has arrived or the line reached `limit` bytes. The terminator
+/// is stored only if `term.keep` is set. Bytes after the end of the line are
+/// ignored.
+fn line_input(
+    data: &WriteableProcessSlice,
+    mut pos: usize,
+    limit: usize,
+    term: Terminator,
+    received: &[u8],
+) -> (usize, bool) {
+    for &b in received {
+        match b {
+            _ if b == term.byte => {
+                if term.keep && pos < data.len() {
+                    data[pos].set(b);
+                    pos += 1;
+                }
+                return (pos, true);
+            }
+            BACKSPACE | DELETE => pos = pos.saturating_sub(1),
+            _ => {
+                if pos < data.len() {
+                    data[pos].set(b);
+                    pos += 1;
+                }
+            }
+        }
+        if pos >= limit {
+            return (pos, true);
+        }
+    }
+    (pos, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak_buffer, QueuedUpcall, TestKernel};
+    use kernel::syscall::SyscallReturn;
+
+    /// A UART that holds on to the buffers it is given until the test
+    /// completes the transfer.
+    struct MockUart {
+        applied: OptionalCell<uart::Parameters>,
+        tx: TakeCell<'static, [u8]>,
+        tx_len: Cell<usize>,
+        rx: TakeCell<'static, [u8]>,
+        rx_len: Cell<usize>,
+    }
+
+    impl MockUart {
+        fn new() -> Self {
+            MockUart {
+                applied: OptionalCell::empty(),
+                tx: TakeCell::empty(),
+                tx_len: Cell::new(0),
+                rx: TakeCell::empty(),
+                rx_len: Cell::new(0),
+            }
+        }
+
+        /// Whether the transmission in flight is `expected`.
+        fn sending(&self, expected: &[u8]) -> bool {
+            self.tx
+                .map_or(false, |tx| &tx[..self.tx_len.get()] == expected)
+        }
+
+        /// Complete the transmission in flight after `len` bytes.
+        fn transmitted(
+            &self,
+            client: &dyn uart::TransmitClient,
+            len: usize,
+            rcode: Result<(), ErrorCode>,
+        ) {
+            let buffer = self.tx.take().expect("no transmission in flight");
+            client.transmitted_buffer(buffer, len, rcode);
+        }
+
+        /// Complete the receive in flight with `bytes`.
+        fn receive(&self, client: &dyn uart::ReceiveClient, bytes: &[u8]) {
+            let buffer = self.rx.take().expect("no receive in flight");
+            assert!(bytes.len() <= self.rx_len.get());
+            buffer[..bytes.len()].copy_from_slice(bytes);
+            client.received_buffer(buffer, bytes.len(), Ok(()), uart::Error::None);
+        }
+    }
+
+    impl<'a> uart::Transmit<'a> for MockUart {
+        fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            assert!(self.tx.is_none());
+            self.tx.replace(tx_buffer);
+            self.tx_len.set(tx_len);
+            Ok(())
+        }
+
+        fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn transmit_abort(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    impl<'a> uart::Receive<'a> for MockUart {
+        fn set_receive_client(&self, _client: &'a dyn uart::ReceiveClient) {}
+
+        fn receive_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            assert!(self.rx.is_none());
+            self.rx.replace(rx_buffer);
+            self.rx_len.set(rx_len);
+            Ok(())
+        }
+
+        fn receive_word(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn receive_abort(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    impl uart::Configure for MockUart {
+        fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+            // Like many UARTs, no parity bit with a 6-bit word.
+            if params.width == uart::Width::Six && params.parity != uart::Parity::None {
+                return Err(ErrorCode::NOSUPPORT);
+            }
+            self.applied.set(params);
+            Ok(())
+        }
+    }
+
+    fn params_8n1() -> uart::Parameters {
+        uart::Parameters {
+            baud_rate: 115200,
+            width: uart::Width::Eight,
+            parity: uart::Parity::None,
+            stop_bits: uart::StopBits::One,
+            hw_flow_control: false,
+        }
+    }
+
+    #[test]
+    fn unsupported_break_fails_through_a_shared_uart() {
+        use crate::virtualizers::virtual_uart::{MuxUart, UartDevice};
+        use capsules_testing::leak;
+
+        let kernel = TestKernel::new(&["app"]);
+        let uart = leak(MockUart::new());
+        let mux = leak(MuxUart::new(uart, leak_buffer(16), 115200));
+        let device = leak(UartDevice::new(mux, false));
+        device.setup();
+        let console = leak(Console::new(
+            device,
+            leak_buffer(16),
+            leak_buffer(16),
+            kernel.create_grant(DRIVER_NUM),
+        ));
+        uart::Transmit::set_transmit_client(device, console);
+        let app = kernel.process(0);
+        app.subscribe(console, DRIVER_NUM, 4);
+
+        // The UART cannot send a break, and the app is told straight away.
+        assert!(matches!(
+            app.command(console, DRIVER_NUM, 8, 750, 0),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ));
+        assert!(app.take_upcalls().is_empty());
+    }
+
+    fn console<'a>(kernel: &TestKernel, uart: &'a MockUart, tx_len: usize) -> Console<'a> {
+        Console::new(
+            uart,
+            leak_buffer(tx_len),
+            leak_buffer(16),
+            kernel.create_grant(DRIVER_NUM),
+        )
+    }
+
+    #[test]
+    fn zero_length_transfers_complete_at_once() {
+        let kernel = TestKernel::new(&["app"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 16);
+        let app = kernel.process(0);
+        app.subscribe(&console, DRIVER_NUM, 1);
+        app.subscribe(&console, DRIVER_NUM, 2);
+
+        // A write with no buffer allowed sends nothing.
+        assert!(matches!(
+            app.command(&console, DRIVER_NUM, 1, 5, 0),
+            SyscallReturn::Success
+        ));
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 1, (0, 0, 0))]
+        );
+
+        // Nor does one of zero bytes from an allowed buffer, and neither
+        // direction reaches the UART.
+        let write = app.buffer(b"hello");
+        app.allow_readonly(&console, DRIVER_NUM, ro_allow::WRITE, write);
+        let read = app.buffer(&[0; 8]);
+        app.allow_readwrite(&console, DRIVER_NUM, rw_allow::READ, read);
+        assert!(matches!(
+            app.command(&console, DRIVER_NUM, 1, 0, 0),
+            SyscallReturn::Success
+        ));
+        assert!(matches!(
+            app.command(&console, DRIVER_NUM, 2, 0, 0),
+            SyscallReturn::Success
+        ));
+        assert_eq!(
+            app.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, 1, (0, 0, 0)),
+                QueuedUpcall::new(DRIVER_NUM, 2, (0, 0, 0)),
+            ]
+        );
+        assert!(uart.tx.is_none());
+        assert!(uart.rx.is_none());
+    }
+
+    #[test]
+    fn tx_error_reports_bytes_written() {
+        let kernel = TestKernel::new(&["app"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 4);
+        let app = kernel.process(0);
+        app.subscribe(&console, DRIVER_NUM, 1);
+        let write = app.buffer(b"hello, world");
+        app.allow_readonly(&console, DRIVER_NUM, ro_allow::WRITE, write);
+
+        // The write goes out four bytes at a time, and the UART fails
+        // halfway through the second chunk.
+        app.command(&console, DRIVER_NUM, 1, 12, 0);
+        assert!(uart.sending(b"hell"));
+        uart.transmitted(&console, 4, Ok(()));
+        assert!(uart.sending(b"o, w"));
+        assert!(app.take_upcalls().is_empty());
+        uart.transmitted(&console, 2, Err(ErrorCode::FAIL));
+
+        // Only the bytes the UART sent are reported, and the rest of the
+        // write is dropped.
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(
+                DRIVER_NUM,
+                1,
+                (
+                    6,
+                    kernel::errorcode::into_statuscode(Err(ErrorCode::FAIL)),
+                    0
+                )
+            )]
+        );
+        assert!(uart.tx.is_none());
+    }
+
+    #[test]
+    fn flush_waits_for_queued_write() {
+        let kernel = TestKernel::new(&["first", "second"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 4);
+        let (first, second) = (kernel.process(0), kernel.process(1));
+        for (app, contents) in [(first, &b"abcd"[..]), (second, b"efgh")] {
+            app.subscribe(&console, DRIVER_NUM, 1);
+            app.subscribe(&console, DRIVER_NUM, 3);
+            let write = app.buffer(contents);
+            app.allow_readonly(&console, DRIVER_NUM, ro_allow::WRITE, write);
+        }
+
+        // The second write queues behind the first, and its flush waits
+        // for it to be sent.
+        first.command(&console, DRIVER_NUM, 1, 4, 0);
+        second.command(&console, DRIVER_NUM, 1, 4, 0);
+        second.command(&console, DRIVER_NUM, 4, 0, 0);
+        assert!(second.take_upcalls().is_empty());
+
+        uart.transmitted(&console, 4, Ok(()));
+        assert_eq!(
+            first.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 1, (4, 0, 0))]
+        );
+        assert!(uart.sending(b"efgh"));
+        assert!(second.take_upcalls().is_empty());
+
+        uart.transmitted(&console, 4, Ok(()));
+        assert_eq!(
+            second.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, 1, (4, 0, 0)),
+                QueuedUpcall::new(DRIVER_NUM, 3, (0, 0, 0)),
+            ]
+        );
+
+        // With nothing left to send, a flush completes at once.
+        first.command(&console, DRIVER_NUM, 4, 0, 0);
+        assert_eq!(
+            first.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 3, (0, 0, 0))]
+        );
+    }
+
+    #[test]
+    fn configure_7e1() {
+        let mock = MockUart::new();
+
+        let params = apply_line_settings(&mock, params_8n1(), 2, 7 << 8 | 1).unwrap();
+        let applied = mock.applied.extract().unwrap();
+        assert_eq!(applied.baud_rate, 115200);
+        assert_eq!(applied.width, uart::Width::Seven);
+        assert_eq!(applied.parity, uart::Parity::Even);
+        assert_eq!(applied.stop_bits, uart::StopBits::One);
+        assert_eq!(params.width, applied.width);
+    }
+
+    #[test]
+    fn configure_rejects_unsupported_settings() {
+        let mock = MockUart::new();
+
+        assert_eq!(
+            apply_line_settings(&mock, params_8n1(), 3, 1).err(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            apply_line_settings(&mock, params_8n1(), 0, 3).err(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            apply_line_settings(&mock, params_8n1(), 1, 6 << 8 | 1).err(),
+            Some(ErrorCode::NOSUPPORT)
+        );
+        assert!(mock.applied.is_none());
+
+        // 8N2 is accepted and keeps the current width.
+        apply_line_settings(&mock, params_8n1(), 0, 2).unwrap();
+        let applied = mock.applied.extract().unwrap();
+        assert_eq!(applied.width, uart::Width::Eight);
+        assert_eq!(applied.stop_bits, uart::StopBits::Two);
+    }
+
+    #[test]
+    fn high_water_marks() {
+        let marks = HighWater::default();
+        let mut tx_buffer = [0u8; 8];
+
+        // Writes are split into transmissions no larger than the buffer, and
+        // escaped bytes take up two bytes of it.
+        for write in [&b"hi"[..], b"hello, world", b"\x1b\x1b\x1b"] {
+            let slice: &ReadableProcessSlice = write.into();
+            let mut sent = 0;
+            while sent < write.len() {
+                let (consumed, out_len) = fill_tx(&mut tx_buffer, &slice[sent..], true);
+                marks.record_tx(out_len);
+                sent += consumed;
+            }
+        }
+        for rx_len in [3, 16, 1] {
+            marks.record_rx(rx_len);
+        }
+        assert_eq!(marks.read(false), (8, 16));
+
+        // Reading with reset starts the marks again from the next transfer.
+        assert_eq!(marks.read(true), (8, 16));
+        marks.record_tx(6);
+        marks.record_rx(2);
+        assert_eq!(marks.read(false), (6, 2));
+    }
+
+    #[test]
+    fn sanitize_escapes_ansi_sequence() {
+        let data = *b"a\x1b[31mb\r\n";
+        let slice: &ReadableProcessSlice = (&data[..]).into();
+        let mut out = [0u8; 16];
+
+        assert_eq!(fill_tx(&mut out, slice, true), (9, 10));
+        assert_eq!(&out[..10], b"a^[[31mb\r\n");
+
+        // Without sanitizing the bytes are passed through unchanged.
+        assert_eq!(fill_tx(&mut out, slice, false), (9, 9));
+        assert_eq!(&out[..9], &data[..]);
+    }
+
+    #[test]
+    fn sanitize_does_not_split_escapes() {
+        let data = *b"ab\x1b";
+        let slice: &ReadableProcessSlice = (&data[..]).into();
+        let mut out = [0u8; 3];
+
+        assert_eq!(fill_tx(&mut out, slice, true), (2, 2));
+        assert_eq!(&out[..2], b"ab");
+    }
+
+    #[test]
+    fn framing_error_keeps_partial_data() {
+        let mut data = [0u8; 8];
+        let buffer: &WriteableProcessSlice = (&mut data[..]).into();
+
+        let (ret, len, kind) = copy_received(
+            buffer,
+            &[1, 2, 3],
+            Err(ErrorCode::FAIL),
+            uart::Error::FramingError,
+        );
+        assert_eq!(ret, Err(ErrorCode::FAIL));
+        assert_eq!(len, 3);
+        assert_eq!(kind, rx_error::FRAMING);
+        assert_eq!(data, [1, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn line_mode_stops_at_newline() {
+        let kernel = TestKernel::new(&["app"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 16);
+        let app = kernel.process(0);
+        app.subscribe(&console, DRIVER_NUM, 2);
+        let read = app.buffer(&[0; 8]);
+        app.allow_readwrite(&console, DRIVER_NUM, rw_allow::READ, read);
+        app.command(&console, DRIVER_NUM, 5, 1, 0);
+
+        // Bytes arrive one at a time, and the app starts a new read as soon
+        // as a line is delivered.
+        for line in [b"ab\n", b"cd\n"] {
+            assert!(matches!(
+                app.command(&console, DRIVER_NUM, 2, 8, 0),
+                SyscallReturn::Success
+            ));
+            for b in line {
+                assert!(app.take_upcalls().is_empty());
+                uart.receive(&console, &[*b]);
+            }
+            assert_eq!(
+                app.take_upcalls(),
+                [QueuedUpcall::new(DRIVER_NUM, 2, (0, 3, 0))]
+            );
+            assert_eq!(app.read(read)[..3], *line);
+        }
+        assert!(uart.rx.is_none());
+    }
+
+    #[test]
+    fn line_mode_backspace_and_full_buffer() {
+        let mut data = [0u8; 3];
+        let buffer: &WriteableProcessSlice = (&mut data[..]).into();
+
+        let term = Terminator::default();
+        assert_eq!(line_input(buffer, 0, 3, term, b"ax\x08b"), (2, false));
+        assert_eq!(line_input(buffer, 2, 3, term, b"cd"), (3, true));
+        assert_eq!(data, *b"abc");
+    }
+
+    #[test]
+    fn carriage_return_terminator() {
+        let kernel = TestKernel::new(&["app"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 16);
+        let app = kernel.process(0);
+        app.subscribe(&console, DRIVER_NUM, 2);
+        let read = app.buffer(&[0; 8]);
+        app.allow_readwrite(&console, DRIVER_NUM, rw_allow::READ, read);
+
+        let terminator = |byte, keep| app.command(&console, DRIVER_NUM, 10, byte, keep);
+        assert!(matches!(
+            terminator(0x100, 0),
+            SyscallReturn::Failure(ErrorCode::INVAL)
+        ));
+        assert!(matches!(
+            terminator(b'\r' as usize, 2),
+            SyscallReturn::Failure(ErrorCode::INVAL)
+        ));
+
+        // Setting the terminator selects line mode, and a dropped terminator
+        // is not delivered.
+        assert!(matches!(
+            terminator(b'\r' as usize, 0),
+            SyscallReturn::Success
+        ));
+        app.command(&console, DRIVER_NUM, 2, 8, 0);
+        for b in b"hi\r" {
+            assert!(app.take_upcalls().is_empty());
+            uart.receive(&console, &[*b]);
+        }
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 2, (0, 2, 0))]
+        );
+        assert_eq!(app.read(read)[..3], *b"hi\0");
+
+        // When kept, the terminator ends the delivered line.
+        terminator(b'\r' as usize, 1);
+        app.command(&console, DRIVER_NUM, 2, 8, 0);
+        for b in b"ok\r" {
+            uart.receive(&console, &[*b]);
+        }
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 2, (0, 3, 0))]
+        );
+        assert_eq!(app.read(read)[..3], *b"ok\r");
+    }
+
+    #[test]
+    fn broadcast_line_and_raw_readers() {
+        // Two apps reading five bytes each, one raw and one line-buffered,
+        // see the same bytes as they arrive.
+        let mut raw = App {
+            read_len: 5,
+            read_pending: true,
+            ..App::default()
+        };
+        let mut line = App {
+            read_len: 5,
+            line_mode: true,
+            read_pending: true,
+            ..App::default()
+        };
+        let mut raw_data = [0u8; 8];
+        let mut line_data = [0u8; 8];
+        let raw_buffer: &WriteableProcessSlice = (&mut raw_data[..]).into();
+        let line_buffer: &WriteableProcessSlice = (&mut line_data[..]).into();
+
+        let mut done = (false, false);
+        for b in b"hi
+tock"
+        {
+            if !done.0 {
+                done.0 = broadcast_input(&mut raw, raw_buffer, &[*b]).1;
+            }
+            if !done.1 {
+                done.1 = broadcast_input(&mut line, line_buffer, &[*b]).1;
+            }
+        }
+        assert_eq!(done, (true, true));
+        assert_eq!(
+            &raw_data[..raw.read_pos],
+            b"hi
+to"
+        );
+        assert_eq!(
+            &line_data[..line.read_pos],
+            b"hi
+"
+        );
+    }
+
+    #[test]
+    fn broadcast_readers_receive_same_bytes() {
+        let kernel = TestKernel::new(&["short", "long"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 16);
+        console.set_broadcast(true);
+        let (short, long) = (kernel.process(0), kernel.process(1));
+        let [short_read, long_read] = [(short, 2), (long, 4)].map(|(app, len)| {
+            app.subscribe(&console, DRIVER_NUM, 2);
+            let read = app.buffer(&[0; 4]);
+            app.allow_readwrite(&console, DRIVER_NUM, rw_allow::READ, read);
+            assert!(matches!(
+                app.command(&console, DRIVER_NUM, 2, len, 0),
+                SyscallReturn::Success
+            ));
+            read
+        });
+
+        // Each byte goes to both apps, and each read completes at its own
+        // length.
+        for b in b"abcd" {
+            uart.receive(&console, &[*b]);
+        }
+        assert_eq!(
+            short.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 2, (0, 2, 0))]
+        );
+        assert_eq!(
+            long.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 2, (0, 4, 0))]
+        );
+        assert_eq!(short.read(short_read), *b"ab\0\0");
+        assert_eq!(long.read(long_read), *b"abcd");
+        // Nobody is waiting any more, so the console stops receiving.
+        assert!(uart.rx.is_none());
+    }
+
+    #[test]
+    fn exchange_reports_only_the_reply() {
+        // A device answering the request: the read was armed before the
+        // write, so the whole reply lands in the read buffer.
+        let mut data = [0u8; 8];
+        let buffer: &WriteableProcessSlice = (&mut data[..]).into();
+
+        // Request sent: no write callback, and the read callback carries
+        // the reply.
+        assert_eq!(write_done(true, Ok(())), WriteDone::Silent);
+        let (ret, len, kind) = copy_received(buffer, b"OK\r\n", Ok(()), uart::Error::None);
+        assert_eq!((ret, len, kind), (Ok(()), 4, rx_error::NONE));
+        assert_eq!(&data[..4], b"OK\r\n");
+
+        // A failed request cancels the read, and plain writes still report.
+        assert_eq!(
+            write_done(true, Err(ErrorCode::FAIL)),
+            WriteDone::CancelRead
+        );
+        assert_eq!(write_done(false, Ok(())), WriteDone::Report);
+        assert_eq!(write_done(false, Err(ErrorCode::FAIL)), WriteDone::Report);
+    }
+
+    #[test]
+    fn exchange_delivers_echoed_reply() {
+        let kernel = TestKernel::new(&["app"]);
+        let uart = MockUart::new();
+        let console = console(&kernel, &uart, 16);
+        let app = kernel.process(0);
+        app.subscribe(&console, DRIVER_NUM, 1);
+        app.subscribe(&console, DRIVER_NUM, 2);
+        let write = app.buffer(b"AT\r");
+        app.allow_readonly(&console, DRIVER_NUM, ro_allow::WRITE, write);
+        let read = app.buffer(&[0; 8]);
+        app.allow_readwrite(&console, DRIVER_NUM, rw_allow::READ, read);
+
+        // The read is armed by the time the request goes out.
+        assert!(matches!(
+            app.command(&console, DRIVER_NUM, 9, 3, 3),
+            SyscallReturn::Success
+        ));
+        assert!(uart.rx.is_some());
+        assert!(uart.sending(b"AT\r"));
+
+        // The write completes silently, and the line echoes the request
+        // back as the reply.
+        uart.transmitted(&console, 3, Ok(()));
+        assert!(app.take_upcalls().is_empty());
+        uart.receive(&console, b"AT\r");
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 2, (0, 3, rx_error::NONE))]
+        );
+        assert_eq!(&app.read(read)[..3], b"AT\r");
+    }
+
+    #[test]
+    fn oversized_receive_reports_size() {
+        let mut data = [0u8; 2];
+        let buffer: &WriteableProcessSlice = (&mut data[..]).into();
 
-        // Whatever happens, we want to make sure to replace the rx_buffer for future transactions
-        self.rx_buffer.replace(buffer);
+        let (ret, len, kind) = copy_received(buffer, &[1, 2, 3], Ok(()), uart::Error::None);
+        assert_eq!(ret, Err(ErrorCode::SIZE));
+        assert_eq!(len, 2);
+        assert_eq!(kind, rx_error::NONE);
+        assert_eq!(data, [1, 2]);
     }
 }
@@ -0,0 +1,196 @@
+//! One-shot millisecond delays for other capsules.
+//!
+//! `Delay` wraps an alarm, normally a `VirtualMuxAlarm`, so that a capsule
+//! that needs to wait before its next step (for example, between the phases
+//! of a bus recovery sequence) does not have to convert milliseconds to ticks
+//! and handle alarm callbacks itself. It is an internal building block for
+//! capsules and does not provide a system call interface.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+//! # use capsules_core::delay::Delay;
+//!
+//! let delay_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! delay_alarm.setup();
+//! let delay = static_init!(
+//!     Delay<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     Delay::new(delay_alarm)
+//! );
+//! delay_alarm.set_alarm_client(delay);
+//!
+//! // Later, from a capsule implementing `DelayClient`:
+//! delay.delay_ms(50, my_capsule);
+//! ```
+
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+/// Client notified when a delay has elapsed.
+pub trait DelayClient {
+    fn delay_done(&self);
+}
+
+pub struct Delay<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn DelayClient>,
+}
+
+impl<'a, A: Alarm<'a>> Delay<'a, A> {
+    pub fn new(alarm: &'a A) -> Delay<'a, A> {
+        Delay {
+            alarm,
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Call `client.delay_done()` once `ms` milliseconds have passed. Only one
+    /// delay may be pending at a time; returns `BUSY` otherwise.
+    pub fn delay_ms(&self, ms: u32, client: &'a dyn DelayClient) -> Result<(), ErrorCode> {
+        if self.client.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.client.set(client);
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(ms));
+        Ok(())
+    }
+
+    /// Cancel the pending delay, if any, without calling its client.
+    pub fn cancel(&self) {
+        if self.client.take().is_some() {
+            let _ = self.alarm.disarm();
+        }
+    }
+
+    /// Whether a delay is pending.
+    pub fn is_pending(&self) -> bool {
+        self.client.is_some()
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for Delay<'a, A> {
+    fn alarm(&self) {
+        self.client.take().map(|client| client.delay_done());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use kernel::hil::time::{Freq1KHz, Ticks, Ticks32, Time};
+
+    struct FakeAlarm<'a> {
+        now: Cell<Ticks32>,
+        reference: Cell<Ticks32>,
+        dt: Cell<Ticks32>,
+        armed: Cell<bool>,
+        client: OptionalCell<&'a dyn AlarmClient>,
+    }
+
+    impl FakeAlarm<'_> {
+        fn new() -> Self {
+            Self {
+                now: Cell::new(1_000u32.into()),
+                reference: Cell::new(0u32.into()),
+                dt: Cell::new(0u32.into()),
+                armed: Cell::new(false),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        /// Advances time by `ticks`, calling the client if the alarm expires.
+        fn advance(&self, ticks: u32) {
+            self.now.set(self.now.get().wrapping_add(ticks.into()));
+            let expired = !self
+                .now
+                .get()
+                .within_range(self.reference.get(), self.get_alarm());
+            if self.armed.get() && expired {
+                self.armed.set(false);
+                self.client.map(|c| c.alarm());
+            }
+        }
+    }
+
+    impl Time for FakeAlarm<'_> {
+        type Ticks = Ticks32;
+        type Frequency = Freq1KHz;
+
+        fn now(&self) -> Ticks32 {
+            self.now.get()
+        }
+    }
+
+    impl<'a> Alarm<'a> for FakeAlarm<'a> {
+        fn set_alarm_client(&self, client: &'a dyn AlarmClient) {
+            self.client.set(client);
+        }
+
+        fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+            self.reference.set(reference);
+            self.dt.set(dt);
+            self.armed.set(true);
+        }
+
+        fn get_alarm(&self) -> Self::Ticks {
+            self.reference.get().wrapping_add(self.dt.get())
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            self.armed.set(false);
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+
+        fn minimum_dt(&self) -> Self::Ticks {
+            0u32.into()
+        }
+    }
+
+    struct Counter<'a>(&'a Cell<u32>);
+
+    impl DelayClient for Counter<'_> {
+        fn delay_done(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn fires_after_50ms() {
+        let fired = Cell::new(0);
+        let client = Counter(&fired);
+        let alarm = FakeAlarm::new();
+        let delay = Delay::new(&alarm);
+        alarm.set_alarm_client(&delay);
+
+        assert_eq!(delay.delay_ms(50, &client), Ok(()));
+        assert_eq!(delay.delay_ms(10, &client), Err(ErrorCode::BUSY));
+
+        // At 1 kHz, 50ms is 50 ticks after the delay was started.
+        alarm.advance(49);
+        assert_eq!(fired.get(), 0);
+        assert!(delay.is_pending());
+        alarm.advance(1);
+        assert_eq!(fired.get(), 1);
+        assert_eq!(alarm.now().into_u32(), 1_050);
+        assert!(!delay.is_pending());
+
+        // Another delay can be started once the first has completed.
+        assert_eq!(delay.delay_ms(10, &client), Ok(()));
+        delay.cancel();
+        alarm.advance(100);
+        assert_eq!(fired.get(), 1);
+    }
+}
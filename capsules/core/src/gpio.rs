@@ -46,32 +46,383 @@
 //!
 //! The GPIO interface provides only one callback, which is used for pins that
 //! have had interrupts enabled.
+//!
+//! The kernel also counts the interrupts of each of the first
+//! `EDGE_COUNTERS` pins, so that apps only interested in how many edges
+//! occurred can poll the count instead of waking up for every edge.
+//!
+//! Boards that give the capsule a `GpioCoalescer` (see `set_coalescer`) let
+//! apps set a minimum interval between callbacks for a pin. Edges during the
+//! interval are counted and reported in a single callback once it expires.
+//!
+//! By default every process with the callback subscribed is woken by the
+//! interrupts of every pin. A process can instead watch specific pins (command
+//! 15), and is then only woken by edges on those pins.
+//!
+//! Boards that give the capsule a clock (see `set_edge_clock`) let apps
+//! receive the tick count at which each interrupt was handled (command 16),
+//! for measuring pulse widths without the skew of a later read.
+//!
+//! Boards where several apps share the pins can call
+//! `set_pin_ownership(true)`. The first process to configure one of the first
+//! `OWNED_PINS` pins then owns it, and other processes get `RESERVE` when they
+//! configure or write it, until the owner releases it (command 20) or exits.
 
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Gpio as usize;
 
+use core::cell::Cell;
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::gpio;
 use kernel::hil::gpio::{Configure, Input, InterruptWithValue, Output};
+use kernel::hil::time::{self, Alarm, ConvertTicks, Ticks};
 use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
 use kernel::{ErrorCode, ProcessId};
 
 /// ### `subscribe_num`
 ///
 /// - `0`: Subscribe to interrupts from all pins with interrupts enabled.
-///        The callback signature is `fn(pin_num: usize, pin_state: bool)`
+///        The callback signature is
+///        `fn(pin_num: usize, pin_state: bool, coalesced: usize)`, where
+///        `coalesced` is the number of edges a coalescing window held back,
+///        or 0 for a plain interrupt. With timestamps enabled it is
+///        `fn(pin_num: usize, pin_state | coalesced << 1, ticks: usize)`.
 const UPCALL_NUM: usize = 0;
 
+/// Number of pins, starting from pin 0, that a process can watch.
+const WATCHABLE_PINS: usize = 32;
+
+#[derive(Default)]
+pub struct App {
+    /// Bitmask of the pins whose interrupts this process watches. If no pin
+    /// is watched, the process receives the interrupts of every pin.
+    watched: u32,
+    /// Whether this process receives the tick count of each interrupt.
+    timestamps: bool,
+}
+
+/// Whether a process watching the pins in `watched` is woken by an interrupt
+/// on `pin`.
+fn watches(watched: u32, pin: usize) -> bool {
+    watched == 0 || (pin < WATCHABLE_PINS && watched & (1 << pin) != 0)
+}
+
+/// Clock used to timestamp pin interrupts.
+///
+/// The capsule only reads it, so a free-running counter is enough and no
+/// alarm has to be set aside; any `hil::time::Time` will do.
+pub trait EdgeClock {
+    /// Returns the current raw tick value, truncated to 32 bits.
+    fn now_raw(&self) -> u32;
+
+    /// Returns the number of ticks per second.
+    fn frequency(&self) -> u32;
+}
+
+impl<T: time::Time> EdgeClock for T {
+    fn now_raw(&self) -> u32 {
+        self.now().into_u32()
+    }
+
+    fn frequency(&self) -> u32 {
+        <T::Frequency as time::Frequency>::frequency()
+    }
+}
+
+/// The upcall arguments for an interrupt on `pin`. `ticks` is the time of
+/// the interrupt if the process asked for timestamps.
+fn upcall_args(
+    pin: usize,
+    pin_state: bool,
+    coalesced: u32,
+    ticks: Option<u32>,
+) -> (usize, usize, usize) {
+    match ticks {
+        Some(ticks) => (
+            pin,
+            pin_state as usize | (coalesced as usize) << 1,
+            ticks as usize,
+        ),
+        None => (pin, pin_state as usize, coalesced as usize),
+    }
+}
+
+/// Capability bits returned by command 12 for a pin.
+mod capability {
+    pub const INPUT: u32 = 1 << 0;
+    pub const OUTPUT: u32 = 1 << 1;
+    pub const INTERRUPT: u32 = 1 << 2;
+    pub const PULL: u32 = 1 << 3;
+}
+
+/// Number of pins, starting from pin 0, whose interrupts are counted.
+pub const EDGE_COUNTERS: usize = 32;
+
+/// Number of pins, starting from pin 0, that a process can own.
+pub const OWNED_PINS: usize = 32;
+
+/// How a command affects the pins it names, with pin ownership enforced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PinUse {
+    /// Reads the pins, or does not name any.
+    Read,
+    /// Changes the pins without configuring them.
+    Write,
+    /// Configures the pins, claiming them if nobody owns them.
+    Configure,
+    /// Gives up ownership of the pins.
+    Release,
+}
+
+impl PinUse {
+    fn of(command_num: usize) -> PinUse {
+        match command_num {
+            1 | 5 | 7 | 13 | 17 | 18 | 19 => PinUse::Configure,
+            2 | 3 | 4 | 8 | 9 | 10 | 14 => PinUse::Write,
+            20 => PinUse::Release,
+            _ => PinUse::Read,
+        }
+    }
+}
+
+/// The process, if any, that owns each of the first `OWNED_PINS` pins.
+struct PinOwners<T> {
+    owners: [Cell<Option<T>>; OWNED_PINS],
+}
+
+impl<T: Copy + PartialEq> PinOwners<T> {
+    fn new() -> Self {
+        Self {
+            owners: [(); OWNED_PINS].map(|()| Cell::new(None)),
+        }
+    }
+
+    /// Whether `who` may use the pins selected by `mask` as `pin_use` says.
+    /// Reading is always allowed. Anything else fails with `RESERVE` if
+    /// another process that is still `alive` owns one of the pins.
+    fn check(
+        &self,
+        mask: u32,
+        who: T,
+        pin_use: PinUse,
+        alive: impl Fn(T) -> bool,
+    ) -> Result<(), ErrorCode> {
+        if pin_use == PinUse::Read {
+            return Ok(());
+        }
+        let taken = self.selected(mask).any(|owner| match owner.get() {
+            Some(owner) => owner != who && alive(owner),
+            None => false,
+        });
+        if taken {
+            Err(ErrorCode::RESERVE)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record that `who` has used the pins selected by `mask` as `pin_use`
+    /// says, once `check` has allowed it and the command has succeeded:
+    /// configuring claims the pins, and releasing frees them.
+    fn update(&self, mask: u32, who: T, pin_use: PinUse) {
+        match pin_use {
+            PinUse::Configure => self.selected(mask).for_each(|owner| owner.set(Some(who))),
+            PinUse::Release => self.selected(mask).for_each(|owner| owner.set(None)),
+            PinUse::Write | PinUse::Read => {}
+        }
+    }
+
+    fn selected(&self, mask: u32) -> impl Iterator<Item = &Cell<Option<T>>> {
+        self.owners
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| mask & (1 << i) != 0)
+            .map(|(_, owner)| owner)
+    }
+}
+
+/// The ownable pins that command `command_num` with first argument `data1`
+/// names, as a bitmask. Pins that are not present are left out, as the
+/// command fails on them anyway.
+fn owned_mask<P>(pins: &[Option<&P>], command_num: usize, data1: usize) -> u32 {
+    let mask = match command_num {
+        10 | 17 => data1 as u32,
+        _ if data1 < OWNED_PINS => 1 << data1,
+        _ => 0,
+    };
+    let present = pins
+        .iter()
+        .take(OWNED_PINS)
+        .enumerate()
+        .filter(|(_, pin)| pin.is_some())
+        .fold(0, |present, (i, _)| present | 1 << i);
+    mask & present
+}
+
+/// Per-pin count of interrupts seen since the count was last reset.
+struct EdgeCounters {
+    counts: [Cell<u32>; EDGE_COUNTERS],
+}
+
+impl EdgeCounters {
+    fn new() -> Self {
+        Self {
+            counts: [(); EDGE_COUNTERS].map(|()| Cell::new(0)),
+        }
+    }
+
+    fn record(&self, pin: usize) {
+        if let Some(count) = self.counts.get(pin) {
+            count.set(count.get().wrapping_add(1));
+        }
+    }
+
+    /// Returns the count for `pin`, zeroing it afterwards if `reset` is set,
+    /// or `None` if the pin is not counted.
+    fn read(&self, pin: usize, reset: bool) -> Option<u32> {
+        self.counts
+            .get(pin)
+            .map(|count| if reset { count.take() } else { count.get() })
+    }
+}
+
+/// Receives the edges a `Coalesce` held back once a pin's window expires.
+pub trait CoalesceClient {
+    fn coalesced(&self, pin: usize, count: u32);
+}
+
+/// Holds back the interrupts of pins that have a minimum callback interval.
+pub trait Coalesce<'a> {
+    fn set_client(&self, client: &'a dyn CoalesceClient);
+
+    /// Set the minimum interval between callbacks of `pin` in milliseconds,
+    /// or disable coalescing for the pin with 0.
+    fn set_window(&self, pin: usize, ms: u32) -> Result<(), ErrorCode>;
+
+    /// Record an edge on `pin`. Returns `true` if the edge is held back until
+    /// the pin's window expires, or `false` if it should be delivered now.
+    fn edge(&self, pin: usize) -> bool;
+}
+
+/// Coalesces the interrupts of the first `EDGE_COUNTERS` pins using an
+/// alarm. The first edge on a pin opens its window, and every edge seen
+/// until the window expires is reported in one `coalesced` call.
+pub struct GpioCoalescer<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn CoalesceClient>,
+    /// Window length of each pin, 0 if coalescing is disabled.
+    windows: [Cell<A::Ticks>; EDGE_COUNTERS],
+    /// Edges held back in the open window, 0 if no window is open.
+    counts: [Cell<u32>; EDGE_COUNTERS],
+    /// When the open window started.
+    references: [Cell<A::Ticks>; EDGE_COUNTERS],
+}
+
+impl<'a, A: Alarm<'a>> GpioCoalescer<'a, A> {
+    pub fn new(alarm: &'a A) -> Self {
+        Self {
+            alarm,
+            client: OptionalCell::empty(),
+            windows: [(); EDGE_COUNTERS].map(|()| Cell::new(A::Ticks::from(0))),
+            counts: [(); EDGE_COUNTERS].map(|()| Cell::new(0)),
+            references: [(); EDGE_COUNTERS].map(|()| Cell::new(A::Ticks::from(0))),
+        }
+    }
+
+    /// Arm the alarm for the open window that expires first, or disarm it if
+    /// no window is open.
+    fn rearm(&self) {
+        let now = self.alarm.now();
+        let mut earliest: Option<(A::Ticks, A::Ticks)> = None;
+        for pin in 0..EDGE_COUNTERS {
+            if self.counts[pin].get() == 0 {
+                continue;
+            }
+            let reference = self.references[pin].get();
+            let dt = self.windows[pin].get();
+            let remaining = reference.wrapping_add(dt).wrapping_sub(now);
+            if earliest.map_or(true, |(r, d)| {
+                remaining < r.wrapping_add(d).wrapping_sub(now)
+            }) {
+                earliest = Some((reference, dt));
+            }
+        }
+        match earliest {
+            Some((reference, dt)) => self.alarm.set_alarm(reference, dt),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Coalesce<'a> for GpioCoalescer<'a, A> {
+    fn set_client(&self, client: &'a dyn CoalesceClient) {
+        self.client.set(client);
+    }
+
+    fn set_window(&self, pin: usize, ms: u32) -> Result<(), ErrorCode> {
+        let window = self.windows.get(pin).ok_or(ErrorCode::NOSUPPORT)?;
+        window.set(self.alarm.ticks_from_ms(ms));
+        if window.get() == A::Ticks::from(0) && self.counts[pin].get() != 0 {
+            // Deliver what the closed window held back rather than drop it.
+            let count = self.counts[pin].take();
+            self.client.map(|client| client.coalesced(pin, count));
+            self.rearm();
+        }
+        Ok(())
+    }
+
+    fn edge(&self, pin: usize) -> bool {
+        match self.windows.get(pin) {
+            Some(window) if window.get() != A::Ticks::from(0) => {}
+            _ => return false,
+        }
+        let count = self.counts[pin].get();
+        self.counts[pin].set(count.saturating_add(1));
+        if count == 0 {
+            self.references[pin].set(self.alarm.now());
+            self.rearm();
+        }
+        true
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for GpioCoalescer<'a, A> {
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        for pin in 0..EDGE_COUNTERS {
+            let count = self.counts[pin].get();
+            if count == 0 {
+                continue;
+            }
+            let reference = self.references[pin].get();
+            let end = reference.wrapping_add(self.windows[pin].get());
+            if !now.within_range(reference, end) {
+                self.counts[pin].set(0);
+                self.client.map(|client| client.coalesced(pin, count));
+            }
+        }
+        self.rearm();
+    }
+}
+
 pub struct GPIO<'a, IP: gpio::InterruptPin<'a>> {
     pins: &'a [Option<&'a gpio::InterruptValueWrapper<'a, IP>>],
-    apps: Grant<(), UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+    edge_counts: EdgeCounters,
+    coalescer: OptionalCell<&'a dyn Coalesce<'a>>,
+    edge_clock: OptionalCell<&'a dyn EdgeClock>,
+    ownership: Cell<bool>,
+    owners: PinOwners<ProcessId>,
 }
 
 impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
     pub fn new(
         pins: &'a [Option<&'a gpio::InterruptValueWrapper<'a, IP>>],
-        grant: Grant<(), UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
+        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<0>>,
     ) -> Self {
         for (i, maybe_pin) in pins.iter().enumerate() {
             if let Some(pin) = maybe_pin {
@@ -81,9 +432,50 @@ impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
         Self {
             pins: pins,
             apps: grant,
+            edge_counts: EdgeCounters::new(),
+            coalescer: OptionalCell::empty(),
+            edge_clock: OptionalCell::empty(),
+            ownership: Cell::new(false),
+            owners: PinOwners::new(),
         }
     }
 
+    /// Make the first process to configure a pin its owner, so that other
+    /// processes cannot configure or write it until it is released.
+    pub fn set_pin_ownership(&self, enforce: bool) {
+        self.ownership.set(enforce);
+    }
+
+    /// Let apps timestamp pin interrupts with command 16.
+    pub fn set_edge_clock(&self, clock: &'a dyn EdgeClock) {
+        self.edge_clock.set(clock);
+    }
+
+    /// Let apps coalesce pin interrupts with command 13.
+    pub fn set_coalescer(&'a self, coalescer: &'a dyn Coalesce<'a>) {
+        coalescer.set_client(self);
+        self.coalescer.set(coalescer);
+    }
+
+    fn schedule_pin_upcall(&self, pin_num: usize, pin_state: bool, coalesced: u32) {
+        // Sample the clock once, before any process is visited, so every
+        // process sees the same time for the interrupt.
+        let ticks = self.edge_clock.map(|clock| clock.now_raw());
+        self.apps.each(|_, app, upcalls| {
+            if watches(app.watched, pin_num) {
+                let ticks = ticks.filter(|_| app.timestamps);
+                // Scheduling the upcall also makes a yielded process
+                // runnable again.
+                upcalls
+                    .schedule_upcall(
+                        UPCALL_NUM,
+                        upcall_args(pin_num, pin_state, coalesced, ticks),
+                    )
+                    .ok();
+            }
+        });
+    }
+
     fn configure_input_pin(&self, pin_num: u32, config: usize) -> CommandReturn {
         let maybe_pin = self.pins[pin_num as usize];
         if let Some(pin) = maybe_pin {
@@ -142,13 +534,23 @@ impl<'a, IP: gpio::InterruptPin<'a>> gpio::ClientWithValue for GPIO<'a, IP> {
         let pins = self.pins.as_ref();
         if let Some(pin) = pins[pin_num as usize] {
             let pin_state = pin.read();
+            self.edge_counts.record(pin_num as usize);
+
+            // edges inside a coalescing window are reported when it expires
+            if self.coalescer.map_or(false, |c| c.edge(pin_num as usize)) {
+                return;
+            }
 
             // schedule callback with the pin number and value
-            self.apps.each(|_, _, upcalls| {
-                upcalls
-                    .schedule_upcall(UPCALL_NUM, (pin_num as usize, pin_state as usize, 0))
-                    .ok();
-            });
+            self.schedule_pin_upcall(pin_num as usize, pin_state, 0);
+        }
+    }
+}
+
+impl<'a, IP: gpio::InterruptPin<'a>> CoalesceClient for GPIO<'a, IP> {
+    fn coalesced(&self, pin: usize, count: u32) {
+        if let Some(Some(p)) = self.pins.get(pin) {
+            self.schedule_pin_upcall(pin, p.read(), count);
         }
     }
 }
@@ -184,16 +586,52 @@ impl<'a, IP: gpio::InterruptPin<'a>> SyscallDriver for GPIO<'a, IP> {
     /// - `7`: Configure interrupt on `pin` with `irq_config` in 0x00XX00000
     /// - `8`: Disable interrupt on `pin`.
     /// - `9`: Disable `pin`.
+    /// - `10`: Write the pins selected by the bitmask `data1` to the matching
+    ///         bits of `data2`, in one pass.
+    /// - `11`: Read the number of interrupts seen on `pin`, resetting the
+    ///         count to zero if `data2` is `1`.
+    /// - `12`: Get the capabilities of `pin` as a bitfield.
+    /// - `13`: Set the minimum interval between callbacks of `pin` to `data2`
+    ///         milliseconds, or stop coalescing its interrupts with 0.
+    /// - `14`: Disable interrupts on `pin` and hand it back to its peripheral
+    ///         function.
+    /// - `15`: Watch (`data2` = 1) or stop watching (`data2` = 0) `pin`, so
+    ///         that this process is only woken by interrupts on watched pins.
+    /// - `16`: Enable (`data1` = 1) or disable (`data1` = 0) interrupt
+    ///         timestamps for this process. Returns the clock frequency in Hz.
+    /// - `17`: Configure every pin selected by the bitmask `data1` the same
+    ///         way: `data2` is `0` for output, or `1` + `pin_config` for
+    ///         input. On failure, returns the index of the first bad pin.
+    /// - `18`: Enable (`data2` = 1) or disable (`data2` = 0) the hardware
+    ///         glitch filter of input `pin`.
+    /// - `19`: Set the output drive strength of `pin` to standard
+    ///         (`data2` = 0) or high (`data2` = 1).
+    /// - `20`: Release this process's ownership of `pin`.
+    ///
+    /// With pin ownership enforced, the commands that configure a pin claim
+    /// it if they succeed, and the commands that configure or write a pin
+    /// owned by another process fail with `RESERVE`.
     fn command(
         &self,
         command_num: usize,
         data1: usize,
         data2: usize,
-        _: ProcessId,
+        processid: ProcessId,
     ) -> CommandReturn {
         let pins = self.pins.as_ref();
         let pin_index = data1;
-        match command_num {
+        let pin_use = PinUse::of(command_num);
+        let owned = if self.ownership.get() {
+            let mask = owned_mask(pins, command_num, data1);
+            let alive = |owner| self.apps.enter(owner, |_, _| {}).is_ok();
+            if let Err(e) = self.owners.check(mask, processid, pin_use, alive) {
+                return CommandReturn::failure(e);
+            }
+            Some(mask)
+        } else {
+            None
+        };
+        let result = match command_num {
             // number of pins
             0 => CommandReturn::success_u32(pins.len() as u32),
 
@@ -327,12 +765,966 @@ impl<'a, IP: gpio::InterruptPin<'a>> SyscallDriver for GPIO<'a, IP> {
                 }
             }
 
+            // write masked group of pins
+            10 => match write_masked(pins, data1, data2) {
+                Ok(()) => CommandReturn::success(),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // read (and optionally reset) edge count
+            11 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else if pins[pin_index].is_none() {
+                    CommandReturn::failure(ErrorCode::NODEVICE)
+                } else {
+                    match (data2, self.edge_counts.read(pin_index, data2 == 1)) {
+                        (0 | 1, Some(count)) => CommandReturn::success_u32(count),
+                        (0 | 1, None) => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                        _ => CommandReturn::failure(ErrorCode::INVAL),
+                    }
+                }
+            }
+
+            // pin capabilities
+            12 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    match pin_capabilities(pins[pin_index]) {
+                        Ok(caps) => CommandReturn::success_u32(caps),
+                        Err(e) => CommandReturn::failure(e),
+                    }
+                }
+            }
+
+            // set interrupt coalescing window
+            13 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else if pins[pin_index].is_none() {
+                    CommandReturn::failure(ErrorCode::NODEVICE)
+                } else {
+                    self.coalescer
+                        .map_or(Err(ErrorCode::NOSUPPORT), |c| {
+                            c.set_window(pin_index, data2 as u32)
+                        })
+                        .into()
+                }
+            }
+
+            // release pin to its peripheral function
+            14 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    if let Some(pin) = pins[pin_index] {
+                        pin.disable_interrupts();
+                        release_to_function(pin).into()
+                    } else {
+                        CommandReturn::failure(ErrorCode::NODEVICE)
+                    }
+                }
+            }
+
+            // watch pin
+            15 => {
+                if pin_index >= pins.len() || data2 > 1 {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else if pin_index >= WATCHABLE_PINS {
+                    CommandReturn::failure(ErrorCode::NOSUPPORT)
+                } else {
+                    self.apps
+                        .enter(processid, |app, _| {
+                            if data2 == 1 {
+                                app.watched |= 1 << pin_index;
+                            } else {
+                                app.watched &= !(1 << pin_index);
+                            }
+                            CommandReturn::success()
+                        })
+                        .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+                }
+            }
+
+            // interrupt timestamps
+            16 => {
+                if data1 > 1 {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.edge_clock
+                        .map_or(CommandReturn::failure(ErrorCode::NOSUPPORT), |clock| {
+                            self.apps
+                                .enter(processid, |app, _| {
+                                    app.timestamps = data1 == 1;
+                                    CommandReturn::success_u32(clock.frequency())
+                                })
+                                .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+                        })
+                }
+            }
+
+            // configure masked group of pins
+            17 => match BatchConfig::from_mode(data2) {
+                Some(config) => match configure_masked(pins, data1, config) {
+                    Ok(()) => CommandReturn::success(),
+                    Err((e, index)) => CommandReturn::failure_u32(e, index as u32),
+                },
+                None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+            },
+
+            // hardware glitch filter
+            18 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    pins[pin_index]
+                        .map_or(Err(ErrorCode::NODEVICE), |pin| glitch_filter(pin, data2))
+                        .into()
+                }
+            }
+
+            // output drive strength
+            19 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    pins[pin_index]
+                        .map_or(Err(ErrorCode::NODEVICE), |pin| drive_strength(pin, data2))
+                        .into()
+                }
+            }
+
+            // release pin ownership
+            20 => {
+                if pin_index >= pins.len() {
+                    /* impossible pin */
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else if pins[pin_index].is_none() {
+                    CommandReturn::failure(ErrorCode::NODEVICE)
+                } else {
+                    // Ownership is released below.
+                    CommandReturn::success()
+                }
+            }
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+        // A command that fails, for example on a bad argument, leaves the
+        // pins' owners unchanged.
+        if let Some(mask) = owned.filter(|_| result.is_success()) {
+            self.owners.update(mask, processid, pin_use);
         }
+        result
     }
 
     fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
         self.apps.enter(processid, |_, _| {})
     }
 }
+
+/// Returns the `capability` bits of a pin. Fails with `NODEVICE` if the pin
+/// is not present, and `NOSUPPORT` if the chip does not report what it
+/// supports.
+fn pin_capabilities<P: Configure>(pin: Option<&P>) -> Result<u32, ErrorCode> {
+    let pin = pin.ok_or(ErrorCode::NODEVICE)?;
+    pin.capabilities().ok_or(ErrorCode::NOSUPPORT).map(|caps| {
+        [
+            (caps.input, capability::INPUT),
+            (caps.output, capability::OUTPUT),
+            (caps.interrupt, capability::INTERRUPT),
+            (caps.pull, capability::PULL),
+        ]
+        .iter()
+        .filter(|(supported, _)| *supported)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    })
+}
+
+/// Hands `pin` to its peripheral function, failing with `FAIL` if the pin
+/// does not end up configured for it.
+fn release_to_function<P: Configure>(pin: &P) -> Result<(), ErrorCode> {
+    match pin.make_function()? {
+        gpio::Configuration::Function => Ok(()),
+        _ => Err(ErrorCode::FAIL),
+    }
+}
+
+/// Enables (`mode` 1) or disables (`mode` 0) the hardware glitch filter of
+/// `pin`.
+fn glitch_filter<P: Configure>(pin: &P, mode: usize) -> Result<(), ErrorCode> {
+    match mode {
+        0 | 1 => pin.set_glitch_filter(mode == 1),
+        _ => Err(ErrorCode::INVAL),
+    }
+}
+
+/// Sets the output drive strength of `pin` to standard (`level` 0) or high
+/// (`level` 1).
+fn drive_strength<P: Configure>(pin: &P, level: usize) -> Result<(), ErrorCode> {
+    match level {
+        0 => pin.set_drive_strength(gpio::DriveStrength::Standard),
+        1 => pin.set_drive_strength(gpio::DriveStrength::High),
+        _ => Err(ErrorCode::INVAL),
+    }
+}
+
+/// Sets each pin selected by `mask` to the corresponding bit of `value`.
+///
+/// All selected pins are checked before any is written, so that the writes
+/// happen back-to-back and an invalid mask leaves every pin untouched.
+fn write_masked<P: Output>(
+    pins: &[Option<&P>],
+    mask: usize,
+    value: usize,
+) -> Result<(), ErrorCode> {
+    if pins.len() < usize::BITS as usize && mask >> pins.len() != 0 {
+        return Err(ErrorCode::INVAL);
+    }
+    let selected = pins.iter().enumerate().take(usize::BITS as usize);
+    for (i, pin) in selected.clone() {
+        if mask & (1 << i) != 0 && pin.is_none() {
+            return Err(ErrorCode::NODEVICE);
+        }
+    }
+    for (i, pin) in selected {
+        if mask & (1 << i) != 0 {
+            pin.map(|pin| {
+                if value & (1 << i) != 0 {
+                    pin.set()
+                } else {
+                    pin.clear()
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Configuration applied to each pin by command 17.
+#[derive(Clone, Copy)]
+enum BatchConfig {
+    Output,
+    Input(gpio::FloatingState),
+}
+
+impl BatchConfig {
+    /// Decodes the mode argument of command 17: `0` for output, and `1`-`3`
+    /// for input with the `pin_config` of command 5 plus one.
+    fn from_mode(mode: usize) -> Option<BatchConfig> {
+        match mode {
+            0 => Some(BatchConfig::Output),
+            1 => Some(BatchConfig::Input(gpio::FloatingState::PullNone)),
+            2 => Some(BatchConfig::Input(gpio::FloatingState::PullUp)),
+            3 => Some(BatchConfig::Input(gpio::FloatingState::PullDown)),
+            _ => None,
+        }
+    }
+}
+
+/// Applies `config` to each pin selected by `mask`.
+///
+/// As with `write_masked`, every selected pin is checked first, so that an
+/// invalid mask leaves every pin untouched. The error carries the index of the
+/// lowest selected pin that is out of range (`INVAL`) or not present
+/// (`NODEVICE`).
+fn configure_masked<P: Configure>(
+    pins: &[Option<&P>],
+    mask: usize,
+    config: BatchConfig,
+) -> Result<(), (ErrorCode, usize)> {
+    let mut bits = mask;
+    while bits != 0 {
+        let i = bits.trailing_zeros() as usize;
+        match pins.get(i) {
+            None => return Err((ErrorCode::INVAL, i)),
+            Some(None) => return Err((ErrorCode::NODEVICE, i)),
+            Some(Some(_)) => {}
+        }
+        bits &= bits - 1;
+    }
+    for (i, pin) in pins.iter().enumerate().take(usize::BITS as usize) {
+        if mask & (1 << i) != 0 {
+            pin.map(|pin| match config {
+                BatchConfig::Output => {
+                    pin.make_output();
+                }
+                BatchConfig::Input(floating) => {
+                    pin.make_input();
+                    pin.set_floating_state(floating);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak, QueuedUpcall, TestKernel};
+    use core::cell::Cell;
+    use kernel::hil::time::{AlarmClient, Freq1KHz, Ticks32, Time};
+    use kernel::process::Process;
+    use kernel::syscall::SyscallReturn;
+
+    struct MockPin(Cell<bool>);
+
+    impl Output for MockPin {
+        fn set(&self) {
+            self.0.set(true);
+        }
+
+        fn clear(&self) {
+            self.0.set(false);
+        }
+
+        fn toggle(&self) -> bool {
+            self.0.set(!self.0.get());
+            self.0.get()
+        }
+    }
+
+    /// Pin whose configuration is tracked, optionally with a peripheral
+    /// function it can be muxed to, a glitch filter enable register and a
+    /// selectable drive strength.
+    struct MockConfigPin {
+        config: Cell<gpio::Configuration>,
+        has_function: bool,
+        gfer: Option<Cell<u32>>,
+        drive: Option<Cell<gpio::DriveStrength>>,
+    }
+
+    const MOCK_PIN_MASK: u32 = 1 << 6;
+
+    impl Configure for MockConfigPin {
+        fn configuration(&self) -> gpio::Configuration {
+            self.config.get()
+        }
+        fn make_output(&self) -> gpio::Configuration {
+            self.config.set(gpio::Configuration::Output);
+            self.config.get()
+        }
+        fn disable_output(&self) -> gpio::Configuration {
+            self.config.set(gpio::Configuration::LowPower);
+            self.config.get()
+        }
+        fn make_input(&self) -> gpio::Configuration {
+            self.config.set(gpio::Configuration::Input);
+            self.config.get()
+        }
+        fn disable_input(&self) -> gpio::Configuration {
+            self.config.set(gpio::Configuration::LowPower);
+            self.config.get()
+        }
+        fn deactivate_to_low_power(&self) {
+            self.config.set(gpio::Configuration::LowPower);
+        }
+        fn set_floating_state(&self, _state: gpio::FloatingState) {}
+        fn floating_state(&self) -> gpio::FloatingState {
+            gpio::FloatingState::PullNone
+        }
+        fn make_function(&self) -> Result<gpio::Configuration, ErrorCode> {
+            if !self.has_function {
+                return Err(ErrorCode::NOSUPPORT);
+            }
+            self.config.set(gpio::Configuration::Function);
+            Ok(self.config.get())
+        }
+        fn set_glitch_filter(&self, enable: bool) -> Result<(), ErrorCode> {
+            let gfer = self.gfer.as_ref().ok_or(ErrorCode::NOSUPPORT)?;
+            if enable {
+                gfer.set(gfer.get() | MOCK_PIN_MASK);
+            } else {
+                gfer.set(gfer.get() & !MOCK_PIN_MASK);
+            }
+            Ok(())
+        }
+        fn set_drive_strength(&self, strength: gpio::DriveStrength) -> Result<(), ErrorCode> {
+            let drive = self.drive.as_ref().ok_or(ErrorCode::NOSUPPORT)?;
+            drive.set(strength);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn release_pin_to_function() {
+        let capable = MockConfigPin {
+            config: Cell::new(gpio::Configuration::Output),
+            has_function: true,
+            gfer: None,
+            drive: None,
+        };
+        assert_eq!(release_to_function(&capable), Ok(()));
+        assert!(matches!(
+            capable.configuration(),
+            gpio::Configuration::Function
+        ));
+
+        let plain = MockConfigPin {
+            config: Cell::new(gpio::Configuration::Output),
+            has_function: false,
+            gfer: None,
+            drive: None,
+        };
+        assert_eq!(release_to_function(&plain), Err(ErrorCode::NOSUPPORT));
+        assert!(matches!(plain.configuration(), gpio::Configuration::Output));
+    }
+
+    #[test]
+    fn glitch_filter_sets_pin_bit() {
+        let capable = MockConfigPin {
+            config: Cell::new(gpio::Configuration::Input),
+            has_function: false,
+            gfer: Some(Cell::new(0b1)),
+            drive: None,
+        };
+        assert_eq!(glitch_filter(&capable, 1), Ok(()));
+        assert_eq!(
+            capable.gfer.as_ref().map(Cell::get),
+            Some(0b1 | MOCK_PIN_MASK)
+        );
+        assert_eq!(glitch_filter(&capable, 0), Ok(()));
+        assert_eq!(capable.gfer.as_ref().map(Cell::get), Some(0b1));
+        assert_eq!(glitch_filter(&capable, 2), Err(ErrorCode::INVAL));
+
+        let plain = config_pins::<1>();
+        assert_eq!(glitch_filter(&plain[0], 1), Err(ErrorCode::NOSUPPORT));
+    }
+
+    #[test]
+    fn drive_strength_configures_capable_pin() {
+        let capable = MockConfigPin {
+            config: Cell::new(gpio::Configuration::Output),
+            has_function: false,
+            gfer: None,
+            drive: Some(Cell::new(gpio::DriveStrength::Standard)),
+        };
+        assert_eq!(drive_strength(&capable, 1), Ok(()));
+        assert_eq!(
+            capable.drive.as_ref().map(Cell::get),
+            Some(gpio::DriveStrength::High)
+        );
+        assert_eq!(drive_strength(&capable, 2), Err(ErrorCode::INVAL));
+        assert_eq!(
+            capable.drive.as_ref().map(Cell::get),
+            Some(gpio::DriveStrength::High)
+        );
+        assert_eq!(drive_strength(&capable, 0), Ok(()));
+        assert_eq!(
+            capable.drive.as_ref().map(Cell::get),
+            Some(gpio::DriveStrength::Standard)
+        );
+
+        // A pin without selectable drive strength refuses high drive.
+        let plain = config_pins::<1>();
+        assert_eq!(drive_strength(&plain[0], 1), Err(ErrorCode::NOSUPPORT));
+    }
+
+    #[test]
+    fn owned_pin_rejects_other_process() {
+        const A: usize = 1;
+        const B: usize = 2;
+        let owners = PinOwners::new();
+        let pin = 1 << 3;
+        let alive: fn(usize) -> bool = |_| true;
+        let access = |mask, who, pin_use, alive: fn(usize) -> bool| {
+            owners.check(mask, who, pin_use, alive)?;
+            owners.update(mask, who, pin_use);
+            Ok(())
+        };
+
+        // A configures the pin and so claims it; B cannot write it.
+        let configure = PinUse::of(1);
+        let write = PinUse::of(2);
+        assert_eq!(access(pin, A, configure, alive), Ok(()));
+        assert_eq!(access(pin, B, write, alive), Err(ErrorCode::RESERVE));
+        assert_eq!(
+            access(pin | 1, B, PinUse::of(10), alive),
+            Err(ErrorCode::RESERVE)
+        );
+        assert_eq!(access(1, B, write, alive), Ok(()));
+        assert_eq!(access(pin, B, PinUse::of(6), alive), Ok(()));
+        assert_eq!(access(pin, A, write, alive), Ok(()));
+
+        // Only the owner can release it, after which B succeeds.
+        let release = PinUse::of(20);
+        assert_eq!(access(pin, B, release, alive), Err(ErrorCode::RESERVE));
+        assert_eq!(access(pin, A, release, alive), Ok(()));
+        assert_eq!(access(pin, B, write, alive), Ok(()));
+
+        // The pin is freed once its owner has exited.
+        assert_eq!(access(pin, B, configure, alive), Ok(()));
+        assert_eq!(access(pin, A, write, |_| false), Ok(()));
+    }
+
+    /// A pin that tracks its level and whether its interrupts are enabled.
+    struct MockIrqPin {
+        level: Cell<bool>,
+        interrupts: Cell<Option<gpio::InterruptEdge>>,
+        capabilities: Cell<Option<gpio::Capabilities>>,
+    }
+
+    impl MockIrqPin {
+        fn new() -> Self {
+            MockIrqPin {
+                level: Cell::new(false),
+                interrupts: Cell::new(None),
+                capabilities: Cell::new(Some(gpio::Capabilities::ALL)),
+            }
+        }
+    }
+
+    impl Output for MockIrqPin {
+        fn set(&self) {
+            self.level.set(true);
+        }
+        fn clear(&self) {
+            self.level.set(false);
+        }
+        fn toggle(&self) -> bool {
+            self.level.set(!self.level.get());
+            self.level.get()
+        }
+    }
+
+    impl gpio::Input for MockIrqPin {
+        fn read(&self) -> bool {
+            self.level.get()
+        }
+    }
+
+    impl Configure for MockIrqPin {
+        fn configuration(&self) -> gpio::Configuration {
+            gpio::Configuration::InputOutput
+        }
+        fn make_output(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn disable_output(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn make_input(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn disable_input(&self) -> gpio::Configuration {
+            self.configuration()
+        }
+        fn deactivate_to_low_power(&self) {}
+        fn set_floating_state(&self, _state: gpio::FloatingState) {}
+        fn floating_state(&self) -> gpio::FloatingState {
+            gpio::FloatingState::PullNone
+        }
+        fn capabilities(&self) -> Option<gpio::Capabilities> {
+            self.capabilities.get()
+        }
+    }
+
+    impl<'a> gpio::Interrupt<'a> for MockIrqPin {
+        fn set_client(&self, _client: &'a dyn gpio::Client) {}
+        fn enable_interrupts(&self, mode: gpio::InterruptEdge) {
+            self.interrupts.set(Some(mode));
+        }
+        fn disable_interrupts(&self) {
+            self.interrupts.set(None);
+        }
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    type MockGpio = GPIO<'static, MockIrqPin>;
+
+    /// A GPIO driver over `N` mock pins, and the pins themselves.
+    fn mock_gpio<const N: usize>(
+        kernel: &'static TestKernel,
+    ) -> (&'static MockGpio, [&'static MockIrqPin; N]) {
+        let pins: [&'static MockIrqPin; N] = core::array::from_fn(|_| &*leak(MockIrqPin::new()));
+        let wrapped = leak(pins.map(|pin| Some(&*leak(gpio::InterruptValueWrapper::new(pin)))));
+        let gpio = leak(GPIO::new(wrapped, kernel.create_grant(DRIVER_NUM)));
+        (gpio, pins)
+    }
+
+    #[test]
+    fn failed_configure_does_not_claim_pin() {
+        let kernel = TestKernel::new(&["a", "b"]);
+        let (gpio, pins) = mock_gpio::<1>(kernel);
+        gpio.set_pin_ownership(true);
+        let (a, b) = (kernel.process(0), kernel.process(1));
+
+        // Bad pull and interrupt configurations fail without claiming pin 0.
+        assert!(matches!(
+            a.command(gpio, DRIVER_NUM, 5, 0, 7),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ));
+        assert!(matches!(
+            a.command(gpio, DRIVER_NUM, 7, 0, 7),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ));
+        assert!(matches!(
+            b.command(gpio, DRIVER_NUM, 2, 0, 0),
+            SyscallReturn::Success
+        ));
+        assert!(pins[0].level.get());
+
+        // A successful configuration claims it.
+        assert!(matches!(
+            b.command(gpio, DRIVER_NUM, 1, 0, 0),
+            SyscallReturn::Success
+        ));
+        assert!(matches!(
+            a.command(gpio, DRIVER_NUM, 3, 0, 0),
+            SyscallReturn::Failure(ErrorCode::RESERVE)
+        ));
+        assert!(pins[0].level.get());
+    }
+
+    #[test]
+    fn edge_wakes_only_watching_processes() {
+        // Three yielded processes: one watching pin 1, one watching pin 2,
+        // and one that watches nothing and so receives every pin.
+        let kernel = TestKernel::new(&["one", "two", "all"]);
+        let (gpio, pins) = mock_gpio::<3>(kernel);
+        let processes: [_; 3] = core::array::from_fn(|i| kernel.process(i));
+        for (process, watched) in processes.iter().zip([Some(1), Some(2), None]) {
+            process.subscribe(gpio, DRIVER_NUM, UPCALL_NUM);
+            if let Some(pin) = watched {
+                assert!(matches!(
+                    process.command(gpio, DRIVER_NUM, 15, pin, 1),
+                    SyscallReturn::Success
+                ));
+            }
+        }
+        assert!(matches!(
+            processes[0].command(gpio, DRIVER_NUM, 7, 1, 0),
+            SyscallReturn::Success
+        ));
+        assert!(matches!(
+            pins[1].interrupts.get(),
+            Some(gpio::InterruptEdge::EitherEdge)
+        ));
+
+        pins[1].level.set(true);
+        gpio::ClientWithValue::fired(gpio, 1);
+        let woken = processes.map(|process| process.ready());
+        assert_eq!(woken, [true, false, true]);
+        let upcall = QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (1, 1, 0));
+        assert_eq!(processes[0].take_upcalls(), [upcall]);
+        assert_eq!(processes[1].take_upcalls(), []);
+        assert_eq!(processes[2].take_upcalls(), [upcall]);
+
+        // Pins that cannot be watched still reach processes watching nothing.
+        assert!(!watches(1 << 3, WATCHABLE_PINS));
+        assert!(watches(0, WATCHABLE_PINS));
+    }
+
+    #[test]
+    fn owned_mask_skips_missing_pins() {
+        let mock = [MockPin(Cell::new(false)), MockPin(Cell::new(false))];
+        let pins = [Some(&mock[0]), None, Some(&mock[1])];
+        assert_eq!(owned_mask(&pins, 2, 0), 1);
+        assert_eq!(owned_mask(&pins, 2, 1), 0);
+        assert_eq!(owned_mask(&pins, 2, OWNED_PINS), 0);
+        assert_eq!(owned_mask(&pins, 10, 0b111), 0b101);
+    }
+
+    #[test]
+    fn write_masked_pattern() {
+        let mock = [
+            MockPin(Cell::new(false)),
+            MockPin(Cell::new(true)),
+            MockPin(Cell::new(false)),
+            MockPin(Cell::new(true)),
+        ];
+        let pins = [
+            Some(&mock[0]),
+            Some(&mock[1]),
+            Some(&mock[2]),
+            Some(&mock[3]),
+        ];
+
+        // Only pins 0-2 are selected; pin 3 keeps its previous value.
+        assert_eq!(write_masked(&pins, 0b0111, 0b1101), Ok(()));
+        let levels: [bool; 4] = [0, 1, 2, 3].map(|i| mock[i].0.get());
+        assert_eq!(levels, [true, false, true, true]);
+    }
+
+    #[test]
+    fn write_masked_rejects_bad_mask() {
+        let mock = [MockPin(Cell::new(false)), MockPin(Cell::new(false))];
+        let pins = [Some(&mock[0]), None, Some(&mock[1])];
+
+        assert_eq!(write_masked(&pins, 0b1001, 0b1001), Err(ErrorCode::INVAL));
+        assert_eq!(write_masked(&pins, 0b011, 0b011), Err(ErrorCode::NODEVICE));
+        // Nothing is written when the mask is rejected.
+        assert!(!mock[0].0.get());
+        assert!(!mock[1].0.get());
+    }
+
+    fn config_pins<const N: usize>() -> [MockConfigPin; N] {
+        core::array::from_fn(|_| MockConfigPin {
+            config: Cell::new(gpio::Configuration::LowPower),
+            has_function: false,
+            gfer: None,
+            drive: None,
+        })
+    }
+
+    #[test]
+    fn configure_masked_outputs() {
+        let mock: [MockConfigPin; 5] = config_pins();
+        let pins: [Option<&MockConfigPin>; 5] = core::array::from_fn(|i| Some(&mock[i]));
+
+        assert_eq!(
+            configure_masked(&pins, 0b01111, BatchConfig::Output),
+            Ok(())
+        );
+        for pin in &mock[..4] {
+            assert!(matches!(pin.configuration(), gpio::Configuration::Output));
+        }
+        // Pin 4 is not selected.
+        assert!(matches!(
+            mock[4].configuration(),
+            gpio::Configuration::LowPower
+        ));
+    }
+
+    #[test]
+    fn configure_masked_reports_first_bad_pin() {
+        let mock: [MockConfigPin; 3] = config_pins();
+        let pins = [Some(&mock[0]), None, Some(&mock[1]), Some(&mock[2])];
+
+        let input = BatchConfig::Input(gpio::FloatingState::PullUp);
+        assert_eq!(
+            configure_masked(&pins, 0b100101, input),
+            Err((ErrorCode::INVAL, 5))
+        );
+        assert_eq!(
+            configure_masked(&pins, 0b1110, input),
+            Err((ErrorCode::NODEVICE, 1))
+        );
+        // Nothing is configured when the mask is rejected.
+        for pin in &mock {
+            assert!(matches!(pin.configuration(), gpio::Configuration::LowPower));
+        }
+    }
+
+    #[test]
+    fn pin_capabilities_of_mixed_pins() {
+        let mock = [MockIrqPin::new(), MockIrqPin::new(), MockIrqPin::new()];
+        mock[1].capabilities.set(Some(gpio::Capabilities {
+            output: false,
+            pull: false,
+            ..gpio::Capabilities::ALL
+        }));
+        // A chip that does not say what its pin supports.
+        mock[2].capabilities.set(None);
+        let pins = [Some(&mock[0]), None, Some(&mock[1]), Some(&mock[2])];
+
+        let caps = pins.map(pin_capabilities);
+        assert_eq!(caps[0], Ok(0b1111));
+        assert_eq!(caps[1], Err(ErrorCode::NODEVICE));
+        assert_eq!(caps[2], Ok(capability::INPUT | capability::INTERRUPT));
+        assert_eq!(caps[3], Err(ErrorCode::NOSUPPORT));
+    }
+
+    #[test]
+    fn edge_counter_read_and_reset() {
+        let counters = EdgeCounters::new();
+        for _ in 0..5 {
+            counters.record(3);
+        }
+        counters.record(4);
+
+        assert_eq!(counters.read(3, false), Some(5));
+        assert_eq!(counters.read(3, true), Some(5));
+        assert_eq!(counters.read(3, false), Some(0));
+        // Other pins are counted separately.
+        assert_eq!(counters.read(4, false), Some(1));
+        // Pins beyond the counters are ignored.
+        counters.record(EDGE_COUNTERS);
+        assert_eq!(counters.read(EDGE_COUNTERS, false), None);
+    }
+
+    struct FakeAlarm<'a> {
+        now: Cell<Ticks32>,
+        reference: Cell<Ticks32>,
+        dt: Cell<Ticks32>,
+        armed: Cell<bool>,
+        client: OptionalCell<&'a dyn AlarmClient>,
+    }
+
+    impl FakeAlarm<'_> {
+        fn new() -> Self {
+            Self {
+                now: Cell::new(1_000u32.into()),
+                reference: Cell::new(0u32.into()),
+                dt: Cell::new(0u32.into()),
+                armed: Cell::new(false),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        /// Fast forwards time past the armed alarm and calls the client.
+        fn trigger(&self) {
+            assert!(self.armed.get());
+            let end = self.reference.get().wrapping_add(self.dt.get());
+            self.now.set(end.wrapping_add(1u32.into()));
+            self.client.map(|c| c.alarm());
+        }
+    }
+
+    impl Time for FakeAlarm<'_> {
+        type Ticks = Ticks32;
+        type Frequency = Freq1KHz;
+
+        fn now(&self) -> Ticks32 {
+            self.now.get()
+        }
+    }
+
+    impl<'a> Alarm<'a> for FakeAlarm<'a> {
+        fn set_alarm_client(&self, client: &'a dyn AlarmClient) {
+            self.client.set(client);
+        }
+
+        fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+            self.reference.set(reference);
+            self.dt.set(dt);
+            self.armed.set(true);
+        }
+
+        fn get_alarm(&self) -> Self::Ticks {
+            self.reference.get().wrapping_add(self.dt.get())
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            self.armed.set(false);
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+
+        fn minimum_dt(&self) -> Self::Ticks {
+            0u32.into()
+        }
+    }
+
+    #[derive(Default)]
+    struct CoalescedLog {
+        calls: Cell<usize>,
+        last: Cell<(usize, u32)>,
+    }
+
+    impl CoalesceClient for CoalescedLog {
+        fn coalesced(&self, pin: usize, count: u32) {
+            self.calls.set(self.calls.get() + 1);
+            self.last.set((pin, count));
+        }
+    }
+
+    #[test]
+    fn edges_report_capture_ticks() {
+        let kernel = TestKernel::new(&["stamped", "plain"]);
+        let (gpio, pins) = mock_gpio::<5>(kernel);
+        let (stamped, plain) = (kernel.process(0), kernel.process(1));
+        stamped.subscribe(gpio, DRIVER_NUM, UPCALL_NUM);
+        plain.subscribe(gpio, DRIVER_NUM, UPCALL_NUM);
+        assert!(matches!(
+            stamped.command(gpio, DRIVER_NUM, 16, 1, 0),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ));
+
+        let alarm = leak(FakeAlarm::new());
+        let coalescer = leak(GpioCoalescer::new(&*alarm));
+        alarm.set_alarm_client(coalescer);
+        gpio.set_edge_clock(alarm);
+        gpio.set_coalescer(coalescer);
+        assert!(matches!(
+            stamped.command(gpio, DRIVER_NUM, 16, 1, 0),
+            SyscallReturn::SuccessU32(1000)
+        ));
+
+        // Two edges on pin 4, handled at ticks 1000 and 1250.
+        pins[4].level.set(true);
+        gpio::ClientWithValue::fired(gpio, 4);
+        alarm.now.set(1_250u32.into());
+        pins[4].level.set(false);
+        gpio::ClientWithValue::fired(gpio, 4);
+        assert_eq!(
+            stamped.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (4, 1, 1_000)),
+                QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (4, 0, 1_250)),
+            ]
+        );
+        assert_eq!(
+            plain.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (4, 1, 0)),
+                QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (4, 0, 0)),
+            ]
+        );
+
+        // A coalesced callback keeps its count next to the pin state, and
+        // processes without timestamps get the plain arguments.
+        assert!(matches!(
+            plain.command(gpio, DRIVER_NUM, 13, 4, 50),
+            SyscallReturn::Success
+        ));
+        pins[4].level.set(true);
+        for _ in 0..3 {
+            gpio::ClientWithValue::fired(gpio, 4);
+        }
+        assert_eq!(stamped.take_upcalls(), []);
+        alarm.trigger();
+        assert_eq!(alarm.now(), 1_301u32.into());
+        assert_eq!(
+            stamped.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (4, 0b111, 1_301))]
+        );
+        assert_eq!(
+            plain.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, UPCALL_NUM, (4, 1, 3))]
+        );
+    }
+
+    #[test]
+    fn coalescer_merges_edges_in_window() {
+        let alarm = FakeAlarm::new();
+        let coalescer = GpioCoalescer::new(&alarm);
+        let log = CoalescedLog::default();
+        alarm.set_alarm_client(&coalescer);
+        coalescer.set_client(&log);
+
+        // Pins without a window are delivered immediately.
+        assert!(!coalescer.edge(2));
+
+        assert_eq!(coalescer.set_window(2, 50), Ok(()));
+        for _ in 0..10 {
+            assert!(coalescer.edge(2));
+            alarm.now.set(alarm.now.get().wrapping_add(1u32.into()));
+        }
+        assert_eq!(log.calls.get(), 0);
+        assert!(alarm.is_armed());
+
+        alarm.trigger();
+        assert_eq!(log.calls.get(), 1);
+        assert_eq!(log.last.get(), (2, 10));
+        assert!(!alarm.is_armed());
+
+        assert_eq!(
+            coalescer.set_window(EDGE_COUNTERS, 50),
+            Err(ErrorCode::NOSUPPORT)
+        );
+    }
+}
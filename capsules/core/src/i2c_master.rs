@@ -6,7 +6,7 @@ use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCo
 use kernel::hil::i2c;
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
-use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::utilities::cells::{MapCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
 /// Syscall driver number.
@@ -20,8 +20,18 @@ mod rw_allow {
     pub const COUNT: u8 = 2;
 }
 
+/// The I2C general-call address, which every device that supports it
+/// listens on for broadcast resets and configuration.
+const GENERAL_CALL_ADDR: u8 = 0x00;
+
 #[derive(Default)]
-pub struct App;
+pub struct App {
+    /// Number of bytes of the current transfer that have completed, counting
+    /// the bytes written followed by the bytes read.
+    offset: usize,
+    /// Target address set with command 5 for the commands that omit it.
+    default_addr: Option<u8>,
+}
 
 pub static mut BUF: [u8; 64] = [0; 64];
 
@@ -29,8 +39,92 @@ struct Transaction {
     /// The buffer containing the bytes to transmit as it should be returned to
     /// the client
     processid: ProcessId,
-    /// The total amount to transmit
-    read_len: OptionalCell<usize>,
+    command: Cmd,
+    addr: u8,
+    /// The total amount to write
+    write_len: usize,
+    /// The total amount to read
+    read_len: usize,
+}
+
+/// One hardware operation of an app transfer. Transfers that do not fit in a
+/// single operation are split into chunks joined by repeated starts, or by
+/// a stop and a new start on controllers that cannot continue a transaction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Step {
+    /// The whole transfer fits in one operation.
+    Whole,
+    /// Write `len` bytes starting at `start` in the app buffer. `last` is set
+    /// for the chunk that ends the transaction with a stop.
+    Write {
+        start: usize,
+        len: usize,
+        last: bool,
+    },
+    /// Read `len` bytes into the app buffer at `start`.
+    Read {
+        start: usize,
+        len: usize,
+        last: bool,
+    },
+}
+
+/// The operation that continues a transfer of `wlen` bytes written and `rlen`
+/// bytes read once `offset` bytes have completed, using chunks of at most
+/// `chunk` bytes. Returns `None` once the transfer is complete.
+fn next_step(wlen: usize, rlen: usize, offset: usize, chunk: usize) -> Option<Step> {
+    let total = wlen + rlen;
+    if offset >= total {
+        None
+    } else if offset == 0 && wlen <= chunk && rlen <= chunk {
+        Some(Step::Whole)
+    } else if offset < wlen {
+        let len = chunk.min(wlen - offset);
+        Some(Step::Write {
+            start: offset,
+            len,
+            last: offset + len == total,
+        })
+    } else {
+        let len = chunk.min(total - offset);
+        Some(Step::Read {
+            start: offset - wlen,
+            len,
+            last: offset + len == total,
+        })
+    }
+}
+
+impl Step {
+    /// Number of bytes of the transfer this step completes.
+    fn len(&self, wlen: usize, rlen: usize) -> usize {
+        match *self {
+            Step::Whole => wlen + rlen,
+            Step::Write { len, .. } | Step::Read { len, .. } => len,
+        }
+    }
+
+    /// Number of bytes of the transfer that this step got through, given how
+    /// it finished. A step that failed with a data NACK counts the bytes the
+    /// device acknowledged before it, as reported by `acked`.
+    fn completed(
+        &self,
+        wlen: usize,
+        rlen: usize,
+        status: Result<(), i2c::Error>,
+        acked: Option<usize>,
+    ) -> usize {
+        let written = match *self {
+            Step::Whole => wlen,
+            Step::Write { len, .. } => len,
+            Step::Read { .. } => 0,
+        };
+        match status {
+            Ok(()) => self.len(wlen, rlen),
+            Err(i2c::Error::DataNak) => acked.map_or(0, |acked| acked.min(written)),
+            Err(_) => 0,
+        }
+    }
 }
 
 pub struct I2CMasterDriver<'a, I: 'a + i2c::I2CMaster> {
@@ -54,53 +148,127 @@ impl<'a, I: 'a + i2c::I2CMaster> I2CMasterDriver<'a, I> {
         }
     }
 
+    /// Largest number of bytes moved by one hardware operation.
+    fn chunk_len(&self) -> usize {
+        self.buf
+            .map_or(0, |buf| buf.len())
+            .min(self.i2c.max_transfer_len())
+    }
+
     fn operation(
         &self,
         processid: ProcessId,
+        app: &mut App,
         kernel_data: &GrantKernelData,
         command: Cmd,
         addr: u8,
         wlen: usize,
         rlen: usize,
     ) -> Result<(), ErrorCode> {
-        kernel_data
+        if wlen == 0 && rlen == 0 {
+            // Nothing to transfer, so complete right away without
+            // involving the I2C hardware.
+            kernel_data.schedule_upcall(0, (0, 0, 0)).ok();
+            return Ok(());
+        }
+        if self.buf.is_none() {
+            return Err(ErrorCode::NOMEM);
+        }
+        let app_len = kernel_data
             .get_readwrite_processbuffer(rw_allow::BUFFER)
-            .and_then(|buffer| {
-                buffer.enter(|app_buffer| {
-                    self.buf.take().map_or(Err(ErrorCode::NOMEM), |buffer| {
-                        app_buffer[..(wlen as usize)].copy_to_slice(&mut buffer[..(wlen as usize)]);
-
-                        let read_len: OptionalCell<usize>;
-                        if rlen == 0 {
-                            read_len = OptionalCell::empty();
-                        } else {
-                            read_len = OptionalCell::new(rlen as usize);
-                        }
-                        self.tx.put(Transaction {
-                            processid,
-                            read_len,
-                        });
+            .map_or(0, |buffer| buffer.len());
+        if wlen > app_len || rlen > app_len {
+            return Err(ErrorCode::INVAL);
+        }
 
-                        let res = match command {
-                            Cmd::Ping => {
-                                self.buf.put(Some(buffer));
-                                return Err(ErrorCode::INVAL);
-                            }
-                            Cmd::Write => self.i2c.write(addr, buffer, wlen),
-                            Cmd::Read => self.i2c.read(addr, buffer, rlen),
-                            Cmd::WriteRead => self.i2c.write_read(addr, buffer, wlen, rlen),
-                        };
-                        match res {
-                            Ok(_) => Ok(()),
-                            Err((error, data)) => {
-                                self.buf.put(Some(data));
-                                Err(error.into())
-                            }
-                        }
-                    })
+        app.offset = 0;
+        let tx = Transaction {
+            processid,
+            command,
+            addr,
+            write_len: wlen,
+            read_len: rlen,
+        };
+        let res = self.start_step(&tx, app.offset, kernel_data);
+        if res.is_ok() {
+            self.tx.put(tx);
+        }
+        res
+    }
+
+    /// Start the hardware operation that continues `tx` after `offset` bytes
+    /// have completed.
+    fn start_step(
+        &self,
+        tx: &Transaction,
+        offset: usize,
+        kernel_data: &GrantKernelData,
+    ) -> Result<(), ErrorCode> {
+        let step = next_step(tx.write_len, tx.read_len, offset, self.chunk_len())
+            .ok_or(ErrorCode::FAIL)?;
+        let buffer = self.buf.take().ok_or(ErrorCode::NOMEM)?;
+
+        // Stage the bytes to write in the kernel buffer.
+        let (wstart, wlen) = match step {
+            Step::Whole => (0, tx.write_len),
+            Step::Write { start, len, .. } => (start, len),
+            Step::Read { .. } => (0, 0),
+        };
+        let copied = kernel_data
+            .get_readwrite_processbuffer(rw_allow::BUFFER)
+            .and_then(|app_buffer| {
+                app_buffer.enter(|app_buffer| {
+                    app_buffer
+                        .get(wstart..wstart + wlen)
+                        .map(|src| src.copy_to_slice(&mut buffer[..wlen]))
+                        .is_some()
                 })
             })
-            .unwrap_or(Err(ErrorCode::INVAL))
+            .unwrap_or(false);
+        if !copied {
+            self.buf.replace(buffer);
+            return Err(ErrorCode::INVAL);
+        }
+
+        let addr = tx.addr;
+        let res = match step {
+            Step::Whole => match tx.command {
+                Cmd::Write | Cmd::GeneralCall => self.i2c.write(addr, buffer, tx.write_len),
+                Cmd::Read => self.i2c.read(addr, buffer, tx.read_len),
+                Cmd::WriteRead => self.i2c.write_read(addr, buffer, tx.write_len, tx.read_len),
+                Cmd::Ping
+                | Cmd::SetDefaultAddress
+                | Cmd::DefaultWrite
+                | Cmd::DefaultRead
+                | Cmd::DefaultWriteRead => Err((i2c::Error::NotSupported, buffer)),
+            },
+            Step::Write {
+                len, last: true, ..
+            } => self.i2c.write(addr, buffer, len),
+            // Without repeated starts, each chunk is its own transaction.
+            Step::Write {
+                len, last: false, ..
+            } => match self.i2c.write_continued(addr, buffer, len) {
+                Err((i2c::Error::NotSupported, buffer)) => self.i2c.write(addr, buffer, len),
+                res => res,
+            },
+            Step::Read {
+                len, last: true, ..
+            } => self.i2c.read(addr, buffer, len),
+            Step::Read {
+                len, last: false, ..
+            } => match self.i2c.read_continued(addr, buffer, len) {
+                Err((i2c::Error::NotSupported, buffer)) => self.i2c.read(addr, buffer, len),
+                res => res,
+            },
+        };
+        match res {
+            Ok(_) => Ok(()),
+            Err((error, data)) => {
+                self.buf.put(Some(data));
+                Err(error.into())
+            }
+        }
     }
 }
 
@@ -113,7 +281,35 @@ pub enum Cmd {
     Write = 1,
     Read = 2,
     WriteRead = 3,
+    GeneralCall = 4,
+    SetDefaultAddress = 5,
+    DefaultWrite = 6,
+    DefaultRead = 7,
+    DefaultWriteRead = 8,
+}
 }
+
+/// Decode the arguments of a transfer command into the transfer to run, its
+/// target address, and the number of bytes to write and read. The `Default*`
+/// commands use `default_addr` and fail with `INVAL` if none is set.
+fn transfer_args(
+    cmd: Cmd,
+    arg1: usize,
+    arg2: usize,
+    default_addr: Option<u8>,
+) -> Result<(Cmd, u8, usize, usize), ErrorCode> {
+    let default_addr = || default_addr.ok_or(ErrorCode::INVAL);
+    match cmd {
+        Cmd::Write => Ok((Cmd::Write, arg1 as u8, arg2, 0)),
+        Cmd::Read => Ok((Cmd::Read, arg1 as u8, 0, arg2)),
+        // can extend to 24 bit write length and 32 bit read length
+        Cmd::WriteRead => Ok((Cmd::WriteRead, arg1 as u8, arg1 >> 8, arg2)),
+        Cmd::GeneralCall => Ok((Cmd::GeneralCall, GENERAL_CALL_ADDR, arg1, 0)),
+        Cmd::DefaultWrite => Ok((Cmd::Write, default_addr()?, arg1, 0)),
+        Cmd::DefaultRead => Ok((Cmd::Read, default_addr()?, 0, arg1)),
+        Cmd::DefaultWriteRead => Ok((Cmd::WriteRead, default_addr()?, arg1, arg2)),
+        Cmd::Ping | Cmd::SetDefaultAddress => Err(ErrorCode::NOSUPPORT),
+    }
 }
 
 impl<'a, I: 'a + i2c::I2CMaster> SyscallDriver for I2CMasterDriver<'a, I> {
@@ -127,9 +323,34 @@ impl<'a, I: 'a + i2c::I2CMaster> SyscallDriver for I2CMasterDriver<'a, I> {
     //
     // ### `subscribe_num`
     //
-    // - `0`: Write buffer completed callback
+    // - `0`: Transfer completed callback, with a statuscode and the number of
+    //        bytes transferred. Transfers longer than the hardware accepts
+    //        are split into chunks joined by repeated starts (or, if the
+    //        controller cannot continue a transaction, by a stop and a new
+    //        start), and the callback is delivered once at the end. If the
+    //        device NACKs a written byte, the status is `NOACK` and, where
+    //        the hardware reports it, the count includes the bytes
+    //        acknowledged before the NACK.
 
     /// Initiate transfers
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Write `arg2` bytes of the buffer to the device at address `arg1`.
+    /// - `2`: Read `arg2` bytes into the buffer from the device at address
+    ///        `arg1`.
+    /// - `3`: Write `arg1 >> 8` bytes to the device at address `arg1 & 0xff`,
+    ///        then read `arg2` bytes from it.
+    /// - `4`: Broadcast a write of `arg1` bytes of the buffer to the
+    ///        general-call address (0x00). This is an alias of command `1`
+    ///        with `arg1` = 0, which sends a general call just the same.
+    /// - `5`: Set the 7-bit address `arg1` as the default target of commands
+    ///        `6`-`8`.
+    /// - `6`: Write `arg1` bytes of the buffer to the default target.
+    /// - `7`: Read `arg1` bytes into the buffer from the default target.
+    /// - `8`: Write `arg1` bytes to the default target, then read `arg2`
+    ///        bytes from it.
     fn command(
         &self,
         cmd_num: usize,
@@ -140,42 +361,35 @@ impl<'a, I: 'a + i2c::I2CMaster> SyscallDriver for I2CMasterDriver<'a, I> {
         if let Some(cmd) = Cmd::from_usize(cmd_num) {
             match cmd {
                 Cmd::Ping => CommandReturn::success(),
-                Cmd::Write => self
+                Cmd::SetDefaultAddress => self
                     .apps
-                    .enter(processid, |_, kernel_data| {
-                        let addr = arg1 as u8;
-                        let write_len = arg2;
-                        self.operation(processid, kernel_data, Cmd::Write, addr, write_len, 0)
-                            .into()
+                    .enter(processid, |app, _| {
+                        if arg1 > 0x7f {
+                            return CommandReturn::failure(ErrorCode::INVAL);
+                        }
+                        app.default_addr = Some(arg1 as u8);
+                        CommandReturn::success()
                     })
                     .unwrap_or_else(|err| err.into()),
-                Cmd::Read => self
+                _ => self
                     .apps
-                    .enter(processid, |_, kernel_data| {
-                        let addr = arg1 as u8;
-                        let read_len = arg2;
-                        self.operation(processid, kernel_data, Cmd::Read, addr, 0, read_len)
-                            .into()
+                    .enter(processid, |app, kernel_data| {
+                        match transfer_args(cmd, arg1, arg2, app.default_addr) {
+                            Ok((command, addr, write_len, read_len)) => self
+                                .operation(
+                                    processid,
+                                    app,
+                                    kernel_data,
+                                    command,
+                                    addr,
+                                    write_len,
+                                    read_len,
+                                )
+                                .into(),
+                            Err(e) => CommandReturn::failure(e),
+                        }
                     })
                     .unwrap_or_else(|err| err.into()),
-                Cmd::WriteRead => {
-                    let addr = arg1 as u8;
-                    let write_len = arg1 >> 8; // can extend to 24 bit write length
-                    let read_len = arg2; // can extend to 32 bit read length
-                    self.apps
-                        .enter(processid, |_, kernel_data| {
-                            self.operation(
-                                processid,
-                                kernel_data,
-                                Cmd::WriteRead,
-                                addr,
-                                write_len,
-                                read_len,
-                            )
-                            .into()
-                        })
-                        .unwrap_or_else(|err| err.into())
-                }
             }
         } else {
             CommandReturn::failure(ErrorCode::NOSUPPORT)
@@ -188,25 +402,468 @@ impl<'a, I: 'a + i2c::I2CMaster> SyscallDriver for I2CMasterDriver<'a, I> {
 }
 
 impl<'a, I: 'a + i2c::I2CMaster> i2c::I2CHwMasterClient for I2CMasterDriver<'a, I> {
-    fn command_complete(&self, buffer: &'static mut [u8], _status: Result<(), i2c::Error>) {
+    fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), i2c::Error>) {
+        let chunk = buffer.len().min(self.i2c.max_transfer_len());
+        let mut buffer = Some(buffer);
         self.tx.take().map(|tx| {
-            self.apps.enter(tx.processid, |_, kernel_data| {
-                if let Some(read_len) = tx.read_len.take() {
+            self.apps.enter(tx.processid, |app, kernel_data| {
+                let (wlen, rlen) = (tx.write_len, tx.read_len);
+                let step = next_step(wlen, rlen, app.offset, chunk);
+                // Copy out any bytes this step read.
+                let read = match step {
+                    Some(Step::Whole) => Some((0, rlen)),
+                    Some(Step::Read { start, len, .. }) => Some((start, len)),
+                    _ => None,
+                };
+                if let (Some((start, len)), Some(buffer)) = (read, buffer.as_ref()) {
                     let _ = kernel_data
                         .get_readwrite_processbuffer(rw_allow::BUFFER)
                         .and_then(|app_buffer| {
                             app_buffer.mut_enter(|app_buffer| {
-                                app_buffer[..read_len].copy_from_slice(&buffer[..read_len]);
+                                if let Some(dest) = app_buffer.get(start..start + len) {
+                                    dest.copy_from_slice(&buffer[..len]);
+                                }
                             })
                         });
                 }
 
+                let acked = match status {
+                    Err(i2c::Error::DataNak) => self.i2c.acked_len(),
+                    _ => None,
+                };
+                app.offset += step.map_or(0, |step| step.completed(wlen, rlen, status, acked));
+                let mut res = status.map_err(|e| e.into());
+                if res.is_ok() && app.offset < wlen + rlen {
+                    // Continue the transaction with the next chunk.
+                    self.buf.put(buffer.take());
+                    res = self.start_step(&tx, app.offset, kernel_data);
+                    if res.is_ok() {
+                        self.tx.put(tx);
+                        return;
+                    }
+                }
+
                 // signal to driver that tx complete
-                kernel_data.schedule_upcall(0, (0, 0, 0)).ok();
+                kernel_data
+                    .schedule_upcall(0, (kernel::errorcode::into_statuscode(res), app.offset, 0))
+                    .ok();
             })
         });
 
         //recover buffer
-        self.buf.put(Some(buffer));
+        if let Some(buffer) = buffer {
+            self.buf.put(Some(buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak_buffer, QueuedUpcall, TestKernel};
+    use core::cell::Cell;
+    use kernel::syscall::SyscallReturn;
+
+    /// Most bytes `ChunkedBus` moves in one call.
+    const CHUNK: usize = 16;
+
+    /// A controller that moves at most `CHUNK` bytes per call, connected to a
+    /// 40 byte device that streams out its memory and stores what is written
+    /// to it. The bus holds on to each buffer until `complete` is called.
+    struct ChunkedBus {
+        /// Whether the controller can end an operation without a stop.
+        continued: bool,
+        memory: Cell<[u8; 40]>,
+        position: Cell<usize>,
+        /// The held operation: whether it reads, its length and whether it
+        /// ends with a stop.
+        pending: Cell<Option<(bool, usize, bool)>>,
+        buffer: TakeCell<'static, [u8]>,
+        calls: Cell<usize>,
+        stops: Cell<usize>,
+    }
+
+    impl ChunkedBus {
+        fn new(continued: bool, memory: [u8; 40]) -> Self {
+            ChunkedBus {
+                continued,
+                memory: Cell::new(memory),
+                position: Cell::new(0),
+                pending: Cell::new(None),
+                buffer: TakeCell::empty(),
+                calls: Cell::new(0),
+                stops: Cell::new(0),
+            }
+        }
+
+        fn start(
+            &self,
+            read: bool,
+            data: &'static mut [u8],
+            len: usize,
+            stop: bool,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            if len > CHUNK {
+                return Err((i2c::Error::NotSupported, data));
+            }
+            self.pending.set(Some((read, len, stop)));
+            self.buffer.replace(data);
+            Ok(())
+        }
+
+        /// Run held operations until the driver stops issuing them.
+        fn run(&self, client: &dyn i2c::I2CHwMasterClient) {
+            while let Some((read, len, stop)) = self.pending.take() {
+                let buffer = self.buffer.take().unwrap();
+                let mut memory = self.memory.get();
+                let range = self.position.get()..self.position.get() + len;
+                if read {
+                    buffer[..len].copy_from_slice(&memory[range.clone()]);
+                } else {
+                    memory[range.clone()].copy_from_slice(&buffer[..len]);
+                    self.memory.set(memory);
+                }
+                self.position.set(range.end);
+                self.calls.set(self.calls.get() + 1);
+                self.stops.set(self.stops.get() + stop as usize);
+                client.command_complete(buffer, Ok(()));
+            }
+        }
+    }
+
+    impl i2c::I2CMaster for ChunkedBus {
+        fn set_master_client(&self, _client: &'static dyn i2c::I2CHwMasterClient) {}
+        fn enable(&self) {}
+        fn disable(&self) {}
+
+        fn write_read(
+            &self,
+            _addr: u8,
+            data: &'static mut [u8],
+            _write_len: usize,
+            _read_len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            Err((i2c::Error::NotSupported, data))
+        }
+
+        fn write(
+            &self,
+            _addr: u8,
+            data: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            self.start(false, data, len, true)
+        }
+
+        fn read(
+            &self,
+            _addr: u8,
+            buffer: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            self.start(true, buffer, len, true)
+        }
+
+        fn max_transfer_len(&self) -> usize {
+            CHUNK
+        }
+
+        fn write_continued(
+            &self,
+            _addr: u8,
+            data: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            if !self.continued {
+                return Err((i2c::Error::NotSupported, data));
+            }
+            self.start(false, data, len, false)
+        }
+
+        fn read_continued(
+            &self,
+            _addr: u8,
+            buffer: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            if !self.continued {
+                return Err((i2c::Error::NotSupported, buffer));
+            }
+            self.start(true, buffer, len, false)
+        }
+    }
+
+    #[test]
+    fn large_read_is_reassembled_from_chunks() {
+        let kernel = TestKernel::new(&["app"]);
+        let device: [u8; 40] = core::array::from_fn(|i| i as u8);
+        let bus = ChunkedBus::new(true, device);
+        let driver = I2CMasterDriver::new(&bus, leak_buffer(64), kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let buffer = app.buffer(&[0; 40]);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::BUFFER, buffer);
+
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, Cmd::Read as usize, 0x50, 40),
+            SyscallReturn::Success
+        ));
+        bus.run(&driver);
+
+        // Three chunks, and only the final one ends the transaction.
+        assert_eq!(bus.calls.get(), 3);
+        assert_eq!(bus.stops.get(), 1);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (0, 40, 0))]
+        );
+        assert_eq!(app.read(buffer), device);
+    }
+
+    #[test]
+    fn large_write_without_repeated_starts() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = ChunkedBus::new(false, [0; 40]);
+        let driver = I2CMasterDriver::new(&bus, leak_buffer(64), kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let data: [u8; 40] = core::array::from_fn(|i| 0x80 | i as u8);
+        let buffer = app.buffer(&data);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::BUFFER, buffer);
+
+        // The controller cannot continue a transaction, so every chunk is
+        // sent as a transaction of its own.
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, Cmd::Write as usize, 0x50, 40),
+            SyscallReturn::Success
+        ));
+        bus.run(&driver);
+        assert_eq!(bus.calls.get(), 3);
+        assert_eq!(bus.stops.get(), 3);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (0, 40, 0))]
+        );
+        assert_eq!(bus.memory.get(), data);
+    }
+
+    #[test]
+    fn default_address_commands() {
+        // Without a default, the short commands are rejected.
+        assert_eq!(
+            transfer_args(Cmd::DefaultWrite, 4, 0, None),
+            Err(ErrorCode::INVAL)
+        );
+
+        // Once set, a default write goes to that address, and the addressed
+        // commands are unaffected.
+        let default = Some(0x48);
+        assert_eq!(
+            transfer_args(Cmd::DefaultWrite, 4, 0, default),
+            Ok((Cmd::Write, 0x48, 4, 0))
+        );
+        assert_eq!(
+            transfer_args(Cmd::DefaultWriteRead, 1, 2, default),
+            Ok((Cmd::WriteRead, 0x48, 1, 2))
+        );
+        assert_eq!(
+            transfer_args(Cmd::Write, 0x50, 4, default),
+            Ok((Cmd::Write, 0x50, 4, 0))
+        );
+        assert_eq!(
+            transfer_args(Cmd::WriteRead, 0x50 | 3 << 8, 2, default),
+            Ok((Cmd::WriteRead, 0x50, 3, 2))
+        );
+    }
+
+    #[test]
+    fn write_read_chunks_write_before_read() {
+        assert_eq!(next_step(4, 8, 0, 16), Some(Step::Whole));
+        assert_eq!(
+            next_step(20, 4, 0, 16),
+            Some(Step::Write {
+                start: 0,
+                len: 16,
+                last: false
+            })
+        );
+        assert_eq!(
+            next_step(20, 4, 16, 16),
+            Some(Step::Write {
+                start: 16,
+                len: 4,
+                last: false
+            })
+        );
+        assert_eq!(
+            next_step(20, 4, 20, 16),
+            Some(Step::Read {
+                start: 0,
+                len: 4,
+                last: true
+            })
+        );
+        assert_eq!(next_step(20, 4, 24, 16), None);
+    }
+
+    /// A bus with one device that acknowledges a fixed number of written
+    /// bytes and NACKs the next one. The bus holds on to each buffer it is
+    /// given until `complete` is called.
+    struct NackingSlave {
+        acks: usize,
+        addr: Cell<Option<u8>>,
+        len: Cell<usize>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl NackingSlave {
+        fn new(acks: usize) -> Self {
+            NackingSlave {
+                acks,
+                addr: Cell::new(None),
+                len: Cell::new(0),
+                buffer: TakeCell::empty(),
+            }
+        }
+
+        /// The status and acknowledged count a controller reports after
+        /// writing `len` bytes to the device.
+        fn finish_write(&self, len: usize) -> (Result<(), i2c::Error>, Option<usize>) {
+            if len > self.acks {
+                (Err(i2c::Error::DataNak), Some(self.acks))
+            } else {
+                (Ok(()), None)
+            }
+        }
+
+        /// Finish the held write, handing the buffer back to `client`.
+        fn complete(&self, client: &dyn i2c::I2CHwMasterClient) {
+            let (status, _) = self.finish_write(self.len.get());
+            client.command_complete(self.buffer.take().unwrap(), status);
+        }
+    }
+
+    impl i2c::I2CMaster for NackingSlave {
+        fn set_master_client(&self, _client: &'static dyn i2c::I2CHwMasterClient) {}
+        fn enable(&self) {}
+        fn disable(&self) {}
+
+        fn write_read(
+            &self,
+            _addr: u8,
+            data: &'static mut [u8],
+            _write_len: usize,
+            _read_len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            Err((i2c::Error::NotSupported, data))
+        }
+
+        fn write(
+            &self,
+            addr: u8,
+            data: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            self.addr.set(Some(addr));
+            self.len.set(len);
+            self.buffer.replace(data);
+            Ok(())
+        }
+
+        fn read(
+            &self,
+            _addr: u8,
+            buffer: &'static mut [u8],
+            _len: usize,
+        ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+            Err((i2c::Error::NotSupported, buffer))
+        }
+
+        fn acked_len(&self) -> Option<usize> {
+            self.finish_write(self.len.get()).1
+        }
+    }
+
+    #[test]
+    fn nack_reports_acknowledged_bytes() {
+        let slave = NackingSlave::new(3);
+        let step = next_step(5, 0, 0, 16).unwrap();
+        let (status, acked) = slave.finish_write(5);
+        assert_eq!(step.completed(5, 0, status, acked), 3);
+
+        // Without a count from the hardware nothing is known to have landed.
+        assert_eq!(step.completed(5, 0, status, None), 0);
+
+        // In a chunked write the count is added to the chunks already done.
+        let offset = 16;
+        let step = next_step(20, 0, offset, 16).unwrap();
+        let (status, acked) = slave.finish_write(step.len(20, 0));
+        assert_eq!(offset + step.completed(20, 0, status, acked), 19);
+
+        // An address NACK means the device took nothing.
+        let status = Err(i2c::Error::AddressNak);
+        assert_eq!(step.completed(20, 0, status, Some(3)), 0);
+    }
+
+    #[test]
+    fn general_call_writes_to_address_zero() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = NackingSlave::new(3);
+        let driver = I2CMasterDriver::new(&bus, leak_buffer(16), kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let buffer = app.buffer(&[0x06, 0x04, 0x00, 0x00, 0x00]);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::BUFFER, buffer);
+
+        // Command 4 and command 1 to address 0 both broadcast.
+        for (command, arg1, arg2) in [(Cmd::GeneralCall, 2, 0), (Cmd::Write, 0, 2)] {
+            bus.addr.set(None);
+            assert!(matches!(
+                app.command(&driver, DRIVER_NUM, command as usize, arg1, arg2),
+                SyscallReturn::Success
+            ));
+            assert_eq!(bus.addr.get(), Some(GENERAL_CALL_ADDR));
+            assert_eq!(
+                bus.buffer.map(|buffer| buffer[..2] == [0x06, 0x04]),
+                Some(true)
+            );
+            bus.complete(&driver);
+            let upcall = QueuedUpcall::new(DRIVER_NUM, 0, (0, 2, 0));
+            assert_eq!(app.take_upcalls(), [upcall]);
+        }
+
+        // A device that NACKs part of the broadcast reports what it took.
+        app.command(&driver, DRIVER_NUM, Cmd::GeneralCall as usize, 5, 0);
+        bus.complete(&driver);
+        let nack = kernel::errorcode::into_statuscode(Err(ErrorCode::NOACK));
+        let upcall = QueuedUpcall::new(DRIVER_NUM, 0, (nack, 3, 0));
+        assert_eq!(app.take_upcalls(), [upcall]);
+    }
+
+    #[test]
+    fn zero_length_transfer_completes_without_bus() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = NackingSlave::new(3);
+        let driver = I2CMasterDriver::new(&bus, leak_buffer(16), kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+
+        // No buffer is needed, and the device is never addressed.
+        for (command, arg1, arg2) in [
+            (Cmd::Write, 0x50, 0),
+            (Cmd::Read, 0x50, 0),
+            (Cmd::WriteRead, 0x50, 0),
+            (Cmd::GeneralCall, 0, 0),
+        ] {
+            assert!(matches!(
+                app.command(&driver, DRIVER_NUM, command as usize, arg1, arg2),
+                SyscallReturn::Success
+            ));
+            assert_eq!(
+                app.take_upcalls(),
+                [QueuedUpcall::new(DRIVER_NUM, 0, (0, 0, 0))]
+            );
+        }
+        assert_eq!(bus.addr.get(), None);
     }
 }
@@ -15,7 +15,7 @@ use core::cell::Cell;
 use core::cmp;
 
 use kernel::hil;
-use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::processbuffer::{ReadableProcessBuffer, ReadableProcessSlice, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
@@ -59,6 +59,12 @@ enum MasterAction {
 pub struct I2CMasterSlaveDriver<'a> {
     i2c: &'a dyn hil::i2c::I2CMasterSlave,
     listening: Cell<bool>,
+    // Slave address to start listening on once the in-flight master
+    // transaction completes.
+    pending_listen: OptionalCell<u8>,
+    // Whether the hardware is refusing the data of the next write from
+    // another master.
+    nack_next: Cell<bool>,
     master_action: Cell<MasterAction>, // Whether we issued a write or read as master
     master_buffer: TakeCell<'static, [u8]>,
     slave_buffer1: TakeCell<'static, [u8]>,
@@ -88,6 +94,8 @@ impl<'a> I2CMasterSlaveDriver<'a> {
         I2CMasterSlaveDriver {
             i2c,
             listening: Cell::new(false),
+            pending_listen: OptionalCell::empty(),
+            nack_next: Cell::new(false),
             master_action: Cell::new(MasterAction::Write),
             master_buffer: TakeCell::new(master_buffer),
             slave_buffer1: TakeCell::new(slave_buffer1),
@@ -96,6 +104,24 @@ impl<'a> I2CMasterSlaveDriver<'a> {
             apps: grant,
         }
     }
+
+    // Start listening for messages to this device as a slave.
+    fn listen(&self) {
+        // We can always handle a write since this module has a buffer.
+        // .map will handle if we have already done this.
+        self.slave_buffer1.take().map(|buffer| {
+            // TODO verify errors
+            let _ = hil::i2c::I2CSlave::write_receive(self.i2c, buffer, 255);
+        });
+
+        // Actually get things going
+        hil::i2c::I2CSlave::enable(self.i2c);
+        hil::i2c::I2CSlave::listen(self.i2c);
+
+        // Note that we have enabled listening, so that if we switch
+        // to Master mode to send a message we can go back to listening.
+        self.listening.set(true);
+    }
 }
 
 impl hil::i2c::I2CHwMasterClient for I2CMasterSlaveDriver<'_> {
@@ -176,9 +202,14 @@ impl hil::i2c::I2CHwMasterClient for I2CMasterSlaveDriver<'_> {
             }
         }
 
-        // Check to see if we were listening as an I2C slave and should re-enable
-        // that mode.
-        if self.listening.get() {
+        // Check to see if a switch to slave mode was waiting on this
+        // transaction, or we were listening as an I2C slave and should
+        // re-enable that mode.
+        if let Some(address) = self.pending_listen.take() {
+            // TODO verify errors
+            let _ = hil::i2c::I2CSlave::set_address(self.i2c, address);
+            self.listen();
+        } else if self.listening.get() {
             hil::i2c::I2CSlave::enable(self.i2c);
             hil::i2c::I2CSlave::listen(self.i2c);
         }
@@ -199,6 +230,10 @@ impl hil::i2c::I2CHwSlaveClient for I2CMasterSlaveDriver<'_> {
         //     in this driver
         match transmission_type {
             hil::i2c::SlaveTransmissionType::Write => {
+                // A refused write ends the NACK requested with command 9.
+                if self.nack_next.take() {
+                    let _ = hil::i2c::I2CSlave::set_nack(self.i2c, false);
+                }
                 self.app.map(|app| {
                     let _ = self.apps.enter(*app, |_, kernel_data| {
                         kernel_data
@@ -250,8 +285,10 @@ impl hil::i2c::I2CHwSlaveClient for I2CMasterSlaveDriver<'_> {
         self.app.map(|app| {
             let _ = self.apps.enter(*app, |_, kernel_data| {
                 // Ask the app to setup a read buffer. The app must call
-                // command 3 after it has setup the shared read buffer with
-                // the correct bytes.
+                // command 4 after it has setup the shared read buffer with
+                // the correct bytes. The hardware stretches the clock until
+                // then, so the app should answer promptly: masters may give
+                // up on a slave that stretches for too long.
                 kernel_data.schedule_upcall(0, (2, 0, 0)).ok();
             });
         });
@@ -274,7 +311,7 @@ impl SyscallDriver for I2CMasterSlaveDriver<'_> {
         &self,
         command_num: usize,
         data: usize,
-        _: usize,
+        data2: usize,
         process_id: ProcessId,
     ) -> CommandReturn {
         if command_num == 0 {
@@ -386,26 +423,20 @@ impl SyscallDriver for I2CMasterSlaveDriver<'_> {
 
             // Listen for messages to this device as a slave.
             3 => {
-                // We can always handle a write since this module has a buffer.
-                // .map will handle if we have already done this.
-                self.slave_buffer1.take().map(|buffer| {
-                    // TODO verify errors
-                    let _ = hil::i2c::I2CSlave::write_receive(self.i2c, buffer, 255);
-                });
-
-                // Actually get things going
-                hil::i2c::I2CSlave::enable(self.i2c);
-                hil::i2c::I2CSlave::listen(self.i2c);
-
-                // Note that we have enabled listening, so that if we switch
-                // to Master mode to send a message we can go back to listening.
-                self.listening.set(true);
+                self.listen();
                 CommandReturn::success()
             }
 
             // Prepare for a read from another Master by passing what's
             // in the shared slice to the lower level I2C hardware driver.
+            // This is how the app answers upcall 2, sent when a master
+            // starts reading and no data has been provided. Returns BUSY if
+            // data was already provided and has not been read yet.
             4 => {
+                if self.slave_buffer2.is_none() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                let mut res = Ok(());
                 let _ = self.apps.enter(app, |_, kernel_data| {
                     // Because this (somewhat incorrectly) doesn't report
                     // back how many bytes are being read, the result of mut_map_or
@@ -418,19 +449,15 @@ impl SyscallDriver for I2CMasterSlaveDriver<'_> {
                         .and_then(|slave_tx| {
                             slave_tx.enter(|app_tx| {
                                 self.slave_buffer2.take().map(|kernel_tx| {
-                                    // Check bounds for write length
-                                    let len = data;
-                                    let buf_len = cmp::min(app_tx.len(), kernel_tx.len());
-                                    let read_len = cmp::min(buf_len, len);
-
-                                    for (i, c) in kernel_tx[0..read_len].iter_mut().enumerate() {
-                                        *c = app_tx[i].get();
+                                    let read_len = load_slave_tx(kernel_tx, app_tx, data);
+                                    if let Err((e, kernel_tx)) =
+                                        hil::i2c::I2CSlave::read_send(self.i2c, kernel_tx, read_len)
+                                    {
+                                        // Keep the buffer so the app can try
+                                        // again.
+                                        self.slave_buffer2.replace(kernel_tx);
+                                        res = Err(e.into());
                                     }
-
-                                    // TODO verify errors
-                                    let _ = hil::i2c::I2CSlave::read_send(
-                                        self.i2c, kernel_tx, read_len,
-                                    );
                                 });
                                 0
                             })
@@ -438,7 +465,7 @@ impl SyscallDriver for I2CMasterSlaveDriver<'_> {
                         .unwrap_or(0);
                 });
 
-                CommandReturn::success()
+                res.into()
             }
 
             // Stop listening for messages as an I2C slave
@@ -501,6 +528,57 @@ impl SyscallDriver for I2CMasterSlaveDriver<'_> {
                 CommandReturn::success()
             }
 
+            // Switch roles. `data` selects master (0) or slave (1) mode, and
+            // for slave mode `data2` is the address to listen on. If a master
+            // transaction is in flight the switch to slave mode happens once
+            // it completes. Masters addressing us are then reported through
+            // the usual slave upcalls.
+            //
+            // Switching to master mode leaves the receive buffer posted with
+            // the hardware, which keeps it while disabled. Switching back to
+            // slave mode, with this command or command 3, listens with it
+            // again rather than posting another.
+            8 => match data {
+                0 => {
+                    self.pending_listen.clear();
+                    hil::i2c::I2CSlave::disable(self.i2c);
+                    self.listening.set(false);
+                    CommandReturn::success()
+                }
+                1 => {
+                    let address = data2 as u8;
+                    if data2 > 0x7f {
+                        return CommandReturn::failure(ErrorCode::INVAL);
+                    }
+                    if self.master_buffer.is_none() {
+                        self.pending_listen.set(address);
+                    } else {
+                        // TODO verify errors
+                        let _ = hil::i2c::I2CSlave::set_address(self.i2c, address);
+                        self.listen();
+                    }
+                    CommandReturn::success()
+                }
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            // Set whether the next write from another master is ACKed (0)
+            // or NACKed (1), e.g. to push back while the app's buffer is
+            // full. The policy applies right away, even while listening, and
+            // a NACK lasts until a write has been refused. Returns NOSUPPORT
+            // if the hardware cannot NACK on demand: the SAM4L TWIS can, the
+            // nRF52 TWIS cannot.
+            9 => match data {
+                0 | 1 => match hil::i2c::I2CSlave::set_nack(self.i2c, data == 1) {
+                    Ok(()) => {
+                        self.nack_next.set(data == 1);
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e.into()),
+                },
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
@@ -510,3 +588,334 @@ impl SyscallDriver for I2CMasterSlaveDriver<'_> {
         self.apps.enter(processid, |_, _| {})
     }
 }
+
+/// Copy up to `len` bytes of the app's response to a master's read into the
+/// kernel buffer handed to the hardware, returning how many bytes were
+/// copied.
+fn load_slave_tx(kernel_tx: &mut [u8], app_tx: &ReadableProcessSlice, len: usize) -> usize {
+    let read_len = cmp::min(cmp::min(app_tx.len(), kernel_tx.len()), len);
+    app_tx[..read_len].copy_to_slice(&mut kernel_tx[..read_len]);
+    read_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak, leak_buffer, QueuedUpcall, TestKernel};
+    use kernel::syscall::SyscallReturn;
+
+    #[test]
+    fn slave_response_is_clamped() {
+        let app_data = [1, 2, 3, 4, 5, 6];
+        let app_tx: &ReadableProcessSlice = (&app_data[..]).into();
+
+        // Longer than the kernel buffer.
+        let mut kernel_tx = [0u8; 4];
+        assert_eq!(load_slave_tx(&mut kernel_tx, app_tx, 6), 4);
+        assert_eq!(kernel_tx, [1, 2, 3, 4]);
+
+        // Longer than the app's buffer.
+        let mut kernel_tx = [0u8; 8];
+        assert_eq!(load_slave_tx(&mut kernel_tx, app_tx, 10), 6);
+        assert_eq!(&kernel_tx[..6], &app_data);
+    }
+
+    /// A controller that holds on to the buffers it is given, recording the
+    /// slave address it was told to listen on and how much of the buffer
+    /// given to `read_send` it should clock out.
+    struct MockBus {
+        address: Cell<Option<u8>>,
+        listening: Cell<bool>,
+        /// Whether the bus is refusing writes, or `None` if it cannot.
+        nack: Cell<Option<bool>>,
+        master: TakeCell<'static, [u8]>,
+        receive: TakeCell<'static, [u8]>,
+        send: TakeCell<'static, [u8]>,
+        send_len: Cell<usize>,
+    }
+
+    impl MockBus {
+        fn new() -> Self {
+            MockBus {
+                address: Cell::new(None),
+                listening: Cell::new(false),
+                nack: Cell::new(Some(false)),
+                master: TakeCell::empty(),
+                receive: TakeCell::empty(),
+                send: TakeCell::empty(),
+                send_len: Cell::new(0),
+            }
+        }
+
+        /// Plays another master reading from us, returning the bytes clocked
+        /// out to it.
+        fn master_reads(&self, client: &dyn hil::i2c::I2CHwSlaveClient) -> [u8; 8] {
+            let buffer = self.send.take().unwrap();
+            let len = self.send_len.get();
+            let mut sent = [0; 8];
+            sent[..len].copy_from_slice(&buffer[..len]);
+            client.command_complete(buffer, len, hil::i2c::SlaveTransmissionType::Read);
+            sent
+        }
+
+        /// Plays another master writing `data` to us. A refused write ends
+        /// after the address, with nothing received.
+        fn master_writes(&self, client: &dyn hil::i2c::I2CHwSlaveClient, data: &[u8]) {
+            let buffer = self.receive.take().unwrap();
+            let len = if self.nack.get() == Some(true) {
+                0
+            } else {
+                data.len()
+            };
+            buffer[..len].copy_from_slice(&data[..len]);
+            client.command_complete(buffer, len, hil::i2c::SlaveTransmissionType::Write);
+        }
+    }
+
+    impl hil::i2c::I2CMaster for MockBus {
+        fn set_master_client(&self, _client: &'static dyn hil::i2c::I2CHwMasterClient) {}
+        fn enable(&self) {}
+        fn disable(&self) {}
+
+        fn write_read(
+            &self,
+            _addr: u8,
+            data: &'static mut [u8],
+            _write_len: usize,
+            _read_len: usize,
+        ) -> Result<(), (hil::i2c::Error, &'static mut [u8])> {
+            self.master.replace(data);
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            _addr: u8,
+            data: &'static mut [u8],
+            _len: usize,
+        ) -> Result<(), (hil::i2c::Error, &'static mut [u8])> {
+            self.master.replace(data);
+            Ok(())
+        }
+
+        fn read(
+            &self,
+            _addr: u8,
+            buffer: &'static mut [u8],
+            _len: usize,
+        ) -> Result<(), (hil::i2c::Error, &'static mut [u8])> {
+            self.master.replace(buffer);
+            Ok(())
+        }
+    }
+
+    impl hil::i2c::I2CSlave for MockBus {
+        fn set_slave_client(&self, _client: &'static dyn hil::i2c::I2CHwSlaveClient) {}
+        fn enable(&self) {}
+
+        fn disable(&self) {
+            self.listening.set(false);
+        }
+
+        fn set_address(&self, addr: u8) -> Result<(), hil::i2c::Error> {
+            self.address.set(Some(addr));
+            Ok(())
+        }
+
+        fn write_receive(
+            &self,
+            data: &'static mut [u8],
+            _max_len: usize,
+        ) -> Result<(), (hil::i2c::Error, &'static mut [u8])> {
+            self.receive.replace(data);
+            Ok(())
+        }
+
+        fn read_send(
+            &self,
+            data: &'static mut [u8],
+            max_len: usize,
+        ) -> Result<(), (hil::i2c::Error, &'static mut [u8])> {
+            self.send.replace(data);
+            self.send_len.set(max_len);
+            Ok(())
+        }
+
+        fn listen(&self) {
+            self.listening.set(true);
+        }
+
+        fn set_nack(&self, nack: bool) -> Result<(), hil::i2c::Error> {
+            match self.nack.get() {
+                Some(_) => {
+                    self.nack.set(Some(nack));
+                    Ok(())
+                }
+                None => Err(hil::i2c::Error::NotSupported),
+            }
+        }
+    }
+
+    fn driver(kernel: &'static TestKernel, bus: &'static MockBus) -> I2CMasterSlaveDriver<'static> {
+        I2CMasterSlaveDriver::new(
+            bus,
+            leak_buffer(8),
+            leak_buffer(8),
+            leak_buffer(8),
+            kernel.create_grant(DRIVER_NUM),
+        )
+    }
+
+    #[test]
+    fn switch_to_slave_waits_for_master_transaction() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = leak(MockBus::new());
+        let driver = driver(kernel, bus);
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let tx = app.buffer(&[1, 2]);
+        app.allow_readonly(&driver, DRIVER_NUM, ro_allow::MASTER_TX, tx);
+
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 1, 0x50 | 2 << 16, 0),
+            SyscallReturn::Success
+        ));
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 8, 1, 0x42),
+            SyscallReturn::Success
+        ));
+        // The write is still in flight, so nothing has changed yet.
+        assert_eq!(bus.address.get(), None);
+        assert!(!bus.listening.get());
+        assert!(bus.receive.is_none());
+
+        hil::i2c::I2CHwMasterClient::command_complete(&driver, bus.master.take().unwrap(), Ok(()));
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (0, 0, 0))]
+        );
+        assert_eq!(bus.address.get(), Some(0x42));
+        assert!(bus.listening.get());
+        assert!(bus.receive.is_some());
+    }
+
+    #[test]
+    fn slave_response_reaches_master() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = leak(MockBus::new());
+        let driver = driver(kernel, bus);
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let tx = app.buffer(&[0xde, 0xad, 0xbe, 0xef]);
+        app.allow_readonly(&driver, DRIVER_NUM, ro_allow::SLAVE_TX, tx);
+        app.command(&driver, DRIVER_NUM, 8, 1, 0x42);
+
+        // The master starts a read with no data loaded, and the app answers
+        // upcall 2 with four bytes from its shared buffer.
+        hil::i2c::I2CHwSlaveClient::read_expected(&driver);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (2, 0, 0))]
+        );
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 4, 4, 0),
+            SyscallReturn::Success
+        ));
+        // Until the master has read them, the bytes cannot be replaced.
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 4, 4, 0),
+            SyscallReturn::Failure(ErrorCode::BUSY)
+        ));
+
+        assert_eq!(
+            bus.master_reads(&driver),
+            [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (4, 4, 0))]
+        );
+    }
+
+    #[test]
+    fn listening_reports_writes_from_masters() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = leak(MockBus::new());
+        let driver = driver(kernel, bus);
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let rx = app.buffer(&[0; 4]);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::SLAVE_RX, rx);
+
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 8, 1, 0x42),
+            SyscallReturn::Success
+        ));
+        assert_eq!(bus.address.get(), Some(0x42));
+        bus.master_writes(&driver, &[9, 8, 7]);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (3, 3, 0))]
+        );
+        assert_eq!(app.read(rx), [9, 8, 7, 0]);
+
+        // Once the hardware asks for the receive buffer again, it keeps it
+        // across a switch to master mode and back.
+        hil::i2c::I2CHwSlaveClient::write_expected(&driver);
+        assert!(bus.receive.is_some());
+        app.command(&driver, DRIVER_NUM, 8, 0, 0);
+        assert!(!bus.listening.get());
+        assert!(bus.receive.is_some());
+        app.command(&driver, DRIVER_NUM, 8, 1, 0x42);
+        assert!(bus.listening.get());
+        bus.master_writes(&driver, &[6]);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (3, 1, 0))]
+        );
+    }
+
+    #[test]
+    fn nack_refuses_one_write_while_listening() {
+        let kernel = TestKernel::new(&["app"]);
+        let bus = leak(MockBus::new());
+        let driver = driver(kernel, bus);
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let rx = app.buffer(&[0; 4]);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::SLAVE_RX, rx);
+        app.command(&driver, DRIVER_NUM, 8, 1, 0x42);
+
+        // The receive buffer is already with the hardware, and the NACK
+        // still applies to the next write.
+        assert!(bus.receive.is_some());
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 9, 1, 0),
+            SyscallReturn::Success
+        ));
+        assert_eq!(bus.nack.get(), Some(true));
+        bus.master_writes(&driver, &[1, 2]);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (3, 0, 0))]
+        );
+        assert_eq!(app.read(rx), [0; 4]);
+
+        // Only that write is refused.
+        assert_eq!(bus.nack.get(), Some(false));
+        hil::i2c::I2CHwSlaveClient::write_expected(&driver);
+        bus.master_writes(&driver, &[1, 2]);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (3, 2, 0))]
+        );
+        assert_eq!(app.read(rx), [1, 2, 0, 0]);
+
+        // Hardware that cannot NACK on demand says so.
+        bus.nack.set(None);
+        assert!(matches!(
+            app.command(&driver, DRIVER_NUM, 9, 1, 0),
+            SyscallReturn::Failure(ErrorCode::NOSUPPORT)
+        ));
+    }
+}
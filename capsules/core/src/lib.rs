@@ -10,6 +10,7 @@ pub mod adc;
 pub mod alarm;
 pub mod button;
 pub mod console;
+pub mod delay;
 pub mod driver;
 pub mod gpio;
 pub mod i2c_master;
@@ -17,6 +18,7 @@ pub mod i2c_master_slave_driver;
 pub mod led;
 pub mod low_level_debug;
 pub mod process_console;
+pub mod ring_buffer;
 pub mod rng;
 pub mod spi_controller;
 pub mod spi_peripheral;
@@ -7,17 +7,19 @@ use super::DebugEntry;
 //   2. LowLevelDebug: App ## alert code ##\n
 //   3. LowLevelDebug: App ## prints ##\n
 //   4. LowLevelDebug: App ## prints ## ##\n
+//   5. The board's banner, followed by \n, truncated to fit the buffer
+//   6. LowLevelDebug: App ## alert code ## (repeated ## times)\n
 //
 // Each ## above is a usize printed in hexadecimal, with a leading 0x.
 
-// The longest message is either 1 or 4, depending on the size of a usize.
-pub const BUF_LEN: usize = max(45 + 2 * USIZE_DIGITS, 35 + 3 * USIZE_DIGITS);
+// The longest message is 6.
+pub const BUF_LEN: usize = 56 + 3 * USIZE_DIGITS;
 
 // Formats the given DebugEntry using the provided buffer. Returns the length of
 // the message.
 pub(crate) fn format_entry(app_num: usize, entry: DebugEntry, buffer: &mut [u8]) -> usize {
     use core::fmt::write;
-    use DebugEntry::{AlertCode, Dropped, Print1, Print2};
+    use DebugEntry::{AlertCode, Dropped, Print1, Print2, Repeated};
     let mut adapter = WriteAdapter::new(buffer);
     let _ = match entry {
         Dropped(count) => write(
@@ -45,18 +47,55 @@ pub(crate) fn format_entry(app_num: usize, entry: DebugEntry, buffer: &mut [u8])
                 app_num, num1, num2
             ),
         ),
+        Repeated(code, count) => write(
+            &mut adapter,
+            format_args!(
+                "LowLevelDebug: App 0x{:x} alert code 0x{:x} (repeated 0x{:x} times)\n",
+                app_num, code, count
+            ),
+        ),
     };
     adapter.finish()
 }
 
-// The length of a hex-formatted usize, excluding the leading 0x.
-const USIZE_DIGITS: usize = 2 * core::mem::size_of::<usize>();
+// Writes the banner and a newline into the buffer, truncating the banner if
+// needed. Returns the length of the message.
+pub(crate) fn format_banner(banner: &str, buffer: &mut [u8]) -> usize {
+    let Some(max) = buffer.len().checked_sub(1) else {
+        return 0;
+    };
+    let len = core::cmp::min(banner.len(), max);
+    buffer[..len].copy_from_slice(&banner.as_bytes()[..len]);
+    buffer[len] = b'\n';
+    len + 1
+}
 
-// const implementation of max
-const fn max(a: usize, b: usize) -> usize {
-    [a, b][(b > a) as usize]
+// Formats each queued entry in turn and hands the bytes to `write` along with
+// their offset in the output, stopping once `dest_len` bytes have been written.
+// Returns the number of bytes written and whether any output was cut off.
+pub(crate) fn format_entries<F: FnMut(usize, &[u8])>(
+    app_num: usize,
+    entries: &[Option<DebugEntry>],
+    dest_len: usize,
+    mut write: F,
+) -> (usize, bool) {
+    let mut used = 0;
+    for entry in entries.iter().flatten() {
+        let mut buffer = [0; BUF_LEN];
+        let len = format_entry(app_num, *entry, &mut buffer);
+        let fits = core::cmp::min(len, dest_len - used);
+        write(used, &buffer[..fits]);
+        used += fits;
+        if fits < len {
+            return (used, true);
+        }
+    }
+    (used, false)
 }
 
+// The length of a hex-formatted usize, excluding the leading 0x.
+const USIZE_DIGITS: usize = 2 * core::mem::size_of::<usize>();
+
 // Adapter to allow core::fmt::write to write into a u8 slice.
 struct WriteAdapter<'b> {
     buffer: &'b mut [u8],
@@ -82,3 +121,75 @@ impl<'b> core::fmt::Write for WriteAdapter<'b> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DebugEntry::Print1;
+
+    fn dump(entries: &[Option<DebugEntry>], dest: &mut [u8]) -> (usize, bool) {
+        let len = dest.len();
+        format_entries(1, entries, len, |offset, bytes| {
+            dest[offset..offset + bytes.len()].copy_from_slice(bytes)
+        })
+    }
+
+    #[test]
+    fn dump_fits() {
+        const MSG: &[u8] = b"LowLevelDebug: App 0x1 prints 0x2\n";
+        let entries = [Some(Print1(2)), Some(Print1(2)), None, None];
+        let mut dest = [0; 2 * MSG.len()];
+        assert_eq!(dump(&entries, &mut dest), (2 * MSG.len(), false));
+        assert_eq!(&dest[..MSG.len()], MSG);
+        assert_eq!(&dest[MSG.len()..], MSG);
+    }
+
+    #[test]
+    fn dump_truncates() {
+        const MSG: &[u8] = b"LowLevelDebug: App 0x1 prints 0x2\n";
+        let entries = [Some(Print1(2)); 4];
+        let mut dest = [0; MSG.len() + 10];
+        assert_eq!(dump(&entries, &mut dest), (MSG.len() + 10, true));
+        assert_eq!(&dest[..MSG.len()], MSG);
+        assert_eq!(&dest[MSG.len()..], &MSG[..10]);
+    }
+
+    #[test]
+    fn banner_precedes_first_entry() {
+        const BANNER: &str = "Tock 2.1 on hail";
+        let mut output = [0; 2 * BUF_LEN];
+        let mut buffer = [0; BUF_LEN];
+
+        // What the UART sees after set_banner() and one entry: the banner
+        // transmission, then the entry it held back.
+        let len = format_banner(BANNER, &mut buffer);
+        output[..len].copy_from_slice(&buffer[..len]);
+        let entry_len = format_entry(1, Print1(2), &mut buffer);
+        output[len..len + entry_len].copy_from_slice(&buffer[..entry_len]);
+
+        let expected = b"Tock 2.1 on hail\nLowLevelDebug: App 0x1 prints 0x2\n";
+        assert_eq!(&output[..len + entry_len], &expected[..]);
+
+        // Long banners are cut to leave room for the newline.
+        let mut small = [0; 5];
+        assert_eq!(format_banner(BANNER, &mut small), 5);
+        assert_eq!(&small, b"Tock\n");
+    }
+
+    #[test]
+    fn longest_message_fits() {
+        let mut buffer = [0; BUF_LEN + 1];
+        let len = format_entry(
+            usize::MAX,
+            DebugEntry::Repeated(usize::MAX, usize::MAX),
+            &mut buffer,
+        );
+        assert_eq!(len, BUF_LEN);
+    }
+
+    #[test]
+    fn dump_empty() {
+        let mut dest = [0; 8];
+        assert_eq!(dump(&[None; 4], &mut dest), (0, false));
+    }
+}
@@ -5,8 +5,11 @@ mod fmt;
 
 use core::cell::Cell;
 
+use kernel::debug::{debug_level_enabled, DebugLevel};
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks};
 use kernel::hil::uart::{Transmit, TransmitClient};
+use kernel::processbuffer::WriteableProcessBuffer;
 use kernel::syscall::CommandReturn;
 use kernel::{ErrorCode, ProcessId};
 
@@ -15,9 +18,50 @@ pub use fmt::BUF_LEN;
 
 pub const DRIVER_NUM: usize = crate::driver::NUM::LowLevelDebug as usize;
 
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Buffer that queued debug entries are copied into by command 4.
+    pub const DUMP: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Flag for command 4: clear the queue after copying it out.
+const DUMP_FLAG_CLEAR: usize = 1 << 0;
+
+/// Number of consecutive identical alert codes from an app that are printed
+/// before further repeats are collapsed into one summary line.
+pub const REPEAT_THRESHOLD: usize = 3;
+
+/// How long after the last collapsed repeat the summary line is printed, if
+/// the board provided a `RepeatTimeout`.
+pub const REPEAT_TIMEOUT_MS: u32 = 1000;
+
+/// Alarm used to print the summary of collapsed alert codes once an app stops
+/// repeating them, rather than only when it sends a different entry.
+///
+/// It is re-armed for every collapsed repeat, so boards normally give it a
+/// virtual alarm of their own; every `hil::time::Alarm` implements it.
+pub trait RepeatTimeout<'a> {
+    fn set_timeout_client(&self, client: &'a dyn AlarmClient);
+
+    /// (Re)start the timeout, `REPEAT_TIMEOUT_MS` from now.
+    fn start_timeout(&self);
+}
+
+impl<'a, A: Alarm<'a>> RepeatTimeout<'a> for A {
+    fn set_timeout_client(&self, client: &'a dyn AlarmClient) {
+        self.set_alarm_client(client);
+    }
+
+    fn start_timeout(&self) {
+        self.set_alarm(self.now(), self.ticks_from_ms(REPEAT_TIMEOUT_MS));
+    }
+}
+
 pub struct LowLevelDebug<'u, U: Transmit<'u>> {
     buffer: Cell<Option<&'static mut [u8]>>,
-    grant: Grant<AppData, UpcallCount<0>, AllowRoCount<0>, AllowRwCount<0>>,
+    grant: Grant<AppData, UpcallCount<0>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     // grant_failed is set to true when LowLevelDebug fails to allocate an app's
     // grant region. When it has a chance, LowLevelDebug will print a message
     // indicating a grant initialization has failed, then set this back to
@@ -25,22 +69,43 @@ pub struct LowLevelDebug<'u, U: Transmit<'u>> {
     // using grant storage, it will at least output an error indicating some
     // application's message was dropped.
     grant_failed: Cell<bool>,
+    // Banner registered by the board, printed once before the first entry.
+    banner: Cell<Option<&'static str>>,
     uart: &'u U,
+    repeat_timeout: Cell<Option<&'u dyn RepeatTimeout<'u>>>,
 }
 
 impl<'u, U: Transmit<'u>> LowLevelDebug<'u, U> {
     pub fn new(
         buffer: &'static mut [u8],
         uart: &'u U,
-        grant: Grant<AppData, UpcallCount<0>, AllowRoCount<0>, AllowRwCount<0>>,
+        grant: Grant<AppData, UpcallCount<0>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     ) -> LowLevelDebug<'u, U> {
         LowLevelDebug {
             buffer: Cell::new(Some(buffer)),
             grant,
             grant_failed: Cell::new(false),
+            banner: Cell::new(None),
             uart,
+            repeat_timeout: Cell::new(None),
         }
     }
+
+    /// Register a banner, such as the kernel version and board name, that is
+    /// printed on its own line before the first debug entry so that logs
+    /// identify the build they came from. Banners longer than `BUF_LEN - 1`
+    /// bytes are truncated.
+    pub fn set_banner(&self, banner: &'static str) {
+        self.banner.set(Some(banner));
+    }
+
+    /// Print the summary of an app's collapsed alert codes once it has not
+    /// repeated them for `REPEAT_TIMEOUT_MS`. Without a timeout, the summary
+    /// is only printed when the app sends a different entry.
+    pub fn set_repeat_timeout(&'u self, timeout: &'u dyn RepeatTimeout<'u>) {
+        timeout.set_timeout_client(self);
+        self.repeat_timeout.set(Some(timeout));
+    }
 }
 
 impl<'u, U: Transmit<'u>> kernel::syscall::SyscallDriver for LowLevelDebug<'u, U> {
@@ -51,11 +116,20 @@ impl<'u, U: Transmit<'u>> kernel::syscall::SyscallDriver for LowLevelDebug<'u, U
         r3: usize,
         caller_id: ProcessId,
     ) -> CommandReturn {
+        // Entries below the runtime debug verbosity are dropped.
         match minor_num {
             0 => return CommandReturn::success(),
-            1 => self.push_entry(DebugEntry::AlertCode(r2), caller_id),
-            2 => self.push_entry(DebugEntry::Print1(r2), caller_id),
-            3 => self.push_entry(DebugEntry::Print2(r2, r3), caller_id),
+            1 if debug_level_enabled(DebugLevel::Error) => {
+                self.push_entry(DebugEntry::AlertCode(r2), caller_id)
+            }
+            2 if debug_level_enabled(DebugLevel::Debug) => {
+                self.push_entry(DebugEntry::Print1(r2), caller_id)
+            }
+            3 if debug_level_enabled(DebugLevel::Debug) => {
+                self.push_entry(DebugEntry::Print2(r2, r3), caller_id)
+            }
+            1..=3 => {}
+            4 => return self.dump_entries(r2 & DUMP_FLAG_CLEAR != 0, caller_id),
             _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
         CommandReturn::success()
@@ -76,6 +150,13 @@ impl<'u, U: Transmit<'u>> TransmitClient for LowLevelDebug<'u, U> {
         // Identify and transmit the next queued entry. If there are no queued
         // entries remaining, store buffer.
 
+        // The banner goes first, as it was pending when the first entry
+        // arrived.
+        if let Some(banner) = self.banner.take() {
+            self.transmit_banner(tx_buffer, banner);
+            return;
+        }
+
         // Prioritize printing the "grant init failed" message over per-app
         // debug entries.
         if self.grant_failed.take() {
@@ -107,19 +188,65 @@ impl<'u, U: Transmit<'u>> TransmitClient for LowLevelDebug<'u, U> {
     }
 }
 
+impl<'u, U: Transmit<'u>> AlarmClient for LowLevelDebug<'u, U> {
+    fn alarm(&self) {
+        // Print the summary of every app whose alert codes are being
+        // collapsed.
+        for process_grant in self.grant.iter() {
+            let processid = process_grant.processid();
+            let summary = process_grant.enter(|app_data, _| app_data.repeat.flush());
+            if let Some(summary) = summary {
+                self.emit_entry(summary, processid);
+            }
+        }
+    }
+}
+
 // -----------------------------------------------------------------------------
 // Implementation details below
 // -----------------------------------------------------------------------------
 
 impl<'u, U: Transmit<'u>> LowLevelDebug<'u, U> {
+    // Collapses repeated alert codes, then emits the entry along with the
+    // summary of any repeats it ends.
+    fn push_entry(&self, entry: DebugEntry, processid: ProcessId) {
+        match self
+            .grant
+            .enter(processid, |app_data, _| app_data.repeat.push(entry))
+        {
+            Ok(collapsed) => {
+                let (summary, entry) = match collapsed {
+                    Collapsed::Emit(summary, entry) => (summary, Some(entry)),
+                    Collapsed::Suppressed => {
+                        if let Some(timeout) = self.repeat_timeout.get() {
+                            timeout.start_timeout();
+                        }
+                        (None, None)
+                    }
+                };
+                for to_print in [summary, entry].into_iter().flatten() {
+                    self.emit_entry(to_print, processid);
+                }
+            }
+            // emit_entry reports the grant failure.
+            Err(_) => self.emit_entry(entry, processid),
+        }
+    }
+
     // If the UART is not busy (the buffer is available), transmits the entry.
     // Otherwise, adds it to the app's queue.
-    fn push_entry(&self, entry: DebugEntry, processid: ProcessId) {
+    fn emit_entry(&self, entry: DebugEntry, processid: ProcessId) {
         use DebugEntry::Dropped;
 
         if let Some(buffer) = self.buffer.take() {
-            self.transmit_entry(buffer, processid.id(), entry);
-            return;
+            match self.banner.take() {
+                // Print the banner now and queue the entry behind it.
+                Some(banner) => self.transmit_banner(buffer, banner),
+                None => {
+                    self.transmit_entry(buffer, processid.id(), entry);
+                    return;
+                }
+            }
         }
 
         let result = self.grant.enter(processid, |borrow, _| {
@@ -147,6 +274,48 @@ impl<'u, U: Transmit<'u>> LowLevelDebug<'u, U> {
         }
     }
 
+    // Copies the formatted contents of the app's queue into its allowed
+    // buffer, optionally clearing the queue afterwards. Returns the number of
+    // bytes copied and whether the output was truncated.
+    fn dump_entries(&self, clear: bool, processid: ProcessId) -> CommandReturn {
+        self.grant
+            .enter(processid, |app_data, kernel_data| {
+                kernel_data
+                    .get_readwrite_processbuffer(rw_allow::DUMP)
+                    .and_then(|dump| {
+                        dump.mut_enter(|dest| {
+                            fmt::format_entries(
+                                processid.id(),
+                                &app_data.queue,
+                                dest.len(),
+                                |offset, bytes| {
+                                    dest[offset..offset + bytes.len()].copy_from_slice(bytes)
+                                },
+                            )
+                        })
+                    })
+                    .map(|(copied, truncated)| {
+                        if clear {
+                            app_data.queue = Default::default();
+                        }
+                        CommandReturn::success_u32_u32(copied as u32, truncated as u32)
+                    })
+                    .unwrap_or(CommandReturn::failure(ErrorCode::RESERVE))
+            })
+            .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+    }
+
+    // Immediately prints the banner to the UART.
+    fn transmit_banner(&self, buffer: &'static mut [u8], banner: &'static str) {
+        let msg_len = fmt::format_banner(banner, buffer);
+        let _ = self
+            .uart
+            .transmit_buffer(buffer, msg_len)
+            .map_err(|(_, returned_buffer)| {
+                self.buffer.set(Some(returned_buffer));
+            });
+    }
+
     // Immediately prints the provided entry to the UART.
     fn transmit_entry(&self, buffer: &'static mut [u8], app_num: usize, entry: DebugEntry) {
         let msg_len = fmt::format_entry(app_num, entry, buffer);
@@ -169,12 +338,119 @@ const QUEUE_SIZE: usize = 4;
 #[derive(Default)]
 pub struct AppData {
     queue: [Option<DebugEntry>; QUEUE_SIZE],
+    repeat: RepeatRun,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum DebugEntry {
-    Dropped(usize),       // Some debug messages were dropped
-    AlertCode(usize),     // Display a predefined alert code
-    Print1(usize),        // Print a single number
-    Print2(usize, usize), // Print two numbers
+    Dropped(usize),         // Some debug messages were dropped
+    AlertCode(usize),       // Display a predefined alert code
+    Print1(usize),          // Print a single number
+    Print2(usize, usize),   // Print two numbers
+    Repeated(usize, usize), // An alert code was collapsed this many times
+}
+
+// What to print for an entry pushed to a `RepeatRun`.
+#[derive(Debug, PartialEq)]
+enum Collapsed {
+    // Print the summary of the run the entry ended, if any, then the entry.
+    Emit(Option<DebugEntry>, DebugEntry),
+    // The entry repeats the run's code past the threshold, print nothing.
+    Suppressed,
+}
+
+// The run of identical alert codes an app most recently sent.
+#[derive(Default)]
+struct RepeatRun {
+    code: usize,
+    // Number of times `code` was sent in a row, 0 if the app's last entry
+    // was not an alert code.
+    count: usize,
+}
+
+impl RepeatRun {
+    fn push(&mut self, entry: DebugEntry) -> Collapsed {
+        if let DebugEntry::AlertCode(code) = entry {
+            if self.count > 0 && code == self.code {
+                self.count = self.count.saturating_add(1);
+                if self.count > REPEAT_THRESHOLD {
+                    return Collapsed::Suppressed;
+                }
+                return Collapsed::Emit(None, entry);
+            }
+        }
+        let summary = self.flush();
+        if let DebugEntry::AlertCode(code) = entry {
+            self.code = code;
+            self.count = 1;
+        }
+        Collapsed::Emit(summary, entry)
+    }
+
+    // Ends the run, returning its summary if any of its codes was collapsed.
+    fn flush(&mut self) -> Option<DebugEntry> {
+        let count = core::mem::take(&mut self.count);
+        if count > REPEAT_THRESHOLD {
+            Some(DebugEntry::Repeated(self.code, count - REPEAT_THRESHOLD))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DebugEntry::{AlertCode, Print1, Repeated};
+
+    #[test]
+    fn repeated_code_is_collapsed() {
+        let mut run = RepeatRun::default();
+        let mut output = [0u8; 8 * BUF_LEN];
+        let mut used = 0;
+
+        // A fault loop sends the same code 1000 times, then something else.
+        let entries = core::iter::repeat(AlertCode(0x5))
+            .take(1000)
+            .chain([Print1(0x7)]);
+        for entry in entries {
+            if let Collapsed::Emit(summary, entry) = run.push(entry) {
+                for to_print in [summary, Some(entry)].into_iter().flatten() {
+                    used += fmt::format_entry(1, to_print, &mut output[used..]);
+                }
+            }
+        }
+
+        let expected: &[u8] = b"LowLevelDebug: App 0x1 alert code 0x5\n\
+            LowLevelDebug: App 0x1 alert code 0x5\n\
+            LowLevelDebug: App 0x1 alert code 0x5\n\
+            LowLevelDebug: App 0x1 alert code 0x5 (repeated 0x3e5 times)\n\
+            LowLevelDebug: App 0x1 prints 0x7\n";
+        assert_eq!(&output[..used], expected);
+    }
+
+    #[test]
+    fn timeout_flushes_run() {
+        let mut run = RepeatRun::default();
+        for _ in 0..REPEAT_THRESHOLD {
+            assert!(matches!(run.push(AlertCode(0x2)), Collapsed::Emit(None, _)));
+        }
+        assert_eq!(run.push(AlertCode(0x2)), Collapsed::Suppressed);
+        assert_eq!(run.push(AlertCode(0x2)), Collapsed::Suppressed);
+        assert_eq!(run.flush(), Some(Repeated(0x2, 2)));
+
+        // After the flush the code starts a new run and is printed again.
+        assert_eq!(run.flush(), None);
+        assert_eq!(
+            run.push(AlertCode(0x2)),
+            Collapsed::Emit(None, AlertCode(0x2))
+        );
+
+        // A different code ends a run that stayed under the threshold without
+        // a summary.
+        assert_eq!(
+            run.push(AlertCode(0x3)),
+            Collapsed::Emit(None, AlertCode(0x3))
+        );
+    }
 }
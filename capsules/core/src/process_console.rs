@@ -14,10 +14,16 @@ use kernel::utilities::cells::TakeCell;
 use kernel::ProcessId;
 
 use kernel::debug;
+use kernel::debug::DebugLevel;
 use kernel::hil::time::{Alarm, AlarmClient};
 use kernel::hil::uart;
 use kernel::introspection::KernelInfo;
-use kernel::process::{ProcessPrinter, ProcessPrinterContext, State};
+use kernel::ipc::IPCUpcallType;
+use kernel::process::{
+    FunctionCallSource, ProcessAddresses, ProcessPrinter, ProcessPrinterContext, StackUsage, State,
+    Task,
+};
+use kernel::processbuffer::ReadableProcessSlice;
 use kernel::utilities::binary_write::BinaryWrite;
 use kernel::ErrorCode;
 use kernel::Kernel;
@@ -35,11 +41,20 @@ pub const READ_BUF_LEN: usize = 4;
 pub const COMMAND_BUF_LEN: usize = 32;
 /// Default size for the history command.
 pub const DEFAULT_COMMAND_HISTORY_LEN: usize = 10;
+/// Default number of lines of `list` and `sched` output shown before pausing.
+pub const DEFAULT_PAGE_LINES: usize = 20;
 
 /// List of valid commands for printing help. Consolidated as these are
 /// displayed in a few different cases.
 const VALID_COMMANDS_STR: &[u8] =
-    b"help status list stop start fault boot terminate process kernel reset panic\r\n";
+    b"help status list sched stacks stop start fault crash boot terminate process allow upcalls peek kernel verbosity reset panic\r\n";
+
+/// Largest number of bytes of an allow buffer printed by the `allow` command.
+const ALLOW_DUMP_LEN: usize = 64;
+
+/// Number of bytes of process memory on each line printed by the `peek`
+/// command.
+const PEEK_LINE_LEN: usize = 16;
 
 /// Escape character for ANSI escape sequences.
 const ESC: u8 = '\x1B' as u8;
@@ -47,6 +62,12 @@ const ESC: u8 = '\x1B' as u8;
 /// End of line character.
 const EOL: u8 = '\0' as u8;
 
+/// Shown when output pauses at the end of a page.
+const MORE_PROMPT: &[u8] = b"-- more (space: next page, q: quit) --";
+
+/// Erases `MORE_PROMPT` from the terminal.
+const MORE_ERASE: &[u8] = b"\r                                      \r";
+
 /// States used for state machine to allow printing large strings asynchronously
 /// across multiple calls. This reduces the size of the buffer needed to print
 /// each section of the debug message.
@@ -67,6 +88,20 @@ enum WriterState {
         index: isize,
         total: isize,
     },
+    Sched {
+        index: isize,
+        total: isize,
+    },
+    Stacks {
+        index: isize,
+        total: isize,
+    },
+    Peek {
+        process_id: ProcessId,
+        start: usize,
+        len: usize,
+        index: isize,
+    },
 }
 
 impl Default for WriterState {
@@ -75,6 +110,83 @@ impl Default for WriterState {
     }
 }
 
+impl WriterState {
+    /// Whether each step of this state prints one line of output, and so the
+    /// output can be split into pages.
+    fn paged(&self) -> bool {
+        matches!(
+            self,
+            WriterState::List { .. }
+                | WriterState::Sched { .. }
+                | WriterState::Stacks { .. }
+                | WriterState::Peek { .. }
+        )
+    }
+}
+
+/// Pagination state for long command output.
+///
+/// Output pauses once a page of lines has been written and resumes when the
+/// user presses space, or stops when they press `q`.
+struct Pager {
+    /// Lines per page, or 0 to never pause.
+    page_lines: Cell<usize>,
+    /// Lines written on the current page.
+    lines: Cell<usize>,
+    paused: Cell<bool>,
+}
+
+impl Pager {
+    fn new(page_lines: usize) -> Pager {
+        Pager {
+            page_lines: Cell::new(page_lines),
+            lines: Cell::new(0),
+            paused: Cell::new(false),
+        }
+    }
+
+    /// Start a new output on an empty page.
+    fn start(&self) {
+        self.lines.set(0);
+        self.paused.set(false);
+    }
+
+    /// Account for a line about to be written. Returns `false`, and pauses,
+    /// if the current page is already full.
+    fn advance(&self) -> bool {
+        let page_lines = self.page_lines.get();
+        if page_lines != 0 && self.lines.get() >= page_lines {
+            self.paused.set(true);
+            false
+        } else {
+            self.lines.set(self.lines.get() + 1);
+            true
+        }
+    }
+
+    fn paused(&self) -> bool {
+        self.paused.get()
+    }
+
+    /// Handle a key pressed while paused. Returns `Some(true)` to continue
+    /// with the next page, `Some(false)` to stop the output, and `None` if the
+    /// key is ignored.
+    fn key(&self, byte: u8) -> Option<bool> {
+        match byte {
+            b' ' => {
+                self.lines.set(0);
+                self.paused.set(false);
+                Some(true)
+            }
+            b'q' | b'Q' => {
+                self.paused.set(false);
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+}
+
 /// Data structure to hold addresses about how the kernel is stored in memory on
 /// the chip.
 ///
@@ -133,6 +245,9 @@ pub struct ProcessConsole<
     /// received after finishing echoing the last newline character.
     execute: Cell<bool>,
 
+    /// Splits `list` and `sched` output into pages.
+    pager: Pager,
+
     /// Reference to the kernel object so we can access process state.
     kernel: &'static Kernel,
 
@@ -240,6 +355,185 @@ impl BinaryWrite for ConsoleWriter {
     }
 }
 
+/// Parse a decimal or `0x`-prefixed hexadecimal number.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Write the length of `data` followed by a hex dump of at most
+/// `ALLOW_DUMP_LEN` of its bytes, 16 to a line.
+fn write_hex_dump(writer: &mut ConsoleWriter, data: &ReadableProcessSlice) {
+    let _ = write(writer, format_args!("Length: {} bytes\r\n", data.len()));
+    let shown = cmp::min(data.len(), ALLOW_DUMP_LEN);
+    for (i, byte) in data.iter().take(shown).enumerate() {
+        if i % 16 == 0 {
+            let _ = write(writer, format_args!("{:04x}:", i));
+        }
+        let _ = write(writer, format_args!(" {:02x}", byte.get()));
+        if i % 16 == 15 || i + 1 == shown {
+            let _ = write(writer, format_args!("\r\n"));
+        }
+    }
+    if data.len() > shown {
+        let _ = write(
+            writer,
+            format_args!("({} more bytes)\r\n", data.len() - shown),
+        );
+    }
+}
+
+/// Whether all `len` bytes at `addr` lie in the memory the process can
+/// access, between the start of its RAM and its application break.
+fn peek_in_bounds(addresses: &ProcessAddresses, addr: usize, len: usize) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => len > 0 && addr >= addresses.sram_start && end <= addresses.sram_app_brk,
+        None => false,
+    }
+}
+
+/// Write one line of the `peek` dump: the address of `bytes`, their values
+/// in hex, and the same bytes as ASCII with unprintable ones shown as `.`.
+fn write_peek_line(writer: &mut ConsoleWriter, addr: usize, bytes: &[u8]) {
+    let _ = write(writer, format_args!("{:#010x}:", addr));
+    for byte in bytes {
+        let _ = write(writer, format_args!(" {:02x}", byte));
+    }
+    for _ in bytes.len()..PEEK_LINE_LEN {
+        let _ = write(writer, format_args!("   "));
+    }
+    let _ = write(writer, format_args!("  |"));
+    for byte in bytes {
+        let shown = if byte.is_ascii_graphic() || *byte == b' ' {
+            *byte as char
+        } else {
+            '.'
+        };
+        let _ = write(writer, format_args!("{}", shown));
+    }
+    let _ = write(writer, format_args!("|\r\n"));
+}
+
+/// Label for how the scheduler treats a process in `state`.
+fn sched_label(state: State) -> &'static str {
+    match state {
+        State::Running => "runnable",
+        State::Yielded => "yielded",
+        State::StoppedRunning | State::StoppedYielded => "stopped",
+        State::Faulted => "faulted",
+        State::Terminated => "terminated",
+        State::CredentialsUnchecked => "unchecked",
+        State::CredentialsApproved => "approved",
+        State::CredentialsFailed => "failed",
+    }
+}
+
+/// Write one row of the `sched` table.
+fn write_sched_entry(
+    writer: &mut ConsoleWriter,
+    index: isize,
+    name: &str,
+    state: State,
+    ready: bool,
+) {
+    let _ = write(
+        writer,
+        format_args!(
+            " {:<4}{:<20}{:<12}{}\r\n",
+            index,
+            name,
+            sched_label(state),
+            if ready { "yes" } else { "no" }
+        ),
+    );
+}
+
+/// Write one row of the `stacks` table: how much of the process's stack has
+/// been used, if the kernel could measure it.
+fn write_stack_entry(
+    writer: &mut ConsoleWriter,
+    index: isize,
+    name: &str,
+    usage: Option<StackUsage>,
+) {
+    let _ = match usage {
+        Some(StackUsage { total, used }) => write(
+            writer,
+            format_args!(
+                " {:<4}{:<20}{:>7}{:>8}{:>5}%\r\n",
+                index,
+                name,
+                used,
+                total,
+                (used * 100).checked_div(total).unwrap_or(0)
+            ),
+        ),
+        None => write(writer, format_args!(" {:<4}{:<20}unknown\r\n", index, name)),
+    };
+}
+
+/// Write the trailing line of the `sched` table naming the process that will
+/// be offered to the scheduler next.
+fn write_sched_decision(writer: &mut ConsoleWriter, next: Option<&str>) {
+    match next {
+        Some(name) => {
+            let _ = write(writer, format_args!("Next to run: {}\r\n", name));
+        }
+        None => {
+            let _ = write(writer, format_args!("Next to run: none (idle)\r\n"));
+        }
+    }
+}
+
+/// Write the `upcalls` listing: one row per task queued for the process, in
+/// the order they will be delivered, then a summary line.
+fn write_pending_upcalls(
+    writer: &mut ConsoleWriter,
+    state: State,
+    dropped: usize,
+    tasks: impl Iterator<Item = Task>,
+) {
+    let _ = write(
+        writer,
+        format_args!(" #   Source  Driver      Subscribe\r\n"),
+    );
+    let mut queued = 0;
+    for (index, task) in tasks.enumerate() {
+        queued += 1;
+        let _ = match task {
+            Task::FunctionCall(call) => match call.source {
+                FunctionCallSource::Driver(id) => write(
+                    writer,
+                    format_args!(
+                        " {:<4}upcall  {:#010x}  {}\r\n",
+                        index, id.driver_num, id.subscribe_num
+                    ),
+                ),
+                FunctionCallSource::Kernel => {
+                    write(writer, format_args!(" {:<4}kernel\r\n", index))
+                }
+            },
+            Task::IPC((_, IPCUpcallType::Service)) => {
+                write(writer, format_args!(" {:<4}ipc     service\r\n", index))
+            }
+            Task::IPC((_, IPCUpcallType::Client)) => {
+                write(writer, format_args!(" {:<4}ipc     client\r\n", index))
+            }
+        };
+    }
+    let _ = write(
+        writer,
+        format_args!(
+            "Queued: {}  Dropped: {}  State: {}\r\n",
+            queued,
+            dropped,
+            sched_label(state)
+        ),
+    );
+}
+
 impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCapability>
     ProcessConsole<'a, COMMAND_HISTORY_LEN, A, C>
 {
@@ -281,6 +575,7 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
 
             running: Cell::new(false),
             execute: Cell::new(false),
+            pager: Pager::new(DEFAULT_PAGE_LINES),
             kernel: kernel,
             kernel_addresses: kernel_addresses,
             reset_function: reset_function,
@@ -288,6 +583,12 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
         }
     }
 
+    /// Set how many lines of `list` and `sched` output are shown before the
+    /// console waits for a key press. 0 disables paging.
+    pub fn set_page_lines(&self, lines: usize) {
+        self.pager.page_lines.set(lines);
+    }
+
     /// Start the process console listening for user commands.
     pub fn start(&self) -> Result<(), ErrorCode> {
         if self.running.get() == false {
@@ -358,6 +659,43 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                     }
                 }
             }
+            WriterState::Sched { index, total } => {
+                if index + 1 == total {
+                    WriterState::Empty
+                } else {
+                    WriterState::Sched {
+                        index: index + 1,
+                        total,
+                    }
+                }
+            }
+            WriterState::Stacks { index, total } => {
+                if index + 1 == total {
+                    WriterState::Empty
+                } else {
+                    WriterState::Stacks {
+                        index: index + 1,
+                        total,
+                    }
+                }
+            }
+            WriterState::Peek {
+                process_id,
+                start,
+                len,
+                index,
+            } => {
+                if (index + 1) as usize * PEEK_LINE_LEN >= len {
+                    WriterState::Empty
+                } else {
+                    WriterState::Peek {
+                        process_id,
+                        start,
+                        len,
+                        index: index + 1,
+                    }
+                }
+            }
             WriterState::Empty => WriterState::Empty,
         }
     }
@@ -524,6 +862,86 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                         }
                     });
             }
+            WriterState::Sched { index, total } => {
+                let mut console_writer = ConsoleWriter::new();
+                let mut local_index = -1;
+                // The first ready process in kernel order is the one the
+                // kernel loop will offer to run next.
+                let mut next: Option<&'static str> = None;
+                self.kernel
+                    .process_each_capability(&self.capability, |process| {
+                        local_index += 1;
+                        if local_index == index {
+                            write_sched_entry(
+                                &mut console_writer,
+                                index,
+                                process.get_process_name(),
+                                process.get_state(),
+                                process.ready(),
+                            );
+                        }
+                        if next.is_none() && process.ready() {
+                            next = Some(process.get_process_name());
+                        }
+                    });
+                if index + 1 == total {
+                    write_sched_decision(&mut console_writer, next);
+                }
+                let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+            }
+            WriterState::Stacks { index, total: _ } => {
+                let mut console_writer = ConsoleWriter::new();
+                let mut local_index = -1;
+                self.kernel
+                    .process_each_capability(&self.capability, |process| {
+                        local_index += 1;
+                        if local_index == index {
+                            write_stack_entry(
+                                &mut console_writer,
+                                index,
+                                process.get_process_name(),
+                                process.debug_stack_usage(),
+                            );
+                        }
+                    });
+                let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+            }
+            WriterState::Peek {
+                process_id,
+                start,
+                len,
+                index,
+            } => {
+                let offset = index as usize * PEEK_LINE_LEN;
+                let addr = start + offset;
+                let mut bytes = [0; PEEK_LINE_LEN];
+                let line = &mut bytes[..cmp::min(PEEK_LINE_LEN, len - offset)];
+                let mut read = Err(ErrorCode::INVAL);
+                self.kernel
+                    .process_each_capability(&self.capability, |process| {
+                        if process.processid() == process_id {
+                            read = process.debug_read_memory(addr, line);
+                        }
+                    });
+
+                let mut console_writer = ConsoleWriter::new();
+                match read {
+                    Ok(()) => write_peek_line(&mut console_writer, addr, line),
+                    Err(_) => {
+                        // The process exited or its memory shrank since the
+                        // command started.
+                        let _ = write(
+                            &mut console_writer,
+                            format_args!("Memory at {:#010x} is no longer readable\r\n", addr),
+                        );
+                    }
+                }
+                let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+                if read.is_err() {
+                    self.writer_state.replace(WriterState::Empty);
+                    self.prompt();
+                }
+            }
             WriterState::Empty => {
                 self.prompt();
             }
@@ -613,29 +1031,59 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                                         }
                                     });
                             });
-                        } else if clean_str.starts_with("fault") {
+                        } else if clean_str.starts_with("fault") || clean_str.starts_with("crash")
+                        {
+                            // `crash` faults a process like `fault`, but
+                            // reports unknown names and never targets the
+                            // kernel.
+                            let crash = clean_str.starts_with("crash");
                             let argument = clean_str.split_whitespace().nth(1);
-                            argument.map(|name| {
-                                self.kernel
-                                    .process_each_capability(&self.capability, |proc| {
-                                        let proc_name = proc.get_process_name();
-                                        if proc_name == name {
-                                            proc.set_fault_state();
-                                            let mut console_writer = ConsoleWriter::new();
-                                            let _ = write(
-                                                &mut console_writer,
-                                                format_args!(
-                                                    "Process {} now faulted\r\n",
-                                                    proc_name
-                                                ),
-                                            );
+                            match argument {
+                                None if crash => {
+                                    let _ = self.write_bytes(b"Usage: crash <process name>\r\n");
+                                }
+                                Some("kernel") if crash => {
+                                    // Crashing the kernel is what `panic` is
+                                    // for, do not let this command alias it.
+                                    let _ = self.write_bytes(
+                                        b"Refusing to crash the kernel, use `panic` instead\r\n",
+                                    );
+                                }
+                                None => {}
+                                Some(name) => {
+                                    let mut found = false;
+                                    self.kernel
+                                        .process_each_capability(&self.capability, |proc| {
+                                            let proc_name = proc.get_process_name();
+                                            if proc_name == name {
+                                                found = true;
+                                                proc.set_fault_state();
+                                                let mut console_writer = ConsoleWriter::new();
+                                                let _ = write(
+                                                    &mut console_writer,
+                                                    format_args!(
+                                                        "Process {} now faulted\r\n",
+                                                        proc_name
+                                                    ),
+                                                );
 
-                                            let _ = self.write_bytes(
-                                                &(console_writer.buf)[..console_writer.size],
-                                            );
-                                        }
-                                    });
-                            });
+                                                let _ = self.write_bytes(
+                                                    &(console_writer.buf)[..console_writer.size],
+                                                );
+                                            }
+                                        });
+                                    if crash && !found {
+                                        let mut console_writer = ConsoleWriter::new();
+                                        let _ = write(
+                                            &mut console_writer,
+                                            format_args!("No process named {}\r\n", name),
+                                        );
+                                        let _ = self.write_bytes(
+                                            &(console_writer.buf)[..console_writer.size],
+                                        );
+                                    }
+                                }
+                            }
                         } else if clean_str.starts_with("terminate") {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
@@ -681,11 +1129,50 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
 
                             if count > 0 {
                                 // Start the state machine to print each separately.
+                                self.pager.start();
                                 self.write_state(WriterState::List {
                                     index: -1,
                                     total: count,
                                 });
                             }
+                        } else if clean_str.starts_with("sched") {
+                            let _ =
+                                self.write_bytes(b" #   Name                State       Ready\r\n");
+
+                            let mut count = 0;
+                            self.kernel.process_each_capability(&self.capability, |_| {
+                                count += 1;
+                            });
+
+                            if count > 0 {
+                                self.pager.start();
+                                self.write_state(WriterState::Sched {
+                                    index: -1,
+                                    total: count,
+                                });
+                            } else {
+                                let mut console_writer = ConsoleWriter::new();
+                                write_sched_decision(&mut console_writer, None);
+                                let _ =
+                                    self.write_bytes(&(console_writer.buf)[..console_writer.size]);
+                            }
+                        } else if clean_str.starts_with("stacks") {
+                            let _ = self.write_bytes(
+                                b" #   Name                   Used   Total   Use\r\n",
+                            );
+
+                            let mut count = 0;
+                            self.kernel.process_each_capability(&self.capability, |_| {
+                                count += 1;
+                            });
+
+                            if count > 0 {
+                                self.pager.start();
+                                self.write_state(WriterState::Stacks {
+                                    index: -1,
+                                    total: count,
+                                });
+                            }
                         } else if clean_str.starts_with("status") {
                             let info: KernelInfo = KernelInfo::new(self.kernel);
                             let mut console_writer = ConsoleWriter::new();
@@ -753,6 +1240,149 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                                         }
                                     });
                             });
+                        } else if clean_str.starts_with("allow") {
+                            let mut args = clean_str.split_whitespace().skip(1);
+                            let name = args.next();
+                            let driver_num = args.next().and_then(parse_number);
+                            let writeable = match args.next() {
+                                Some("ro") => Some(false),
+                                Some("rw") => Some(true),
+                                _ => None,
+                            };
+                            let allow_num = args.next().and_then(parse_number);
+                            match (name, driver_num, writeable, allow_num) {
+                                (Some(name), Some(driver_num), Some(writeable), Some(allow_num)) => {
+                                    let info: KernelInfo = KernelInfo::new(self.kernel);
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let mut found = false;
+                                    self.kernel
+                                        .process_each_capability(&self.capability, |proc| {
+                                            if found || proc.get_process_name() != name {
+                                                return;
+                                            }
+                                            found = true;
+                                            let res = info.with_allowed_buffer(
+                                                proc.processid(),
+                                                driver_num,
+                                                allow_num,
+                                                writeable,
+                                                &self.capability,
+                                                |data| write_hex_dump(&mut console_writer, data),
+                                            );
+                                            if let Err(e) = res {
+                                                let _ = write(
+                                                    &mut console_writer,
+                                                    format_args!("No such allow buffer: {:?}\r\n", e),
+                                                );
+                                            }
+                                        });
+                                    if !found {
+                                        let _ = write(
+                                            &mut console_writer,
+                                            format_args!("Process {} not found\r\n", name),
+                                        );
+                                    }
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                }
+                                _ => {
+                                    let _ = self.write_bytes(
+                                        b"Usage: allow <process name> <driver> <ro|rw> <allow num>\r\n",
+                                    );
+                                }
+                            }
+                        } else if clean_str.starts_with("upcalls") {
+                            match clean_str.split_whitespace().nth(1) {
+                                Some(name) => {
+                                    let mut console_writer = ConsoleWriter::new();
+                                    let mut found = false;
+                                    self.kernel
+                                        .process_each_capability(&self.capability, |proc| {
+                                            if found || proc.get_process_name() != name {
+                                                return;
+                                            }
+                                            found = true;
+                                            write_pending_upcalls(
+                                                &mut console_writer,
+                                                proc.get_state(),
+                                                proc.debug_dropped_upcall_count(),
+                                                (0..proc.pending_tasks())
+                                                    .map_while(|index| proc.peek_task(index)),
+                                            );
+                                        });
+                                    if !found {
+                                        let _ = write(
+                                            &mut console_writer,
+                                            format_args!("Process {} not found\r\n", name),
+                                        );
+                                    }
+                                    let _ = self
+                                        .write_bytes(&(console_writer.buf)[..console_writer.size]);
+                                }
+                                None => {
+                                    let _ = self.write_bytes(b"Usage: upcalls <process name>\r\n");
+                                }
+                            }
+                        } else if clean_str.starts_with("peek") {
+                            let mut args = clean_str.split_whitespace().skip(1);
+                            let name = args.next();
+                            let addr = args.next().and_then(parse_number);
+                            let len = args.next().and_then(parse_number);
+                            match (name, addr, len) {
+                                (Some(name), Some(addr), Some(len)) => {
+                                    // The process, and whether the range is
+                                    // in its memory.
+                                    let mut found = None;
+                                    self.kernel
+                                        .process_each_capability(&self.capability, |proc| {
+                                            if found.is_none() && proc.get_process_name() == name {
+                                                found = Some((
+                                                    proc.processid(),
+                                                    peek_in_bounds(&proc.get_addresses(), addr, len),
+                                                ));
+                                            }
+                                        });
+                                    let mut console_writer = ConsoleWriter::new();
+                                    match found {
+                                        Some((process_id, true)) => {
+                                            self.pager.start();
+                                            self.write_state(WriterState::Peek {
+                                                process_id,
+                                                start: addr,
+                                                len,
+                                                index: -1,
+                                            });
+                                        }
+                                        Some((_, false)) => {
+                                            let _ = write(
+                                                &mut console_writer,
+                                                format_args!(
+                                                    "Range is outside the memory of process {}\r\n",
+                                                    name
+                                                ),
+                                            );
+                                        }
+                                        None => {
+                                            let _ = write(
+                                                &mut console_writer,
+                                                format_args!("Process {} not found\r\n", name),
+                                            );
+                                        }
+                                    }
+                                    // The dump itself is written by the state
+                                    // machine.
+                                    if console_writer.size > 0 {
+                                        let _ = self.write_bytes(
+                                            &(console_writer.buf)[..console_writer.size],
+                                        );
+                                    }
+                                }
+                                _ => {
+                                    let _ = self.write_bytes(
+                                        b"Usage: peek <process name> <address> <length>\r\n",
+                                    );
+                                }
+                            }
                         } else if clean_str.starts_with("kernel") {
                             let mut console_writer = ConsoleWriter::new();
                             let _ = write(
@@ -770,6 +1400,39 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
                             // Prints kernel memory by moving the writer to the
                             // start state.
                             self.writer_state.replace(WriterState::KernelStart);
+                        } else if clean_str.starts_with("verbosity") {
+                            let argument = clean_str.split_whitespace().nth(1);
+                            let mut console_writer = ConsoleWriter::new();
+                            match argument.map(DebugLevel::from_name) {
+                                None => {
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!(
+                                            "Debug verbosity is {}.\r\n",
+                                            debug::debug_level().name()
+                                        ),
+                                    );
+                                }
+                                Some(Some(level)) => {
+                                    debug::set_debug_level(level);
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!(
+                                            "Debug verbosity set to {}.\r\n",
+                                            level.name()
+                                        ),
+                                    );
+                                }
+                                Some(None) => {
+                                    let _ = write(
+                                        &mut console_writer,
+                                        format_args!(
+                                            "Valid levels are: off error info debug trace\r\n"
+                                        ),
+                                    );
+                                }
+                            }
+                            let _ = self.write_bytes(&(console_writer.buf)[..console_writer.size]);
                         } else if clean_str.starts_with("reset") {
                             self.reset_function.map_or_else(
                                 || {
@@ -813,8 +1476,14 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
     /// Start or iterate the state machine for an asynchronous write operation
     /// spread across multiple callback cycles.
     fn write_state(&self, state: WriterState) {
-        self.writer_state.replace(self.next_state(state));
-        self.create_state_buffer(self.writer_state.get());
+        let next = self.next_state(state);
+        if next.paged() && !self.pager.advance() {
+            // The page is full. Stay in `state` until a key is pressed.
+            let _ = self.write_bytes(MORE_PROMPT);
+            return;
+        }
+        self.writer_state.replace(next);
+        self.create_state_buffer(next);
     }
 
     fn write_byte(&self, byte: u8) -> Result<(), ErrorCode> {
@@ -935,7 +1604,10 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
 
             let current_state = self.writer_state.get();
             if current_state != WriterState::Empty {
-                self.write_state(current_state);
+                // While paused, the output continues from `received_buffer`.
+                if !self.pager.paused() {
+                    self.write_state(current_state);
+                }
                 return;
             }
 
@@ -962,6 +1634,18 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
         if error == uart::Error::None {
             match rx_len {
                 0 => debug!("ProcessConsole had read of 0 bytes"),
+                1 if self.pager.paused() => match self.pager.key(read_buf[0]) {
+                    Some(true) => {
+                        let _ = self.write_bytes(MORE_ERASE);
+                        self.write_state(self.writer_state.get());
+                    }
+                    Some(false) => {
+                        let _ = self.write_bytes(MORE_ERASE);
+                        self.writer_state.replace(WriterState::Empty);
+                        self.prompt();
+                    }
+                    None => {}
+                },
                 1 => {
                     self.command_buffer.map(|command| {
                         let previous_byte = self.previous_byte.get();
@@ -1164,3 +1848,445 @@ impl<'a, const COMMAND_HISTORY_LEN: usize, A: Alarm<'a>, C: ProcessManagementCap
         let _ = self.uart.receive_buffer(read_buf, 1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak, leak_buffer, TestCapability, TestKernel};
+    use kernel::hil::time::{Freq1KHz, Ticks32, Time};
+    use kernel::process::{Process, ProcessPrinterText};
+
+    fn output(writer: &ConsoleWriter) -> &str {
+        str::from_utf8(&writer.buf[..writer.size]).unwrap()
+    }
+
+    #[test]
+    fn sched_lists_each_state() {
+        let processes = [
+            ("blink", State::Running, true),
+            ("c_hello", State::Yielded, false),
+            ("sensors", State::Yielded, true),
+            ("paused", State::StoppedYielded, false),
+            ("broken", State::Faulted, false),
+        ];
+
+        let mut writer = ConsoleWriter::new();
+        let mut next = None;
+        for (index, &(name, state, ready)) in processes.iter().enumerate() {
+            write_sched_entry(&mut writer, index as isize, name, state, ready);
+            if next.is_none() && ready {
+                next = Some(name);
+            }
+        }
+        write_sched_decision(&mut writer, next);
+
+        let mut lines = output(&writer).split("\r\n");
+        let expected = [
+            ("blink", "runnable", "yes"),
+            ("c_hello", "yielded", "no"),
+            ("sensors", "yielded", "yes"),
+            ("paused", "stopped", "no"),
+            ("broken", "faulted", "no"),
+        ];
+        for (index, (name, label, ready)) in expected.iter().enumerate() {
+            let mut fields = lines.next().unwrap().split_whitespace();
+            assert_eq!(fields.next().map(|f| f.parse::<usize>()), Some(Ok(index)));
+            assert_eq!(fields.next(), Some(*name));
+            assert_eq!(fields.next(), Some(*label));
+            assert_eq!(fields.next(), Some(*ready));
+        }
+        assert_eq!(lines.next(), Some("Next to run: blink"));
+    }
+
+    #[test]
+    fn upcalls_lists_queued_tasks() {
+        use kernel::process::FunctionCall;
+        use kernel::upcall::UpcallId;
+
+        let upcall = |driver_num, subscribe_num| {
+            Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(UpcallId {
+                    driver_num,
+                    subscribe_num,
+                }),
+                argument0: 0,
+                argument1: 0,
+                argument2: 0,
+                argument3: 0,
+                pc: 0,
+            })
+        };
+        // The task queue of a process with an alarm and a console upcall
+        // waiting for it to yield.
+        let queue = [upcall(0x0, 0), upcall(0x1, 2)];
+
+        let mut writer = ConsoleWriter::new();
+        write_pending_upcalls(&mut writer, State::Running, 1, queue.iter().copied());
+        assert_eq!(
+            output(&writer),
+            " #   Source  Driver      Subscribe\r\n \
+              0   upcall  0x00000000  0\r\n \
+              1   upcall  0x00000001  2\r\n\
+             Queued: 2  Dropped: 1  State: runnable\r\n"
+        );
+    }
+
+    #[test]
+    fn stacks_reports_painted_usage() {
+        use kernel::process::STACK_PAINT;
+
+        // A 2 KiB stack region painted at load, whose top 412 bytes the
+        // process has since written.
+        let mut stack = [STACK_PAINT; 2048];
+        stack[2048 - 412..].fill(0);
+        // A value equal to the paint above the deepest write is still used.
+        stack[2040] = STACK_PAINT;
+        let usage = StackUsage::measure(&stack);
+        assert_eq!(
+            usage,
+            StackUsage {
+                total: 2048,
+                used: 412
+            }
+        );
+
+        let mut writer = ConsoleWriter::new();
+        write_stack_entry(&mut writer, 0, "blink", Some(usage));
+        write_stack_entry(&mut writer, 1, "c_hello", None);
+        assert_eq!(
+            output(&writer),
+            " 0   blink                   412    2048   20%\r\n \
+              1   c_hello             unknown\r\n"
+        );
+    }
+
+    #[test]
+    fn allow_dump_is_hex() {
+        let data: [u8; 20] = core::array::from_fn(|i| 0xe0 + i as u8);
+        let slice: &ReadableProcessSlice = (&data[..]).into();
+        let mut writer = ConsoleWriter::new();
+        write_hex_dump(&mut writer, slice);
+        assert_eq!(
+            output(&writer),
+            "Length: 20 bytes\r\n\
+             0000: e0 e1 e2 e3 e4 e5 e6 e7 e8 e9 ea eb ec ed ee ef\r\n\
+             0010: f0 f1 f2 f3\r\n"
+        );
+    }
+
+    #[test]
+    fn allow_dump_is_truncated() {
+        let data = [0u8; ALLOW_DUMP_LEN + 6];
+        let slice: &ReadableProcessSlice = (&data[..]).into();
+        let mut writer = ConsoleWriter::new();
+        write_hex_dump(&mut writer, slice);
+        assert!(output(&writer).ends_with(
+            "0030: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\r\n(6 more bytes)\r\n"
+        ));
+    }
+
+    #[test]
+    fn pager_pauses_after_a_page() {
+        let pager = Pager::new(3);
+        pager.start();
+
+        // Write seven lines, stopping whenever the pager pauses.
+        let write_until_paused = |written: &mut usize| {
+            while *written < 7 && pager.advance() {
+                *written += 1;
+            }
+        };
+        let mut written = 0;
+
+        write_until_paused(&mut written);
+        assert_eq!(written, 3);
+        assert!(pager.paused());
+
+        // Other keys leave the output paused.
+        assert_eq!(pager.key(b'x'), None);
+        assert!(pager.paused());
+
+        assert_eq!(pager.key(b' '), Some(true));
+        assert!(!pager.paused());
+        write_until_paused(&mut written);
+        assert_eq!(written, 6);
+
+        assert_eq!(pager.key(b'q'), Some(false));
+        assert!(!pager.paused());
+    }
+
+    #[test]
+    fn pager_disabled_never_pauses() {
+        let pager = Pager::new(0);
+        pager.start();
+        assert!((0..100).all(|_| pager.advance()));
+        assert!(!pager.paused());
+    }
+
+    #[test]
+    fn numbers_parse_as_decimal_or_hex() {
+        assert_eq!(parse_number("1"), Some(1));
+        assert_eq!(parse_number("0x20003"), Some(0x20003));
+        assert_eq!(parse_number("0xg"), None);
+        assert_eq!(parse_number("rw"), None);
+    }
+
+    #[test]
+    fn sched_reports_idle_when_nothing_is_ready() {
+        let mut writer = ConsoleWriter::new();
+        write_sched_decision(&mut writer, None);
+        assert_eq!(output(&writer), "Next to run: none (idle)\r\n");
+    }
+
+    #[test]
+    fn peek_only_reads_process_memory() {
+        // The addresses a process with 4 KiB of accessible RAM would report.
+        let addresses = ProcessAddresses {
+            flash_start: 0x40000,
+            flash_non_protected_start: 0x40040,
+            flash_integrity_end: core::ptr::null(),
+            flash_end: 0x48000,
+            sram_start: 0x2000_4000,
+            sram_app_brk: 0x2000_5000,
+            sram_grant_start: 0x2000_5800,
+            sram_end: 0x2000_6000,
+            sram_heap_start: None,
+            sram_stack_top: None,
+            sram_stack_bottom: None,
+        };
+        assert!(peek_in_bounds(&addresses, 0x2000_4000, 0x1000));
+        assert!(peek_in_bounds(&addresses, 0x2000_4ff0, 16));
+        // Past the application break, into grant memory.
+        assert!(!peek_in_bounds(&addresses, 0x2000_4ff0, 17));
+        assert!(!peek_in_bounds(&addresses, 0x2000_5800, 4));
+        // Below the process's memory, and wrapping around the address space.
+        assert!(!peek_in_bounds(&addresses, 0x2000_3ffc, 8));
+        assert!(!peek_in_bounds(&addresses, 0x2000_4000, usize::MAX));
+        assert!(!peek_in_bounds(&addresses, 0x2000_4000, 0));
+
+        let mut writer = ConsoleWriter::new();
+        write_peek_line(&mut writer, 0x2000_4000, b"Hello, Tock!\n\0\xff\x7f");
+        write_peek_line(&mut writer, 0x2000_4010, b"ab");
+        assert_eq!(
+            output(&writer),
+            "0x20004000: 48 65 6c 6c 6f 2c 20 54 6f 63 6b 21 0a 00 ff 7f  |Hello, Tock!....|\r\n\
+             0x20004010: 61 62                                            |ab|\r\n"
+        );
+    }
+
+    /// A UART that holds on to whatever it is asked to send.
+    struct MockUart {
+        tx: TakeCell<'static, [u8]>,
+        tx_len: Cell<usize>,
+    }
+
+    impl MockUart {
+        fn new() -> Self {
+            MockUart {
+                tx: TakeCell::empty(),
+                tx_len: Cell::new(0),
+            }
+        }
+
+        /// Whether the console's last transmission was `expected`.
+        fn sent(&self, expected: &str) -> bool {
+            self.tx
+                .map_or(false, |tx| &tx[..self.tx_len.get()] == expected.as_bytes())
+        }
+    }
+
+    impl<'a> uart::Transmit<'a> for MockUart {
+        fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.tx.replace(tx_buffer);
+            self.tx_len.set(tx_len);
+            Ok(())
+        }
+
+        fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn transmit_abort(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    impl<'a> uart::Receive<'a> for MockUart {
+        fn set_receive_client(&self, _client: &'a dyn uart::ReceiveClient) {}
+
+        fn receive_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            _rx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::NOSUPPORT, rx_buffer))
+        }
+
+        fn receive_word(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn receive_abort(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    /// An alarm that never fires.
+    struct MockAlarm;
+
+    impl Time for MockAlarm {
+        type Ticks = Ticks32;
+        type Frequency = Freq1KHz;
+
+        fn now(&self) -> Ticks32 {
+            0u32.into()
+        }
+    }
+
+    impl<'a> Alarm<'a> for MockAlarm {
+        fn set_alarm_client(&self, _client: &'a dyn AlarmClient) {}
+
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {}
+
+        fn get_alarm(&self) -> Ticks32 {
+            0u32.into()
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            false
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            0u32.into()
+        }
+    }
+
+    fn console<'a>(
+        uart: &'a MockUart,
+        kernel: &'static Kernel,
+    ) -> ProcessConsole<'a, 1, MockAlarm, TestCapability> {
+        let null = core::ptr::null();
+        ProcessConsole::new(
+            uart,
+            &MockAlarm,
+            leak(ProcessPrinterText::new()),
+            leak_buffer(500),
+            leak_buffer(1),
+            leak_buffer(500),
+            leak_buffer(COMMAND_BUF_LEN),
+            leak([Command::default()]),
+            kernel,
+            KernelAddresses {
+                stack_start: null,
+                stack_end: null,
+                text_start: null,
+                text_end: null,
+                read_only_data_start: null,
+                relocations_start: null,
+                relocations_end: null,
+                bss_start: null,
+                bss_end: null,
+            },
+            None,
+            TestCapability,
+        )
+    }
+
+    /// Run `command` as if it had been typed at the console.
+    fn run(console: &ProcessConsole<1, MockAlarm, TestCapability>, command: &str) {
+        console.command_buffer.map(|buffer| {
+            buffer.fill(0);
+            buffer[..command.len()].copy_from_slice(command.as_bytes());
+        });
+        console.read_command();
+    }
+
+    #[test]
+    fn crash_checks_its_target() {
+        for command in ["fault crashy", "crash crashy"] {
+            let kernel = TestKernel::new(&["blink", "crashy"]);
+            let uart = MockUart::new();
+            let console = console(&uart, kernel.kernel());
+
+            run(&console, command);
+            assert!(uart.sent("Process crashy now faulted\r\n"));
+            assert_eq!(kernel.process(0).get_state(), State::Yielded);
+            assert_eq!(kernel.process(1).get_state(), State::Faulted);
+        }
+
+        // Unlike `fault`, `crash` refuses the kernel, even when a process
+        // has that name, and reports names that match no process.
+        let kernel = TestKernel::new(&["kernel"]);
+        for (command, expected) in [
+            (
+                "crash kernel",
+                "Refusing to crash the kernel, use `panic` instead\r\n",
+            ),
+            ("crash blnk", "No process named blnk\r\n"),
+        ] {
+            let uart = MockUart::new();
+            run(&console(&uart, kernel.kernel()), command);
+            assert!(uart.sent(expected));
+            assert_eq!(kernel.process(0).get_state(), State::Yielded);
+        }
+    }
+
+    #[test]
+    fn verbosity_sets_debug_level() {
+        let kernel = TestKernel::new(&[]);
+        // Runs `command` on a fresh console and returns what it printed.
+        let verbosity = |command: fmt::Arguments| {
+            let mut typed = ConsoleWriter::new();
+            let _ = write(&mut typed, command);
+            let uart = MockUart::new();
+            run(&console(&uart, kernel.kernel()), output(&typed));
+            uart.tx.map_or(ConsoleWriter::new(), |tx| {
+                let mut printed = ConsoleWriter::new();
+                let _ = fmt::Write::write_str(
+                    &mut printed,
+                    str::from_utf8(&tx[..uart.tx_len.get()]).unwrap(),
+                );
+                printed
+            })
+        };
+
+        for level in DebugLevel::ALL {
+            let mut expected = ConsoleWriter::new();
+            let _ = write(
+                &mut expected,
+                format_args!("Debug verbosity set to {}.\r\n", level.name()),
+            );
+            let printed = verbosity(format_args!("verbosity {}", level.name()));
+            assert_eq!(output(&printed), output(&expected));
+            assert_eq!(debug::debug_level(), level);
+
+            let mut expected = ConsoleWriter::new();
+            let _ = write(
+                &mut expected,
+                format_args!("Debug verbosity is {}.\r\n", level.name()),
+            );
+            let printed = verbosity(format_args!("verbosity"));
+            assert_eq!(output(&printed), output(&expected));
+        }
+
+        let printed = verbosity(format_args!("verbosity loud"));
+        assert_eq!(
+            output(&printed),
+            "Valid levels are: off error info debug trace\r\n"
+        );
+        assert_eq!(debug::debug_level(), DebugLevel::Trace);
+
+        verbosity(format_args!("verbosity debug"));
+        assert_eq!(debug::debug_level(), DebugLevel::Debug);
+    }
+}
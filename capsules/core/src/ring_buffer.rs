@@ -0,0 +1,202 @@
+//! Fixed-capacity ring buffer for capsules that buffer data between events.
+//!
+//! Unlike `kernel::collections::ring_buffer::RingBuffer`, which wraps a slice
+//! the caller provides, this buffer owns its storage, so it can be placed
+//! directly in a grant region or a capsule's state without a separate static
+//! buffer. All `N` slots are usable.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! use capsules_core::ring_buffer::RingBuffer;
+//!
+//! let mut samples: RingBuffer<u16, 4> = RingBuffer::new();
+//! assert_eq!(samples.push(10), Ok(()));
+//! assert_eq!(samples.push(20), Ok(()));
+//! assert_eq!(samples.peek(), Some(&10));
+//! assert_eq!(samples.pop(), Some(10));
+//! assert_eq!(samples.len(), 1);
+//! ```
+
+/// A FIFO queue of up to `N` elements of `T`.
+pub struct RingBuffer<T, const N: usize> {
+    ring: [Option<T>; N],
+    // Index of the oldest element.
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub fn new() -> RingBuffer<T, N> {
+        RingBuffer {
+            ring: core::array::from_fn(|_| None),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// The most elements the buffer can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The number of elements in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Add `val` after the newest element. If the buffer is full it is left
+    /// unchanged and `val` is returned.
+    pub fn push(&mut self, val: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(val);
+        }
+        self.ring[(self.head + self.len) % N] = Some(val);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Add `val` after the newest element, dropping the oldest element to
+    /// make room if the buffer is full. Returns the dropped element.
+    pub fn push_overwrite(&mut self, val: T) -> Option<T> {
+        let dropped = if self.is_full() { self.pop() } else { None };
+        // A zero-capacity buffer has no room even after dropping.
+        if self.push(val).is_err() {
+            return None;
+        }
+        dropped
+    }
+
+    /// Remove and return the oldest element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let val = self.ring[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        val
+    }
+
+    /// The oldest element, without removing it.
+    pub fn peek(&self) -> Option<&T> {
+        if self.is_empty() {
+            None
+        } else {
+            self.ring[self.head].as_ref()
+        }
+    }
+
+    /// Remove every element.
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+        self.head = 0;
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> RingBuffer<T, N> {
+        RingBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+
+    #[test]
+    fn fill_and_drain() {
+        let mut buf: RingBuffer<usize, 4> = RingBuffer::new();
+        assert_eq!(buf.capacity(), 4);
+        assert!(buf.is_empty());
+        assert_eq!(buf.peek(), None);
+
+        for i in 0..4 {
+            assert_eq!(buf.len(), i);
+            assert_eq!(buf.push(i), Ok(()));
+        }
+        assert!(buf.is_full());
+        assert_eq!(buf.peek(), Some(&0));
+
+        for i in 0..4 {
+            assert_eq!(buf.pop(), Some(i));
+        }
+        assert!(buf.is_empty());
+        assert_eq!(buf.pop(), None);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut buf: RingBuffer<usize, 3> = RingBuffer::new();
+
+        // Keep one element queued while the head moves round the buffer
+        // several times.
+        assert_eq!(buf.push(0), Ok(()));
+        for i in 1..10 {
+            assert_eq!(buf.push(i), Ok(()));
+            assert_eq!(buf.pop(), Some(i - 1));
+            assert_eq!(buf.peek(), Some(&i));
+            assert_eq!(buf.len(), 1);
+        }
+        assert_eq!(buf.push(10), Ok(()));
+        assert_eq!(buf.push(11), Ok(()));
+        assert!(buf.is_full());
+        assert_eq!(
+            [buf.pop(), buf.pop(), buf.pop()],
+            [Some(9), Some(10), Some(11)]
+        );
+    }
+
+    #[test]
+    fn push_when_full() {
+        let mut buf: RingBuffer<u8, 2> = RingBuffer::new();
+        assert_eq!(buf.push(1), Ok(()));
+        assert_eq!(buf.push(2), Ok(()));
+
+        // A plain push is refused and leaves the contents alone.
+        assert_eq!(buf.push(3), Err(3));
+        assert_eq!(buf.peek(), Some(&1));
+
+        // Overwriting drops the oldest element instead.
+        assert_eq!(buf.push_overwrite(3), Some(1));
+        assert_eq!(buf.push_overwrite(4), Some(2));
+        assert_eq!(buf.len(), 2);
+        assert_eq!([buf.pop(), buf.pop()], [Some(3), Some(4)]);
+
+        // Overwriting with room to spare drops nothing.
+        assert_eq!(buf.push_overwrite(5), None);
+        assert_eq!(buf.len(), 1);
+    }
+
+    #[test]
+    fn clear_empties_buffer() {
+        let mut buf: RingBuffer<u8, 3> = RingBuffer::new();
+        for b in [1, 2, 3] {
+            assert_eq!(buf.push(b), Ok(()));
+        }
+        assert_eq!(buf.pop(), Some(1));
+        buf.clear();
+        assert!(buf.is_empty());
+        assert_eq!(buf.pop(), None);
+        assert_eq!(buf.push(7), Ok(()));
+        assert_eq!(buf.peek(), Some(&7));
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut buf: RingBuffer<u8, 0> = RingBuffer::new();
+        assert!(buf.is_full());
+        assert_eq!(buf.push(1), Err(1));
+        assert_eq!(buf.push_overwrite(1), None);
+        assert_eq!(buf.pop(), None);
+    }
+}
@@ -7,6 +7,15 @@
 //! randomness. A single command starts the RNG, the callback is called when the
 //! requested amount of randomness is received, or the buffer is filled.
 //!
+//! An app can also flush the randomness buffered by the RNG stack (for
+//! example, after a restart) so its next request is served only from entropy
+//! generated afterwards. The randomness an app has requested but not yet
+//! received is kept in its grant, which is freed with the process.
+//!
+//! An app that needs a single random 32-bit word can ask for it without a
+//! buffer round trip. The driver keeps one word back from the source for this,
+//! and returns it directly from the command when it has one.
+//!
 //! Usage
 //! -----
 //!
@@ -27,7 +36,7 @@ use kernel::hil::entropy;
 use kernel::hil::entropy::{Entropy32, Entropy8};
 use kernel::hil::rng;
 use kernel::hil::rng::{Client, Continue, Random, Rng};
-use kernel::processbuffer::WriteableProcessBuffer;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer, WriteableProcessSlice};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::OptionalCell;
 use kernel::{ErrorCode, ProcessId};
@@ -49,10 +58,36 @@ pub struct App {
     idx: usize,
 }
 
+/// A word of randomness kept back from the source so that it can be
+/// returned without waiting.
+struct SpareWord(OptionalCell<u32>);
+
+impl SpareWord {
+    fn take(&self) -> Option<u32> {
+        self.0.take()
+    }
+
+    /// Keep the next word of `randomness` if no word is kept yet. Returns
+    /// whether a word is kept.
+    fn fill(&self, randomness: &mut dyn Iterator<Item = u32>) -> bool {
+        if self.0.is_none() {
+            if let Some(word) = randomness.next() {
+                self.0.set(word);
+            }
+        }
+        self.0.is_some()
+    }
+
+    fn clear(&self) {
+        self.0.clear();
+    }
+}
+
 pub struct RngDriver<'a> {
     rng: &'a dyn Rng<'a>,
     apps: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
     getting_randomness: Cell<bool>,
+    spare: SpareWord,
 }
 
 impl<'a> RngDriver<'a> {
@@ -64,6 +99,15 @@ impl<'a> RngDriver<'a> {
             rng: rng,
             apps: grant,
             getting_randomness: Cell::new(false),
+            spare: SpareWord(OptionalCell::empty()),
+        }
+    }
+
+    /// Ask the source for randomness, unless a request is already running.
+    fn request(&self) {
+        if !self.getting_randomness.get() {
+            self.getting_randomness.set(true);
+            let _ = self.rng.get();
         }
     }
 }
@@ -86,48 +130,7 @@ impl rng::Client for RngDriver<'_> {
                         .get_readwrite_processbuffer(rw_allow::BUFFER)
                         .and_then(|buffer| {
                             buffer.mut_enter(|buffer| {
-                                let mut idx = oldidx;
-                                let mut remaining = oldremaining;
-
-                                // Check that the app is not asking for more than can
-                                // fit in the provided buffer
-                                if buffer.len() < idx {
-                                    // The buffer does not fit at all
-                                    // anymore (the app must've swapped
-                                    // buffers), end the operation
-                                    return (0, 0);
-                                } else if buffer.len() < idx + remaining {
-                                    remaining = buffer.len() - idx;
-                                }
-
-                                // Add all available and requested randomness to the app buffer.
-
-                                // 1. Slice buffer to start from current idx
-                                let buf = &buffer[idx..(idx + remaining)];
-                                // 2. Take at most as many random samples as needed to fill the buffer
-                                //    (if app.remaining is not word-sized, take an extra one).
-                                let remaining_ints = if remaining % 4 == 0 {
-                                    remaining / 4
-                                } else {
-                                    remaining / 4 + 1
-                                };
-
-                                // 3. Zip over the randomness iterator and chunks
-                                //    of up to 4 bytes from the buffer.
-                                for (inp, outs) in
-                                    randomness.take(remaining_ints).zip(buf.chunks(4))
-                                {
-                                    // 4. For each word of randomness input, update
-                                    //    the remaining and idx and add to buffer.
-                                    let inbytes = u32::to_le_bytes(inp);
-                                    outs.iter().zip(inbytes.iter()).for_each(|(out, inb)| {
-                                        out.set(*inb);
-                                        remaining -= 1;
-                                        idx += 1;
-                                    });
-                                }
-
-                                (idx, remaining)
+                                fill_buffer(buffer, oldidx, oldremaining, randomness)
                             })
                         })
                         .unwrap_or(
@@ -158,7 +161,8 @@ impl rng::Client for RngDriver<'_> {
             }
         }
 
-        if done {
+        // Once every app is served, keep a word back for command 4.
+        if done && self.spare.fill(randomness) {
             self.getting_randomness.set(false);
             rng::Continue::Done
         } else {
@@ -167,7 +171,68 @@ impl rng::Client for RngDriver<'_> {
     }
 }
 
+// Writes up to `remaining` bytes of randomness into `buffer`, starting at
+// `idx`, and leaves the rest of the buffer untouched. Returns the updated
+// `(idx, remaining)` pair.
+fn fill_buffer(
+    buffer: &WriteableProcessSlice,
+    idx: usize,
+    remaining: usize,
+    randomness: &mut dyn Iterator<Item = u32>,
+) -> (usize, usize) {
+    let mut idx = idx;
+    let mut remaining = remaining;
+
+    // Check that the app is not asking for more than can
+    // fit in the provided buffer
+    if buffer.len() < idx {
+        // The buffer does not fit at all
+        // anymore (the app must've swapped
+        // buffers), end the operation
+        return (0, 0);
+    } else if buffer.len() < idx + remaining {
+        remaining = buffer.len() - idx;
+    }
+
+    // Add all available and requested randomness to the app buffer.
+
+    // 1. Slice buffer to start from current idx
+    let buf = &buffer[idx..(idx + remaining)];
+    // 2. Take at most as many random samples as needed to fill the buffer
+    //    (if app.remaining is not word-sized, take an extra one).
+    let remaining_ints = if remaining % 4 == 0 {
+        remaining / 4
+    } else {
+        remaining / 4 + 1
+    };
+
+    // 3. Zip over the randomness iterator and chunks
+    //    of up to 4 bytes from the buffer.
+    for (inp, outs) in randomness.take(remaining_ints).zip(buf.chunks(4)) {
+        // 4. For each word of randomness input, update
+        //    the remaining and idx and add to buffer.
+        let inbytes = u32::to_le_bytes(inp);
+        outs.iter().zip(inbytes.iter()).for_each(|(out, inb)| {
+            out.set(*inb);
+            remaining -= 1;
+            idx += 1;
+        });
+    }
+
+    (idx, remaining)
+}
+
 impl<'a> SyscallDriver for RngDriver<'a> {
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Fill the buffer with up to `data` random bytes.
+    /// - `2`: Fill exactly `data` bytes of the buffer.
+    /// - `3`: Discard randomness buffered by the RNG stack.
+    /// - `4`: Get one random 32-bit word. Returns it directly if one is ready.
+    ///        Otherwise returns success without a value and delivers the word
+    ///        in the first 4 bytes of the buffer with the callback, or fails
+    ///        with `SIZE` if the buffer is shorter than 4 bytes.
     fn command(
         &self,
         command_num: usize,
@@ -190,14 +255,62 @@ impl<'a> SyscallDriver for RngDriver<'a> {
                     // Assume that the process has a callback & slice
                     // set. It might die or revoke them before the
                     // result arrives anyways
-                    if !self.getting_randomness.get() {
-                        self.getting_randomness.set(true);
-                        let _ = self.rng.get();
+                    self.request();
+
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+
+            2 /* Fill exactly the given number of bytes of the buffer */ => self
+                .apps
+                .enter(processid, |app, kernel_data| {
+                    // Unlike command 1, the count must fit in the buffer, so
+                    // the callback always reports exactly this many bytes and
+                    // the rest of the buffer is left as is.
+                    let len = kernel_data
+                        .get_readwrite_processbuffer(rw_allow::BUFFER)
+                        .map_or(0, |buffer| buffer.len());
+                    if data == 0 || data > len {
+                        return CommandReturn::failure(ErrorCode::INVAL);
                     }
+                    app.remaining = data;
+                    app.idx = 0;
+
+                    self.request();
 
                     CommandReturn::success()
                 })
                 .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+            3 /* Discard buffered randomness */ => {
+                self.rng.flush();
+                self.spare.clear();
+                CommandReturn::success()
+            }
+
+            4 /* Get a single random word */ => match self.spare.take() {
+                Some(word) => {
+                    // Replace the word for the next caller.
+                    self.request();
+                    CommandReturn::success_u32(word)
+                }
+                None => self
+                    .apps
+                    .enter(processid, |app, kernel_data| {
+                        // No word is ready, so fall back to delivering one
+                        // through the buffer and callback, as command 2 does.
+                        let len = kernel_data
+                            .get_readwrite_processbuffer(rw_allow::BUFFER)
+                            .map_or(0, |buffer| buffer.len());
+                        if len < 4 {
+                            return CommandReturn::failure(ErrorCode::SIZE);
+                        }
+                        app.remaining = 4;
+                        app.idx = 0;
+                        self.request();
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+            },
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -207,6 +320,9 @@ impl<'a> SyscallDriver for RngDriver<'a> {
     }
 }
 
+/// Provides the `Rng` interface on top of an `Entropy32` source, so a board
+/// with only an entropy-style TRNG can back `RngDriver`. Entropy words are
+/// passed to the client unchanged.
 pub struct Entropy32ToRandom<'a> {
     egen: &'a dyn Entropy32<'a>,
     client: OptionalCell<&'a dyn rng::Client>,
@@ -234,6 +350,10 @@ impl<'a> Rng<'a> for Entropy32ToRandom<'a> {
         self.egen.set_client(self);
         self.client.set(client);
     }
+
+    fn flush(&self) {
+        self.egen.flush();
+    }
 }
 
 impl entropy::Client32 for Entropy32ToRandom<'_> {
@@ -307,6 +427,13 @@ impl<'a> Entropy32<'a> for Entropy8To32<'a> {
         self.egen.set_client(self);
         self.client.set(client);
     }
+
+    fn flush(&self) {
+        // Drop the bytes collected towards the next word.
+        self.count.set(0);
+        self.bytes.set(0);
+        self.egen.flush();
+    }
 }
 
 impl entropy::Client8 for Entropy8To32<'_> {
@@ -403,6 +530,13 @@ impl<'a> Entropy8<'a> for Entropy32To8<'a> {
         self.egen.set_client(self);
         self.client.set(client);
     }
+
+    fn flush(&self) {
+        // Mark the rest of the current word as consumed.
+        self.entropy.set(0);
+        self.bytes_consumed.set(4);
+        self.egen.flush();
+    }
 }
 
 impl entropy::Client32 for Entropy32To8<'_> {
@@ -506,3 +640,231 @@ impl Client for SynchronousRandom<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{QueuedUpcall, TestKernel};
+    use kernel::hil::entropy::{Client32, Client8};
+    use kernel::syscall::SyscallReturn;
+
+    #[test]
+    fn fill_buffer_leaves_remainder() {
+        let mut data = [0xAAu8; 8];
+        let mut randomness = [0x04030201u32, 0x08070605].into_iter();
+        let buffer: &WriteableProcessSlice = (&mut data[..]).into();
+
+        assert_eq!(fill_buffer(buffer, 0, 4, &mut randomness), (4, 0));
+        assert_eq!(data, [1, 2, 3, 4, 0xAA, 0xAA, 0xAA, 0xAA]);
+        // Only the single word needed was consumed.
+        assert_eq!(randomness.next(), Some(0x08070605));
+    }
+
+    #[test]
+    fn fill_buffer_partial_word() {
+        let mut data = [0xAAu8; 8];
+        let mut randomness = [0x04030201u32, 0x08070605].into_iter();
+        let buffer: &WriteableProcessSlice = (&mut data[..]).into();
+
+        assert_eq!(fill_buffer(buffer, 2, 5, &mut randomness), (7, 0));
+        assert_eq!(data, [0xAA, 0xAA, 1, 2, 3, 4, 5, 0xAA]);
+    }
+
+    #[derive(Default)]
+    struct MockSource {
+        flushed: Cell<bool>,
+    }
+
+    impl<'a> Entropy8<'a> for MockSource {
+        fn get(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_client(&'a self, _: &'a dyn entropy::Client8) {}
+
+        fn flush(&self) {
+            self.flushed.set(true);
+        }
+    }
+
+    #[derive(Default)]
+    struct WordSink(Cell<Option<u32>>);
+
+    impl entropy::Client32 for WordSink {
+        fn entropy_available(
+            &self,
+            entropy: &mut dyn Iterator<Item = u32>,
+            _error: Result<(), ErrorCode>,
+        ) -> entropy::Continue {
+            self.0.set(entropy.next());
+            entropy::Continue::Done
+        }
+    }
+
+    #[derive(Default)]
+    struct MockSource32 {
+        requested: Cell<bool>,
+    }
+
+    impl<'a> Entropy32<'a> for MockSource32 {
+        fn get(&self) -> Result<(), ErrorCode> {
+            self.requested.set(true);
+            Ok(())
+        }
+
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_client(&'a self, _: &'a dyn entropy::Client32) {}
+    }
+
+    #[derive(Default)]
+    struct RandomSink {
+        words: [Cell<u32>; 4],
+        count: Cell<usize>,
+    }
+
+    impl rng::Client for RandomSink {
+        fn randomness_available(
+            &self,
+            randomness: &mut dyn Iterator<Item = u32>,
+            _error: Result<(), ErrorCode>,
+        ) -> rng::Continue {
+            for word in randomness {
+                let count = self.count.get();
+                if count == self.words.len() {
+                    return rng::Continue::Done;
+                }
+                self.words[count].set(word);
+                self.count.set(count + 1);
+            }
+            rng::Continue::More
+        }
+    }
+
+    #[test]
+    fn entropy32_backs_rng() {
+        let source = MockSource32::default();
+        let sink = RandomSink::default();
+        let rng = Entropy32ToRandom::new(&source);
+        rng.set_client(&sink);
+
+        assert_eq!(rng.get(), Ok(()));
+        assert!(source.requested.get());
+
+        // Words are delivered in order, and the client's request for more is
+        // passed back to the entropy source.
+        let more = rng.entropy_available(&mut [1u32, 2, 3].into_iter(), Ok(()));
+        assert_eq!(more, entropy::Continue::More);
+        let done = rng.entropy_available(&mut [4u32, 5].into_iter(), Ok(()));
+        assert_eq!(done, entropy::Continue::Done);
+        let words: [u32; 4] = core::array::from_fn(|i| sink.words[i].get());
+        assert_eq!(words, [1, 2, 3, 4]);
+    }
+
+    /// An RNG that records whether randomness was asked for.
+    #[derive(Default)]
+    struct MockRng {
+        requested: Cell<bool>,
+    }
+
+    impl<'a> Rng<'a> for MockRng {
+        fn get(&self) -> Result<(), ErrorCode> {
+            self.requested.set(true);
+            Ok(())
+        }
+
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_client(&'a self, _: &'a dyn Client) {}
+    }
+
+    #[test]
+    fn command_4_returns_a_word() {
+        let kernel = TestKernel::new(&["app"]);
+        let source = MockRng::default();
+        let driver = RngDriver::new(&source, kernel.create_grant(DRIVER_NUM));
+        let app = kernel.process(0);
+        app.subscribe(&driver, DRIVER_NUM, 0);
+        let word = || app.command(&driver, DRIVER_NUM, 4, 0, 0);
+
+        // With no word ready, a buffer too short to hold one is refused
+        // before anything is asked of the source.
+        let short = app.buffer(&[0; 2]);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::BUFFER, short);
+        assert!(matches!(word(), SyscallReturn::Failure(ErrorCode::SIZE)));
+        assert!(!source.requested.get());
+
+        // The word arrives through the buffer, and the next one is kept.
+        let buffer = app.buffer(&[0xAA; 8]);
+        app.allow_readwrite(&driver, DRIVER_NUM, rw_allow::BUFFER, buffer);
+        assert!(matches!(word(), SyscallReturn::Success));
+        assert!(source.requested.replace(false));
+        let more =
+            driver.randomness_available(&mut [0x04030201u32, 0xCAFEF00D].into_iter(), Ok(()));
+        assert_eq!(more, rng::Continue::Done);
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (0, 4, 0))]
+        );
+        assert_eq!(app.read(buffer), [1, 2, 3, 4, 0xAA, 0xAA, 0xAA, 0xAA]);
+
+        // The kept word is returned directly, and replaced.
+        assert!(matches!(word(), SyscallReturn::SuccessU32(0xCAFEF00D)));
+        assert!(source.requested.get());
+        assert!(app.take_upcalls().is_empty());
+    }
+
+    #[test]
+    fn spare_word_ready() {
+        let spare = SpareWord(OptionalCell::empty());
+        let mut randomness = [0xCAFEF00Du32, 0x12345678].into_iter();
+
+        // Only one word is kept, and it is handed out only once.
+        assert!(spare.fill(&mut randomness));
+        assert!(spare.fill(&mut randomness));
+        assert_eq!(randomness.next(), Some(0x12345678));
+        assert_eq!(spare.take(), Some(0xCAFEF00D));
+        assert_eq!(spare.take(), None);
+    }
+
+    #[test]
+    fn spare_word_must_wait() {
+        let spare = SpareWord(OptionalCell::empty());
+
+        // A source with nothing left leaves the caller waiting for more.
+        assert!(!spare.fill(&mut core::iter::empty()));
+        assert_eq!(spare.take(), None);
+
+        assert!(spare.fill(&mut [7u32].into_iter()));
+        spare.clear();
+        assert_eq!(spare.take(), None);
+    }
+
+    #[test]
+    fn flush_discards_buffered_bytes() {
+        let source = MockSource::default();
+        let sink = WordSink::default();
+        let conv = Entropy8To32::new(&source);
+        conv.client.set(&sink);
+
+        // Two bytes arrive before the request goes away and stay buffered.
+        let more = conv.entropy_available(&mut [0xEE, 0xEE].into_iter(), Ok(()));
+        assert_eq!(more, entropy::Continue::More);
+        assert_eq!(sink.0.get(), None);
+
+        conv.flush();
+        assert!(source.flushed.get());
+
+        // The next word is built only from bytes generated after the flush.
+        conv.entropy_available(&mut [1, 2, 3, 4].into_iter(), Ok(()));
+        assert_eq!(sink.0.get(), Some(0x04030201));
+    }
+}
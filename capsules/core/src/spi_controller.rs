@@ -1,5 +1,8 @@
 //! Provides userspace applications with the ability to communicate over the SPI
 //! bus.
+//!
+//! To check the wiring and driver underneath this capsule without an external
+//! device, see the loopback self-test in `test::spi_loopback`.
 
 use core::cell::Cell;
 use core::cmp;
@@ -8,7 +11,8 @@ use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCo
 use kernel::hil::spi::ClockPhase;
 use kernel::hil::spi::ClockPolarity;
 use kernel::hil::spi::{SpiMasterClient, SpiMasterDevice};
-use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::hil::time::{self, ConvertTicks};
+use kernel::processbuffer::{ReadableProcessBuffer, ReadableProcessSlice, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
@@ -44,18 +48,62 @@ pub const DEFAULT_WRITE_BUF_LENGTH: usize = 1024;
 // operation, while the index variable keeps track of the
 // index an ongoing operation is at in the buffers.
 
+/// Alarm used by `Spi` to wait between the bytes of a transfer.
+///
+/// This is implemented for every `hil::time::Alarm`, so boards can pass any
+/// alarm, normally a `VirtualMuxAlarm`.
+pub trait ByteDelay<'a> {
+    fn set_client(&self, client: &'a dyn time::AlarmClient);
+
+    /// Call the client once `us` microseconds have passed.
+    fn start_us(&self, us: u32);
+
+    /// Cancel a wait started with `start_us`.
+    fn stop(&self);
+}
+
+impl<'a, A: time::Alarm<'a>> ByteDelay<'a> for A {
+    fn set_client(&self, client: &'a dyn time::AlarmClient) {
+        self.set_alarm_client(client);
+    }
+
+    fn start_us(&self, us: u32) {
+        self.set_alarm(self.now(), self.ticks_from_us(us));
+    }
+
+    fn stop(&self) {
+        let _ = self.disarm();
+    }
+}
+
 #[derive(Default)]
 pub struct App {
     len: usize,
     index: usize,
+    // The transfer only writes, and the bytes received are discarded
+    write_only: bool,
+}
+
+impl App {
+    // Forget the progress of the last transfer.
+    fn reset(&mut self) {
+        self.len = 0;
+        self.index = 0;
+        self.write_only = false;
+    }
 }
 
 pub struct Spi<'a, S: SpiMasterDevice> {
     spi_master: &'a S,
     busy: Cell<bool>,
+    // An aborted operation is still held by the SPI hardware, and its
+    // completion should be dropped
+    aborted: Cell<bool>,
     kernel_read: TakeCell<'static, [u8]>,
     kernel_write: TakeCell<'static, [u8]>,
     kernel_len: Cell<usize>,
+    // Length of the operation in flight
+    op_len: Cell<usize>,
     grants: Grant<
         App,
         UpcallCount<1>,
@@ -63,6 +111,9 @@ pub struct Spi<'a, S: SpiMasterDevice> {
         AllowRwCount<{ rw_allow::COUNT }>,
     >,
     current_process: OptionalCell<ProcessId>,
+    // Alarm for waiting between bytes, and the gap requested by the process
+    byte_delay: OptionalCell<&'a dyn ByteDelay<'a>>,
+    byte_delay_us: Cell<u32>,
 }
 
 impl<'a, S: SpiMasterDevice> Spi<'a, S> {
@@ -78,14 +129,29 @@ impl<'a, S: SpiMasterDevice> Spi<'a, S> {
         Spi {
             spi_master: spi_master,
             busy: Cell::new(false),
+            aborted: Cell::new(false),
             kernel_len: Cell::new(0),
+            op_len: Cell::new(0),
             kernel_read: TakeCell::empty(),
             kernel_write: TakeCell::empty(),
             grants,
             current_process: OptionalCell::empty(),
+            byte_delay: OptionalCell::empty(),
+            byte_delay_us: Cell::new(0),
         }
     }
 
+    /// Largest number of bytes handed to the SPI hardware in one operation.
+    fn max_transfer_len(&self) -> usize {
+        transfer_limit(self.kernel_len.get(), self.spi_master.max_transfer_len())
+    }
+
+    /// Allow processes to request a delay between the bytes of a transfer.
+    pub fn set_byte_delay(&'a self, alarm: &'a dyn ByteDelay<'a>) {
+        alarm.set_client(self);
+        self.byte_delay.set(alarm);
+    }
+
     pub fn config_buffers(&mut self, read: &'static mut [u8], write: &'static mut [u8]) {
         let len = cmp::min(read.len(), write.len());
         self.kernel_len.set(len);
@@ -95,33 +161,69 @@ impl<'a, S: SpiMasterDevice> Spi<'a, S> {
 
     // Assumes checks for busy/etc. already done
     // Updates app.index to be index + length of op
-    fn do_next_read_write(&self, app: &mut App, kernel_data: &GrantKernelData) {
+    // If the operation cannot be started, the kernel buffers are kept and
+    // the error is returned.
+    fn do_next_read_write(
+        &self,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+    ) -> Result<(), ErrorCode> {
         let write_len = self.kernel_write.map_or(0, |kwbuf| {
             let mut start = app.index;
             let tmp_len = kernel_data
                 .get_readonly_processbuffer(ro_allow::WRITE)
                 .and_then(|write| {
                     write.enter(|src| {
-                        let len = cmp::min(app.len - start, self.kernel_len.get());
-                        let end = cmp::min(start + len, src.len());
-                        start = cmp::min(start, end);
-
-                        for (i, c) in src[start..end].iter().enumerate() {
-                            kwbuf[i] = c.get();
-                        }
-                        end - start
+                        let len = cmp::min(
+                            app.len - start,
+                            op_limit(self.max_transfer_len(), self.byte_delay_us.get()),
+                        );
+                        let (first, copied) = stage_write(src, start, len, kwbuf);
+                        start = first;
+                        copied
                     })
                 })
                 .unwrap_or(0);
             app.index = start + tmp_len;
             tmp_len
         });
-        // TODO verify SPI return value
-        let _ = self.spi_master.read_write_bytes(
-            self.kernel_write.take().unwrap(),
-            self.kernel_read.take(),
-            write_len,
-        );
+        self.op_len.set(write_len);
+        let kwbuf = self.kernel_write.take().ok_or(ErrorCode::NOMEM)?;
+        // Write-only transfers give the hardware no buffer to receive into.
+        let krbuf = if app.write_only {
+            None
+        } else {
+            self.kernel_read.take()
+        };
+        self.spi_master
+            .read_write_bytes(kwbuf, krbuf, write_len)
+            .map_err(|(error, kwbuf, krbuf)| {
+                krbuf.map(|krbuf| self.kernel_read.replace(krbuf));
+                self.kernel_write.replace(kwbuf);
+                error
+            })
+    }
+
+    // Drops the chip select hold and inter-byte delay of a process that has
+    // gone away, so that they do not keep the bus from other devices.
+    fn release_owner(&self) {
+        self.spi_master.release_low();
+        self.byte_delay_us.set(0);
+    }
+
+    // Ends the transfer in progress and notifies the process.
+    fn finish(
+        &self,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+        len: usize,
+        status: Result<(), ErrorCode>,
+    ) {
+        self.busy.set(false);
+        app.reset();
+        kernel_data
+            .schedule_upcall(0, (len, kernel::errorcode::into_statuscode(status), 0))
+            .ok();
     }
 }
 
@@ -129,6 +231,8 @@ impl<'a, S: SpiMasterDevice> SyscallDriver for Spi<'a, S> {
     // 2: read/write buffers
     //   - requires write buffer registered with allow
     //   - read buffer optional
+    //   - the callback receives the number of bytes transferred and a
+    //     statuscode, which is an error if the hardware failed part way
     // 3: set chip select
     //   - selects which peripheral (CS line) the SPI should
     //     activate
@@ -152,6 +256,34 @@ impl<'a, S: SpiMasterDevice> SyscallDriver for Spi<'a, S> {
     // 10: get clock polarity on current peripheral
     //   - 0 is idle low
     //   - non-zero is idle high
+    // 11: hold chip select across transfers
+    //   - non-zero keeps CS asserted after each read/write until
+    //     released, so several buffers form one transaction
+    //   - 0 releases CS, which is raised once any ongoing
+    //     read/write completes
+    //   - a hold left by a process that has exited is released,
+    //     along with its inter-byte delay, when its transfer
+    //     completes or another process takes over the driver
+    // 12: set inter-byte delay
+    //   - parameter in microseconds; non-zero transfers one byte at
+    //     a time and waits this long between bytes
+    //   - 0 transfers whole buffers without gaps
+    //   - NOSUPPORT if the board did not provide an alarm
+    // 13: abort read/write
+    //   - stops the transfer in progress and releases CS; the
+    //     callback receives the number of bytes transferred and
+    //     CANCEL
+    //   - an operation already started in hardware runs to
+    //     completion but is not copied to the read buffer, and a new
+    //     read/write returns BUSY until it has
+    //   - INVAL if no transfer is in progress
+    // 14: get maximum transfer length
+    //   - largest number of bytes sent to the hardware in one
+    //     operation
+    // 15: write buffer
+    //   - like 2, but the bytes received are discarded and any read
+    //     buffer is left untouched, so a constant buffer can be
+    //     written straight from read-only allow 0
     //
     // x: lock spi
     //   - if you perform an operation without the lock,
@@ -175,12 +307,17 @@ impl<'a, S: SpiMasterDevice> SyscallDriver for Spi<'a, S> {
         }
 
         // Check if this driver is free, or already dedicated to this process.
-        let match_or_empty_or_nonexistant = self.current_process.map_or(true, |current_process| {
+        let owner = self.current_process.extract();
+        let match_or_empty_or_nonexistant = owner.map_or(true, |current_process| {
             self.grants
-                .enter(*current_process, |_, _| current_process == &process_id)
+                .enter(current_process, |_, _| current_process == process_id)
                 .unwrap_or(true)
         });
         if match_or_empty_or_nonexistant {
+            if owner.map_or(false, |current_process| current_process != process_id) {
+                // The previous process has gone away.
+                self.release_owner();
+            }
             self.current_process.set(process_id);
         } else {
             return CommandReturn::failure(ErrorCode::NOMEM);
@@ -189,15 +326,17 @@ impl<'a, S: SpiMasterDevice> SyscallDriver for Spi<'a, S> {
         match command_num {
             // No longer supported, wrap inside a read_write_bytes
             1 /* read_write_byte */ => CommandReturn::failure(ErrorCode::NOSUPPORT),
-            2 /* read_write_bytes */ => {
-                if self.busy.get() {
+            2 /* read_write_bytes */ | 15 /* write_bytes */ => {
+                if self.busy.get() || self.aborted.get() {
                     return CommandReturn::failure(ErrorCode::BUSY);
                 }
+                let write_only = command_num == 15;
                 self.grants.enter(process_id, |app, kernel_data| {
                     // When we do a read/write, the read part is optional.
                     // So there are three cases:
                     // 1) Write and read buffers present: len is min of lengths
-                    // 2) Only write buffer present: len is len of write
+                    // 2) Only write buffer present, or a write-only
+                    //    transfer: len is len of write
                     // 3) No write buffer present: no operation
                     let wlen = kernel_data
                         .get_readonly_processbuffer(ro_allow::WRITE)
@@ -206,16 +345,27 @@ impl<'a, S: SpiMasterDevice> SyscallDriver for Spi<'a, S> {
                         .get_readwrite_processbuffer(rw_allow::READ)
                         .map_or(0, |read| read.len());
                     // Note that non-shared and 0-sized read buffers both report 0 as size
-                    let len = if rlen == 0 { wlen } else { wlen.min(rlen) };
+                    let len = if write_only || rlen == 0 { wlen } else { wlen.min(rlen) };
 
-                    if len >= arg1 && arg1 > 0 {
+                    if arg1 == 0 {
+                        // Zero-length transfer: complete right away
+                        // without involving the SPI hardware.
+                        kernel_data.schedule_upcall(0, (0, 0, 0)).ok();
+                        CommandReturn::success()
+                    } else if len >= arg1 {
                         app.len = arg1;
                         app.index = 0;
+                        app.write_only = write_only;
                         self.busy.set(true);
-                        self.do_next_read_write(app, kernel_data);
-                        CommandReturn::success()
+                        match self.do_next_read_write(app, kernel_data) {
+                            Ok(()) => CommandReturn::success(),
+                            Err(error) => {
+                                self.busy.set(false);
+                                CommandReturn::failure(error)
+                            }
+                        }
                     } else {
-                        /* write buffer too small, or zero length write */
+                        /* write buffer too small */
                         CommandReturn::failure(ErrorCode::INVAL)
                     }
                 }).unwrap_or(CommandReturn::failure(ErrorCode::FAIL))
@@ -265,6 +415,46 @@ impl<'a, S: SpiMasterDevice> SyscallDriver for Spi<'a, S> {
             10 /* get polarity */ => {
                 CommandReturn::success_u32(self.spi_master.get_polarity() as u32)
             }
+            11 /* hold chip select */ => {
+                match arg1 {
+                    0 => self.spi_master.release_low(),
+                    _ => self.spi_master.hold_low(),
+                }
+                CommandReturn::success()
+            }
+            12 /* set inter-byte delay */ => {
+                if self.busy.get() {
+                    CommandReturn::failure(ErrorCode::BUSY)
+                } else if arg1 != 0 && self.byte_delay.is_none() {
+                    CommandReturn::failure(ErrorCode::NOSUPPORT)
+                } else {
+                    self.byte_delay_us.set(arg1 as u32);
+                    CommandReturn::success()
+                }
+            }
+            13 /* abort read/write */ => {
+                if !self.busy.get() {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.grants.enter(process_id, |app, kernel_data| {
+                    // The kernel write buffer is only missing while an
+                    // operation is with the hardware, otherwise the
+                    // transfer is waiting between bytes.
+                    let in_flight = self.kernel_write.is_none();
+                    if in_flight {
+                        self.aborted.set(true);
+                    } else {
+                        self.byte_delay.map(|alarm| alarm.stop());
+                    }
+                    self.spi_master.release_low();
+                    let len = aborted_len(app, self.op_len.get(), in_flight);
+                    self.finish(app, kernel_data, len, Err(ErrorCode::CANCEL));
+                    CommandReturn::success()
+                }).unwrap_or(CommandReturn::failure(ErrorCode::FAIL))
+            }
+            14 /* get maximum transfer length */ => {
+                CommandReturn::success_u32(self.max_transfer_len() as u32)
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT)
         }
     }
@@ -280,57 +470,473 @@ impl<S: SpiMasterDevice> SpiMasterClient for Spi<'_, S> {
         writebuf: &'static mut [u8],
         readbuf: Option<&'static mut [u8]>,
         length: usize,
-        _status: Result<(), ErrorCode>,
+        status: Result<(), ErrorCode>,
     ) {
-        self.current_process.map(|process_id| {
-            let _ = self.grants.enter(*process_id, move |app, kernel_data| {
-                let rbuf = readbuf.map(|src| {
-                    let index = app.index;
-                    let _ = kernel_data
-                        .get_readwrite_processbuffer(rw_allow::READ)
-                        .and_then(|read| {
-                            read.mut_enter(|dest| {
-                                // Need to be careful that app_read hasn't changed
-                                // under us, so check all values against actual
-                                // slice lengths.
-                                //
-                                // If app_read is shorter than before, and shorter
-                                // than what we have read would require, then truncate.
-                                // -pal 12/9/20
-                                let end = index;
-                                let start = index - length;
-                                let end = cmp::min(end, dest.len());
-
-                                // If the new endpoint is earlier than our expected
-                                // startpoint, we set the startpoint to be the same;
-                                // This results in a zero-length operation. -pal 12/9/20
-                                let start = cmp::min(start, end);
-
-                                // The amount to copy can't be longer than the size of the
-                                // read buffer. -pal 6/8/21
-                                let real_len = cmp::min(end - start, src.len());
-                                let dest_area = &dest[start..end];
-                                for (i, c) in src[0..real_len].iter().enumerate() {
-                                    dest_area[i].set(*c);
-                                }
-                            })
+        // Take the buffers back first so that they are kept even if the
+        // process has gone away or the operation failed. A write-only
+        // transfer had no read buffer, and the kernel still holds it.
+        readbuf.map(|readbuf| self.kernel_read.replace(readbuf));
+        self.kernel_write.replace(writebuf);
+        if self.aborted.replace(false) {
+            // The process has already been told the transfer was aborted.
+            return;
+        }
+        let op_len = self.op_len.get();
+
+        let entered =
+            self.current_process
+                .map_or(Err(kernel::process::Error::NoSuchApp), |process_id| {
+                    self.grants.enter(*process_id, |app, kernel_data| {
+                        self.kernel_read.map(|src| {
+                            if app.write_only {
+                                return;
+                            }
+                            let index = app.index;
+                            let _ = kernel_data
+                                .get_readwrite_processbuffer(rw_allow::READ)
+                                .and_then(|read| {
+                                    read.mut_enter(|dest| {
+                                        // Need to be careful that app_read hasn't changed
+                                        // under us, so check all values against actual
+                                        // slice lengths.
+                                        //
+                                        // If app_read is shorter than before, and shorter
+                                        // than what we have read would require, then truncate.
+                                        // -pal 12/9/20
+                                        let start = index - op_len;
+                                        let end =
+                                            cmp::min(start + cmp::min(length, op_len), dest.len());
+
+                                        // If the new endpoint is earlier than our expected
+                                        // startpoint, we set the startpoint to be the same;
+                                        // This results in a zero-length operation. -pal 12/9/20
+                                        let start = cmp::min(start, end);
+
+                                        // The amount to copy can't be longer than the size of the
+                                        // read buffer. -pal 6/8/21
+                                        let real_len = cmp::min(end - start, src.len());
+                                        let dest_area = &dest[start..end];
+                                        for (i, c) in src[0..real_len].iter().enumerate() {
+                                            dest_area[i].set(*c);
+                                        }
+                                    })
+                                });
                         });
-                    src
+
+                        match next_step(app, op_len, length, status, self.byte_delay_us.get()) {
+                            Next::Done(len, status) => self.finish(app, kernel_data, len, status),
+                            Next::Wait => {
+                                let delay_us = self.byte_delay_us.get();
+                                self.byte_delay.map(|alarm| alarm.start_us(delay_us));
+                            }
+                            Next::Continue => {
+                                if let Err(error) = self.do_next_read_write(app, kernel_data) {
+                                    let len = app.index - self.op_len.get();
+                                    self.finish(app, kernel_data, len, Err(error));
+                                }
+                            }
+                        }
+                    })
                 });
+        if entered.is_err() {
+            self.busy.set(false);
+            self.release_owner();
+            self.current_process.clear();
+        }
+    }
+}
 
-                self.kernel_read.put(rbuf);
-                self.kernel_write.replace(writebuf);
+impl<S: SpiMasterDevice> time::AlarmClient for Spi<'_, S> {
+    // The gap between two bytes has passed, so transfer the next one.
+    fn alarm(&self) {
+        if !self.busy.get() {
+            // The transfer was aborted while waiting.
+            return;
+        }
+        let entered =
+            self.current_process
+                .map_or(Err(kernel::process::Error::NoSuchApp), |process_id| {
+                    self.grants.enter(*process_id, |app, kernel_data| {
+                        if let Err(error) = self.do_next_read_write(app, kernel_data) {
+                            let len = app.index - self.op_len.get();
+                            self.finish(app, kernel_data, len, Err(error));
+                        }
+                    })
+                });
+        if entered.is_err() {
+            self.busy.set(false);
+            self.release_owner();
+            self.current_process.clear();
+        }
+    }
+}
 
-                if app.index == app.len {
-                    self.busy.set(false);
-                    let len = app.len;
-                    app.len = 0;
-                    app.index = 0;
-                    kernel_data.schedule_upcall(0, (len, 0, 0)).ok();
-                } else {
-                    self.do_next_read_write(app, kernel_data);
-                }
-            });
-        });
+/// Copies the next `len` bytes of a transfer, starting at `start`, from the
+/// process's write buffer into the kernel buffer that goes on the wire. A
+/// write buffer that shrank since the transfer started is truncated. Returns
+/// where the copy started and how many bytes were copied.
+fn stage_write(
+    src: &ReadableProcessSlice,
+    start: usize,
+    len: usize,
+    kwbuf: &mut [u8],
+) -> (usize, usize) {
+    let end = cmp::min(start + len, src.len());
+    let start = cmp::min(start, end);
+    for (i, c) in src[start..end].iter().enumerate() {
+        kwbuf[i] = c.get();
+    }
+    (start, end - start)
+}
+
+/// Largest operation the hardware can take: the kernel buffer, unless the
+/// SPI master has a smaller limit.
+fn transfer_limit(kernel_len: usize, spi_max: Option<usize>) -> usize {
+    spi_max.map_or(kernel_len, |max| cmp::min(kernel_len, max))
+}
+
+/// Largest operation to issue for a transfer: `max_len` bytes, or a single
+/// byte when there is a delay between bytes.
+fn op_limit(max_len: usize, byte_delay_us: u32) -> usize {
+    if byte_delay_us == 0 {
+        max_len
+    } else {
+        cmp::min(max_len, 1)
+    }
+}
+
+/// Number of bytes to report for a transfer aborted after `app.index` bytes
+/// were handed to the hardware. An operation still in flight is dropped, so
+/// its bytes do not count.
+fn aborted_len(app: &App, op_len: usize, in_flight: bool) -> usize {
+    if in_flight {
+        app.index - op_len
+    } else {
+        app.index
+    }
+}
+
+/// How a transfer continues after an operation completes.
+#[derive(Debug, PartialEq)]
+enum Next {
+    /// The transfer has finished, with this many bytes and status.
+    Done(usize, Result<(), ErrorCode>),
+    /// Wait for the inter-byte delay before the next operation.
+    Wait,
+    /// Start the next operation right away.
+    Continue,
+}
+
+fn next_step(
+    app: &App,
+    op_len: usize,
+    length: usize,
+    status: Result<(), ErrorCode>,
+    byte_delay_us: u32,
+) -> Next {
+    match completion(app, op_len, length, status) {
+        Some((len, status)) => Next::Done(len, status),
+        None if byte_delay_us > 0 => Next::Wait,
+        None => Next::Continue,
+    }
+}
+
+/// Decide how a transfer continues after an operation of `op_len` bytes
+/// completed `length` bytes with `status`. Returns the number of bytes to
+/// report to the app and the status when the transfer has finished, or `None`
+/// if there is more to transfer.
+fn completion(
+    app: &App,
+    op_len: usize,
+    length: usize,
+    status: Result<(), ErrorCode>,
+) -> Option<(usize, Result<(), ErrorCode>)> {
+    match status {
+        // Stop at the failed operation and report how far the transfer got.
+        Err(error) => Some((app.index - op_len + cmp::min(length, op_len), Err(error))),
+        Ok(()) if app.index == app.len => Some((app.len, Ok(()))),
+        Ok(()) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak_buffer, QueuedUpcall, TestKernel};
+    use kernel::process::Process;
+    use kernel::syscall::SyscallReturn;
+
+    /// SPI device whose DMA can move at most `max` bytes per transfer, and
+    /// which tracks whether its chip select is held.
+    struct MockSpi {
+        max: Option<usize>,
+        held: Cell<bool>,
+    }
+
+    impl MockSpi {
+        fn new(max: Option<usize>) -> Self {
+            MockSpi {
+                max,
+                held: Cell::new(false),
+            }
+        }
+    }
+
+    impl SpiMasterDevice for MockSpi {
+        fn set_client(&self, _client: &'static dyn SpiMasterClient) {}
+        fn configure(
+            &self,
+            _cpol: ClockPolarity,
+            _cpal: ClockPhase,
+            _rate: u32,
+        ) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn read_write_bytes(
+            &self,
+            write_buffer: &'static mut [u8],
+            read_buffer: Option<&'static mut [u8]>,
+            _len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8], Option<&'static mut [u8]>)> {
+            Err((ErrorCode::NOSUPPORT, write_buffer, read_buffer))
+        }
+        fn set_rate(&self, _rate: u32) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn get_rate(&self) -> u32 {
+            1_000_000
+        }
+        fn set_polarity(&self, _polarity: ClockPolarity) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+        fn set_phase(&self, _phase: ClockPhase) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+        fn max_transfer_len(&self) -> Option<usize> {
+            self.max
+        }
+        fn hold_low(&self) {
+            self.held.set(true);
+        }
+        fn release_low(&self) {
+            self.held.set(false);
+        }
+    }
+
+    #[test]
+    fn max_transfer_len_is_limited_by_master() {
+        let dma = MockSpi::new(Some(256));
+        assert_eq!(transfer_limit(1024, dma.max_transfer_len()), 256);
+        // A smaller kernel buffer is the limit instead.
+        assert_eq!(transfer_limit(128, dma.max_transfer_len()), 128);
+
+        let unlimited = MockSpi::new(None);
+        assert_eq!(transfer_limit(1024, unlimited.max_transfer_len()), 1024);
+        assert_eq!(
+            op_limit(transfer_limit(1024, dma.max_transfer_len()), 0),
+            256
+        );
+    }
+
+    #[test]
+    fn completion_reports_error_and_progress() {
+        // Second 16-byte operation of a 40-byte transfer aborted after 4 bytes.
+        let app = App {
+            len: 40,
+            index: 32,
+            ..App::default()
+        };
+        assert_eq!(
+            completion(&app, 16, 4, Err(ErrorCode::FAIL)),
+            Some((20, Err(ErrorCode::FAIL)))
+        );
+        assert_eq!(completion(&app, 16, 16, Ok(())), None);
+
+        let app = App {
+            len: 40,
+            index: 40,
+            ..App::default()
+        };
+        assert_eq!(completion(&app, 8, 8, Ok(())), Some((40, Ok(()))));
+    }
+
+    #[test]
+    fn abort_counts_completed_operations() {
+        // Aborted during the second 16-byte operation of a 40-byte transfer.
+        let app = App {
+            len: 40,
+            index: 32,
+            ..App::default()
+        };
+        assert_eq!(aborted_len(&app, 16, true), 16);
+
+        // Aborted between bytes, after the third byte completed.
+        let app = App {
+            len: 5,
+            index: 3,
+            ..App::default()
+        };
+        assert_eq!(aborted_len(&app, 1, false), 3);
+    }
+
+    // Steps taken after each operation of a `len`-byte transfer.
+    fn steps(len: usize, byte_delay_us: u32) -> ([Option<Next>; 4], usize) {
+        let mut app = App {
+            len,
+            ..App::default()
+        };
+        let mut steps = [None, None, None, None];
+        let mut ops = 0;
+        for step in steps.iter_mut() {
+            let op_len = cmp::min(len - app.index, op_limit(1024, byte_delay_us));
+            app.index += op_len;
+            ops += 1;
+            let next = next_step(&app, op_len, op_len, Ok(()), byte_delay_us);
+            let done = matches!(next, Next::Done(..));
+            *step = Some(next);
+            if done {
+                break;
+            }
+        }
+        (steps, ops)
+    }
+
+    #[test]
+    fn byte_delay_waits_between_bytes() {
+        // Three bytes with a delay are sent one at a time, waiting on the
+        // alarm between them but not after the last.
+        assert_eq!(
+            steps(3, 10),
+            (
+                [
+                    Some(Next::Wait),
+                    Some(Next::Wait),
+                    Some(Next::Done(3, Ok(()))),
+                    None
+                ],
+                3
+            )
+        );
+
+        // Without a delay the transfer is a single operation.
+        assert_eq!(
+            steps(3, 0),
+            ([Some(Next::Done(3, Ok(()))), None, None, None], 1)
+        );
+    }
+
+    #[test]
+    fn write_only_transfer_from_read_only_buffer() {
+        // A constant the process allowed read-only, written in 4-byte
+        // operations without the process copying it to a writable buffer.
+        const FRAME: [u8; 10] = [0x9f, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
+        let src: &ReadableProcessSlice = (&FRAME[..]).into();
+
+        let mut wire = [0; 10];
+        let mut kwbuf = [0; 4];
+        let mut index = 0;
+        while index < FRAME.len() {
+            let (start, copied) =
+                stage_write(src, index, cmp::min(FRAME.len() - index, 4), &mut kwbuf);
+            assert_eq!(start, index);
+            wire[index..index + copied].copy_from_slice(&kwbuf[..copied]);
+            index += copied;
+        }
+        assert_eq!(wire, FRAME);
+
+        // Nothing is staged past the end of a buffer that shrank.
+        assert_eq!(stage_write(src, 12, 4, &mut kwbuf), (10, 0));
+
+        // The flag is cleared with the rest of the transfer.
+        let mut app = App {
+            len: 10,
+            index: 10,
+            write_only: true,
+        };
+        app.reset();
+        assert!(!app.write_only);
+    }
+
+    #[test]
+    fn transfer_after_abort_starts_fresh() {
+        // Abort a delayed transfer part way through; finishing it resets the
+        // progress, so the next transfer runs from the start.
+        let mut app = App {
+            len: 3,
+            index: 1,
+            ..App::default()
+        };
+        assert_eq!(aborted_len(&app, 1, true), 0);
+        app.reset();
+        assert_eq!((app.len, app.index), (0, 0));
+
+        assert_eq!(
+            steps(2, 10),
+            (
+                [Some(Next::Wait), Some(Next::Done(2, Ok(()))), None, None],
+                2
+            )
+        );
+    }
+
+    #[test]
+    fn zero_length_transfer_completes_without_hardware() {
+        let kernel = TestKernel::new(&["app"]);
+        // Any transfer that reached this controller would fail.
+        let spi = MockSpi::new(None);
+        let mut driver = Spi::new(&spi, kernel.create_grant(DRIVER_NUM));
+        driver.config_buffers(leak_buffer(8), leak_buffer(8));
+        let driver = &driver;
+        let app = kernel.process(0);
+        app.subscribe(driver, DRIVER_NUM, 0);
+        let write = app.buffer(&[1, 2, 3]);
+        app.allow_readonly(driver, DRIVER_NUM, ro_allow::WRITE, write);
+
+        for command_num in [2, 15] {
+            assert!(matches!(
+                app.command(driver, DRIVER_NUM, command_num, 0, 0),
+                SyscallReturn::Success
+            ));
+            assert_eq!(
+                app.take_upcalls(),
+                [QueuedUpcall::new(DRIVER_NUM, 0, (0, 0, 0))]
+            );
+        }
+    }
+
+    #[test]
+    fn departed_process_releases_chip_select() {
+        let kernel = TestKernel::new(&["holder", "next"]);
+        let spi = MockSpi::new(None);
+        let driver = Spi::new(&spi, kernel.create_grant(DRIVER_NUM));
+        let (holder, next) = (kernel.process(0), kernel.process(1));
+
+        assert!(matches!(
+            holder.command(&driver, DRIVER_NUM, 11, 1, 0),
+            SyscallReturn::Success
+        ));
+        // As set by command 12 on a board with an alarm.
+        driver.byte_delay_us.set(10);
+        assert!(spi.held.get());
+
+        // The hold outlasts the holder's commands, but not the holder.
+        assert!(matches!(
+            next.command(&driver, DRIVER_NUM, 6, 0, 0),
+            SyscallReturn::Failure(ErrorCode::NOMEM)
+        ));
+        assert!(spi.held.get());
+        holder.terminate(None);
+        assert!(matches!(
+            next.command(&driver, DRIVER_NUM, 6, 0, 0),
+            SyscallReturn::SuccessU32(1_000_000)
+        ));
+        assert!(!spi.held.get());
+        assert_eq!(driver.byte_delay_us.get(), 0);
     }
 }
@@ -8,7 +8,7 @@ use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCo
 use kernel::hil::spi::ClockPhase;
 use kernel::hil::spi::ClockPolarity;
 use kernel::hil::spi::{SpiSlaveClient, SpiSlaveDevice};
-use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::processbuffer::{ReadableProcessBuffer, ReadableProcessSlice, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
@@ -20,8 +20,10 @@ pub const DRIVER_NUM: usize = driver::NUM::SpiPeripheral as usize;
 /// Ids for read-only allow buffers
 mod ro_allow {
     pub const WRITE: usize = 0;
+    /// Response preloaded with command 7 for the following transaction
+    pub const NEXT_WRITE: usize = 1;
     /// The number of allow buffers the kernel stores for this grant
-    pub const COUNT: u8 = 1;
+    pub const COUNT: u8 = 2;
 }
 
 /// Ids for read-write allow buffers
@@ -42,6 +44,90 @@ pub const DEFAULT_WRITE_BUF_LENGTH: usize = 1024;
 pub struct PeripheralApp {
     len: usize,
     index: usize,
+    // Length of the HAL operation in flight, used to locate where its bytes
+    // belong when the master ends the transaction early.
+    op_len: usize,
+    // Read-only allow the transaction in flight transmits from.
+    source: usize,
+    // Length of the transaction preloaded from `ro_allow::NEXT_WRITE`, or 0.
+    next_len: usize,
+    // Byte sent when the master clocks past the end of the write buffer, set
+    // with command 8. `None` leaves it to the hardware.
+    underrun_fill: Option<u8>,
+}
+
+impl PeripheralApp {
+    /// Start the preloaded transaction, if any, once the current one is done.
+    /// Returns whether there was one to start.
+    fn swap_in_next(&mut self) -> bool {
+        self.index = 0;
+        self.op_len = 0;
+        if self.next_len == 0 {
+            self.len = 0;
+            self.source = ro_allow::WRITE;
+            return false;
+        }
+        self.len = self.next_len;
+        self.next_len = 0;
+        self.source = ro_allow::NEXT_WRITE;
+        true
+    }
+
+    /// Copy the next chunk of the transaction from `src` into `kwbuf`, at most
+    /// `limit` bytes, and advance the index past it. Returns the chunk length.
+    ///
+    /// If the app has shrunk `src` since starting the transaction and set an
+    /// underrun fill byte, the rest of the chunk is padded with that byte;
+    /// otherwise the chunk stops at the end of `src`.
+    fn fill_tx(&mut self, kwbuf: &mut [u8], limit: usize, src: &ReadableProcessSlice) -> usize {
+        let len = cmp::min(self.len - self.index, limit);
+        let end = cmp::min(self.index + len, src.len());
+        let start = cmp::min(self.index, end);
+
+        for (i, c) in src[start..end].iter().enumerate() {
+            kwbuf[i] = c.get();
+        }
+        match self.underrun_fill {
+            Some(fill) => {
+                kwbuf[end - start..len].fill(fill);
+                self.index += len;
+                self.op_len = len;
+            }
+            None => {
+                self.index = end;
+                self.op_len = end - start;
+            }
+        }
+        self.op_len
+    }
+
+    /// Tell the hardware what to send once the master clocks past the bytes
+    /// of the operation, if the app set an underrun fill byte.
+    fn set_underrun_fill<S: SpiSlaveDevice>(&self, spi_slave: &S) {
+        if let Some(fill) = self.underrun_fill {
+            spi_slave.set_write_byte(fill);
+        }
+    }
+}
+
+/// ### `subscribe_num`
+///
+/// - `0`: Operation complete, called with the number of bytes transferred.
+/// - `1`: Chip select changed by the master. Called with the length of the
+///        transaction set up and `0` when chip select is asserted, or `1`
+///        when it is deasserted (on hardware that reports it).
+/// - `2`: Short transaction: the master deasserted chip select before the
+///        buffer filled. Called with the number of bytes clocked in and the
+///        requested length, before upcall `0`.
+mod upcall {
+    pub const DONE: usize = 0;
+    pub const SELECTED: usize = 1;
+    /// Second argument of the `SELECTED` upcall for each chip select edge.
+    pub const EDGE_ASSERTED: usize = 0;
+    pub const EDGE_DEASSERTED: usize = 1;
+    pub const SHORT: usize = 2;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 3;
 }
 
 pub struct SpiPeripheral<'a, S: SpiSlaveDevice> {
@@ -52,7 +138,7 @@ pub struct SpiPeripheral<'a, S: SpiSlaveDevice> {
     kernel_len: Cell<usize>,
     grants: Grant<
         PeripheralApp,
-        UpcallCount<2>,
+        UpcallCount<{ upcall::COUNT }>,
         AllowRoCount<{ ro_allow::COUNT }>,
         AllowRwCount<{ rw_allow::COUNT }>,
     >,
@@ -64,7 +150,7 @@ impl<'a, S: SpiSlaveDevice> SpiPeripheral<'a, S> {
         spi_slave: &'a S,
         grants: Grant<
             PeripheralApp,
-            UpcallCount<2>,
+            UpcallCount<{ upcall::COUNT }>,
             AllowRoCount<{ ro_allow::COUNT }>,
             AllowRwCount<{ rw_allow::COUNT }>,
         >,
@@ -90,25 +176,15 @@ impl<'a, S: SpiSlaveDevice> SpiPeripheral<'a, S> {
     // Assumes checks for busy/etc. already done
     // Updates app.index to be index + length of op
     fn do_next_read_write(&self, app: &mut PeripheralApp, kernel_data: &GrantKernelData) {
+        app.set_underrun_fill(self.spi_slave);
         let write_len = self.kernel_write.map_or(0, |kwbuf| {
-            let mut start = app.index;
-            let tmp_len = kernel_data
-                .get_readonly_processbuffer(ro_allow::WRITE)
-                .and_then(|write| {
-                    write.enter(|src| {
-                        let len = cmp::min(app.len - start, self.kernel_len.get());
-                        let end = cmp::min(start + len, src.len());
-                        start = cmp::min(start, end);
-
-                        for (i, c) in src[start..end].iter().enumerate() {
-                            kwbuf[i] = c.get();
-                        }
-                        end - start
-                    })
+            kernel_data
+                .get_readonly_processbuffer(app.source)
+                .and_then(|write| write.enter(|src| app.fill_tx(kwbuf, self.kernel_len.get(), src)))
+                .unwrap_or_else(|_| {
+                    app.op_len = 0;
+                    0
                 })
-                .unwrap_or(0);
-            app.index = start + tmp_len;
-            tmp_len
         });
         // TODO verify SPI return value
         let _ = self.spi_slave.read_write_bytes(
@@ -117,6 +193,19 @@ impl<'a, S: SpiSlaveDevice> SpiPeripheral<'a, S> {
             write_len,
         );
     }
+
+    // Tell the app the master changed chip select, so it can delimit
+    // transactions.
+    fn chip_select_edge(&self, edge: usize) {
+        self.current_process.map(|process_id| {
+            let _ = self.grants.enter(*process_id, move |app, kernel_data| {
+                let len = app.len;
+                kernel_data
+                    .schedule_upcall(upcall::SELECTED, (len, edge, 0))
+                    .ok();
+            });
+        });
+    }
 }
 
 impl<S: SpiSlaveDevice> SyscallDriver for SpiPeripheral<'_, S> {
@@ -148,6 +237,19 @@ impl<S: SpiSlaveDevice> SyscallDriver for SpiPeripheral<'_, S> {
     /// - 6: get clock polarity on current peripheral
     ///   - 0 is idle low
     ///   - non-zero is idle high
+    /// - 7: preload the next transaction
+    ///   - arg1 bytes are sent from read-only allow 1 as soon
+    ///     as the current transaction completes, without waiting
+    ///     for the app to issue command 1
+    ///   - the response is received into the same read buffer
+    ///   - fails with BUSY if a preloaded transaction is already
+    ///     pending or in flight
+    /// - 8: set the underrun fill byte
+    ///   - arg1 is sent for every byte the master clocks past the
+    ///     end of the write buffer, e.g. 0x00 or 0xFF
+    ///   - until it is set, what the master reads there depends on
+    ///     the hardware
+    ///   - fails with INVAL if arg1 does not fit in a byte
     /// - x: lock spi
     ///   - if you perform an operation without the lock,
     ///     it implicitly acquires the lock before the
@@ -205,6 +307,7 @@ impl<S: SpiSlaveDevice> SyscallDriver for SpiPeripheral<'_, S> {
                     if len >= arg1 && arg1 > 0 {
                         app.len = arg1;
                         app.index = 0;
+                        app.source = ro_allow::WRITE;
                         self.busy.set(true);
                         self.do_next_read_write(app, kernel_data);
                         CommandReturn::success()
@@ -243,6 +346,43 @@ impl<S: SpiSlaveDevice> SyscallDriver for SpiPeripheral<'_, S> {
             6 /* get polarity */ => {
                 CommandReturn::success_u32(self.spi_slave.get_polarity() as u32)
             }
+            7 /* preload next transaction */ => {
+                self.grants.enter(process_id, |app, kernel_data| {
+                    if app.next_len != 0 || app.source == ro_allow::NEXT_WRITE {
+                        return CommandReturn::failure(ErrorCode::BUSY);
+                    }
+                    // Same length rules as command 1, against the preload buffer.
+                    let wlen = kernel_data
+                        .get_readonly_processbuffer(ro_allow::NEXT_WRITE)
+                        .map_or(0, |write| write.len());
+                    let rlen = kernel_data
+                        .get_readwrite_processbuffer(rw_allow::READ)
+                        .map_or(0, |read| read.len());
+                    let len = if rlen == 0 { wlen } else { wlen.min(rlen) };
+
+                    if len >= arg1 && arg1 > 0 {
+                        app.next_len = arg1;
+                        CommandReturn::success()
+                    } else {
+                        CommandReturn::failure(ErrorCode::INVAL)
+                    }
+                }).unwrap_or(CommandReturn::failure(ErrorCode::NOMEM))
+            }
+            8 /* set underrun fill byte */ => {
+                let fill = match u8::try_from(arg1) {
+                    Ok(fill) => fill,
+                    Err(_) => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                self.grants.enter(process_id, |app, _| {
+                    app.underrun_fill = Some(fill);
+                    // Apply it now in case the master over-reads the
+                    // transaction in flight.
+                    if self.busy.get() {
+                        app.set_underrun_fill(self.spi_slave);
+                    }
+                    CommandReturn::success()
+                }).unwrap_or(CommandReturn::failure(ErrorCode::NOMEM))
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT)
         }
     }
@@ -275,8 +415,8 @@ impl<S: SpiSlaveDevice> SpiSlaveClient for SpiPeripheral<'_, S> {
                                 // If app_read is shorter than before, and shorter
                                 // than what we have read would require, then truncate.
                                 // -pal 12/9/20
-                                let end = index;
-                                let start = index - length;
+                                let start = index - app.op_len;
+                                let end = start + length;
                                 let end = cmp::min(end, cmp::min(src.len(), dest.len()));
 
                                 // If the new endpoint is earlier than our expected
@@ -298,12 +438,23 @@ impl<S: SpiSlaveDevice> SpiSlaveClient for SpiPeripheral<'_, S> {
                 self.kernel_read.put(rbuf);
                 self.kernel_write.put(writebuf);
 
-                if app.index == app.len {
-                    self.busy.set(false);
-                    let len = app.len;
-                    app.len = 0;
-                    app.index = 0;
-                    kernel_data.schedule_upcall(0, (len, 0, 0)).ok();
+                // The master may deassert chip select before filling the
+                // buffer, in which case the transaction ends here.
+                let short = length < app.op_len;
+                if short || app.index == app.len {
+                    let len = app.index - app.op_len + length;
+                    if short {
+                        kernel_data
+                            .schedule_upcall(upcall::SHORT, (len, app.len, 0))
+                            .ok();
+                    }
+                    kernel_data.schedule_upcall(upcall::DONE, (len, 0, 0)).ok();
+                    // Hand the master the preloaded response straight away.
+                    if app.swap_in_next() {
+                        self.do_next_read_write(app, kernel_data);
+                    } else {
+                        self.busy.set(false);
+                    }
                 } else {
                     self.do_next_read_write(app, kernel_data);
                 }
@@ -313,11 +464,255 @@ impl<S: SpiSlaveDevice> SpiSlaveClient for SpiPeripheral<'_, S> {
 
     // Simple callback for when chip has been selected
     fn chip_selected(&self) {
-        self.current_process.map(|process_id| {
-            let _ = self.grants.enter(*process_id, move |app, kernel_data| {
-                let len = app.len;
-                kernel_data.schedule_upcall(1, (len, 0, 0)).ok();
+        self.chip_select_edge(upcall::EDGE_ASSERTED);
+    }
+
+    fn chip_deselected(&self) {
+        self.chip_select_edge(upcall::EDGE_DEASSERTED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::{leak_buffer, QueuedUpcall, TestKernel};
+    use kernel::syscall::SyscallReturn;
+
+    /// Plays the master clocking a whole transaction out of `app`, returning
+    /// the bytes it received.
+    fn master_read(app: &mut PeripheralApp, bufs: [&ReadableProcessSlice; 2]) -> [u8; 4] {
+        let mut kwbuf = [0; 4];
+        let src = bufs[app.source];
+        let len = app.fill_tx(&mut kwbuf, 4, src);
+        assert_eq!(len, app.len);
+        assert_eq!(app.index, app.len);
+        kwbuf
+    }
+
+    #[test]
+    fn back_to_back_reads_use_preloaded_buffer() {
+        let first = [1, 2, 3, 4];
+        let second = [5, 6, 7, 8];
+        let bufs: [&ReadableProcessSlice; 2] = [(&first[..]).into(), (&second[..]).into()];
+
+        // Command 1 starts a transaction, then command 7 preloads the next.
+        let mut app = PeripheralApp {
+            len: 4,
+            ..Default::default()
+        };
+        app.next_len = 4;
+
+        assert_eq!(master_read(&mut app, bufs), first);
+        assert!(app.swap_in_next());
+        assert_eq!(app.source, ro_allow::NEXT_WRITE);
+        assert_eq!(master_read(&mut app, bufs), second);
+
+        // Nothing else is preloaded, so the capsule goes idle.
+        assert!(!app.swap_in_next());
+        assert_eq!(app.source, ro_allow::WRITE);
+        assert_eq!(app.len, 0);
+    }
+
+    /// A peripheral that sends its operation's bytes and then its write byte
+    /// for as long as the master keeps clocking. It holds on to the buffers
+    /// of an operation until the master ends it with `deselect`.
+    struct MockSlave {
+        write_byte: Cell<u8>,
+        write: TakeCell<'static, [u8]>,
+        read: TakeCell<'static, [u8]>,
+    }
+
+    impl MockSlave {
+        fn new(write_byte: u8) -> Self {
+            MockSlave {
+                write_byte: Cell::new(write_byte),
+                write: TakeCell::empty(),
+                read: TakeCell::empty(),
+            }
+        }
+
+        /// Plays the master clocking `sent` in and then deasserting chip
+        /// select, ending the operation in flight the way the SAM4L's NSS
+        /// rising interrupt does.
+        fn deselect(&self, client: &dyn SpiSlaveClient, sent: &[u8]) {
+            client.chip_selected();
+            let read = self.read.take().map(|read| {
+                read[..sent.len()].copy_from_slice(sent);
+                read
             });
-        });
+            client.read_write_done(self.write.take(), read, sent.len(), Ok(()));
+            client.chip_deselected();
+        }
+
+        /// Plays the master clocking `N` bytes out of an operation of the
+        /// first `len` bytes of `kwbuf`, returning the bytes it received.
+        fn over_read<const N: usize>(&self, kwbuf: &[u8], len: usize) -> [u8; N] {
+            core::array::from_fn(|i| {
+                if i < len {
+                    kwbuf[i]
+                } else {
+                    self.write_byte.get()
+                }
+            })
+        }
+    }
+
+    impl SpiSlaveDevice for MockSlave {
+        fn set_client(&self, _client: &'static dyn SpiSlaveClient) {}
+
+        fn configure(&self, _cpol: ClockPolarity, _cpal: ClockPhase) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_write_byte(&self, write_byte: u8) {
+            self.write_byte.set(write_byte);
+        }
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: Option<&'static mut [u8]>,
+            read_buffer: Option<&'static mut [u8]>,
+            _len: usize,
+        ) -> Result<
+            (),
+            (
+                ErrorCode,
+                Option<&'static mut [u8]>,
+                Option<&'static mut [u8]>,
+            ),
+        > {
+            self.write.put(write_buffer);
+            self.read.put(read_buffer);
+            Ok(())
+        }
+
+        fn set_polarity(&self, _polarity: ClockPolarity) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+
+        fn set_phase(&self, _phase: ClockPhase) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+    }
+
+    #[test]
+    fn over_read_sends_fill_byte() {
+        let data = [1, 2, 3, 4];
+        let src: &ReadableProcessSlice = (&data[..]).into();
+        // Whatever the hardware would send on its own.
+        let slave = MockSlave::new(0x5a);
+        let mut kwbuf = [0; 8];
+
+        // Command 8 set 0xff, and the master reads past a 4 byte transaction.
+        let mut app = PeripheralApp {
+            len: 4,
+            underrun_fill: Some(0xff),
+            ..Default::default()
+        };
+        app.set_underrun_fill(&slave);
+        let len = app.fill_tx(&mut kwbuf, 8, src);
+        assert_eq!(len, 4);
+        assert_eq!(slave.over_read(&kwbuf, len), [1, 2, 3, 4, 0xff, 0xff]);
+
+        // The app shrank its write buffer after starting a 6 byte transaction.
+        let mut app = PeripheralApp {
+            len: 6,
+            underrun_fill: Some(0x00),
+            ..Default::default()
+        };
+        app.set_underrun_fill(&slave);
+        let len = app.fill_tx(&mut kwbuf, 8, src);
+        assert_eq!(len, 6);
+        assert_eq!(app.index, 6);
+        assert_eq!(slave.over_read(&kwbuf, len), [1, 2, 3, 4, 0, 0, 0, 0]);
+
+        // Without a fill byte the hardware's choice is left alone.
+        slave.write_byte.set(0x5a);
+        PeripheralApp::default().set_underrun_fill(&slave);
+        assert_eq!(slave.write_byte.get(), 0x5a);
+    }
+
+    #[test]
+    fn short_transaction_reports_count_before_done() {
+        let kernel = TestKernel::new(&["app"]);
+        let slave = MockSlave::new(0);
+        let mut peripheral = SpiPeripheral::new(&slave, kernel.create_grant(DRIVER_NUM));
+        peripheral.config_buffers(leak_buffer(8), leak_buffer(8));
+        let peripheral = &peripheral;
+
+        let app = kernel.process(0);
+        for subscribe_num in [upcall::DONE, upcall::SHORT] {
+            app.subscribe(peripheral, DRIVER_NUM, subscribe_num);
+        }
+        let write = app.buffer(&[1, 2, 3, 4, 5, 6]);
+        let read = app.buffer(&[0; 6]);
+        app.allow_readonly(peripheral, DRIVER_NUM, ro_allow::WRITE, write);
+        app.allow_readwrite(peripheral, DRIVER_NUM, rw_allow::READ, read);
+        assert!(matches!(
+            app.command(peripheral, DRIVER_NUM, 1, 6, 0),
+            SyscallReturn::Success
+        ));
+
+        // The master clocks 3 of the 6 bytes and deasserts chip select.
+        slave.deselect(peripheral, &[0xa, 0xb, 0xc]);
+        assert_eq!(
+            app.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, upcall::SHORT, (3, 6, 0)),
+                QueuedUpcall::new(DRIVER_NUM, upcall::DONE, (3, 0, 0)),
+            ]
+        );
+        assert_eq!(app.read(read), [0xa, 0xb, 0xc, 0, 0, 0]);
+
+        // The transaction is over, so the app can start the next one.
+        assert!(matches!(
+            app.command(peripheral, DRIVER_NUM, 1, 6, 0),
+            SyscallReturn::Success
+        ));
+    }
+
+    #[test]
+    fn deassert_follows_the_data() {
+        let kernel = TestKernel::new(&["app"]);
+        let slave = MockSlave::new(0);
+        let mut peripheral = SpiPeripheral::new(&slave, kernel.create_grant(DRIVER_NUM));
+        peripheral.config_buffers(leak_buffer(8), leak_buffer(8));
+        let peripheral = &peripheral;
+
+        let app = kernel.process(0);
+        for subscribe_num in [upcall::DONE, upcall::SELECTED, upcall::SHORT] {
+            app.subscribe(peripheral, DRIVER_NUM, subscribe_num);
+        }
+        let write = app.buffer(&[1, 2, 3, 4]);
+        let read = app.buffer(&[0; 4]);
+        app.allow_readonly(peripheral, DRIVER_NUM, ro_allow::WRITE, write);
+        app.allow_readwrite(peripheral, DRIVER_NUM, rw_allow::READ, read);
+        app.command(peripheral, DRIVER_NUM, 1, 4, 0);
+
+        // When the master ends the transaction early, the app learns what
+        // was received before it learns that chip select went high, by
+        // which time no transaction is set up.
+        slave.deselect(peripheral, &[0xa, 0xb]);
+        assert_eq!(
+            app.take_upcalls(),
+            [
+                QueuedUpcall::new(DRIVER_NUM, upcall::SELECTED, (4, upcall::EDGE_ASSERTED, 0)),
+                QueuedUpcall::new(DRIVER_NUM, upcall::SHORT, (2, 4, 0)),
+                QueuedUpcall::new(DRIVER_NUM, upcall::DONE, (2, 0, 0)),
+                QueuedUpcall::new(
+                    DRIVER_NUM,
+                    upcall::SELECTED,
+                    (0, upcall::EDGE_DEASSERTED, 0)
+                ),
+            ]
+        );
     }
 }
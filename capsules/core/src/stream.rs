@@ -230,6 +230,25 @@ macro_rules! dec_consume {
     } };
 }
 
+/// Checks that `len` bytes starting at `offset` fit in a buffer of `buf_len`
+/// bytes. On success the offset just past the range is returned, so that it
+/// composes with `enc_try!`/`dec_try!`. Returns `Needed` if the buffer is too
+/// short, or an error if `offset + len` overflows.
+pub fn check_bounds(buf_len: usize, offset: usize, len: usize) -> SResult {
+    let end = stream_from_option!(offset.checked_add(len));
+    if buf_len < end {
+        return SResult::Needed(end);
+    }
+    stream_done!(end);
+}
+
+/// Returns the `len` bytes of `buf` starting at `offset`, with the same range
+/// checks as `check_bounds`.
+pub fn subslice(buf: &[u8], offset: usize, len: usize) -> SResult<&[u8]> {
+    let (end, _) = dec_try!(check_bounds(buf.len(), offset, len));
+    stream_done!(end, &buf[offset..end]);
+}
+
 pub fn encode_u8(buf: &mut [u8], b: u8) -> SResult {
     stream_len_cond!(buf, 1);
     buf[0] = b;
@@ -298,3 +317,30 @@ pub fn decode_bytes_be(buf: &[u8], out: &mut [u8]) -> SResult {
     }
     stream_done!(out.len());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_within_buffer() {
+        assert_eq!(check_bounds(8, 2, 6).done(), Some((8, ())));
+        assert_eq!(check_bounds(8, 8, 0).done(), Some((8, ())));
+        assert_eq!(check_bounds(8, 3, 6).needed(), Some(9));
+        assert_eq!(check_bounds(0, 1, 0).needed(), Some(1));
+    }
+
+    #[test]
+    fn bounds_overflow_is_error() {
+        // `offset + len` would wrap around to 1 here.
+        assert!(check_bounds(8, usize::MAX, 2).is_err());
+        assert!(subslice(&[0; 8], 2, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn subslice_of_buffer() {
+        let buf = [1, 2, 3, 4, 5];
+        assert_eq!(subslice(&buf, 1, 3).done(), Some((4, &buf[1..4])));
+        assert_eq!(subslice(&buf, 4, 2).needed(), Some(6));
+    }
+}
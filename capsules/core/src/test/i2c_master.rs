@@ -0,0 +1,152 @@
+//! Round-trip self-test for an I2C master.
+//!
+//! Performs a write, a read, and a combined write-read transaction against a
+//! slave that returns the last bytes written to it, and checks that the bytes
+//! read back match. Such a slave can be a second I2C controller on the board
+//! acting as an echoing slave, or a mock device under QEMU. Each transaction
+//! is started from the completion callback of the previous one, so the whole
+//! test runs through the asynchronous client path. Results are printed with
+//! `debug!`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let i2c_device = static_init!(
+//!     I2CDevice<'static, I2C>,
+//!     I2CDevice::new(mux_i2c, 0x42)
+//! );
+//! let test = static_init!(
+//!     I2CMasterTest<'static, I2CDevice<'static, I2C>>,
+//!     I2CMasterTest::new(i2c_device, buffer)
+//! );
+//! i2c_device.set_client(test);
+//! test.run();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::debug;
+use kernel::hil::i2c::{Error, I2CClient, I2CDevice};
+use kernel::utilities::cells::TakeCell;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Step {
+    Idle,
+    Write,
+    Read,
+    WriteRead,
+}
+
+pub struct I2CMasterTest<'a, I: I2CDevice> {
+    i2c: &'a I,
+    buffer: TakeCell<'static, [u8]>,
+    step: Cell<Step>,
+}
+
+impl<'a, I: I2CDevice> I2CMasterTest<'a, I> {
+    pub fn new(i2c: &'a I, buffer: &'static mut [u8]) -> I2CMasterTest<'a, I> {
+        I2CMasterTest {
+            i2c,
+            buffer: TakeCell::new(buffer),
+            step: Cell::new(Step::Idle),
+        }
+    }
+
+    pub fn run(&self) {
+        if self.step.get() != Step::Idle {
+            debug!("I2C master test already running");
+            return;
+        }
+        self.i2c.enable();
+        self.start(Step::Write);
+    }
+
+    // Starts the transaction for `step` using the test buffer.
+    fn start(&self, step: Step) {
+        let Some(buffer) = self.buffer.take() else {
+            debug!("I2C master test failed: buffer missing");
+            return;
+        };
+        let len = buffer.len();
+        self.step.set(step);
+        let res = match step {
+            Step::Write => {
+                fill(buffer, 0x5A);
+                self.i2c.write(buffer, len)
+            }
+            Step::Read => {
+                buffer.iter_mut().for_each(|b| *b = 0);
+                self.i2c.read(buffer, len)
+            }
+            Step::WriteRead => {
+                fill(buffer, 0xC3);
+                self.i2c.write_read(buffer, len, len)
+            }
+            Step::Idle => Ok(()),
+        };
+        if let Err((e, buffer)) = res {
+            self.buffer.replace(buffer);
+            self.finish();
+            debug!("I2C master test failed to start {:?}: {:?}", step, e);
+        }
+    }
+
+    fn finish(&self) {
+        self.step.set(Step::Idle);
+        self.i2c.disable();
+    }
+}
+
+// A pattern covering varied byte values, offset by `seed` so that the two
+// writes put different bytes on the bus.
+fn fill(buffer: &mut [u8], seed: u8) {
+    for (i, b) in buffer.iter_mut().enumerate() {
+        *b = (i as u8).wrapping_mul(7).wrapping_add(seed);
+    }
+}
+
+// Index of the first byte that differs from the pattern written with `seed`.
+fn mismatch(buffer: &[u8], seed: u8) -> Option<usize> {
+    (0..buffer.len()).find(|&i| buffer[i] != (i as u8).wrapping_mul(7).wrapping_add(seed))
+}
+
+impl<'a, I: I2CDevice> I2CClient for I2CMasterTest<'a, I> {
+    fn command_complete(&self, buffer: &'static mut [u8], status: Result<(), Error>) {
+        let step = self.step.get();
+        let next = match (status, step) {
+            (Err(e), _) => {
+                debug!("I2C master test failed: {:?} error {:?}", step, e);
+                None
+            }
+            (Ok(()), Step::Write) => Some(Step::Read),
+            (Ok(()), Step::Read) => match mismatch(buffer, 0x5A) {
+                Some(i) => {
+                    debug!(
+                        "I2C master test failed: read byte {} was {:#x}",
+                        i, buffer[i]
+                    );
+                    None
+                }
+                None => Some(Step::WriteRead),
+            },
+            (Ok(()), Step::WriteRead) => {
+                match mismatch(buffer, 0xC3) {
+                    Some(i) => debug!(
+                        "I2C master test failed: write-read byte {} was {:#x}",
+                        i, buffer[i]
+                    ),
+                    None => debug!("I2C master test passed ({} bytes)", buffer.len()),
+                }
+                None
+            }
+            (Ok(()), Step::Idle) => None,
+        };
+
+        self.buffer.replace(buffer);
+        match next {
+            Some(step) => self.start(step),
+            None => self.finish(),
+        }
+    }
+}
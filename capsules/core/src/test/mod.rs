@@ -1,8 +1,11 @@
 pub mod alarm;
 pub mod alarm_edge_cases;
 pub mod double_grant_entry;
+pub mod i2c_master;
 pub mod random_alarm;
 pub mod random_timer;
 pub mod rng;
+pub mod spi_loopback;
+pub mod virtual_alarm_order;
 pub mod virtual_rng;
 pub mod virtual_uart;
@@ -0,0 +1,96 @@
+//! Loopback self-test for a SPI controller.
+//!
+//! Writes a known pattern and checks that the same bytes are read back. This
+//! requires MOSI to be jumpered to MISO, or a controller configured for
+//! internal loopback, and is intended as a quick sanity check during board
+//! bring-up. The result is printed with `debug!`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let test = static_init!(
+//!     SpiLoopbackTest<'static, VirtualSpiMasterDevice<'static, Spi>>,
+//!     SpiLoopbackTest::new(spi_device, write_buf, read_buf)
+//! );
+//! spi_device.set_client(test);
+//! test.run();
+//! ```
+
+use kernel::debug;
+use kernel::hil::spi::{SpiMasterClient, SpiMasterDevice};
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
+
+pub struct SpiLoopbackTest<'a, S: SpiMasterDevice> {
+    spi: &'a S,
+    write_buf: TakeCell<'static, [u8]>,
+    read_buf: TakeCell<'static, [u8]>,
+}
+
+impl<'a, S: SpiMasterDevice> SpiLoopbackTest<'a, S> {
+    pub fn new(
+        spi: &'a S,
+        write_buf: &'static mut [u8],
+        read_buf: &'static mut [u8],
+    ) -> SpiLoopbackTest<'a, S> {
+        SpiLoopbackTest {
+            spi,
+            write_buf: TakeCell::new(write_buf),
+            read_buf: TakeCell::new(read_buf),
+        }
+    }
+
+    pub fn run(&self) {
+        let write_buf = self.write_buf.take().unwrap();
+        let read_buf = self.read_buf.take().unwrap();
+        for (i, b) in write_buf.iter_mut().enumerate() {
+            *b = pattern(i);
+        }
+        for b in read_buf.iter_mut() {
+            *b = 0;
+        }
+
+        let len = core::cmp::min(write_buf.len(), read_buf.len());
+        if let Err((e, write_buf, read_buf)) =
+            self.spi.read_write_bytes(write_buf, Some(read_buf), len)
+        {
+            self.write_buf.replace(write_buf);
+            self.read_buf.put(read_buf);
+            debug!("SPI loopback test failed to start: {:?}", e);
+        }
+    }
+}
+
+// Every byte value, offset so that a stuck line cannot pass.
+fn pattern(i: usize) -> u8 {
+    (i as u8).wrapping_mul(7).wrapping_add(0xA5)
+}
+
+impl<'a, S: SpiMasterDevice> SpiMasterClient for SpiLoopbackTest<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+        status: Result<(), ErrorCode>,
+    ) {
+        let mismatch = read_buffer.as_ref().map_or(Some(0), |read_buf| {
+            (0..len).find(|&i| read_buf[i] != pattern(i))
+        });
+
+        match (status, mismatch) {
+            (Err(e), _) => debug!("SPI loopback test failed: transfer error {:?}", e),
+            (Ok(()), Some(i)) => debug!(
+                "SPI loopback test failed: byte {} read {:#x}, expected {:#x}",
+                i,
+                read_buffer.as_ref().map_or(0, |read_buf| read_buf[i]),
+                pattern(i)
+            ),
+            (Ok(()), None) => debug!("SPI loopback test passed ({} bytes)", len),
+        }
+
+        self.write_buf.replace(write_buffer);
+        self.read_buf.put(read_buffer);
+    }
+}
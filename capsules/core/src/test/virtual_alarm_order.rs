@@ -0,0 +1,321 @@
+//! Self-test for the ordering and fairness of the virtual alarm mux.
+//!
+//! Multiplexes several `VirtualMuxAlarm`s over a `MockAlarm` whose clock only
+//! moves when the test moves it, so the results do not depend on the speed
+//! of the board or on other alarms in the system. The test arms the virtual
+//! alarms in different orders and with coincident deadlines and checks that
+//! they fire in deadline order, that coincident alarms take turns firing
+//! first, and that disarmed alarms stay silent. Each case prints its number
+//! and whether it passed with `debug!`, so a board can run it at boot to
+//! validate the timer subsystem.
+//!
+//! Usage
+//! -----
+//!
+//! The `VirtualAlarmOrderTestComponent` in the `components` crate builds the
+//! test. By hand:
+//!
+//! ```rust,ignore
+//! let mock = static_init!(MockAlarm<'static>, MockAlarm::new());
+//! let mux = static_init!(MuxAlarm<'static, MockAlarm<'static>>, MuxAlarm::new(mock));
+//! mock.set_alarm_client(mux);
+//! let log = static_init!(FireLog, FireLog::new());
+//! let recorders = static_init!(
+//!     [FireRecorder<'static>; NUM_ALARMS],
+//!     core::array::from_fn(|id| FireRecorder::new(id as u8, log))
+//! );
+//! let alarms = static_init!(
+//!     [VirtualMuxAlarm<'static, MockAlarm<'static>>; NUM_ALARMS],
+//!     core::array::from_fn(|_| VirtualMuxAlarm::new(mux))
+//! );
+//! for (alarm, recorder) in alarms.iter().zip(recorders.iter()) {
+//!     alarm.setup();
+//!     alarm.set_alarm_client(recorder);
+//! }
+//! let test = static_init!(
+//!     TestVirtualAlarmOrder<'static>,
+//!     TestVirtualAlarmOrder::new(mock, alarms, log)
+//! );
+//! test.run();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::debug;
+use kernel::hil::time::{Alarm, AlarmClient, Freq1KHz, Ticks, Ticks32, Time};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+use crate::virtualizers::virtual_alarm::VirtualMuxAlarm;
+
+/// Number of virtual alarms the test multiplexes.
+pub const NUM_ALARMS: usize = 3;
+
+/// Rounds of coincident deadlines, enough for every alarm to go first once.
+const ROUNDS: usize = NUM_ALARMS;
+
+/// Most firings a single case records.
+const LOG_LEN: usize = NUM_ALARMS * ROUNDS;
+
+/// An alarm whose counter only advances when told to.
+pub struct MockAlarm<'a> {
+    now: Cell<Ticks32>,
+    reference: Cell<Ticks32>,
+    dt: Cell<Ticks32>,
+    armed: Cell<bool>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+}
+
+impl MockAlarm<'_> {
+    pub fn new() -> Self {
+        MockAlarm {
+            now: Cell::new(1_000u32.into()),
+            reference: Cell::new(0u32.into()),
+            dt: Cell::new(0u32.into()),
+            armed: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Move the counter forward by `ticks` without firing.
+    fn advance(&self, ticks: u32) {
+        self.now.set(self.now.get().wrapping_add(ticks.into()));
+    }
+
+    /// Move the counter to the armed deadline and call the client, until
+    /// nothing is left armed. Gives up after `limit` firings in case the
+    /// alarm keeps re-arming itself.
+    fn fire_all(&self, limit: usize) {
+        for _ in 0..limit {
+            if !self.armed.get() {
+                return;
+            }
+            self.armed.set(false);
+            self.now.set(self.get_alarm());
+            self.client.map(|client| client.alarm());
+        }
+    }
+}
+
+impl Time for MockAlarm<'_> {
+    type Frequency = Freq1KHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        self.now.get()
+    }
+}
+
+impl<'a> Alarm<'a> for MockAlarm<'a> {
+    fn set_alarm_client(&self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.reference.set(reference);
+        self.dt.set(dt);
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        self.reference.get().wrapping_add(self.dt.get())
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(false);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        1u32.into()
+    }
+}
+
+/// The order in which the virtual alarms fired.
+pub struct FireLog {
+    ids: Cell<[u8; LOG_LEN]>,
+    len: Cell<usize>,
+}
+
+impl FireLog {
+    pub fn new() -> FireLog {
+        FireLog {
+            ids: Cell::new([0; LOG_LEN]),
+            len: Cell::new(0),
+        }
+    }
+
+    fn record(&self, id: u8) {
+        let len = self.len.get();
+        if len < LOG_LEN {
+            let mut ids = self.ids.get();
+            ids[len] = id;
+            self.ids.set(ids);
+        }
+        // Keep counting past the end so that extra firings are noticed.
+        self.len.set(len + 1);
+    }
+
+    /// The firings recorded so far, after which the log is empty again.
+    fn take(&self) -> ([u8; LOG_LEN], usize) {
+        let len = self.len.replace(0);
+        (self.ids.get(), len)
+    }
+}
+
+/// Client of one virtual alarm, which logs its `id` when the alarm fires.
+pub struct FireRecorder<'a> {
+    id: u8,
+    log: &'a FireLog,
+}
+
+impl<'a> FireRecorder<'a> {
+    pub fn new(id: u8, log: &'a FireLog) -> FireRecorder<'a> {
+        FireRecorder { id, log }
+    }
+}
+
+impl AlarmClient for FireRecorder<'_> {
+    fn alarm(&self) {
+        self.log.record(self.id);
+    }
+}
+
+pub struct TestVirtualAlarmOrder<'a> {
+    mock: &'a MockAlarm<'a>,
+    alarms: &'a [VirtualMuxAlarm<'a, MockAlarm<'a>>; NUM_ALARMS],
+    log: &'a FireLog,
+}
+
+impl<'a> TestVirtualAlarmOrder<'a> {
+    /// `alarms[i]` must be multiplexed over `mock` and have a `FireRecorder`
+    /// with id `i` writing to `log` as its client.
+    pub fn new(
+        mock: &'a MockAlarm<'a>,
+        alarms: &'a [VirtualMuxAlarm<'a, MockAlarm<'a>>; NUM_ALARMS],
+        log: &'a FireLog,
+    ) -> TestVirtualAlarmOrder<'a> {
+        TestVirtualAlarmOrder { mock, alarms, log }
+    }
+
+    /// Run every case, and return whether they all passed.
+    pub fn run(&self) -> bool {
+        debug!("Starting virtual alarm order tests.");
+        let results = [
+            self.distinct_deadlines(),
+            self.coincident_deadlines(),
+            self.disarmed_alarm(),
+            self.earlier_alarm_armed_later(),
+        ];
+        for (case, passed) in results.iter().enumerate() {
+            if *passed {
+                debug!("Virtual alarm order test {}: passed", case);
+            } else {
+                debug!("Virtual alarm order test {}: FAILED", case);
+            }
+        }
+        let passed = results.iter().all(|passed| *passed);
+        if passed {
+            debug!("Virtual alarm order tests passed.");
+        } else {
+            debug!("Virtual alarm order tests FAILED.");
+        }
+        passed
+    }
+
+    /// Fire everything armed, and check it fired in the order `expected`.
+    fn fires_in_order(&self, expected: &[u8]) -> bool {
+        self.mock.fire_all(LOG_LEN + 1);
+        let (ids, len) = self.log.take();
+        len == expected.len() && ids[..len] == *expected
+    }
+
+    /// Alarms armed out of order fire in deadline order.
+    fn distinct_deadlines(&self) -> bool {
+        let now = self.mock.now();
+        self.alarms[0].set_alarm(now, 30.into());
+        self.alarms[1].set_alarm(now, 10.into());
+        self.alarms[2].set_alarm(now, 20.into());
+        self.fires_in_order(&[1, 2, 0])
+    }
+
+    /// Alarms sharing a deadline all fire on it, and each of them is first
+    /// in one of the rounds.
+    fn coincident_deadlines(&self) -> bool {
+        let mut firsts = [false; NUM_ALARMS];
+        for _ in 0..ROUNDS {
+            let now = self.mock.now();
+            for alarm in self.alarms {
+                alarm.set_alarm(now, 10.into());
+            }
+            self.mock.fire_all(LOG_LEN + 1);
+            let (ids, len) = self.log.take();
+            if len != NUM_ALARMS {
+                return false;
+            }
+            let mut fired = [false; NUM_ALARMS];
+            for id in &ids[..len] {
+                fired[*id as usize] = true;
+            }
+            if fired.contains(&false) {
+                return false;
+            }
+            firsts[ids[0] as usize] = true;
+        }
+        !firsts.contains(&false)
+    }
+
+    /// An alarm disarmed before its deadline does not fire, and the others
+    /// still do.
+    fn disarmed_alarm(&self) -> bool {
+        let now = self.mock.now();
+        self.alarms[0].set_alarm(now, 10.into());
+        self.alarms[1].set_alarm(now, 15.into());
+        self.alarms[2].set_alarm(now, 20.into());
+        let disarmed = self.alarms[1].disarm().is_ok() && !self.alarms[1].is_armed();
+        disarmed && self.fires_in_order(&[0, 2])
+    }
+
+    /// An alarm armed after another, but with an earlier deadline, fires
+    /// first.
+    fn earlier_alarm_armed_later(&self) -> bool {
+        let now = self.mock.now();
+        self.alarms[0].set_alarm(now, 50.into());
+        self.mock.advance(20);
+        let now = self.mock.now();
+        self.alarms[1].set_alarm(now, 5.into());
+        self.alarms[2].set_alarm(now, 40.into());
+        self.fires_in_order(&[1, 0, 2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtualizers::virtual_alarm::MuxAlarm;
+
+    #[test]
+    fn mux_passes_order_tests() {
+        let mock = MockAlarm::new();
+        let mux = MuxAlarm::new(&mock);
+        mock.set_alarm_client(&mux);
+        let log = FireLog::new();
+        let recorders: [FireRecorder; NUM_ALARMS] =
+            core::array::from_fn(|id| FireRecorder::new(id as u8, &log));
+        let alarms: [VirtualMuxAlarm<MockAlarm>; NUM_ALARMS] =
+            core::array::from_fn(|_| VirtualMuxAlarm::new(&mux));
+        for (alarm, recorder) in alarms.iter().zip(recorders.iter()) {
+            alarm.setup();
+            alarm.set_alarm_client(recorder);
+        }
+        let test = TestVirtualAlarmOrder::new(&mock, &alarms, &log);
+        assert!(test.distinct_deadlines());
+        assert!(test.coincident_deadlines());
+        assert!(test.disarmed_alarm());
+        assert!(test.earlier_alarm_armed_later());
+    }
+}
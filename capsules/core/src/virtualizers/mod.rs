@@ -1,4 +1,5 @@
 pub mod virtual_adc;
+pub mod virtual_aes;
 pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
 pub mod virtual_digest;
@@ -0,0 +1,626 @@
+//! Virtualize an AES128 engine so that several clients can share it.
+//!
+//! Each `VirtualAES128` keeps its own key, IV and mode. Calls to `crypt()` are
+//! queued on the mux, which loads the issuing client's key, IV and mode into
+//! the engine before running the operation and routes `crypt_done` back to
+//! that client.
+//!
+//! A message may span several `crypt()` calls even if other clients use the
+//! engine in between. Each client keeps the chaining state of its message,
+//! the next counter block in CTR mode or the last ciphertext block in CBC
+//! mode, and the mux loads it in place of the IV when the client's next
+//! operation runs. The saved counter is advanced as one 128-bit big-endian
+//! number, so the engine must increment the whole counter block.
+//!
+//! If an operation fails to start once it reaches the front of the queue, its
+//! buffers are returned through `crypt_done` with a length of 0.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use capsules::virtualizers::virtual_aes::{MuxAES128, VirtualAES128};
+//! # use kernel::static_init;
+//! let aes_mux = static_init!(
+//!     MuxAES128<'static, nrf52840::aes::AesECB<'static>>,
+//!     MuxAES128::new(&base_peripherals.ecb)
+//! );
+//! aes_mux.register();
+//! let aes_user = static_init!(
+//!     VirtualAES128<'static, nrf52840::aes::AesECB<'static>>,
+//!     VirtualAES128::new(aes_mux)
+//! );
+//! aes_user.setup();
+//! aes_user.set_client(storage_encryption);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, Client, Modes, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Mode {
+    Ctr,
+    Cbc,
+    Ecb,
+}
+
+// The arguments of a queued `crypt()` call.
+struct Request {
+    source: Option<&'static mut [u8]>,
+    dest: &'static mut [u8],
+    start_index: usize,
+    stop_index: usize,
+}
+
+// Where the chaining state after an operation comes from.
+#[derive(Copy, Clone)]
+enum NextChain {
+    // Known before the operation runs.
+    Block([u8; AES128_BLOCK_SIZE]),
+    // The output block ending at this index of the destination buffer.
+    OutputEndingAt(usize),
+}
+
+/// `counter` advanced by `blocks` as a big-endian number.
+fn add_blocks(counter: [u8; AES128_BLOCK_SIZE], blocks: usize) -> [u8; AES128_BLOCK_SIZE] {
+    u128::from_be_bytes(counter)
+        .wrapping_add(blocks as u128)
+        .to_be_bytes()
+}
+
+pub struct MuxAES128<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> {
+    aes: &'a A,
+    clients: List<'a, VirtualAES128<'a, A>>,
+    inflight: OptionalCell<&'a VirtualAES128<'a, A>>,
+    // The client whose key, IV and message state are loaded in the engine.
+    loaded: OptionalCell<&'a VirtualAES128<'a, A>>,
+    deferred_call: DeferredCall,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> MuxAES128<'a, A> {
+    pub fn new(aes: &'a A) -> Self {
+        Self {
+            aes,
+            clients: List::new(),
+            inflight: OptionalCell::empty(),
+            loaded: OptionalCell::empty(),
+            deferred_call: DeferredCall::new(),
+        }
+    }
+
+    fn is_loaded(&self, node: &VirtualAES128<'a, A>) -> bool {
+        self.loaded
+            .map_or(false, |loaded| core::ptr::eq(*loaded, node))
+    }
+
+    fn do_next_op(&self) {
+        if self.inflight.is_some() {
+            return;
+        }
+        let mnode = self.clients.iter().find(|node| node.request.is_some());
+        mnode.map(|node| {
+            if let Some(request) = node.request.take() {
+                match self.start(node, request) {
+                    None => self.inflight.set(node),
+                    Some((_, source, dest)) => {
+                        node.client
+                            .map(move |client| client.crypt_done(source, dest, 0));
+                        self.do_next_op();
+                    }
+                }
+            }
+        });
+    }
+
+    fn start(
+        &self,
+        node: &'a VirtualAES128<'a, A>,
+        request: Request,
+    ) -> Option<(
+        Result<(), ErrorCode>,
+        Option<&'static mut [u8]>,
+        &'static mut [u8],
+    )> {
+        if node.reload.get() {
+            node.chain.set(node.iv.get());
+        }
+        if node.reload.get() || !self.is_loaded(node) {
+            if let Err(e) = node.load(self.aes) {
+                self.loaded.clear();
+                return Some((Err(e), request.source, request.dest));
+            }
+            node.reload.set(false);
+            self.loaded.set(node);
+        }
+        let next = node.next_chain(&request);
+        let res = self.aes.crypt(
+            request.source,
+            request.dest,
+            request.start_index,
+            request.stop_index,
+        );
+        if res.is_none() {
+            node.pending_chain.insert(next);
+        }
+        res
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> DeferredCallClient
+    for MuxAES128<'a, A>
+{
+    fn handle_deferred_call(&self) {
+        self.do_next_op();
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> Client<'a> for MuxAES128<'a, A> {
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        length: usize,
+    ) {
+        self.inflight.take().map(move |node| {
+            match node.pending_chain.take() {
+                Some(NextChain::Block(block)) => node.chain.set(block),
+                Some(NextChain::OutputEndingAt(end)) => {
+                    if let Some(Ok(block)) = end
+                        .checked_sub(AES128_BLOCK_SIZE)
+                        .and_then(|start| dest.get(start..end))
+                        .map(<[u8; AES128_BLOCK_SIZE]>::try_from)
+                    {
+                        node.chain.set(block);
+                    }
+                }
+                None => {}
+            }
+            node.client
+                .map(move |client| client.crypt_done(source, dest, length));
+        });
+        self.do_next_op();
+    }
+}
+
+pub struct VirtualAES128<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> {
+    mux: &'a MuxAES128<'a, A>,
+    next: ListLink<'a, VirtualAES128<'a, A>>,
+    client: OptionalCell<&'a dyn Client<'a>>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    mode: OptionalCell<(Mode, bool)>,
+    // Set when the engine must be loaded with this client's settings again
+    // and a new message started.
+    reload: Cell<bool>,
+    // The IV that continues the current message after another client used
+    // the engine.
+    chain: Cell<[u8; AES128_BLOCK_SIZE]>,
+    // How to update `chain` once the operation in flight completes.
+    pending_chain: OptionalCell<NextChain>,
+    request: OptionalCell<Request>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> ListNode<'a, VirtualAES128<'a, A>>
+    for VirtualAES128<'a, A>
+{
+    fn next(&'a self) -> &'a ListLink<'a, VirtualAES128<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128<'a, A> {
+    pub fn new(mux: &'a MuxAES128<'a, A>) -> Self {
+        Self {
+            mux,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            mode: OptionalCell::empty(),
+            reload: Cell::new(true),
+            chain: Cell::new([0; AES128_BLOCK_SIZE]),
+            pending_chain: OptionalCell::empty(),
+            request: OptionalCell::empty(),
+        }
+    }
+
+    pub fn setup(&'a self) {
+        self.mux.clients.push_head(self);
+    }
+
+    /// Load this client's key, chaining state and mode into `aes`.
+    fn load(&self, aes: &A) -> Result<(), ErrorCode> {
+        let (mode, encrypting) = self.mode.extract().ok_or(ErrorCode::INVAL)?;
+        aes.set_key(&self.key.get())?;
+        aes.set_iv(&self.chain.get())?;
+        match mode {
+            Mode::Ctr => aes.set_mode_aes128ctr(encrypting),
+            Mode::Cbc => aes.set_mode_aes128cbc(encrypting),
+            Mode::Ecb => aes.set_mode_aes128ecb(encrypting),
+        }?;
+        aes.start_message();
+        Ok(())
+    }
+
+    /// The chaining state once `request` has run. Decryption in place
+    /// overwrites the ciphertext, so its last block is saved beforehand.
+    fn next_chain(&self, request: &Request) -> Option<NextChain> {
+        let (start, stop) = (request.start_index, request.stop_index);
+        let len = stop - start;
+        if len < AES128_BLOCK_SIZE {
+            return None;
+        }
+        match self.mode.extract()? {
+            (Mode::Ctr, _) => Some(NextChain::Block(add_blocks(
+                self.chain.get(),
+                len / AES128_BLOCK_SIZE,
+            ))),
+            (Mode::Cbc, true) => Some(NextChain::OutputEndingAt(stop)),
+            (Mode::Cbc, false) => {
+                let input = match request.source.as_ref() {
+                    Some(source) => &source[len - AES128_BLOCK_SIZE..],
+                    None => &request.dest[stop - AES128_BLOCK_SIZE..stop],
+                };
+                input.try_into().ok().map(NextChain::Block)
+            }
+            (Mode::Ecb, _) => None,
+        }
+    }
+
+    fn set_mode(&self, mode: Mode, encrypting: bool) -> Result<(), ErrorCode> {
+        self.mode.set((mode, encrypting));
+        self.reload.set(true);
+        Ok(())
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128<'a> for VirtualAES128<'a, A> {
+    fn enable(&self) {
+        self.mux.aes.enable();
+    }
+
+    /// The engine is shared, so it stays enabled for the other clients.
+    fn disable(&self) {}
+
+    fn set_client(&'a self, client: &'a dyn Client<'a>) {
+        self.client.set(client);
+        self.mux.aes.set_client(self.mux);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        let key: [u8; AES128_KEY_SIZE] = key.try_into().map_err(|_| ErrorCode::INVAL)?;
+        self.key.set(key);
+        self.reload.set(true);
+        Ok(())
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+        let iv: [u8; AES128_BLOCK_SIZE] = iv.try_into().map_err(|_| ErrorCode::INVAL)?;
+        self.iv.set(iv);
+        self.reload.set(true);
+        Ok(())
+    }
+
+    fn start_message(&self) {
+        self.reload.set(true);
+    }
+
+    fn max_crypt_len(&self) -> usize {
+        self.mux.aes.max_crypt_len()
+    }
+
+    fn supported_modes(&self) -> Modes {
+        self.mux.aes.supported_modes() & (Modes::ECB | Modes::CTR | Modes::CBC)
+    }
+
+    fn crypt(
+        &self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(
+        Result<(), ErrorCode>,
+        Option<&'static mut [u8]>,
+        &'static mut [u8],
+    )> {
+        let inflight = self
+            .mux
+            .inflight
+            .map_or(false, |node| core::ptr::eq(*node, self));
+        if inflight || self.request.is_some() {
+            return Some((Err(ErrorCode::BUSY), source, dest));
+        }
+        let source_ok = source.as_ref().map_or(true, |src| {
+            src.len() == stop_index.wrapping_sub(start_index)
+        });
+        if start_index > stop_index || stop_index > dest.len() || !source_ok {
+            return Some((Err(ErrorCode::INVAL), source, dest));
+        }
+        self.request.set(Request {
+            source,
+            dest,
+            start_index,
+            stop_index,
+        });
+        self.mux.deferred_call.set();
+        None
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128Ctr for VirtualAES128<'a, A> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.set_mode(Mode::Ctr, encrypting)
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128CBC for VirtualAES128<'a, A> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.set_mode(Mode::Cbc, encrypting)
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128ECB for VirtualAES128<'a, A> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.set_mode(Mode::Ecb, encrypting)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::leak_buffer;
+    use kernel::utilities::cells::TakeCell;
+
+    /// Stand-in engine that records `key ^ iv` for each operation it starts,
+    /// and chains blocks like CTR and CBC over the toy block cipher
+    /// `block ^ key`.
+    struct FakeAes {
+        key: Cell<[u8; AES128_KEY_SIZE]>,
+        iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+        mode: Cell<Option<(Mode, bool)>>,
+        // The counter block or previous ciphertext block of the message.
+        chain: Cell<[u8; AES128_BLOCK_SIZE]>,
+        outputs: [Cell<[u8; AES128_BLOCK_SIZE]>; 4],
+        ops: Cell<usize>,
+        dest: TakeCell<'static, [u8]>,
+    }
+
+    impl FakeAes {
+        fn new() -> Self {
+            Self {
+                key: Cell::new([0; AES128_KEY_SIZE]),
+                iv: Cell::new([0; AES128_BLOCK_SIZE]),
+                mode: Cell::new(None),
+                chain: Cell::new([0; AES128_BLOCK_SIZE]),
+                outputs: [(); 4].map(|()| Cell::new([0; AES128_BLOCK_SIZE])),
+                ops: Cell::new(0),
+                dest: TakeCell::empty(),
+            }
+        }
+
+        fn xor(a: [u8; AES128_BLOCK_SIZE], b: &[u8]) -> [u8; AES128_BLOCK_SIZE] {
+            core::array::from_fn(|i| a[i] ^ b[i])
+        }
+
+        /// Run one block through the configured mode in place.
+        fn crypt_block(&self, block: &mut [u8]) {
+            let (key, chain) = (self.key.get(), self.chain.get());
+            let input: [u8; AES128_BLOCK_SIZE] = (&*block).try_into().unwrap();
+            let output = match self.mode.get() {
+                Some((Mode::Ctr, _)) => {
+                    self.chain.set(add_blocks(chain, 1));
+                    Self::xor(Self::xor(chain, &key), &input)
+                }
+                Some((Mode::Cbc, true)) => {
+                    let output = Self::xor(Self::xor(input, &chain), &key);
+                    self.chain.set(output);
+                    output
+                }
+                Some((Mode::Cbc, false)) => {
+                    self.chain.set(input);
+                    Self::xor(Self::xor(input, &key), &chain)
+                }
+                _ => Self::xor(input, &key),
+            };
+            block.copy_from_slice(&output);
+        }
+    }
+
+    impl<'a> AES128<'a> for FakeAes {
+        fn enable(&self) {}
+        fn disable(&self) {}
+        fn set_client(&'a self, _client: &'a dyn Client<'a>) {}
+        fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+            self.key.set(key.try_into().unwrap());
+            Ok(())
+        }
+        fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+            self.iv.set(iv.try_into().unwrap());
+            Ok(())
+        }
+        fn start_message(&self) {
+            self.chain.set(self.iv.get());
+        }
+        fn max_crypt_len(&self) -> usize {
+            AES128_BLOCK_SIZE
+        }
+        fn crypt(
+            &self,
+            source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            start_index: usize,
+            stop_index: usize,
+        ) -> Option<(
+            Result<(), ErrorCode>,
+            Option<&'static mut [u8]>,
+            &'static mut [u8],
+        )> {
+            if self.dest.is_some() {
+                return Some((Err(ErrorCode::BUSY), source, dest));
+            }
+            let (key, iv) = (self.key.get(), self.iv.get());
+            let block: [u8; AES128_BLOCK_SIZE] = core::array::from_fn(|i| key[i] ^ iv[i]);
+            self.outputs[self.ops.get()].set(block);
+            self.ops.set(self.ops.get() + 1);
+            for block in dest[start_index..stop_index].chunks_mut(AES128_BLOCK_SIZE) {
+                self.crypt_block(block);
+            }
+            self.dest.replace(dest);
+            None
+        }
+    }
+
+    impl AES128Ctr for FakeAes {
+        fn set_mode_aes128ctr(&self, encrypting: bool) -> Result<(), ErrorCode> {
+            self.mode.set(Some((Mode::Ctr, encrypting)));
+            Ok(())
+        }
+    }
+
+    impl AES128CBC for FakeAes {
+        fn set_mode_aes128cbc(&self, encrypting: bool) -> Result<(), ErrorCode> {
+            self.mode.set(Some((Mode::Cbc, encrypting)));
+            Ok(())
+        }
+    }
+
+    impl AES128ECB for FakeAes {
+        fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode> {
+            self.mode.set(Some((Mode::Ecb, encrypting)));
+            Ok(())
+        }
+    }
+
+    struct DoneCounter(Cell<usize>);
+
+    impl<'a> Client<'a> for DoneCounter {
+        fn crypt_done(&'a self, _: Option<&'static mut [u8]>, _: &'static mut [u8], _: usize) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    fn expected(key: u8, iv: u8) -> [u8; AES128_BLOCK_SIZE] {
+        [key ^ iv; AES128_BLOCK_SIZE]
+    }
+
+    #[test]
+    fn two_clients_keep_their_keys() {
+        let aes = FakeAes::new();
+        let mux = MuxAES128::new(&aes);
+        let first = VirtualAES128::new(&mux);
+        let second = VirtualAES128::new(&mux);
+        let (done1, done2) = (DoneCounter(Cell::new(0)), DoneCounter(Cell::new(0)));
+        for (user, done, key, iv) in [(&first, &done1, 0x11, 0x01), (&second, &done2, 0x22, 0x02)] {
+            user.setup();
+            user.set_client(done);
+            assert_eq!(user.set_key(&[key; AES128_KEY_SIZE]), Ok(()));
+            assert_eq!(user.set_iv(&[iv; AES128_BLOCK_SIZE]), Ok(()));
+            assert_eq!(user.set_mode_aes128ctr(true), Ok(()));
+        }
+
+        assert!(first.crypt(None, &mut [], 0, 0).is_none());
+        assert!(second.crypt(None, &mut [], 0, 0).is_none());
+        // Each client has one request outstanding.
+        assert!(first.crypt(None, &mut [], 0, 0).is_some());
+        mux.handle_deferred_call();
+        assert_eq!(aes.ops.get(), 1);
+
+        mux.crypt_done(None, aes.dest.take().unwrap(), 0);
+        assert_eq!(aes.ops.get(), 2);
+        mux.crypt_done(None, aes.dest.take().unwrap(), 0);
+
+        // The second client's key is loaded again after the first used it.
+        assert!(second.crypt(None, &mut [], 0, 0).is_none());
+        mux.handle_deferred_call();
+        mux.crypt_done(None, aes.dest.take().unwrap(), 0);
+
+        // The list is last-in first-out, so the second client ran first.
+        let mut outputs = aes.outputs.iter().map(Cell::get);
+        let order = [(0x22, 0x02), (0x11, 0x01), (0x22, 0x02)];
+        for (key, iv) in order {
+            assert_eq!(outputs.next(), Some(expected(key, iv)));
+        }
+        assert_eq!((done1.0.get(), done2.0.get()), (1, 2));
+    }
+
+    /// Keeps the buffer handed back by each completed operation.
+    struct Collect(TakeCell<'static, [u8]>);
+
+    impl<'a> Client<'a> for Collect {
+        fn crypt_done(&'a self, _: Option<&'static mut [u8]>, dest: &'static mut [u8], _: usize) {
+            self.0.replace(dest);
+        }
+    }
+
+    /// A 64 byte message whose bytes count up from `first`.
+    fn message(first: u8) -> &'static mut [u8] {
+        let buffer = leak_buffer(64);
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = first.wrapping_add(i as u8);
+        }
+        buffer
+    }
+
+    /// The message from `first` run through a fresh engine in one call.
+    fn reference(key: u8, iv: u8, mode: Mode, encrypting: bool, first: u8) -> &'static mut [u8] {
+        let aes = FakeAes::new();
+        aes.set_key(&[key; AES128_KEY_SIZE]).unwrap();
+        aes.set_iv(&[iv; AES128_BLOCK_SIZE]).unwrap();
+        aes.mode.set(Some((mode, encrypting)));
+        aes.start_message();
+        assert!(aes.crypt(None, message(first), 0, 64).is_none());
+        aes.dest.take().unwrap()
+    }
+
+    #[test]
+    fn interleaved_messages_keep_their_chaining() {
+        let aes = FakeAes::new();
+        let mux = MuxAES128::new(&aes);
+        let ctr = VirtualAES128::new(&mux);
+        let cbc = VirtualAES128::new(&mux);
+        let (ctr_done, cbc_done) = (Collect(TakeCell::empty()), Collect(TakeCell::empty()));
+        for (user, done, key, iv) in [(&ctr, &ctr_done, 0x11, 0x01), (&cbc, &cbc_done, 0x22, 0x02)]
+        {
+            user.setup();
+            user.set_client(done);
+            assert_eq!(user.set_key(&[key; AES128_KEY_SIZE]), Ok(()));
+            assert_eq!(user.set_iv(&[iv; AES128_BLOCK_SIZE]), Ok(()));
+        }
+        assert_eq!(ctr.set_mode_aes128ctr(true), Ok(()));
+        assert_eq!(cbc.set_mode_aes128cbc(false), Ok(()));
+        ctr_done.0.replace(message(0x40));
+        cbc_done.0.replace(message(0x80));
+
+        // Each message is processed in two halves, alternating with the
+        // other client, and the CBC one is decrypted in place.
+        for (user, done, start) in [
+            (&ctr, &ctr_done, 0),
+            (&cbc, &cbc_done, 0),
+            (&ctr, &ctr_done, 32),
+            (&cbc, &cbc_done, 32),
+        ] {
+            let buffer = done.0.take().unwrap();
+            assert!(user.crypt(None, buffer, start, start + 32).is_none());
+            mux.handle_deferred_call();
+            mux.crypt_done(None, aes.dest.take().unwrap(), 32);
+        }
+        assert_eq!(aes.ops.get(), 4);
+
+        let expected = reference(0x11, 0x01, Mode::Ctr, true, 0x40);
+        assert_eq!(ctr_done.0.take().unwrap(), expected);
+        let expected = reference(0x22, 0x02, Mode::Cbc, false, 0x80);
+        assert_eq!(cbc_done.0.take().unwrap(), expected);
+    }
+}
@@ -209,10 +209,15 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> DeferredCallClient
 impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::Client<'a>
     for MuxAES128CCM<'a, A>
 {
-    fn crypt_done(&'a self, source: Option<&'static mut [u8]>, dest: &'static mut [u8]) {
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        length: usize,
+    ) {
         if self.inflight.is_none() {
             self.client.map(move |client| {
-                client.crypt_done(source, dest);
+                client.crypt_done(source, dest, length);
             });
             return;
         }
@@ -222,7 +227,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption
             // and it will call do_next_op to perform the next operation
             // self.do_next_op() will be called when the encryption is failed or is really done
             // search for self.ccm_client
-            vaes_ccm.crypt_done(source, dest);
+            vaes_ccm.crypt_done(source, dest, length);
         });
     }
 }
@@ -755,6 +760,21 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption
         }
     }
 
+    fn max_crypt_len(&self) -> usize {
+        self.mux.aes.max_crypt_len()
+    }
+
+    // CCM is built here from the engine's CBC and CTR modes.
+    fn supported_modes(&self) -> symmetric_encryption::Modes {
+        use symmetric_encryption::Modes;
+        let modes = self.mux.aes.supported_modes() & (Modes::ECB | Modes::CTR | Modes::CBC);
+        if modes.contains(Modes::CBC | Modes::CTR) {
+            modes | Modes::CCM
+        } else {
+            modes
+        }
+    }
+
     fn crypt(
         &self,
         source: Option<&'static mut [u8]>,
@@ -807,7 +827,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AES128CBC for Virtua
 impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::Client<'a>
     for VirtualAES128CCM<'a, A>
 {
-    fn crypt_done(&self, _: Option<&'static mut [u8]>, crypt_buf: &'static mut [u8]) {
+    fn crypt_done(&self, _: Option<&'static mut [u8]>, crypt_buf: &'static mut [u8], _: usize) {
         self.crypt_buf.replace(crypt_buf);
         match self.state.get() {
             CCMState::Idle => {}
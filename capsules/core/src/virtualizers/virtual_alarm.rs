@@ -8,6 +8,14 @@ use kernel::hil::time::{self, Alarm, Ticks, Time};
 use kernel::utilities::cells::OptionalCell;
 use kernel::ErrorCode;
 
+/// How far behind `now()`, in ticks, a deadline handed to the underlying alarm
+/// may be and still be treated as just missed. Interrupt latency can make a
+/// deadline expire between computing it and programming the hardware; such a
+/// deadline is moved to the next tick rather than programmed as is, where
+/// hardware that only matches on the exact counter value would not fire until
+/// the counter wraps around. Deadlines further behind are left unchanged.
+pub const PAST_DEADLINE_GUARD_TICKS: u32 = 64;
+
 #[derive(Copy, Clone)]
 struct TickDtReference<T: Ticks> {
     /// Reference time point when this alarm was setup.
@@ -38,6 +46,9 @@ pub struct VirtualMuxAlarm<'a, A: Alarm<'a>> {
     /// Whether this alarm is currently armed, i.e. whether it should fire when the time has
     /// elapsed.
     armed: Cell<bool>,
+    /// Whether this alarm was armed while the mux was firing alarms. Such an alarm is not fired
+    /// in the same pass, even if it is already expired, but on the next underlying alarm.
+    armed_while_firing: Cell<bool>,
     /// Next alarm in the list.
     next: ListLink<'a, VirtualMuxAlarm<'a, A>>,
     /// Alarm client for this node in the list.
@@ -62,6 +73,7 @@ impl<'a, A: Alarm<'a>> VirtualMuxAlarm<'a, A> {
                 extended: false,
             }),
             armed: Cell::new(false),
+            armed_while_firing: Cell::new(false),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
         }
@@ -142,6 +154,9 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
             self.mux.enabled.set(enabled + 1);
             self.armed.set(true);
         }
+        if self.mux.firing.get() {
+            self.armed_while_firing.set(true);
+        }
 
         // First alarm, so set it
         if enabled == 0 {
@@ -210,6 +225,9 @@ pub struct MuxAlarm<'a, A: Alarm<'a>> {
     firing: Cell<bool>,
     /// Reference to next alarm
     next_tick_vals: Cell<Option<(A::Ticks, A::Ticks)>>,
+    /// Position in the list at which the next firing pass starts. Rotated on every pass so that
+    /// alarms expiring on the same tick take turns being serviced first.
+    first_to_fire: Cell<usize>,
 }
 
 impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
@@ -220,10 +238,14 @@ impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
             alarm: alarm,
             firing: Cell::new(false),
             next_tick_vals: Cell::new(None),
+            first_to_fire: Cell::new(0),
         }
     }
 
     pub fn set_alarm(&self, reference: A::Ticks, dt: A::Ticks) {
+        let (reference, dt) = clamp_past_deadline(self.alarm.now(), reference, dt, || {
+            core::cmp::max(self.alarm.minimum_dt(), A::Ticks::from(1))
+        });
         self.next_tick_vals.set(Some((reference, dt)));
         self.alarm.set_alarm(reference, dt);
     }
@@ -232,6 +254,72 @@ impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
         self.next_tick_vals.set(None);
         let _ = self.alarm.disarm();
     }
+
+    /// Re-baseline all virtual alarms after the underlying counter stopped
+    /// or was reset, for example across a deep sleep. `stopped_at` is the
+    /// counter value when time stopped; every armed alarm is moved so that
+    /// it keeps the deadline it had relative to `stopped_at`, measured from
+    /// the current `now()`, and the underlying alarm is rescheduled.
+    pub fn reset(&self, stopped_at: A::Ticks) {
+        let shift = self.alarm.now().wrapping_sub(stopped_at);
+        for cur in self.virtual_alarms.iter().filter(|cur| cur.armed.get()) {
+            let dt_ref = cur.dt_reference.get();
+            cur.dt_reference.set(TickDtReference {
+                reference: dt_ref.reference.wrapping_add(shift),
+                ..dt_ref
+            });
+        }
+        self.set_soonest_alarm();
+    }
+
+    /// Find the soonest armed virtual alarm (if any) and set the underlying
+    /// alarm based on it.
+    fn set_soonest_alarm(&self) {
+        let now = self.alarm.now();
+        let next = self
+            .virtual_alarms
+            .iter()
+            .filter(|cur| cur.armed.get())
+            .min_by_key(|cur| {
+                let when = cur.dt_reference.get();
+                // If the alarm has already expired, then it should be
+                // considered as the earliest possible (0 ticks), so it
+                // will trigger as soon as possible. This can happen
+                // if the alarm expired *after* it was examined in the
+                // firing loop.
+                if !now.within_range(when.reference, when.reference_plus_dt()) {
+                    A::Ticks::from(0u32)
+                } else {
+                    when.reference_plus_dt().wrapping_sub(now)
+                }
+            });
+
+        // Set the alarm.
+        if let Some(valrm) = next {
+            let dt_reference = valrm.dt_reference.get();
+            self.set_alarm(dt_reference.reference, dt_reference.dt);
+        } else {
+            self.disarm();
+        }
+    }
+}
+
+/// Moves a deadline `reference + dt` that is at most
+/// `PAST_DEADLINE_GUARD_TICKS` behind `now` to `now + next_dt()`, the next
+/// tick the hardware can fire on.
+fn clamp_past_deadline<T: Ticks>(
+    now: T,
+    reference: T,
+    dt: T,
+    next_dt: impl FnOnce() -> T,
+) -> (T, T) {
+    let deadline = reference.wrapping_add(dt);
+    let behind = now.wrapping_sub(deadline);
+    if !now.within_range(reference, deadline) && behind <= T::from(PAST_DEADLINE_GUARD_TICKS) {
+        (now, next_dt())
+    } else {
+        (reference, dt)
+    }
 }
 
 impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
@@ -241,15 +329,22 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
         // Check whether to fire each alarm. At this level, alarms are one-shot,
         // so a repeating client will set it again in the alarm() callback.
         self.firing.set(true);
+        let count = self.virtual_alarms.iter().count();
+        let start = self.first_to_fire.get() % count.max(1);
+        self.first_to_fire.set(start + 1);
         self.virtual_alarms
             .iter()
+            .skip(start)
+            .chain(self.virtual_alarms.iter().take(start))
             .filter(|cur| {
                 let dt_ref = cur.dt_reference.get();
                 // It is very important to get the current now time as the reference could have been
                 // set from now in the previous for_each iteration. We rely on the reference always
                 // being in the past when compared to now.
                 let now = self.alarm.now();
-                cur.armed.get() && !now.within_range(dt_ref.reference, dt_ref.reference_plus_dt())
+                cur.armed.get()
+                    && !cur.armed_while_firing.get()
+                    && !now.within_range(dt_ref.reference, dt_ref.reference_plus_dt())
             })
             .for_each(|cur| {
                 let dt_ref = cur.dt_reference.get();
@@ -270,35 +365,15 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
                 }
             });
         self.firing.set(false);
+        for cur in self.virtual_alarms.iter() {
+            cur.armed_while_firing.set(false);
+        }
         // Find the soonest alarm client (if any) and set the "next" underlying
         // alarm based on it.  This needs to happen after firing all expired
-        // alarms since those may have reset new alarms.
-        let now = self.alarm.now();
-        let next = self
-            .virtual_alarms
-            .iter()
-            .filter(|cur| cur.armed.get())
-            .min_by_key(|cur| {
-                let when = cur.dt_reference.get();
-                // If the alarm has already expired, then it should be
-                // considered as the earliest possible (0 ticks), so it
-                // will trigger as soon as possible. This can happen
-                // if the alarm expired *after* it was examined in the
-                // above loop.
-                if !now.within_range(when.reference, when.reference_plus_dt()) {
-                    A::Ticks::from(0u32)
-                } else {
-                    when.reference_plus_dt().wrapping_sub(now)
-                }
-            });
-
-        // Set the alarm.
-        if let Some(valrm) = next {
-            let dt_reference = valrm.dt_reference.get();
-            self.set_alarm(dt_reference.reference, dt_reference.dt);
-        } else {
-            self.disarm();
-        }
+        // alarms since those may have reset new alarms. Sample now only after
+        // all callbacks have run, so an alarm re-armed from a callback is
+        // compared against the current time.
+        self.set_soonest_alarm();
     }
 }
 
@@ -536,6 +611,195 @@ mod tests {
         assert!(!still_armed);
     }
 
+    struct ArmOtherClient<'a> {
+        alarm: &'a VirtualMuxAlarm<'a, FakeAlarm<'a>>,
+        fired: Cell<usize>,
+    }
+
+    impl AlarmClient for ArmOtherClient<'_> {
+        fn alarm(&self) {
+            self.fired.set(self.fired.get() + 1);
+            // Arm the other alarm so that it is already expired.
+            let now = self.alarm.now();
+            self.alarm.set_alarm(now.wrapping_sub(5.into()), 1.into());
+        }
+    }
+
+    #[test]
+    fn test_alarm_armed_from_callback_fires_next_tick() {
+        let alarm = FakeAlarm::new();
+        let mux = MuxAlarm::new(&alarm);
+        alarm.set_alarm_client(&mux);
+
+        // 0 is setup last so it is first in the linked list, and 1 is visited after 0 fires.
+        let v_alarms = &[VirtualMuxAlarm::new(&mux), VirtualMuxAlarm::new(&mux)];
+        v_alarms[1].setup();
+        v_alarms[0].setup();
+
+        let arm_v1 = ArmOtherClient {
+            alarm: &v_alarms[1],
+            fired: Cell::new(0),
+        };
+        v_alarms[0].set_alarm_client(&arm_v1);
+        let counter = ClientCounter::new();
+        v_alarms[1].set_alarm_client(&counter);
+
+        v_alarms[0].set_alarm(0.into(), 10.into());
+
+        // The alarm armed from the callback is in the past, but must not fire in the same pass.
+        assert!(alarm.trigger_next_alarm());
+        assert_eq!(arm_v1.fired.get(), 1);
+        assert_eq!(counter.count(), 0);
+
+        // It fires exactly once on the next underlying alarm.
+        assert!(!alarm.trigger_next_alarm());
+        assert_eq!(arm_v1.fired.get(), 1);
+        assert_eq!(counter.count(), 1);
+        assert!(!alarm.trigger_next_alarm());
+        assert_eq!(counter.count(), 1);
+    }
+
+    struct OrderClient<'a> {
+        id: u8,
+        log: &'a Cell<[u8; 9]>,
+        len: &'a Cell<usize>,
+    }
+
+    impl AlarmClient for OrderClient<'_> {
+        fn alarm(&self) {
+            let mut log = self.log.get();
+            log[self.len.get()] = self.id;
+            self.log.set(log);
+            self.len.set(self.len.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_simultaneous_alarms_rotate() {
+        let alarm = FakeAlarm::new();
+        let mux = MuxAlarm::new(&alarm);
+        alarm.set_alarm_client(&mux);
+
+        let log = Cell::new([0; 9]);
+        let len = Cell::new(0);
+        let v_alarms = &[
+            VirtualMuxAlarm::new(&mux),
+            VirtualMuxAlarm::new(&mux),
+            VirtualMuxAlarm::new(&mux),
+        ];
+        let clients = [0, 1, 2].map(|id| OrderClient {
+            id,
+            log: &log,
+            len: &len,
+        });
+        for (v, client) in v_alarms.iter().zip(clients.iter()) {
+            v.setup();
+            v.set_alarm_client(client);
+        }
+
+        for _ in 0..3 {
+            let now = alarm.now();
+            for v in v_alarms {
+                v.set_alarm(now, 10.into());
+            }
+            assert!(!alarm.trigger_next_alarm());
+        }
+
+        // Every round services all three alarms, and each one goes first once.
+        assert_eq!(len.get(), 9);
+        let log = log.get();
+        let mut firsts = [log[0], log[3], log[6]];
+        firsts.sort();
+        assert_eq!(firsts, [0, 1, 2]);
+        for round in log.chunks(3) {
+            let mut round = [round[0], round[1], round[2]];
+            round.sort();
+            assert_eq!(round, [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_reset_keeps_relative_deadlines() {
+        let alarm = FakeAlarm::new();
+        let mux = MuxAlarm::new(&alarm);
+        alarm.set_alarm_client(&mux);
+
+        let first = ClientCounter::new();
+        let second = ClientCounter::new();
+        let v_alarms = &[VirtualMuxAlarm::new(&mux), VirtualMuxAlarm::new(&mux)];
+        for (v, client) in v_alarms.iter().zip([&first, &second]) {
+            v.setup();
+            v.set_alarm_client(client);
+            v.set_alarm(1_000.into(), 100.into());
+        }
+        v_alarms[1].set_alarm(1_000.into(), 200.into());
+
+        // 50 ticks in, the counter stops and comes back from sleep at 0.
+        alarm.now.set(1_050.into());
+        let stopped_at = alarm.now.get();
+        alarm.now.set(0.into());
+        mux.reset(stopped_at);
+
+        // The alarms still have 50 and 150 ticks left, now counted from 0.
+        assert!(alarm.trigger_next_alarm());
+        let now = alarm.now().into_u32();
+        assert!((50..50 + 20).contains(&now), "fired at {}", now);
+        assert_eq!((first.count(), second.count()), (1, 0));
+
+        assert!(!alarm.trigger_next_alarm());
+        let now = alarm.now().into_u32();
+        assert!((150..150 + 20).contains(&now), "fired at {}", now);
+        assert_eq!((first.count(), second.count()), (1, 1));
+    }
+
+    #[test]
+    fn test_just_past_deadline_fires_next_tick() {
+        let alarm = FakeAlarm::new();
+        let client = ClientCounter::new();
+        let mux = MuxAlarm::new(&alarm);
+        alarm.set_alarm_client(&mux);
+
+        let valarm = VirtualMuxAlarm::new(&mux);
+        valarm.setup();
+        valarm.set_alarm_client(&client);
+
+        // The deadline passed a few ticks before the alarm was armed.
+        let now = alarm.now.get();
+        valarm.set_alarm(now.wrapping_sub(50.into()), 40.into());
+
+        // The hardware is programmed for the next tick, not for the deadline,
+        // which it would only reach after the counter wraps.
+        let until_fire = alarm.get_alarm().wrapping_sub(alarm.now.get());
+        assert!(until_fire.into_u32() <= 1, "fires in {:?}", until_fire);
+        assert!(!alarm.trigger_next_alarm());
+        assert_eq!(client.count(), 1);
+    }
+
+    #[test]
+    fn clamp_leaves_other_deadlines() {
+        let next = || Ticks32::from(1);
+        let now = Ticks32::from(1_000);
+        // Deadlines in the future, including a full range one.
+        assert_eq!(
+            clamp_past_deadline(now, 900.into(), 200.into(), next),
+            (900.into(), 200.into())
+        );
+        assert_eq!(
+            clamp_past_deadline(now, 999.into(), u32::MAX.into(), next),
+            (999.into(), u32::MAX.into())
+        );
+        // Just inside and just outside the guard band.
+        let edge = 1_000 - PAST_DEADLINE_GUARD_TICKS;
+        assert_eq!(
+            clamp_past_deadline(now, (edge - 5).into(), 5.into(), next),
+            (now, 1.into())
+        );
+        assert_eq!(
+            clamp_past_deadline(now, (edge - 6).into(), 5.into(), next),
+            ((edge - 6).into(), 5.into())
+        );
+    }
+
     #[test]
     fn test_quick_alarms_not_skipped() {
         let alarm = FakeAlarm::new();
@@ -156,6 +156,10 @@ impl<'a> Rng<'a> for VirtualRngMasterDevice<'a> {
         // Set client for rng to be current virtualizer
         self.mux.rng.set_client(self.mux);
     }
+
+    fn flush(&self) {
+        self.mux.rng.flush();
+    }
 }
 
 impl<'a> Client for VirtualRngMasterDevice<'a> {
@@ -49,14 +49,23 @@ impl<'a, Spi: hil::spi::SpiMaster> MuxSpiMaster<'a, Spi> {
 
     fn do_next_op(&self) {
         if self.inflight.is_none() {
-            let mnode = self
-                .devices
-                .iter()
-                .find(|node| node.operation.get() != Op::Idle);
+            // A device holding its chip select keeps the bus to itself.
+            let holder = self.devices.iter().find(|node| node.hold_cs.get());
+            let mnode = match holder {
+                Some(node) if node.operation.get() == Op::Idle => None,
+                Some(node) => Some(node),
+                None => self
+                    .devices
+                    .iter()
+                    .find(|node| node.operation.get() != Op::Idle),
+            };
             mnode.map(|node| {
                 let configuration = node.configuration.get();
                 let cs = configuration.chip_select;
                 let _ = self.spi.specify_chip_select(cs);
+                if node.hold_cs.get() {
+                    self.spi.hold_low();
+                }
 
                 let op = node.operation.get();
                 // Need to set idle here in case callback changes state
@@ -126,6 +135,16 @@ impl<'a, Spi: hil::spi::SpiMaster> MuxSpiMaster<'a, Spi> {
     fn do_next_op_async(&self) {
         self.deferred_call.set();
     }
+
+    // Stops holding `cs` low. If a transfer is in flight it belongs to the
+    // holding device, so the chip select is already the right one.
+    fn release_low(&self, cs: Spi::ChipSelect) {
+        if self.inflight.is_none() {
+            let _ = self.spi.specify_chip_select(cs);
+        }
+        self.spi.release_low();
+        self.do_next_op_async();
+    }
 }
 
 impl<'a, Spi: hil::spi::SpiMaster> DeferredCallClient for MuxSpiMaster<'a, Spi> {
@@ -170,6 +189,7 @@ pub struct VirtualSpiMasterDevice<'a, Spi: hil::spi::SpiMaster> {
     txbuffer: TakeCell<'static, [u8]>,
     rxbuffer: TakeCell<'static, [u8]>,
     operation: Cell<Op>,
+    hold_cs: Cell<bool>,
     next: ListLink<'a, VirtualSpiMasterDevice<'a, Spi>>,
     client: OptionalCell<&'a dyn hil::spi::SpiMasterClient>,
 }
@@ -190,6 +210,7 @@ impl<'a, Spi: hil::spi::SpiMaster> VirtualSpiMasterDevice<'a, Spi> {
             txbuffer: TakeCell::empty(),
             rxbuffer: TakeCell::empty(),
             operation: Cell::new(Op::Idle),
+            hold_cs: Cell::new(false),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
         }
@@ -307,6 +328,20 @@ impl<'a, Spi: hil::spi::SpiMaster> hil::spi::SpiMasterDevice for VirtualSpiMaste
     fn get_rate(&self) -> u32 {
         self.configuration.get().rate
     }
+
+    fn max_transfer_len(&self) -> Option<usize> {
+        self.mux.spi.max_transfer_len()
+    }
+
+    fn hold_low(&self) {
+        self.hold_cs.set(true);
+    }
+
+    fn release_low(&self) {
+        if self.hold_cs.replace(false) {
+            self.mux.release_low(self.configuration.get().chip_select);
+        }
+    }
 }
 
 pub struct SpiSlaveDevice<'a, Spi: hil::spi::SpiSlave> {
@@ -341,6 +376,12 @@ impl<Spi: hil::spi::SpiSlave> hil::spi::SpiSlaveClient for SpiSlaveDevice<'_, Sp
             client.chip_selected();
         });
     }
+
+    fn chip_deselected(&self) {
+        self.client.map(move |client| {
+            client.chip_deselected();
+        });
+    }
 }
 
 impl<'a, Spi: hil::spi::SpiSlave> hil::spi::SpiSlaveDevice for SpiSlaveDevice<'a, Spi> {
@@ -357,6 +398,10 @@ impl<'a, Spi: hil::spi::SpiSlave> hil::spi::SpiSlaveDevice for SpiSlaveDevice<'a
         self.spi.set_phase(cpal)
     }
 
+    fn set_write_byte(&self, write_byte: u8) {
+        self.spi.set_write_byte(write_byte)
+    }
+
     fn read_write_bytes(
         &self,
         write_buffer: Option<&'static mut [u8]>,
@@ -389,3 +434,256 @@ impl<'a, Spi: hil::spi::SpiSlave> hil::spi::SpiSlaveDevice for SpiSlaveDevice<'a
         self.spi.get_phase()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::spi::{ClockPhase, ClockPolarity, SpiMaster, SpiMasterDevice};
+
+    struct FakeSpi {
+        selected: Cell<usize>,
+        cs_low: Cell<bool>,
+        hold: Cell<bool>,
+        transfers: Cell<usize>,
+        txbuffer: TakeCell<'static, [u8]>,
+    }
+
+    impl FakeSpi {
+        fn new() -> Self {
+            Self {
+                selected: Cell::new(0),
+                cs_low: Cell::new(false),
+                hold: Cell::new(false),
+                transfers: Cell::new(0),
+                txbuffer: TakeCell::empty(),
+            }
+        }
+
+        /// Finishes the transfer in flight and returns its write buffer.
+        fn complete(&self) -> &'static mut [u8] {
+            if !self.hold.get() {
+                self.cs_low.set(false);
+            }
+            self.txbuffer.take().unwrap()
+        }
+    }
+
+    impl SpiMaster for FakeSpi {
+        type ChipSelect = usize;
+
+        fn init(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn set_client(&self, _client: &'static dyn SpiMasterClient) {}
+
+        fn is_busy(&self) -> bool {
+            self.txbuffer.is_some()
+        }
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: &'static mut [u8],
+            _read_buffer: Option<&'static mut [u8]>,
+            _len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8], Option<&'static mut [u8]>)> {
+            self.cs_low.set(true);
+            self.transfers.set(self.transfers.get() + 1);
+            self.txbuffer.replace(write_buffer);
+            Ok(())
+        }
+
+        fn write_byte(&self, _val: u8) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn read_byte(&self) -> Result<u8, ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn read_write_byte(&self, _val: u8) -> Result<u8, ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn specify_chip_select(&self, cs: usize) -> Result<(), ErrorCode> {
+            self.selected.set(cs);
+            Ok(())
+        }
+
+        fn set_rate(&self, rate: u32) -> Result<u32, ErrorCode> {
+            Ok(rate)
+        }
+
+        fn get_rate(&self) -> u32 {
+            0
+        }
+
+        fn set_polarity(&self, _polarity: ClockPolarity) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+
+        fn set_phase(&self, _phase: ClockPhase) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+
+        fn hold_low(&self) {
+            self.hold.set(true);
+        }
+
+        fn release_low(&self) {
+            self.hold.set(false);
+            if !self.is_busy() {
+                self.cs_low.set(false);
+            }
+        }
+    }
+
+    #[test]
+    fn held_chip_select_spans_transfers() {
+        let spi = FakeSpi::new();
+        let mux = MuxSpiMaster::new(&spi);
+        let held = VirtualSpiMasterDevice::new(&mux, 1);
+        held.setup();
+        let other = VirtualSpiMasterDevice::new(&mux, 2);
+        other.setup();
+
+        held.hold_low();
+        assert!(held.read_write_bytes(&mut [], None, 0).is_ok());
+        assert_eq!(spi.selected.get(), 1);
+        assert!(spi.cs_low.get());
+
+        // Another device queueing up must not get the bus while CS is held.
+        assert!(other.read_write_bytes(&mut [], None, 0).is_ok());
+        mux.read_write_done(spi.complete(), None, 0, Ok(()));
+        assert!(spi.cs_low.get());
+        assert_eq!(spi.transfers.get(), 1);
+
+        assert!(held.read_write_bytes(&mut [], None, 0).is_ok());
+        assert_eq!(spi.selected.get(), 1);
+        assert_eq!(spi.transfers.get(), 2);
+        mux.read_write_done(spi.complete(), None, 0, Ok(()));
+        assert!(spi.cs_low.get());
+
+        held.release_low();
+        assert_eq!(spi.selected.get(), 1);
+        assert!(!spi.cs_low.get());
+
+        // Once released, the waiting device is served.
+        mux.do_next_op();
+        assert_eq!(spi.selected.get(), 2);
+        assert_eq!(spi.transfers.get(), 3);
+        mux.read_write_done(spi.complete(), None, 0, Ok(()));
+        assert!(!spi.cs_low.get());
+    }
+
+    struct FakeSpiSlave;
+
+    impl hil::spi::SpiSlave for FakeSpiSlave {
+        fn init(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn has_client(&self) -> bool {
+            false
+        }
+
+        fn set_client(&self, _client: Option<&'static dyn hil::spi::SpiSlaveClient>) {}
+
+        fn set_write_byte(&self, _write_byte: u8) {}
+
+        fn read_write_bytes(
+            &self,
+            write_buffer: Option<&'static mut [u8]>,
+            read_buffer: Option<&'static mut [u8]>,
+            _len: usize,
+        ) -> Result<
+            (),
+            (
+                ErrorCode,
+                Option<&'static mut [u8]>,
+                Option<&'static mut [u8]>,
+            ),
+        > {
+            Err((ErrorCode::NOSUPPORT, write_buffer, read_buffer))
+        }
+
+        fn set_polarity(&self, _polarity: ClockPolarity) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+
+        fn set_phase(&self, _phase: ClockPhase) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+    }
+
+    /// Records chip select edges, `true` for selected.
+    struct EdgeLog {
+        edges: Cell<[Option<bool>; 4]>,
+        len: Cell<usize>,
+    }
+
+    impl EdgeLog {
+        fn push(&self, selected: bool) {
+            let mut edges = self.edges.get();
+            edges[self.len.get()] = Some(selected);
+            self.edges.set(edges);
+            self.len.set(self.len.get() + 1);
+        }
+    }
+
+    impl hil::spi::SpiSlaveClient for EdgeLog {
+        fn chip_selected(&self) {
+            self.push(true);
+        }
+
+        fn chip_deselected(&self) {
+            self.push(false);
+        }
+
+        fn read_write_done(
+            &self,
+            _write_buffer: Option<&'static mut [u8]>,
+            _read_buffer: Option<&'static mut [u8]>,
+            _len: usize,
+            _status: Result<(), ErrorCode>,
+        ) {
+        }
+    }
+
+    #[test]
+    fn chip_select_edges_reach_client_in_order() {
+        let log = EdgeLog {
+            edges: Cell::new([None; 4]),
+            len: Cell::new(0),
+        };
+        let spi = FakeSpiSlave;
+        let device = SpiSlaveDevice::new(&spi);
+        device.client.set(&log);
+
+        // The master frames two transactions.
+        for _ in 0..2 {
+            hil::spi::SpiSlaveClient::chip_selected(&device);
+            hil::spi::SpiSlaveClient::chip_deselected(&device);
+        }
+        assert_eq!(
+            log.edges.get(),
+            [Some(true), Some(false), Some(true), Some(false)]
+        );
+    }
+}
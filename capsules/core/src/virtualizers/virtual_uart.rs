@@ -4,8 +4,10 @@
 //! most useful for `printf()` like applications where multiple things want to
 //! write to the same UART channel.
 //!
-//! Clients can choose if they want to receive. Incoming messages will be sent
-//! to all clients that have enabled receiving.
+//! Clients can choose if they want to receive. By default incoming messages
+//! will be sent to all clients that have enabled receiving and have a receive
+//! pending. With `ReceiveMode::Exclusive`, each byte instead goes only to the
+//! client that has been waiting the longest.
 //!
 //! `MuxUart` provides shared access to a single UART bus for multiple users.
 //! `UartDevice` provides access for a single client.
@@ -52,6 +54,16 @@ use kernel::ErrorCode;
 
 pub const RX_BUF_LEN: usize = 64;
 
+/// How `MuxUart` hands received bytes to the devices with a receive pending.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ReceiveMode {
+    /// Every receiving device gets a copy of each byte.
+    Broadcast,
+    /// Each byte goes only to the device that started its receive first; the
+    /// others keep waiting until it is done.
+    Exclusive,
+}
+
 pub struct MuxUart<'a> {
     uart: &'a dyn uart::Uart<'a>,
     speed: u32,
@@ -59,6 +71,11 @@ pub struct MuxUart<'a> {
     inflight: OptionalCell<&'a UartDevice<'a>>,
     buffer: TakeCell<'static, [u8]>,
     completing_read: Cell<bool>,
+    receive_mode: Cell<ReceiveMode>,
+    // Incremented for each receive a device starts, to order the receives
+    rx_ticket: Cell<usize>,
+    // Set once the UART has refused a break as unsupported
+    breaks_unsupported: Cell<bool>,
     deferred_call: DeferredCall,
 }
 
@@ -75,6 +92,13 @@ impl<'a> uart::TransmitClient for MuxUart<'a> {
         });
         self.do_next_op();
     }
+
+    fn transmitted_break(&self, rcode: Result<(), ErrorCode>) {
+        self.inflight.take().map(|device| {
+            device.transmitted_break(rcode);
+        });
+        self.do_next_op();
+    }
 }
 
 impl<'a> uart::ReceiveClient for MuxUart<'a> {
@@ -92,6 +116,12 @@ impl<'a> uart::ReceiveClient for MuxUart<'a> {
         let mut next_read_len = buffer.len();
         let mut read_pending = false;
 
+        // In exclusive mode only the longest waiting device gets the data.
+        let target = match self.receive_mode.get() {
+            ReceiveMode::Broadcast => None,
+            ReceiveMode::Exclusive => Some(self.exclusive_receiver()),
+        };
+
         // Set a flag that we are in this callback handler. This allows us to
         // note that we can wait until all callbacks are finished before
         // starting a new UART receive.
@@ -103,7 +133,10 @@ impl<'a> uart::ReceiveClient for MuxUart<'a> {
         // Multiple client reads of different sizes can be pending. This code
         // copies the underlying UART read into each of the client buffers.
         self.devices.iter().for_each(|device| {
-            if device.receiver {
+            let gets_data = target.map_or(true, |target| {
+                target.map_or(false, |target| core::ptr::eq(target, device))
+            });
+            if device.receiver && gets_data {
                 device.rx_buffer.take().map(|rxbuf| {
                     let state = device.state.get();
                     // Copy the read into the buffer starting at rx_position
@@ -194,10 +227,30 @@ impl<'a> MuxUart<'a> {
             inflight: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
             completing_read: Cell::new(false),
+            receive_mode: Cell::new(ReceiveMode::Broadcast),
+            rx_ticket: Cell::new(0),
+            breaks_unsupported: Cell::new(false),
             deferred_call: DeferredCall::new(),
         }
     }
 
+    /// Choose how received bytes are shared between devices. The default is
+    /// `ReceiveMode::Broadcast`.
+    pub fn set_receive_mode(&self, mode: ReceiveMode) {
+        self.receive_mode.set(mode);
+    }
+
+    /// The receiving device that started its receive first.
+    fn exclusive_receiver(&self) -> Option<&'a UartDevice<'a>> {
+        let now = self.rx_ticket.get();
+        self.devices
+            .iter()
+            .filter(|device| {
+                device.receiver && device.state.get() == UartDeviceReceiveState::Receiving
+            })
+            .max_by_key(|device| now.wrapping_sub(device.rx_ticket.get()))
+    }
+
     pub fn initialize(&self) {
         let _ = self.uart.configure(uart::Parameters {
             baud_rate: self.speed,
@@ -212,35 +265,64 @@ impl<'a> MuxUart<'a> {
         if self.inflight.is_none() {
             let mnode = self.devices.iter().find(|node| node.operation.is_some());
             mnode.map(|node| {
-                node.tx_buffer.take().map(|buf| {
-                    node.operation.map(move |op| match op {
-                        Operation::Transmit { len } => {
-                            let _ = self.uart.transmit_buffer(buf, *len).map_err(
-                                move |(ecode, buf)| {
-                                    node.tx_client.map(move |client| {
-                                        node.transmitting.set(false);
-                                        client.transmitted_buffer(buf, 0, Err(ecode));
-                                    });
-                                },
-                            );
-                        }
-                        Operation::TransmitWord { word } => {
-                            let rcode = self.uart.transmit_word(*word);
-                            if rcode != Ok(()) {
-                                node.tx_client.map(|client| {
+                let started = node.operation.take().map_or(false, |op| match op {
+                    Operation::Transmit { len } => node.tx_buffer.take().map_or(false, |buf| {
+                        match self.uart.transmit_buffer(buf, len) {
+                            Ok(()) => true,
+                            Err((ecode, buf)) => {
+                                node.tx_client.map(move |client| {
                                     node.transmitting.set(false);
-                                    client.transmitted_word(rcode);
+                                    client.transmitted_buffer(buf, 0, Err(ecode));
                                 });
+                                false
                             }
                         }
-                    });
+                    }),
+                    Operation::TransmitWord { word } => {
+                        let rcode = self.uart.transmit_word(word);
+                        if rcode != Ok(()) {
+                            node.tx_client.map(|client| {
+                                node.transmitting.set(false);
+                                client.transmitted_word(rcode);
+                            });
+                        }
+                        rcode == Ok(())
+                    }
+                    Operation::Break { duration_us } => {
+                        let rcode = self.start_break(duration_us);
+                        if rcode != Ok(()) {
+                            node.tx_client.map(|client| {
+                                node.transmitting.set(false);
+                                client.transmitted_break(rcode);
+                            });
+                        }
+                        rcode == Ok(())
+                    }
                 });
-                node.operation.clear();
-                self.inflight.set(node);
+                if started {
+                    self.inflight.set(node);
+                } else {
+                    // Nothing is in flight, so give the next device a turn.
+                    self.do_next_op_async();
+                }
             });
         }
     }
 
+    /// Whether nothing is using the UART's transmit side or waiting to.
+    fn transmit_idle(&self) -> bool {
+        self.inflight.is_none() && self.devices.iter().all(|node| node.operation.is_none())
+    }
+
+    /// Ask the UART to send a break, remembering if it cannot.
+    fn start_break(&self, duration_us: u32) -> Result<(), ErrorCode> {
+        let rcode = self.uart.transmit_break(duration_us);
+        if rcode == Err(ErrorCode::NOSUPPORT) {
+            self.breaks_unsupported.set(true);
+        }
+        rcode
+    }
+
     /// Starts a new UART reception, return value denotes whether starting
     /// the reception will issue a callback before the new read. A callback
     /// needs to be issued before the new read if a read was ongoing; the
@@ -304,6 +386,7 @@ impl DeferredCallClient for MuxUart<'_> {
 enum Operation {
     Transmit { len: usize },
     TransmitWord { word: u32 },
+    Break { duration_us: u32 },
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -322,6 +405,8 @@ pub struct UartDevice<'a> {
     rx_buffer: TakeCell<'static, [u8]>,
     rx_position: Cell<usize>,
     rx_len: Cell<usize>,
+    // The mux's `rx_ticket` when the current receive started
+    rx_ticket: Cell<usize>,
     operation: OptionalCell<Operation>,
     next: ListLink<'a, UartDevice<'a>>,
     rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
@@ -339,6 +424,7 @@ impl<'a> UartDevice<'a> {
             rx_buffer: TakeCell::empty(),
             rx_position: Cell::new(0),
             rx_len: Cell::new(0),
+            rx_ticket: Cell::new(0),
             operation: OptionalCell::empty(),
             next: ListLink::empty(),
             rx_client: OptionalCell::empty(),
@@ -371,6 +457,13 @@ impl<'a> uart::TransmitClient for UartDevice<'a> {
             client.transmitted_word(rcode);
         });
     }
+
+    fn transmitted_break(&self, rcode: Result<(), ErrorCode>) {
+        self.tx_client.map(move |client| {
+            self.transmitting.set(false);
+            client.transmitted_break(rcode);
+        });
+    }
 }
 impl<'a> uart::ReceiveClient for UartDevice<'a> {
     fn received_buffer(
@@ -429,6 +522,34 @@ impl<'a> uart::Transmit<'a> for UartDevice<'a> {
             Ok(())
         }
     }
+
+    /// Send a break, straight away if the UART is free and otherwise once it
+    /// is. A UART that cannot send breaks makes this return `NOSUPPORT`,
+    /// unless the break had to wait and the UART has not refused one before:
+    /// then `NOSUPPORT` is reported in `transmitted_break`.
+    fn transmit_break(&self, duration_us: u32) -> Result<(), ErrorCode> {
+        if duration_us == 0 {
+            Err(ErrorCode::INVAL)
+        } else if self.transmitting.get() {
+            Err(ErrorCode::BUSY)
+        } else if self.mux.breaks_unsupported.get() {
+            Err(ErrorCode::NOSUPPORT)
+        } else if self.mux.transmit_idle() {
+            self.mux.start_break(duration_us)?;
+            self.transmitting.set(true);
+            self.mux
+                .devices
+                .iter()
+                .find(|node| core::ptr::eq(*node, self))
+                .map(|node| self.mux.inflight.set(node));
+            Ok(())
+        } else {
+            self.transmitting.set(true);
+            self.operation.set(Operation::Break { duration_us });
+            self.mux.do_next_op_async();
+            Ok(())
+        }
+    }
 }
 
 impl<'a> uart::Receive<'a> for UartDevice<'a> {
@@ -450,6 +571,9 @@ impl<'a> uart::Receive<'a> for UartDevice<'a> {
             self.rx_buffer.replace(rx_buffer);
             self.rx_len.set(rx_len);
             self.rx_position.set(0);
+            let ticket = self.mux.rx_ticket.get().wrapping_add(1);
+            self.mux.rx_ticket.set(ticket);
+            self.rx_ticket.set(ticket);
             self.state.set(UartDeviceReceiveState::Idle);
             self.mux.start_receive(rx_len);
             self.state.set(UartDeviceReceiveState::Receiving);
@@ -469,3 +593,322 @@ impl<'a> uart::Receive<'a> for UartDevice<'a> {
         Err(ErrorCode::FAIL)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_testing::leak_buffer;
+    use kernel::hil::uart::{Configure, Receive, ReceiveClient, Transmit, TransmitClient};
+
+    /// UART that records the breaks and transmissions it is asked to send,
+    /// and holds on to the receive buffer until the test delivers bytes.
+    struct MockUart {
+        supports_break: bool,
+        break_us: Cell<Option<u32>>,
+        // Buffer being transmitted, and the lengths of the transmissions
+        tx_buffer: TakeCell<'static, [u8]>,
+        tx_lens: Cell<[usize; 2]>,
+        tx_count: Cell<usize>,
+        overlapped: Cell<bool>,
+        rx_buffer: TakeCell<'static, [u8]>,
+        rx_len: Cell<usize>,
+    }
+
+    impl MockUart {
+        fn new(supports_break: bool) -> MockUart {
+            MockUart {
+                supports_break,
+                break_us: Cell::new(None),
+                tx_buffer: TakeCell::empty(),
+                tx_lens: Cell::new([0; 2]),
+                tx_count: Cell::new(0),
+                overlapped: Cell::new(false),
+                rx_buffer: TakeCell::empty(),
+                rx_len: Cell::new(0),
+            }
+        }
+
+        /// Complete the receive in flight with `bytes`.
+        fn receive(&self, client: &dyn ReceiveClient, bytes: &[u8]) {
+            let buffer = self.rx_buffer.take().expect("no receive in flight");
+            assert_eq!(bytes.len(), self.rx_len.get());
+            buffer[..bytes.len()].copy_from_slice(bytes);
+            client.received_buffer(buffer, bytes.len(), Ok(()), uart::Error::None);
+        }
+    }
+
+    impl Configure for MockUart {
+        fn configure(&self, _params: uart::Parameters) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    impl<'a> Transmit<'a> for MockUart {
+        fn set_transmit_client(&self, _client: &'a dyn TransmitClient) {}
+
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            if self.tx_buffer.is_some() {
+                self.overlapped.set(true);
+                return Err((ErrorCode::BUSY, tx_buffer));
+            }
+            let count = self.tx_count.get();
+            let mut lens = self.tx_lens.get();
+            lens[count] = tx_len;
+            self.tx_lens.set(lens);
+            self.tx_count.set(count + 1);
+            self.tx_buffer.replace(tx_buffer);
+            Ok(())
+        }
+
+        fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+
+        fn transmit_abort(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn transmit_break(&self, duration_us: u32) -> Result<(), ErrorCode> {
+            if !self.supports_break {
+                return Err(ErrorCode::NOSUPPORT);
+            }
+            self.break_us.set(Some(duration_us));
+            Ok(())
+        }
+    }
+
+    impl<'a> Receive<'a> for MockUart {
+        fn set_receive_client(&self, _client: &'a dyn ReceiveClient) {}
+
+        fn receive_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.rx_buffer.replace(rx_buffer);
+            self.rx_len.set(rx_len);
+            Ok(())
+        }
+
+        fn receive_word(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+
+        fn receive_abort(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct BreakLog(Cell<Option<Result<(), ErrorCode>>>);
+
+    impl TransmitClient for BreakLog {
+        fn transmitted_buffer(
+            &self,
+            _tx_buffer: &'static mut [u8],
+            _tx_len: usize,
+            _rval: Result<(), ErrorCode>,
+        ) {
+        }
+
+        fn transmitted_break(&self, rval: Result<(), ErrorCode>) {
+            self.0.set(Some(rval));
+        }
+    }
+
+    #[test]
+    fn break_reaches_uart() {
+        let hw = MockUart::new(true);
+        let log = BreakLog::default();
+        let mux = MuxUart::new(&hw, &mut [], 115200);
+        let device = UartDevice::new(&mux, false);
+        device.setup();
+        device.set_transmit_client(&log);
+
+        assert_eq!(device.transmit_break(0), Err(ErrorCode::INVAL));
+        // The UART is free, so the break starts straight away.
+        assert_eq!(device.transmit_break(750), Ok(()));
+        assert_eq!(hw.break_us.get(), Some(750));
+        // A second break waits for the first to complete.
+        assert_eq!(device.transmit_break(750), Err(ErrorCode::BUSY));
+        assert_eq!(log.0.get(), None);
+
+        // The hardware releases the line.
+        mux.transmitted_break(Ok(()));
+        assert_eq!(log.0.get(), Some(Ok(())));
+        assert!(mux.inflight.is_none());
+        assert_eq!(device.transmit_break(100), Ok(()));
+    }
+
+    #[test]
+    fn unsupported_break_is_reported() {
+        let hw = MockUart::new(false);
+        let log = BreakLog::default();
+        let mux = MuxUart::new(&hw, &mut [], 115200);
+        let device = UartDevice::new(&mux, false);
+        device.setup();
+        device.set_transmit_client(&log);
+
+        assert_eq!(device.transmit_break(750), Err(ErrorCode::NOSUPPORT));
+        assert_eq!(log.0.get(), None);
+        // The mux is not left waiting for a break that never started.
+        assert!(mux.inflight.is_none());
+    }
+
+    #[test]
+    fn unsupported_break_behind_a_transmit_is_reported() {
+        let hw = MockUart::new(false);
+        let (log, writer_log) = (BreakLog::default(), TxLog::default());
+        let mux = MuxUart::new(&hw, &mut [], 115200);
+        let writer = UartDevice::new(&mux, false);
+        let device = UartDevice::new(&mux, false);
+        writer.setup();
+        device.setup();
+        writer.set_transmit_client(&writer_log);
+        device.set_transmit_client(&log);
+
+        // The break has to wait, so the UART is only asked once the write is
+        // done.
+        assert!(writer.transmit_buffer(&mut [], 3).is_ok());
+        mux.handle_deferred_call();
+        assert_eq!(device.transmit_break(750), Ok(()));
+        mux.transmitted_buffer(hw.tx_buffer.take().unwrap(), 3, Ok(()));
+        assert_eq!(log.0.get(), Some(Err(ErrorCode::NOSUPPORT)));
+        assert!(mux.inflight.is_none());
+
+        // From then on the mux knows, even while the UART is busy.
+        log.0.set(None);
+        assert!(writer.transmit_buffer(&mut [], 3).is_ok());
+        mux.handle_deferred_call();
+        assert_eq!(device.transmit_break(750), Err(ErrorCode::NOSUPPORT));
+        assert_eq!(log.0.get(), None);
+    }
+
+    #[derive(Default)]
+    struct TxLog(Cell<Option<(usize, Result<(), ErrorCode>)>>);
+
+    impl TransmitClient for TxLog {
+        fn transmitted_buffer(
+            &self,
+            _tx_buffer: &'static mut [u8],
+            tx_len: usize,
+            rval: Result<(), ErrorCode>,
+        ) {
+            self.0.set(Some((tx_len, rval)));
+        }
+    }
+
+    #[test]
+    fn concurrent_transmits_are_serialized() {
+        let hw = MockUart::new(false);
+        let mux = MuxUart::new(&hw, &mut [], 115200);
+        let first = UartDevice::new(&mux, false);
+        let second = UartDevice::new(&mux, false);
+        first.setup();
+        second.setup();
+        let (first_log, second_log) = (TxLog::default(), TxLog::default());
+        first.set_transmit_client(&first_log);
+        second.set_transmit_client(&second_log);
+
+        // Both devices transmit before either has started.
+        assert!(first.transmit_buffer(&mut [], 3).is_ok());
+        assert!(second.transmit_buffer(&mut [], 5).is_ok());
+        mux.handle_deferred_call();
+        mux.handle_deferred_call();
+        assert_eq!(hw.tx_count.get(), 1);
+
+        // Each buffer is handed to the UART whole, and only once the one
+        // before it has been sent.
+        let done = hw.tx_buffer.take().unwrap();
+        let len = hw.tx_lens.get()[0];
+        mux.transmitted_buffer(done, len, Ok(()));
+        assert_eq!(hw.tx_count.get(), 2);
+        let done = hw.tx_buffer.take().unwrap();
+        mux.transmitted_buffer(done, hw.tx_lens.get()[1], Ok(()));
+
+        let mut lens = hw.tx_lens.get();
+        lens.sort_unstable();
+        assert_eq!(lens, [3, 5]);
+        assert!(!hw.overlapped.get());
+        assert_eq!(first_log.0.get(), Some((3, Ok(()))));
+        assert_eq!(second_log.0.get(), Some((5, Ok(()))));
+        assert!(mux.inflight.is_none());
+    }
+
+    #[test]
+    fn exclusive_receive_goes_to_the_oldest_reader() {
+        let hw = MockUart::new(false);
+        let mux = MuxUart::new(&hw, &mut [], 115200);
+        let first = UartDevice::new(&mux, true);
+        let second = UartDevice::new(&mux, true);
+        let transmit_only = UartDevice::new(&mux, false);
+        first.setup();
+        second.setup();
+        transmit_only.setup();
+
+        assert!(first.receive_buffer(&mut [], 0).is_ok());
+        assert!(second.receive_buffer(&mut [], 0).is_ok());
+        mux.set_receive_mode(ReceiveMode::Exclusive);
+        assert!(mux
+            .exclusive_receiver()
+            .map_or(false, |device| core::ptr::eq(device, &first)));
+
+        // Once the first reader is done, the bytes go to the second.
+        first.state.set(UartDeviceReceiveState::Idle);
+        assert!(mux
+            .exclusive_receiver()
+            .map_or(false, |device| core::ptr::eq(device, &second)));
+        second.state.set(UartDeviceReceiveState::Idle);
+        assert!(mux.exclusive_receiver().is_none());
+    }
+
+    /// Records the bytes of the last receive completed for it.
+    #[derive(Default)]
+    struct RxLog(Cell<Option<[u8; 2]>>);
+
+    impl ReceiveClient for RxLog {
+        fn received_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            rx_len: usize,
+            _rval: Result<(), ErrorCode>,
+            _error: uart::Error,
+        ) {
+            let mut bytes = [0; 2];
+            bytes[..rx_len].copy_from_slice(&rx_buffer[..rx_len]);
+            self.0.set(Some(bytes));
+        }
+    }
+
+    #[test]
+    fn exclusive_receive_delivers_each_byte_once() {
+        let hw = MockUart::new(false);
+        let (first_log, second_log) = (RxLog::default(), RxLog::default());
+        let mux = MuxUart::new(&hw, leak_buffer(8), 115200);
+        mux.set_receive_mode(ReceiveMode::Exclusive);
+        let first = UartDevice::new(&mux, true);
+        let second = UartDevice::new(&mux, true);
+        first.setup();
+        second.setup();
+        first.set_receive_client(&first_log);
+        second.set_receive_client(&second_log);
+
+        assert!(first.receive_buffer(leak_buffer(2), 2).is_ok());
+        assert!(second.receive_buffer(leak_buffer(2), 2).is_ok());
+
+        // Only the device that has waited longest gets the bytes.
+        hw.receive(&mux, b"ab");
+        assert_eq!(first_log.0.get(), Some(*b"ab"));
+        assert_eq!(second_log.0.get(), None);
+
+        // The other device is still waiting, and gets the next read.
+        hw.receive(&mux, b"cd");
+        assert_eq!(first_log.0.get(), Some(*b"ab"));
+        assert_eq!(second_log.0.get(), Some(*b"cd"));
+        assert!(hw.rx_buffer.is_none());
+    }
+}
@@ -1,6 +1,9 @@
 #![forbid(unsafe_code)]
 #![no_std]
 
+#[macro_use]
+extern crate capsules_core;
+
 pub mod test;
 
 #[macro_use]
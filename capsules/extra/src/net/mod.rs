@@ -1,14 +1,13 @@
 //! Modules for IPv6 over 6LoWPAN stack
 
 pub mod frag_utils;
-pub mod sixlowpan;
-pub mod util;
-#[macro_use]
-pub mod stream;
 pub mod icmpv6;
 pub mod ieee802154;
 pub mod ipv6;
 pub mod network_capabilities;
+pub mod sixlowpan;
+pub mod stream;
 pub mod tcp;
 pub mod thread;
 pub mod udp;
+pub mod util;
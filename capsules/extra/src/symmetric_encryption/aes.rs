@@ -352,7 +352,12 @@ impl<'a, A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'sta
 impl<'a, A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB + AES128CCM<'static>>
     Client<'static> for AesDriver<'static, A>
 {
-    fn crypt_done(&'a self, source: Option<&'static mut [u8]>, destination: &'static mut [u8]) {
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        destination: &'static mut [u8],
+        _length: usize,
+    ) {
         if let Some(source_buf) = source {
             self.source_buffer.replace(source_buf);
         }
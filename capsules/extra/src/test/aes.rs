@@ -237,7 +237,17 @@ impl<'a, A: AES128<'a> + AES128Ctr> TestAes128Ctr<'a, A> {
 }
 
 impl<'a, A: AES128<'a> + AES128Ctr> hil::symmetric_encryption::Client<'a> for TestAes128Ctr<'a, A> {
-    fn crypt_done(&'a self, source: Option<&'static mut [u8]>, dest: &'static mut [u8]) {
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        length: usize,
+    ) {
+        assert_eq!(
+            length, DATA_LEN,
+            "aes_test: crypt_done reported wrong length"
+        );
+
         if self.use_source.get() {
             // Take back the source buffer
             self.source.put(source);
@@ -390,7 +400,17 @@ impl<'a, A: AES128<'a> + AES128CBC> TestAes128Cbc<'a, A> {
 }
 
 impl<'a, A: AES128<'a> + AES128CBC> hil::symmetric_encryption::Client<'a> for TestAes128Cbc<'a, A> {
-    fn crypt_done(&'a self, source: Option<&'static mut [u8]>, dest: &'static mut [u8]) {
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        length: usize,
+    ) {
+        assert_eq!(
+            length, DATA_LEN,
+            "aes_test: crypt_done reported wrong length"
+        );
+
         if self.use_source.get() {
             // Take back the source buffer
             self.source.put(source);
@@ -445,7 +465,17 @@ impl<'a, A: AES128<'a> + AES128CBC> hil::symmetric_encryption::Client<'a> for Te
 }
 
 impl<'a, A: AES128<'a> + AES128ECB> hil::symmetric_encryption::Client<'a> for TestAes128Ecb<'a, A> {
-    fn crypt_done(&'a self, source: Option<&'static mut [u8]>, dest: &'static mut [u8]) {
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        length: usize,
+    ) {
+        assert_eq!(
+            length, DATA_LEN,
+            "aes_test: crypt_done reported wrong length"
+        );
+
         if self.use_source.get() {
             // Take back the source buffer
             self.source.put(source);
@@ -0,0 +1,1147 @@
+//! Host test harness for capsules.
+//!
+//! Capsules keep per-process state in grants and talk to processes through
+//! allow buffers and upcalls, none of which exist without a kernel and a
+//! process. This crate provides both for unit tests running on the host: a
+//! `TestKernel` holding a fixed set of `TestProcess`es. A test process issues
+//! system calls through the kernel's own system call handling, so subscribe
+//! and allow go through the real grant code, and it records the upcalls
+//! capsules schedule for it instead of running them.
+//!
+//! ```rust,ignore
+//! let kernel = TestKernel::new(&["app"]);
+//! let driver = Driver::new(kernel.create_grant(DRIVER_NUM));
+//! let app = kernel.process(0);
+//! app.subscribe(&driver, DRIVER_NUM, 0);
+//! assert!(matches!(app.command(&driver, DRIVER_NUM, 1, 0, 0), SyscallReturn::Success));
+//! assert_eq!(app.take_upcalls(), [QueuedUpcall::new(DRIVER_NUM, 0, (0, 0, 0))]);
+//! ```
+//!
+//! Everything the harness creates is leaked, so that it can be handed to code
+//! that needs `'static` references. This is only meant for tests.
+
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::ptr::{self, NonNull};
+
+use kernel::capabilities;
+use kernel::deferred_call::DeferredCall;
+use kernel::grant::{AllowRoSize, AllowRwSize, Grant, UpcallSize};
+use kernel::ipc;
+use kernel::platform::chip::Chip;
+use kernel::platform::{KernelResources, SyscallDriverLookup};
+use kernel::process::{
+    Error, FunctionCall, FunctionCallSource, Process, ProcessAddresses,
+    ProcessCustomGrantIdentifier, ProcessSizes, ShortID, StackUsage, State, Task,
+};
+use kernel::processbuffer::{ReadOnlyProcessBuffer, ReadWriteProcessBuffer};
+use kernel::scheduler::{Scheduler, SchedulingDecision};
+use kernel::storage_permissions::StoragePermissions;
+use kernel::syscall::{
+    ContextSwitchReason, Syscall, SyscallDriver, SyscallReturn, UserspaceKernelBoundary,
+};
+use kernel::upcall::UpcallId;
+use kernel::{ErrorCode, Kernel, ProcessId};
+use tock_tbf::types::{CommandPermissions, TbfFooterV2Credentials};
+
+/// Bytes of memory each test process has for buffers it allows.
+const MEMORY_LEN: usize = 4096;
+
+/// Tasks a test process can have queued, as many as a default process.
+const TASK_QUEUE_LEN: usize = 10;
+
+/// Every capability, for the harness and for capsules under test that need
+/// one.
+pub struct TestCapability;
+
+unsafe impl capabilities::ProcessManagementCapability for TestCapability {}
+unsafe impl capabilities::ProcessApprovalCapability for TestCapability {}
+unsafe impl capabilities::ProcessInitCapability for TestCapability {}
+unsafe impl capabilities::MainLoopCapability for TestCapability {}
+unsafe impl capabilities::MemoryAllocationCapability for TestCapability {}
+unsafe impl capabilities::ExternalProcessCapability for TestCapability {}
+
+/// Move `value` to the heap and never free it.
+pub fn leak<T>(value: T) -> &'static mut T {
+    Box::leak(Box::new(value))
+}
+
+/// A zeroed buffer of `len` bytes that is never freed, for capsules that need
+/// a `&'static mut [u8]`.
+pub fn leak_buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
+
+/// Run deferred calls until none are pending.
+///
+/// Deferred calls are global to the test binary, and tests run in parallel
+/// threads, so only tests that take care not to share them with other tests
+/// should rely on this.
+pub fn service_deferred_calls() {
+    while DeferredCall::service_next_pending().is_some() {}
+}
+
+/// A kernel with a fixed set of processes.
+pub struct TestKernel {
+    kernel: &'static Kernel,
+    processes: &'static [&'static TestProcess],
+}
+
+impl TestKernel {
+    /// A kernel with one process for each of `names`, all waiting for
+    /// upcalls.
+    pub fn new(names: &[&'static str]) -> &'static TestKernel {
+        let processes: Vec<&'static TestProcess> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| &*leak(TestProcess::new(name, index)))
+            .collect();
+        let processes: &'static [&'static TestProcess] = Box::leak(processes.into_boxed_slice());
+        let slots: Vec<Option<&'static dyn Process>> = processes
+            .iter()
+            .map(|process| Some(*process as &'static dyn Process))
+            .collect();
+        let kernel = &*leak(Kernel::new(Box::leak(slots.into_boxed_slice())));
+        for process in processes {
+            process.kernel.set(Some(kernel));
+        }
+        leak(TestKernel { kernel, processes })
+    }
+
+    pub fn kernel(&self) -> &'static Kernel {
+        self.kernel
+    }
+
+    pub fn process(&self, index: usize) -> &'static TestProcess {
+        self.processes[index]
+    }
+
+    /// Create a grant for the capsule with driver number `driver_num`. All
+    /// grants must be created before any process uses one.
+    pub fn create_grant<
+        T: Default,
+        Upcalls: UpcallSize,
+        AllowROs: AllowRoSize,
+        AllowRWs: AllowRwSize,
+    >(
+        &self,
+        driver_num: usize,
+    ) -> Grant<T, Upcalls, AllowROs, AllowRWs> {
+        self.kernel.create_grant(driver_num, &TestCapability)
+    }
+}
+
+/// An upcall a capsule scheduled for a test process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueuedUpcall {
+    pub driver_num: usize,
+    pub subscribe_num: usize,
+    pub args: (usize, usize, usize),
+}
+
+impl QueuedUpcall {
+    pub fn new(driver_num: usize, subscribe_num: usize, args: (usize, usize, usize)) -> Self {
+        QueuedUpcall {
+            driver_num,
+            subscribe_num,
+            args,
+        }
+    }
+}
+
+/// A region of a test process's memory, to allow to a capsule.
+#[derive(Clone, Copy, Debug)]
+pub struct AppBuffer {
+    addr: usize,
+    len: usize,
+}
+
+impl AppBuffer {
+    /// The buffer to allow to take a previously allowed one back.
+    pub const EMPTY: AppBuffer = AppBuffer { addr: 0, len: 0 };
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+struct GrantEntry {
+    driver_num: usize,
+    ptr: NonNull<u8>,
+    entered: bool,
+}
+
+/// A process that only exists to make system calls on behalf of a test.
+///
+/// Between system calls the process is yielded, waiting for upcalls, as an
+/// application blocked in `yield` would be. Upcalls are queued but never run;
+/// `take_upcalls` returns them.
+pub struct TestProcess {
+    name: &'static str,
+    index: usize,
+    kernel: Cell<Option<&'static Kernel>>,
+    state: Cell<State>,
+    memory: &'static [Cell<u8>],
+    memory_used: Cell<usize>,
+    grants: RefCell<Vec<Option<GrantEntry>>>,
+    tasks: RefCell<VecDeque<Task>>,
+    dropped_upcalls: Cell<usize>,
+    restarts: Cell<usize>,
+    completion_code: Cell<Option<Option<u32>>>,
+    syscall: Cell<Option<Syscall>>,
+    syscall_return: Cell<Option<SyscallReturn>>,
+    syscall_count: Cell<usize>,
+    last_syscall: Cell<Option<Syscall>>,
+}
+
+impl TestProcess {
+    fn new(name: &'static str, index: usize) -> TestProcess {
+        let memory: Vec<Cell<u8>> = (0..MEMORY_LEN).map(|_| Cell::new(0)).collect();
+        TestProcess {
+            name,
+            index,
+            kernel: Cell::new(None),
+            state: Cell::new(State::Yielded),
+            memory: Box::leak(memory.into_boxed_slice()),
+            memory_used: Cell::new(0),
+            grants: RefCell::new(Vec::new()),
+            tasks: RefCell::new(VecDeque::new()),
+            dropped_upcalls: Cell::new(0),
+            restarts: Cell::new(0),
+            completion_code: Cell::new(None),
+            syscall: Cell::new(None),
+            syscall_return: Cell::new(None),
+            syscall_count: Cell::new(0),
+            last_syscall: Cell::new(None),
+        }
+    }
+
+    fn kernel(&self) -> &'static Kernel {
+        self.kernel.get().expect("process not part of a TestKernel")
+    }
+
+    fn memory_start(&self) -> usize {
+        self.memory.as_ptr() as usize
+    }
+
+    fn in_memory(&self, addr: usize, len: usize) -> bool {
+        let start = self.memory_start();
+        addr >= start
+            && addr
+                .checked_add(len)
+                .map_or(false, |end| end <= start + MEMORY_LEN)
+    }
+
+    /// Copy `contents` into a new region of this process's memory.
+    pub fn buffer(&self, contents: &[u8]) -> AppBuffer {
+        let offset = self.memory_used.get();
+        assert!(
+            offset + contents.len() <= MEMORY_LEN,
+            "test process out of memory"
+        );
+        self.memory_used.set(offset + contents.len());
+        let buffer = AppBuffer {
+            addr: self.memory_start() + offset,
+            len: contents.len(),
+        };
+        self.write(buffer, contents);
+        buffer
+    }
+
+    /// Overwrite the start of `buffer` with `contents`, as the application
+    /// would.
+    pub fn write(&self, buffer: AppBuffer, contents: &[u8]) {
+        assert!(contents.len() <= buffer.len);
+        let offset = buffer.addr - self.memory_start();
+        for (cell, byte) in self.memory[offset..].iter().zip(contents) {
+            cell.set(*byte);
+        }
+    }
+
+    /// The current contents of `buffer`.
+    pub fn read(&self, buffer: AppBuffer) -> Vec<u8> {
+        let offset = buffer.addr - self.memory_start();
+        self.memory[offset..offset + buffer.len]
+            .iter()
+            .map(Cell::get)
+            .collect()
+    }
+
+    /// Make `syscall` to `driver`, whose driver number it names, and return
+    /// what the kernel returned to the process.
+    ///
+    /// Panics if the process cannot run, for example because it faulted.
+    pub fn syscall(
+        &self,
+        driver_num: usize,
+        driver: &dyn SyscallDriver,
+        syscall: Syscall,
+    ) -> SyscallReturn {
+        assert!(
+            matches!(self.state.get(), State::Running | State::Yielded),
+            "process {} cannot make system calls while {:?}",
+            self.name,
+            self.state.get()
+        );
+        self.state.set(State::Running);
+        self.syscall.set(Some(syscall));
+        let resources = Resources {
+            process: self,
+            driver_num,
+            driver,
+        };
+        self.kernel().kernel_loop_operation::<_, _, 0>(
+            &resources,
+            &TestChip,
+            None::<&ipc::IPC<0>>,
+            true,
+            &TestCapability,
+        );
+        // The application goes back to waiting once it has its answer.
+        if self.state.get() == State::Running {
+            self.state.set(State::Yielded);
+        }
+        self.syscall_return
+            .take()
+            .expect("the kernel did not return from the system call")
+    }
+
+    pub fn command(
+        &self,
+        driver: &dyn SyscallDriver,
+        driver_num: usize,
+        command_num: usize,
+        arg0: usize,
+        arg1: usize,
+    ) -> SyscallReturn {
+        self.syscall(
+            driver_num,
+            driver,
+            Syscall::Command {
+                driver_number: driver_num,
+                subdriver_number: command_num,
+                arg0,
+                arg1,
+            },
+        )
+    }
+
+    /// Subscribe to upcall `subscribe_num`, so that the capsule's upcalls are
+    /// queued for this process.
+    pub fn subscribe(
+        &self,
+        driver: &dyn SyscallDriver,
+        driver_num: usize,
+        subscribe_num: usize,
+    ) -> SyscallReturn {
+        self.syscall(
+            driver_num,
+            driver,
+            Syscall::Subscribe {
+                driver_number: driver_num,
+                subdriver_number: subscribe_num,
+                // Any valid address will do, as upcalls never run.
+                upcall_ptr: self.memory_start() as *mut (),
+                appdata: 0,
+            },
+        )
+    }
+
+    pub fn unsubscribe(
+        &self,
+        driver: &dyn SyscallDriver,
+        driver_num: usize,
+        subscribe_num: usize,
+    ) -> SyscallReturn {
+        self.syscall(
+            driver_num,
+            driver,
+            Syscall::Subscribe {
+                driver_number: driver_num,
+                subdriver_number: subscribe_num,
+                upcall_ptr: ptr::null_mut(),
+                appdata: 0,
+            },
+        )
+    }
+
+    pub fn allow_readwrite(
+        &self,
+        driver: &dyn SyscallDriver,
+        driver_num: usize,
+        allow_num: usize,
+        buffer: AppBuffer,
+    ) -> SyscallReturn {
+        self.syscall(
+            driver_num,
+            driver,
+            Syscall::ReadWriteAllow {
+                driver_number: driver_num,
+                subdriver_number: allow_num,
+                allow_address: buffer.addr as *mut u8,
+                allow_size: buffer.len,
+            },
+        )
+    }
+
+    pub fn allow_readonly(
+        &self,
+        driver: &dyn SyscallDriver,
+        driver_num: usize,
+        allow_num: usize,
+        buffer: AppBuffer,
+    ) -> SyscallReturn {
+        self.syscall(
+            driver_num,
+            driver,
+            Syscall::ReadOnlyAllow {
+                driver_number: driver_num,
+                subdriver_number: allow_num,
+                allow_address: buffer.addr as *const u8,
+                allow_size: buffer.len,
+            },
+        )
+    }
+
+    /// Remove and return the upcalls queued for this process, oldest first.
+    pub fn take_upcalls(&self) -> Vec<QueuedUpcall> {
+        let mut upcalls = Vec::new();
+        self.tasks.borrow_mut().retain(|task| match task {
+            Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(id),
+                argument0,
+                argument1,
+                argument2,
+                ..
+            }) => {
+                upcalls.push(QueuedUpcall::new(
+                    id.driver_num,
+                    id.subscribe_num,
+                    (*argument0, *argument1, *argument2),
+                ));
+                false
+            }
+            _ => true,
+        });
+        upcalls
+    }
+}
+
+impl Process for TestProcess {
+    fn processid(&self) -> ProcessId {
+        ProcessId::new_external(self.kernel(), self.index, self.index, &TestCapability)
+    }
+
+    fn short_app_id(&self) -> ShortID {
+        ShortID::LocallyUnique
+    }
+
+    fn binary_version(&self) -> u32 {
+        0
+    }
+
+    fn enqueue_task(&self, task: Task) -> Result<(), ErrorCode> {
+        if !self.is_running() {
+            return Err(ErrorCode::NODEVICE);
+        }
+        let mut tasks = self.tasks.borrow_mut();
+        if tasks.len() >= TASK_QUEUE_LEN {
+            self.dropped_upcalls.set(self.dropped_upcalls.get() + 1);
+            return Err(ErrorCode::NOMEM);
+        }
+        tasks.push_back(task);
+        Ok(())
+    }
+
+    fn enqueue_init_task(
+        &self,
+        _cap: &dyn capabilities::ProcessInitCapability,
+    ) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn mark_credentials_pass(
+        &self,
+        _credentials: Option<TbfFooterV2Credentials>,
+        _short_app_id: ShortID,
+        _capability: &dyn capabilities::ProcessApprovalCapability,
+    ) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn mark_credentials_fail(&self, _capability: &dyn capabilities::ProcessApprovalCapability) {
+        self.state.set(State::CredentialsFailed);
+    }
+
+    fn get_credentials(&self) -> Option<TbfFooterV2Credentials> {
+        None
+    }
+
+    fn ready(&self) -> bool {
+        self.has_tasks()
+            || self.state.get() == State::Running
+            || self.state.get() == State::CredentialsApproved
+    }
+
+    fn has_tasks(&self) -> bool {
+        !self.tasks.borrow().is_empty()
+    }
+
+    fn dequeue_task(&self) -> Option<Task> {
+        self.tasks.borrow_mut().pop_front()
+    }
+
+    fn pending_tasks(&self) -> usize {
+        self.tasks.borrow().len()
+    }
+
+    fn peek_task(&self, index: usize) -> Option<Task> {
+        self.tasks.borrow().get(index).copied()
+    }
+
+    fn remove_pending_upcalls(
+        &self,
+        upcall_id: UpcallId,
+        matching: &dyn Fn((usize, usize, usize)) -> bool,
+    ) -> usize {
+        let mut tasks = self.tasks.borrow_mut();
+        let before = tasks.len();
+        tasks.retain(|task| match task {
+            Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(id),
+                argument0,
+                argument1,
+                argument2,
+                ..
+            }) => *id != upcall_id || !matching((*argument0, *argument1, *argument2)),
+            _ => true,
+        });
+        before - tasks.len()
+    }
+
+    fn get_state(&self) -> State {
+        self.state.get()
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(
+            self.state.get(),
+            State::Running | State::Yielded | State::StoppedRunning | State::StoppedYielded
+        )
+    }
+
+    fn set_yielded_state(&self) {
+        if self.state.get() == State::Running {
+            self.state.set(State::Yielded);
+        }
+    }
+
+    fn stop(&self) {
+        match self.state.get() {
+            State::Running => self.state.set(State::StoppedRunning),
+            State::Yielded => self.state.set(State::StoppedYielded),
+            _ => {}
+        }
+    }
+
+    fn resume(&self) {
+        match self.state.get() {
+            State::StoppedRunning => self.state.set(State::Running),
+            State::StoppedYielded => self.state.set(State::Yielded),
+            _ => {}
+        }
+    }
+
+    fn set_fault_state(&self) {
+        self.state.set(State::Faulted);
+        self.tasks.borrow_mut().clear();
+    }
+
+    fn get_restart_count(&self) -> usize {
+        self.restarts.get()
+    }
+
+    fn get_process_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn get_completion_code(&self) -> Option<Option<u32>> {
+        self.completion_code.get()
+    }
+
+    fn terminate(&self, completion_code: Option<u32>) {
+        self.state.set(State::Terminated);
+        self.completion_code.set(Some(completion_code));
+        self.tasks.borrow_mut().clear();
+        self.grants.borrow_mut().clear();
+    }
+
+    fn try_restart(&self, completion_code: Option<u32>) {
+        self.terminate(completion_code);
+        self.restarts.set(self.restarts.get() + 1);
+        self.state.set(State::Yielded);
+    }
+
+    fn brk(&self, _new_break: *const u8) -> Result<*const u8, Error> {
+        Err(Error::AddressOutOfBounds)
+    }
+
+    fn sbrk(&self, _increment: isize) -> Result<*const u8, Error> {
+        Err(Error::AddressOutOfBounds)
+    }
+
+    fn number_writeable_flash_regions(&self) -> usize {
+        0
+    }
+
+    fn get_writeable_flash_region(&self, _region_index: usize) -> (u32, u32) {
+        (0, 0)
+    }
+
+    fn update_stack_start_pointer(&self, _stack_pointer: *const u8) {}
+
+    fn update_heap_start_pointer(&self, _heap_pointer: *const u8) {}
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn build_readwrite_process_buffer(
+        &self,
+        buf_start_addr: *mut u8,
+        size: usize,
+    ) -> Result<ReadWriteProcessBuffer, ErrorCode> {
+        if !self.is_running() {
+            return Err(ErrorCode::FAIL);
+        }
+        if size != 0 && !self.in_memory(buf_start_addr as usize, size) {
+            return Err(ErrorCode::INVAL);
+        }
+        // Safety: the buffer is empty, or within this process's memory, which
+        // is never freed.
+        Ok(unsafe {
+            ReadWriteProcessBuffer::new_external(
+                buf_start_addr,
+                size,
+                self.processid(),
+                &TestCapability,
+            )
+        })
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn build_readonly_process_buffer(
+        &self,
+        buf_start_addr: *const u8,
+        size: usize,
+    ) -> Result<ReadOnlyProcessBuffer, ErrorCode> {
+        if !self.is_running() {
+            return Err(ErrorCode::FAIL);
+        }
+        if size != 0 && !self.in_memory(buf_start_addr as usize, size) {
+            return Err(ErrorCode::INVAL);
+        }
+        // Safety: as for `build_readwrite_process_buffer`.
+        Ok(unsafe {
+            ReadOnlyProcessBuffer::new_external(
+                buf_start_addr,
+                size,
+                self.processid(),
+                &TestCapability,
+            )
+        })
+    }
+
+    unsafe fn set_byte(&self, addr: *mut u8, value: u8) -> bool {
+        if self.in_memory(addr as usize, 1) {
+            self.memory[addr as usize - self.memory_start()].set(value);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn get_command_permissions(&self, _driver_num: usize, _offset: usize) -> CommandPermissions {
+        CommandPermissions::NoPermsAtAll
+    }
+
+    fn get_storage_permissions(&self) -> Option<StoragePermissions> {
+        None
+    }
+
+    fn setup_mpu(&self) {}
+
+    fn add_mpu_region(
+        &self,
+        _unallocated_memory_start: *const u8,
+        _unallocated_memory_size: usize,
+        _min_region_size: usize,
+    ) -> Option<kernel::platform::mpu::Region> {
+        None
+    }
+
+    fn remove_mpu_region(&self, _region: kernel::platform::mpu::Region) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn allocate_grant(
+        &self,
+        grant_num: usize,
+        driver_num: usize,
+        size: usize,
+        align: usize,
+    ) -> bool {
+        if !self.is_running()
+            || grant_num
+                >= self
+                    .kernel()
+                    .get_grant_count_and_finalize_external(&TestCapability)
+        {
+            return false;
+        }
+        let mut grants = self.grants.borrow_mut();
+        if grants.len() <= grant_num {
+            grants.resize_with(grant_num + 1, || None);
+        }
+        if grants[grant_num].is_some()
+            || grants
+                .iter()
+                .flatten()
+                .any(|grant| grant.driver_num == driver_num)
+        {
+            return false;
+        }
+        let layout = match Layout::from_size_align(size.max(1), align) {
+            Ok(layout) => layout,
+            Err(_) => return false,
+        };
+        // Safety: the layout has a nonzero size. The memory is never freed.
+        match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(ptr) => {
+                grants[grant_num] = Some(GrantEntry {
+                    driver_num,
+                    ptr,
+                    entered: false,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn grant_is_allocated(&self, grant_num: usize) -> Option<bool> {
+        if !self.is_running()
+            || grant_num
+                >= self
+                    .kernel()
+                    .get_grant_count_and_finalize_external(&TestCapability)
+        {
+            return None;
+        }
+        Some(matches!(self.grants.borrow().get(grant_num), Some(Some(_))))
+    }
+
+    fn allocate_custom_grant(
+        &self,
+        _size: usize,
+        _align: usize,
+    ) -> Option<(ProcessCustomGrantIdentifier, NonNull<u8>)> {
+        None
+    }
+
+    fn enter_grant(&self, grant_num: usize) -> Result<NonNull<u8>, Error> {
+        if !self.is_running() {
+            return Err(Error::InactiveApp);
+        }
+        match self.grants.borrow_mut().get_mut(grant_num) {
+            Some(Some(grant)) if grant.entered => Err(Error::AlreadyInUse),
+            Some(Some(grant)) => {
+                grant.entered = true;
+                Ok(grant.ptr)
+            }
+            _ => Err(Error::AddressOutOfBounds),
+        }
+    }
+
+    fn enter_custom_grant(
+        &self,
+        _identifier: ProcessCustomGrantIdentifier,
+    ) -> Result<*mut u8, Error> {
+        Err(Error::KernelError)
+    }
+
+    unsafe fn leave_grant(&self, grant_num: usize) {
+        if let Some(Some(grant)) = self.grants.borrow_mut().get_mut(grant_num) {
+            grant.entered = false;
+        }
+    }
+
+    fn grant_allocated_count(&self) -> Option<usize> {
+        if !self.is_running() {
+            return None;
+        }
+        Some(self.grants.borrow().iter().flatten().count())
+    }
+
+    fn lookup_grant_from_driver_num(&self, driver_num: usize) -> Result<usize, Error> {
+        self.grants
+            .borrow()
+            .iter()
+            .position(|grant| matches!(grant, Some(grant) if grant.driver_num == driver_num))
+            .ok_or(Error::OutOfMemory)
+    }
+
+    fn is_valid_upcall_function_pointer(&self, _upcall_fn: NonNull<()>) -> bool {
+        true
+    }
+
+    fn set_syscall_return_value(&self, return_value: SyscallReturn) {
+        self.syscall_return.set(Some(return_value));
+    }
+
+    fn set_process_function(&self, _callback: FunctionCall) {
+        self.state.set(State::Running);
+    }
+
+    fn switch_to(&self) -> Option<ContextSwitchReason> {
+        self.syscall
+            .take()
+            .map(|syscall| ContextSwitchReason::SyscallFired { syscall })
+    }
+
+    fn get_addresses(&self) -> ProcessAddresses {
+        let start = self.memory_start();
+        ProcessAddresses {
+            flash_start: 0,
+            flash_non_protected_start: 0,
+            flash_integrity_end: ptr::null(),
+            flash_end: 0,
+            sram_start: start,
+            sram_app_brk: start + MEMORY_LEN,
+            sram_grant_start: start + MEMORY_LEN,
+            sram_end: start + MEMORY_LEN,
+            sram_heap_start: None,
+            sram_stack_top: None,
+            sram_stack_bottom: None,
+        }
+    }
+
+    fn get_sizes(&self) -> ProcessSizes {
+        ProcessSizes {
+            grant_pointers: 0,
+            upcall_list: 0,
+            process_control_block: 0,
+        }
+    }
+
+    fn get_stored_state(&self, _out: &mut [u8]) -> Result<usize, ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn print_full_process(&self, writer: &mut dyn Write) {
+        let _ = writer.write_fmt(format_args!("Test process {}\r\n", self.name));
+    }
+
+    fn debug_syscall_count(&self) -> usize {
+        self.syscall_count.get()
+    }
+
+    fn debug_dropped_upcall_count(&self) -> usize {
+        self.dropped_upcalls.get()
+    }
+
+    fn debug_timeslice_expiration_count(&self) -> usize {
+        0
+    }
+
+    fn debug_timeslice_expired(&self) {}
+
+    fn debug_syscall_called(&self, last_syscall: Syscall) {
+        self.syscall_count.set(self.syscall_count.get() + 1);
+        self.last_syscall.set(Some(last_syscall));
+    }
+
+    fn debug_syscall_last(&self) -> Option<Syscall> {
+        self.last_syscall.get()
+    }
+
+    fn debug_stack_usage(&self) -> Option<StackUsage> {
+        None
+    }
+
+    fn debug_read_memory(&self, addr: usize, buf: &mut [u8]) -> Result<(), ErrorCode> {
+        if !self.in_memory(addr, buf.len()) {
+            return Err(ErrorCode::INVAL);
+        }
+        let offset = addr - self.memory_start();
+        for (byte, cell) in buf.iter_mut().zip(&self.memory[offset..]) {
+            *byte = cell.get();
+        }
+        Ok(())
+    }
+}
+
+/// What the kernel needs from a board to run one system call of a test
+/// process.
+struct Resources<'a> {
+    process: &'a TestProcess,
+    driver_num: usize,
+    driver: &'a dyn SyscallDriver,
+}
+
+impl SyscallDriverLookup for Resources<'_> {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn SyscallDriver>) -> R,
+    {
+        if driver_num == self.driver_num {
+            f(Some(self.driver))
+        } else {
+            f(None)
+        }
+    }
+}
+
+impl Scheduler<TestChip> for Resources<'_> {
+    fn next(&self) -> SchedulingDecision {
+        SchedulingDecision::RunProcess((self.process.processid(), None))
+    }
+
+    fn result(&self, _result: kernel::scheduler::StoppedExecutingReason, _time: Option<u32>) {}
+
+    unsafe fn do_kernel_work_now(&self, _chip: &TestChip) -> bool {
+        false
+    }
+
+    unsafe fn continue_process(&self, _id: ProcessId, _chip: &TestChip) -> bool {
+        // Hand control back to the test once the system call is handled.
+        self.process.syscall.get().is_some()
+    }
+}
+
+impl KernelResources<TestChip> for Resources<'_> {
+    type SyscallDriverLookup = Self;
+    type SyscallFilter = ();
+    type ProcessFault = ();
+    type CredentialsCheckingPolicy = ();
+    type ContextSwitchCallback = ();
+    type Scheduler = Self;
+    type SchedulerTimer = ();
+    type WatchDog = ();
+
+    fn syscall_driver_lookup(&self) -> &Self {
+        self
+    }
+
+    fn syscall_filter(&self) -> &() {
+        &()
+    }
+
+    fn process_fault(&self) -> &() {
+        &()
+    }
+
+    fn credentials_checking_policy(&self) -> &'static () {
+        &()
+    }
+
+    fn context_switch_callback(&self) -> &() {
+        &()
+    }
+
+    fn scheduler(&self) -> &Self {
+        self
+    }
+
+    fn scheduler_timer(&self) -> &() {
+        &()
+    }
+
+    fn watchdog(&self) -> &() {
+        &()
+    }
+}
+
+/// A chip with no peripherals, interrupts or memory protection.
+struct TestChip;
+
+struct TestBoundary;
+
+impl Chip for TestChip {
+    type MPU = ();
+    type UserspaceKernelBoundary = TestBoundary;
+
+    fn service_pending_interrupts(&self) {}
+
+    fn has_pending_interrupts(&self) -> bool {
+        false
+    }
+
+    fn mpu(&self) -> &() {
+        &()
+    }
+
+    fn userspace_kernel_boundary(&self) -> &TestBoundary {
+        &TestBoundary
+    }
+
+    fn sleep(&self) {}
+
+    unsafe fn atomic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        f()
+    }
+
+    unsafe fn print_state(&self, _writer: &mut dyn Write) {}
+}
+
+/// Never used: test processes do not run code of their own.
+impl UserspaceKernelBoundary for TestBoundary {
+    type StoredState = ();
+
+    fn initial_process_app_brk_size(&self) -> usize {
+        0
+    }
+
+    unsafe fn initialize_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn set_syscall_return_value(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+        _return_value: SyscallReturn,
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn set_process_function(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+        _upcall: FunctionCall,
+    ) -> Result<(), ()> {
+        Ok(())
+    }
+
+    unsafe fn switch_to_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+    ) -> (ContextSwitchReason, Option<*const u8>) {
+        (ContextSwitchReason::Fault, None)
+    }
+
+    unsafe fn print_context(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &(),
+        _writer: &mut dyn Write,
+    ) {
+    }
+
+    fn store_context(&self, _state: &(), _out: &mut [u8]) -> Result<usize, ErrorCode> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::grant::{AllowRoCount, AllowRwCount, UpcallCount};
+    use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+    use kernel::syscall::CommandReturn;
+
+    const DRIVER_NUM: usize = 0x9999;
+
+    /// Copies its read-only allow into its read-write allow on command 1, and
+    /// reports the length with upcall 0.
+    struct Copier {
+        grant: Grant<(), UpcallCount<1>, AllowRoCount<1>, AllowRwCount<1>>,
+    }
+
+    impl SyscallDriver for Copier {
+        fn command(&self, command: usize, _: usize, _: usize, id: ProcessId) -> CommandReturn {
+            if command != 1 {
+                return CommandReturn::failure(ErrorCode::NOSUPPORT);
+            }
+            self.grant
+                .enter(id, |_, kernel_data| {
+                    let len = kernel_data
+                        .get_readonly_processbuffer(0)
+                        .and_then(|src| {
+                            src.enter(|src| {
+                                kernel_data.get_readwrite_processbuffer(0).and_then(|dest| {
+                                    dest.mut_enter(|dest| {
+                                        let len = src.len().min(dest.len());
+                                        for (d, s) in dest.iter().zip(src.iter()) {
+                                            d.set(s.get());
+                                        }
+                                        len
+                                    })
+                                })
+                            })
+                        })
+                        .and_then(|len| len)
+                        .unwrap_or(0);
+                    kernel_data.schedule_upcall(0, (len, 0, 0)).ok();
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+        }
+
+        fn allocate_grant(&self, id: ProcessId) -> Result<(), kernel::process::Error> {
+            self.grant.enter(id, |_, _| {})
+        }
+    }
+
+    #[test]
+    fn syscalls_reach_the_capsule() {
+        let kernel = TestKernel::new(&["app"]);
+        let copier = Copier {
+            grant: kernel.create_grant(DRIVER_NUM),
+        };
+        let app = kernel.process(0);
+        let src = app.buffer(b"hello");
+        let dest = app.buffer(&[0; 8]);
+
+        assert!(matches!(
+            app.subscribe(&copier, DRIVER_NUM, 0),
+            SyscallReturn::SubscribeSuccess(..)
+        ));
+        assert!(matches!(
+            app.allow_readonly(&copier, DRIVER_NUM, 0, src),
+            SyscallReturn::AllowReadOnlySuccess(..)
+        ));
+        assert!(matches!(
+            app.allow_readwrite(&copier, DRIVER_NUM, 0, dest),
+            SyscallReturn::AllowReadWriteSuccess(..)
+        ));
+        assert!(matches!(
+            app.command(&copier, DRIVER_NUM, 1, 0, 0),
+            SyscallReturn::Success
+        ));
+        assert_eq!(&app.read(dest)[..5], b"hello");
+        assert_eq!(
+            app.take_upcalls(),
+            [QueuedUpcall::new(DRIVER_NUM, 0, (5, 0, 0))]
+        );
+        assert_eq!(app.get_state(), State::Yielded);
+        assert!(!app.ready());
+    }
+}
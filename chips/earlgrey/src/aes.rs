@@ -119,6 +119,8 @@ pub struct Aes<'a> {
     source: TakeCell<'static, [u8]>,
     dest: TakeCell<'static, [u8]>,
     mode: Cell<Mode>,
+    /// Number of bytes processed by the pending operation.
+    length: Cell<usize>,
 
     deferred_call: DeferredCall,
 }
@@ -131,6 +133,7 @@ impl<'a> Aes<'a> {
             source: TakeCell::empty(),
             dest: TakeCell::empty(),
             mode: Cell::new(Mode::IDLE),
+            length: Cell::new(0),
             deferred_call: DeferredCall::new(),
         }
     }
@@ -381,6 +384,16 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
 
     fn start_message(&self) {}
 
+    fn max_crypt_len(&self) -> usize {
+        MAX_LENGTH
+    }
+
+    fn supported_modes(&self) -> hil::symmetric_encryption::Modes {
+        hil::symmetric_encryption::Modes::ECB
+            | hil::symmetric_encryption::Modes::CTR
+            | hil::symmetric_encryption::Modes::CBC
+    }
+
     fn crypt(
         &self,
         source: Option<&'static mut [u8]>,
@@ -426,6 +439,7 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
 
         if ret.is_ok() {
             // Schedule a deferred call
+            self.length.set(stop_index - start_index);
             self.deferred_call.set();
             None
         } else {
@@ -510,7 +524,11 @@ impl<'a> DeferredCallClient for Aes<'_> {
 
     fn handle_deferred_call(&self) {
         self.client.map(|client| {
-            client.crypt_done(self.source.take(), self.dest.take().unwrap());
+            client.crypt_done(
+                self.source.take(),
+                self.dest.take().unwrap(),
+                self.length.get(),
+            );
         });
     }
 }
@@ -1,5 +1,6 @@
 //! ADC driver for the nRF52. Uses the SAADC peripheral.
 
+use core::cell::Cell;
 use kernel::hil;
 use kernel::utilities::cells::{OptionalCell, VolatileCell};
 use kernel::utilities::registers::interfaces::{Readable, Writeable};
@@ -323,6 +324,8 @@ impl AdcChannelSetup {
 pub struct Adc {
     registers: StaticRef<AdcRegisters>,
     client: OptionalCell<&'static dyn hil::adc::Client>,
+    /// The sample in progress is differential.
+    differential: Cell<bool>,
 }
 
 impl Adc {
@@ -330,6 +333,7 @@ impl Adc {
         Self {
             registers: SAADC_BASE,
             client: OptionalCell::empty(),
+            differential: Cell::new(false),
         }
     }
 
@@ -347,6 +351,7 @@ impl Adc {
                 .events_calibratedone
                 .write(EVENT::EVENT::CLEAR);
             self.registers.enable.write(ENABLE::ENABLE::CLEAR);
+            self.client.map(|client| client.calibration_done(Ok(None)));
         } else if self.registers.events_started.is_set(EVENT::EVENT) {
             self.registers.events_started.write(EVENT::EVENT::CLEAR);
             // ADC has started, now issue the sample.
@@ -363,31 +368,47 @@ impl Adc {
             let val = unsafe { SAMPLE[0] as i16 };
             self.client.map(|client| {
                 // shift left to meet the ADC HIL requirement
-                client.sample_ready(if val < 0 { 0 } else { val << 4 } as u16);
+                if self.differential.get() {
+                    client.differential_sample_ready(val << 4);
+                } else {
+                    client.sample_ready(if val < 0 { 0 } else { val << 4 } as u16);
+                }
             });
         }
     }
-}
-
-/// Implements an ADC capable reading ADC samples on any channel.
-impl hil::adc::Adc for Adc {
-    type Channel = AdcChannelSetup;
 
-    fn sample(&self, channel: &Self::Channel) -> Result<(), ErrorCode> {
-        // Positive goes to the channel passed in, negative not connected.
+    /// Start a single read of `channel`, made relative to `negative` if
+    /// given. The gain and acquisition time are those of `channel`.
+    fn start_sample(
+        &self,
+        channel: &AdcChannelSetup,
+        negative: Option<&AdcChannelSetup>,
+    ) -> Result<(), ErrorCode> {
+        // Positive goes to the channel passed in, negative to the other
+        // channel of a differential read or not connected.
         self.registers.ch[0]
             .pselp
             .write(PSEL::PSEL.val(channel.channel as u32));
-        self.registers.ch[0].pseln.write(PSEL::PSEL::NotConnected);
+        match negative {
+            Some(negative) => self.registers.ch[0]
+                .pseln
+                .write(PSEL::PSEL.val(negative.channel as u32)),
+            None => self.registers.ch[0].pseln.write(PSEL::PSEL::NotConnected),
+        }
+        self.differential.set(negative.is_some());
 
         // Configure the ADC for a single read.
+        let mode = match negative {
+            Some(_) => CONFIG::MODE::Diff,
+            None => CONFIG::MODE::SE,
+        };
         self.registers.ch[0].config.write(
             CONFIG::GAIN.val(channel.gain as u32)
                 + CONFIG::REFSEL::VDD1_4
                 + CONFIG::TACQ.val(channel.sampling_time as u32)
                 + CONFIG::RESP.val(channel.resp as u32)
                 + CONFIG::RESN.val(channel.resn as u32)
-                + CONFIG::MODE::SE,
+                + mode,
         );
 
         // Set max resolution (with oversampling).
@@ -418,6 +439,15 @@ impl hil::adc::Adc for Adc {
 
         Ok(())
     }
+}
+
+/// Implements an ADC capable reading ADC samples on any channel.
+impl hil::adc::Adc for Adc {
+    type Channel = AdcChannelSetup;
+
+    fn sample(&self, channel: &Self::Channel) -> Result<(), ErrorCode> {
+        self.start_sample(channel, None)
+    }
 
     fn sample_continuous(
         &self,
@@ -439,6 +469,19 @@ impl hil::adc::Adc for Adc {
         Some(3300)
     }
 
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        Adc::calibrate(self);
+        Ok(())
+    }
+
+    fn sample_differential(
+        &self,
+        positive: &Self::Channel,
+        negative: &Self::Channel,
+    ) -> Result<(), ErrorCode> {
+        self.start_sample(positive, Some(negative))
+    }
+
     fn set_client(&self, client: &'static dyn hil::adc::Client) {
         self.client.set(client);
     }
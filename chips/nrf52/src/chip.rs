@@ -78,6 +78,7 @@ impl<'a> Nrf52DefaultPeripherals<'a> {
         self.ieee802154_radio.set_timer_ref(&self.timer0);
         self.timer0.set_alarm_client(&self.ieee802154_radio);
         kernel::deferred_call::DeferredCallClient::register(&self.nvmc);
+        kernel::deferred_call::DeferredCallClient::register(&self.ecb);
     }
 }
 impl<'a> kernel::platform::chip::InterruptService for Nrf52DefaultPeripherals<'a> {
@@ -16,12 +16,19 @@
 //! * 32.768 kHz synthesized from HFCLK (LFSYNT)
 //!
 
+use kernel::platform::scheduler_timer::SchedulerTimer;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::registers::interfaces::{Readable, Writeable};
 use kernel::utilities::registers::{
     register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
+
+/// Longest interval timed in one go while waiting for a clock to start, so
+/// that timers with a short range (the SysTick counts at most 2^24 ticks) can
+/// time longer waits.
+const TIMER_WINDOW_US: u32 = 100_000;
 
 register_structs! {
     ClockRegisters {
@@ -134,6 +141,7 @@ pub enum InterruptField {
 }
 
 /// Low frequency clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LowClockSource {
     RC = 0,
     XTAL = 1,
@@ -277,4 +285,224 @@ impl Clock {
             .lfclksrc
             .write(LfClkSrc::SRC.val(clock_source as u32));
     }
+
+    /// Start the low frequency clock from `source` and wait up to
+    /// `timeout_us`, as measured by `timer`, for it to start. If it has not
+    /// started by then, for example because the board has no crystal, the
+    /// clock is stopped again and `FAIL` is returned.
+    pub fn low_start_timeout(
+        &self,
+        source: LowClockSource,
+        timer: &dyn SchedulerTimer,
+        timeout_us: u32,
+    ) -> Result<(), ErrorCode> {
+        low_start_timeout(self, source, timer, timeout_us)
+    }
+
+    /// Start the low frequency clock from `source`, falling back to the
+    /// internal RC oscillator if it does not start within `timeout_us`.
+    /// Returns the source the clock runs from.
+    pub fn low_start_or_rc(
+        &self,
+        source: LowClockSource,
+        timer: &dyn SchedulerTimer,
+        timeout_us: u32,
+    ) -> LowClockSource {
+        low_start_or_rc(self, source, timer, timeout_us)
+    }
+}
+
+/// The low frequency clock operations used to start it.
+trait LowClock {
+    fn set_source(&self, source: LowClockSource);
+    fn start(&self);
+    fn stop(&self);
+    fn started(&self) -> bool;
+}
+
+impl LowClock for Clock {
+    fn set_source(&self, source: LowClockSource) {
+        self.low_set_source(source);
+    }
+    fn start(&self) {
+        self.low_start();
+    }
+    fn stop(&self) {
+        self.low_stop();
+    }
+    fn started(&self) -> bool {
+        self.low_started()
+    }
+}
+
+fn low_start_timeout(
+    clock: &dyn LowClock,
+    source: LowClockSource,
+    timer: &dyn SchedulerTimer,
+    timeout_us: u32,
+) -> Result<(), ErrorCode> {
+    clock.set_source(source);
+    clock.start();
+    let res = wait_started(timer, timeout_us, || clock.started());
+    if res.is_err() {
+        clock.stop();
+    }
+    res
+}
+
+fn low_start_or_rc(
+    clock: &dyn LowClock,
+    source: LowClockSource,
+    timer: &dyn SchedulerTimer,
+    timeout_us: u32,
+) -> LowClockSource {
+    if low_start_timeout(clock, source, timer, timeout_us).is_ok() {
+        return source;
+    }
+    // The RC oscillator is always present.
+    clock.set_source(LowClockSource::RC);
+    clock.start();
+    while !clock.started() {}
+    LowClockSource::RC
+}
+
+/// Poll `started` until it returns true or `timeout_us` has passed, as
+/// measured by `timer`. The timer is reset before returning.
+fn wait_started(
+    timer: &dyn SchedulerTimer,
+    timeout_us: u32,
+    started: impl Fn() -> bool,
+) -> Result<(), ErrorCode> {
+    let mut remaining_us = timeout_us;
+    let res = loop {
+        if started() {
+            break Ok(());
+        }
+        if remaining_us == 0 {
+            break Err(ErrorCode::FAIL);
+        }
+        let window_us = core::cmp::min(remaining_us, TIMER_WINDOW_US);
+        remaining_us -= window_us;
+        timer.start(window_us);
+        while !started() && timer.get_remaining_us().is_some() {}
+    };
+    timer.reset();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Timer whose count drops by `step_us` every time it is read.
+    struct MockTimer {
+        remaining_us: Cell<u32>,
+        step_us: u32,
+        started_us: Cell<u32>,
+    }
+
+    impl MockTimer {
+        fn new(step_us: u32) -> MockTimer {
+            MockTimer {
+                remaining_us: Cell::new(0),
+                step_us,
+                started_us: Cell::new(0),
+            }
+        }
+    }
+
+    impl SchedulerTimer for MockTimer {
+        fn start(&self, us: u32) {
+            self.remaining_us.set(us);
+            self.started_us.set(self.started_us.get() + us);
+        }
+        fn reset(&self) {
+            self.remaining_us.set(0);
+        }
+        fn arm(&self) {}
+        fn disarm(&self) {}
+        fn get_remaining_us(&self) -> Option<u32> {
+            let remaining_us = self.remaining_us.get().saturating_sub(self.step_us);
+            self.remaining_us.set(remaining_us);
+            if remaining_us == 0 {
+                None
+            } else {
+                Some(remaining_us)
+            }
+        }
+    }
+
+    /// Low frequency clock that starts after `polls` calls to `started`, but
+    /// only from the RC oscillator if `has_crystal` is false.
+    struct MockClock {
+        has_crystal: bool,
+        polls: Cell<usize>,
+        source: Cell<Option<LowClockSource>>,
+        running: Cell<bool>,
+    }
+
+    impl MockClock {
+        fn new(has_crystal: bool, polls: usize) -> MockClock {
+            MockClock {
+                has_crystal,
+                polls: Cell::new(polls),
+                source: Cell::new(None),
+                running: Cell::new(false),
+            }
+        }
+    }
+
+    impl LowClock for MockClock {
+        fn set_source(&self, source: LowClockSource) {
+            self.source.set(Some(source));
+        }
+        fn start(&self) {
+            self.running.set(true);
+        }
+        fn stop(&self) {
+            self.running.set(false);
+        }
+        fn started(&self) -> bool {
+            if !self.running.get()
+                || (!self.has_crystal && self.source.get() == Some(LowClockSource::XTAL))
+            {
+                return false;
+            }
+            let polls = self.polls.get();
+            self.polls.set(polls.saturating_sub(1));
+            polls == 0
+        }
+    }
+
+    #[test]
+    fn missing_crystal_times_out_and_falls_back_to_rc() {
+        let clock = MockClock::new(false, 3);
+        let timer = MockTimer::new(1_000);
+
+        assert_eq!(
+            low_start_timeout(&clock, LowClockSource::XTAL, &timer, 250_000),
+            Err(ErrorCode::FAIL)
+        );
+        // The whole timeout was waited for, in windows, and the clock was
+        // left stopped.
+        assert_eq!(timer.started_us.get(), 250_000);
+        assert!(!clock.running.get());
+
+        let source = low_start_or_rc(&clock, LowClockSource::XTAL, &timer, 250_000);
+        assert_eq!(source, LowClockSource::RC);
+        assert_eq!(clock.source.get(), Some(LowClockSource::RC));
+        assert!(clock.running.get());
+    }
+
+    #[test]
+    fn crystal_that_starts_is_kept() {
+        let clock = MockClock::new(true, 5);
+        let timer = MockTimer::new(1_000);
+
+        let source = low_start_or_rc(&clock, LowClockSource::XTAL, &timer, 250_000);
+        assert_eq!(source, LowClockSource::XTAL);
+        assert_eq!(clock.source.get(), Some(LowClockSource::XTAL));
+        assert_eq!(timer.started_us.get(), TIMER_WINDOW_US);
+    }
 }
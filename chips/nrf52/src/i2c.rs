@@ -291,6 +291,12 @@ impl hil::i2c::I2CMaster for TWI {
         self.buf.replace(buffer);
         Ok(())
     }
+
+    fn acked_len(&self) -> Option<usize> {
+        // After a NACK, TXD.AMOUNT includes the byte that was NACKed.
+        let sent = self.registers.txd_amount.read(AMOUNT::AMOUNT) as usize;
+        Some(sent.saturating_sub(1))
+    }
 }
 
 impl hil::i2c::I2CSlave for TWI {
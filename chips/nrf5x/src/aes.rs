@@ -2,6 +2,8 @@
 //!
 //! Provides a simple driverto encrypt and decrypt
 //! messages using aes128-ctr mode on top of aes128-ecb.
+//! ECB and CBC encryption are also supported, but the hardware can only run
+//! the forward cipher, so ECB and CBC decryption return `NOSUPPORT`.
 //!
 //! Roughly, the module three buffers with the following content:
 //!
@@ -14,15 +16,38 @@
 //!
 //! ### Initial Counter
 //! Counter to be used for aes-ctr and it is entered into AES to generate the
-//! the keystream. After each encryption the initial counter is incremented
+//! the keystream. After each encryption the initial counter is incremented.
+//! The IV and the current counter are kept in the driver and copied into the
+//! DMA buffer when `crypt` is called, so the key and IV only need to be set
+//! once for a sequence of `crypt` calls. `start_message` rewinds the counter
+//! to the IV and clears the keystream left by the previous message.
 //!
 //! ### Payload
 //! Data to be encrypted or decrypted it is XOR:ed with the generated keystream
 //!
+//! A client cannot pass the same buffer as both source and destination, since
+//! both are unique `&'static mut` references. To encrypt in place, pass `None`
+//! as the source: the input is then read from `dest[start_index..stop_index]`
+//! and overwritten with the result, and `crypt_done` hands `None` back as the
+//! source.
+//!
+//! ### Streaming
+//! In CTR mode the driver also implements `AES128Stream`, for messages fed in
+//! chunks of any length. The unused keystream of a chunk's last, partial block
+//! is kept and applied to the start of the next chunk, so the chunks produce the
+//! same output as a single `crypt`.
+//!
+//! Once the whole keystream has been generated, the XOR and the `crypt_done`
+//! callback happen in a deferred call rather than in the interrupt handler, so
+//! a client that starts the next `crypt` from `crypt_done` does not run inside
+//! the handler.
+//!
 //! ### Things to highlight that can be improved:
 //!
 //! * ECB_DATA must be a static mut \[u8\] and can't be located in the struct
-//! * PAYLOAD size is restricted to 128 bytes
+//! * PAYLOAD size is restricted to `N` bytes per `crypt` call, 128 by default.
+//!   Boards can pick a smaller `N` to save RAM or a larger one to split long
+//!   messages into fewer calls.
 //!
 //! Authors
 //! --------
@@ -31,6 +56,7 @@
 //! * Date: April 21, 2017
 
 use core::cell::Cell;
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::hil::symmetric_encryption;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::cells::TakeCell;
@@ -45,9 +71,7 @@ use kernel::ErrorCode;
 // Byte 33-47  - Ciphertext
 static mut ECB_DATA: [u8; 48] = [0; 48];
 
-#[allow(dead_code)]
 const KEY_START: usize = 0;
-#[allow(dead_code)]
 const KEY_END: usize = 15;
 const PLAINTEXT_START: usize = 16;
 const PLAINTEXT_END: usize = 32;
@@ -55,7 +79,15 @@ const PLAINTEXT_END: usize = 32;
 const CIPHERTEXT_START: usize = 33;
 #[allow(dead_code)]
 const CIPHERTEXT_END: usize = 47;
-const MAX_LENGTH: usize = 128;
+/// Default size of the keystream buffer, and so of a single `crypt` call.
+pub const DEFAULT_MAX_LENGTH: usize = 128;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Ctr,
+    Ecb,
+    Cbc,
+}
 
 const AESECB_BASE: StaticRef<AesEcbRegisters> =
     unsafe { StaticRef::new(0x4000E000 as *const AesEcbRegisters) };
@@ -120,50 +152,151 @@ register_bitfields! [u32,
     ]
 ];
 
-pub struct AesECB<'a> {
+pub struct AesECB<'a, const N: usize = DEFAULT_MAX_LENGTH> {
     registers: StaticRef<AesEcbRegisters>,
     client: OptionalCell<&'a dyn kernel::hil::symmetric_encryption::Client<'a>>,
     /// Input either plaintext or ciphertext to be encrypted or decrypted.
     input: TakeCell<'static, [u8]>,
     output: TakeCell<'static, [u8]>,
     /// Keystream to be XOR'ed with the input.
-    keystream: Cell<[u8; MAX_LENGTH]>,
+    keystream: Cell<[u8; N]>,
+    /// Initial counter value set by `set_iv`.
+    iv: Cell<[u8; symmetric_encryption::AES128_BLOCK_SIZE]>,
+    /// Counter value for the next block of keystream in CTR mode, or the
+    /// previous ciphertext block in CBC mode.
+    counter: Cell<[u8; symmetric_encryption::AES128_BLOCK_SIZE]>,
+    /// Last block of CTR keystream, of which the final `carry_len` bytes
+    /// have not been used yet.
+    carry: Cell<[u8; symmetric_encryption::AES128_BLOCK_SIZE]>,
+    carry_len: Cell<usize>,
+    mode: Cell<Mode>,
+    encrypting: Cell<bool>,
+    /// The operation in progress reads its input from `output`.
+    in_place: Cell<bool>,
     current_idx: Cell<usize>,
     start_idx: Cell<usize>,
     end_idx: Cell<usize>,
+    deferred_call: DeferredCall,
 }
 
-impl<'a> AesECB<'a> {
-    pub fn new() -> AesECB<'a> {
+impl<'a, const N: usize> AesECB<'a, N> {
+    pub fn new() -> AesECB<'a, N> {
         AesECB {
             registers: AESECB_BASE,
             client: OptionalCell::empty(),
             input: TakeCell::empty(),
             output: TakeCell::empty(),
-            keystream: Cell::new([0; MAX_LENGTH]),
+            keystream: Cell::new([0; N]),
+            iv: Cell::new([0; symmetric_encryption::AES128_BLOCK_SIZE]),
+            counter: Cell::new([0; symmetric_encryption::AES128_BLOCK_SIZE]),
+            carry: Cell::new([0; symmetric_encryption::AES128_BLOCK_SIZE]),
+            carry_len: Cell::new(0),
+            mode: Cell::new(Mode::Ctr),
+            encrypting: Cell::new(true),
+            in_place: Cell::new(false),
             current_idx: Cell::new(0),
             start_idx: Cell::new(0),
             end_idx: Cell::new(0),
+            deferred_call: DeferredCall::new(),
         }
     }
 
+    /// Zero the keystream and its indices, so that nothing generated for a
+    /// previous message can end up in the next one. Unlike `disable`, this
+    /// keeps the key, IV and mode. Fails with `BUSY` while an operation is in
+    /// progress.
+    pub fn reset_keystream(&self) -> Result<(), ErrorCode> {
+        if self.input.is_some() || self.output.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.keystream.set([0; N]);
+        self.carry.set([0; symmetric_encryption::AES128_BLOCK_SIZE]);
+        self.carry_len.set(0);
+        self.current_idx.set(0);
+        self.start_idx.set(0);
+        self.end_idx.set(0);
+        Ok(())
+    }
+
     fn set_dma(&self) {
         unsafe {
             self.registers.ecbdataptr.set(ECB_DATA.as_ptr() as u32);
         }
     }
 
+    /// Copy the current counter into the DMA buffer as the next block to
+    /// encrypt.
+    fn load_ctr(&self) {
+        let counter = self.counter.get();
+        unsafe {
+            ECB_DATA[PLAINTEXT_START..PLAINTEXT_END].copy_from_slice(&counter);
+        }
+    }
+
+    /// Copy the next input block into the DMA buffer, chained with the
+    /// previous ciphertext block in CBC mode.
+    fn load_input(&self) {
+        let chain = self.counter.get();
+        let cbc = self.mode.get() == Mode::Cbc;
+        let (buffer, idx) = if self.in_place.get() {
+            (&self.output, self.start_idx.get() + self.current_idx.get())
+        } else {
+            (&self.input, self.current_idx.get())
+        };
+        buffer.map(|src| {
+            if let Some(block) = src.get(idx..idx + symmetric_encryption::AES128_BLOCK_SIZE) {
+                for (i, b) in block.iter().enumerate() {
+                    let b = if cbc { *b ^ chain[i] } else { *b };
+                    unsafe {
+                        ECB_DATA[PLAINTEXT_START + i] = b;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Load the DMA buffer with the next block to encrypt for the current
+    /// mode.
+    fn load_block(&self) {
+        match self.mode.get() {
+            Mode::Ctr => self.load_ctr(),
+            Mode::Ecb | Mode::Cbc => self.load_input(),
+        }
+    }
+
+    /// Prepare the next block after `ciphertext` has been produced.
+    fn next_block(&self, ciphertext: [u8; symmetric_encryption::AES128_BLOCK_SIZE]) {
+        match self.mode.get() {
+            Mode::Ctr => self.update_ctr(),
+            Mode::Ecb => self.load_input(),
+            Mode::Cbc => {
+                self.counter.set(ciphertext);
+                self.load_input();
+            }
+        }
+    }
+
+    /// The hardware only runs the forward cipher, which is all CTR needs in
+    /// either direction, but ECB and CBC can only encrypt.
+    fn check_mode(&self) -> Result<(), ErrorCode> {
+        match (self.mode.get(), self.encrypting.get()) {
+            (Mode::Ecb | Mode::Cbc, false) => Err(ErrorCode::NOSUPPORT),
+            _ => Ok(()),
+        }
+    }
+
     // FIXME: should this be performed in constant time i.e. skip the break part
     // and always loop 16 times?
     fn update_ctr(&self) {
-        for i in (PLAINTEXT_START..PLAINTEXT_END).rev() {
-            unsafe {
-                ECB_DATA[i] += 1;
-                if ECB_DATA[i] != 0 {
-                    break;
-                }
+        let mut counter = self.counter.get();
+        for byte in counter.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
             }
         }
+        self.counter.set(counter);
+        self.load_ctr();
     }
 
     fn crypt(&self) {
@@ -179,58 +312,134 @@ impl<'a> AesECB<'a> {
         self.disable_interrupts();
 
         if self.registers.event_endecb.get() == 1 {
-            let current_idx = self.current_idx.get();
-            let end_idx = self.end_idx.get();
-
-            // Get the number of bytes to be used in the keystream/block
-            let take = match end_idx.checked_sub(current_idx) {
-                Some(v) if v > symmetric_encryption::AES128_BLOCK_SIZE => {
-                    symmetric_encryption::AES128_BLOCK_SIZE
-                }
-                Some(v) => v,
-                None => 0,
-            };
-
-            let mut ks = self.keystream.get();
-
-            // Append keystream to the KEYSTREAM array
-            if take > 0 {
-                for i in current_idx..current_idx + take {
-                    ks[i] = unsafe { ECB_DATA[i - current_idx + PLAINTEXT_END] }
-                }
-                self.current_idx.set(current_idx + take);
-                self.update_ctr();
+            let mut ciphertext = [0; symmetric_encryption::AES128_BLOCK_SIZE];
+            unsafe {
+                ciphertext.copy_from_slice(
+                    &ECB_DATA
+                        [PLAINTEXT_END..PLAINTEXT_END + symmetric_encryption::AES128_BLOCK_SIZE],
+                );
             }
-
             // More bytes to encrypt!!!
-            if self.current_idx.get() < self.end_idx.get() {
+            if self.block_done(ciphertext) {
                 self.crypt();
             }
-            // Entire keystream generated we are done!
-            // XOR keystream the input
-            else if self.input.is_some() && self.output.is_some() {
-                self.input.take().map(|slice| {
-                    self.output.take().map(|buf| {
-                        let start = self.start_idx.get();
-                        let end = self.end_idx.get();
-                        let len = end - start;
-
-                        for ((i, out), inp) in buf.as_mut()[start..end]
-                            .iter_mut()
-                            .enumerate()
-                            .zip(slice.as_ref()[0..len].iter())
-                        {
-                            *out = ks[i] ^ *inp;
-                        }
-
-                        self.client
-                            .map(move |client| client.crypt_done(Some(slice), buf));
-                    });
-                });
+        }
+    }
+
+    /// Add the block the hardware produced to the keystream and prepare the
+    /// next one. Returns whether another block is needed; otherwise the
+    /// operation is finished in a deferred call.
+    fn block_done(&self, ciphertext: [u8; symmetric_encryption::AES128_BLOCK_SIZE]) -> bool {
+        // The keystream and the input are indexed from 0 rather than from
+        // `start_idx`.
+        let current_idx = self.current_idx.get();
+        let end_idx = self.end_idx.get() - self.start_idx.get();
+
+        // Get the number of bytes to be used in the keystream/block
+        let take = match end_idx.checked_sub(current_idx) {
+            Some(v) if v > symmetric_encryption::AES128_BLOCK_SIZE => {
+                symmetric_encryption::AES128_BLOCK_SIZE
             }
+            Some(v) => v,
+            None => 0,
+        };
 
+        // Append keystream to the KEYSTREAM array
+        if take > 0 {
+            let mut ks = self.keystream.get();
+            ks[current_idx..current_idx + take].copy_from_slice(&ciphertext[..take]);
             self.keystream.set(ks);
+            self.current_idx.set(current_idx + take);
+            // Keep the rest of a partial CTR block for the next chunk of a
+            // stream.
+            if self.mode.get() == Mode::Ctr {
+                self.carry.set(ciphertext);
+                self.carry_len
+                    .set(symmetric_encryption::AES128_BLOCK_SIZE - take);
+            }
+            self.next_block(ciphertext);
+        }
+
+        if self.current_idx.get() < end_idx {
+            return true;
+        }
+        // Entire keystream generated we are done! Finish the operation
+        // outside of the interrupt handler.
+        if self.output.is_some() && (self.in_place.get() || self.input.is_some()) {
+            self.deferred_call.set();
+        }
+        false
+    }
+
+    /// Set up an `AES128Stream::update` of `buf[..len]`, starting its
+    /// keystream with what is left of the previous chunk's last block.
+    /// Returns whether the hardware must generate more keystream.
+    fn start_update(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<bool, (ErrorCode, &'static mut [u8])> {
+        if self.mode.get() != Mode::Ctr {
+            return Err((ErrorCode::NOSUPPORT, buf));
+        } else if self.input.is_some() || self.output.is_some() {
+            return Err((ErrorCode::BUSY, buf));
+        } else if len > N {
+            return Err((ErrorCode::SIZE, buf));
+        } else if len == 0 || len > buf.len() {
+            return Err((ErrorCode::INVAL, buf));
         }
+
+        let carry_len = self.carry_len.get();
+        let reuse = core::cmp::min(len, carry_len);
+        let unused = symmetric_encryption::AES128_BLOCK_SIZE - carry_len;
+        let mut ks = self.keystream.get();
+        ks[..reuse].copy_from_slice(&self.carry.get()[unused..unused + reuse]);
+        self.keystream.set(ks);
+        self.carry_len.set(carry_len - reuse);
+
+        self.output.replace(buf);
+        self.in_place.set(true);
+        self.current_idx.set(reuse);
+        self.start_idx.set(0);
+        self.end_idx.set(len);
+        Ok(reuse < len)
+    }
+
+    /// XOR the keystream with the input (CTR), or copy out the ciphertext
+    /// (ECB and CBC), and hand the buffers back to the client.
+    fn crypt_done(&self) {
+        let ks = self.keystream.get();
+        let ctr = self.mode.get() == Mode::Ctr;
+        let source = self.input.take();
+        if source.is_none() && !self.in_place.get() {
+            return;
+        }
+        self.output.take().map(|buf| {
+            let start = self.start_idx.get();
+            let end = self.end_idx.get();
+            let len = end - start;
+
+            match source.as_ref() {
+                Some(slice) => {
+                    for ((i, out), inp) in buf.as_mut()[start..end]
+                        .iter_mut()
+                        .enumerate()
+                        .zip(slice.as_ref()[0..len].iter())
+                    {
+                        *out = if ctr { ks[i] ^ *inp } else { ks[i] };
+                    }
+                }
+                None => {
+                    for (i, b) in buf.as_mut()[start..end].iter_mut().enumerate() {
+                        *b = if ctr { ks[i] ^ *b } else { ks[i] };
+                    }
+                }
+            }
+
+            self.in_place.set(false);
+            self.client
+                .map(move |client| client.crypt_done(source, buf, len));
+        });
     }
 
     fn enable_interrupts(&self) {
@@ -246,7 +455,28 @@ impl<'a> AesECB<'a> {
     }
 }
 
-impl<'a> kernel::hil::symmetric_encryption::AES128<'a> for AesECB<'a> {
+impl<const N: usize> DeferredCallClient for AesECB<'_, N> {
+    fn handle_deferred_call(&self) {
+        self.crypt_done();
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
+/// Copy `src` over the whole of `dest`, as a key or IV must be. A `src` of
+/// any other length fails with `INVAL` and leaves `dest` unchanged, so a
+/// short key cannot leave stale bytes of the previous one behind.
+fn copy_exact(dest: &mut [u8], src: &[u8]) -> Result<(), ErrorCode> {
+    if src.len() != dest.len() {
+        return Err(ErrorCode::INVAL);
+    }
+    dest.copy_from_slice(src);
+    Ok(())
+}
+
+impl<'a, const N: usize> kernel::hil::symmetric_encryption::AES128<'a> for AesECB<'a, N> {
     fn enable(&self) {
         self.set_dma();
     }
@@ -261,34 +491,33 @@ impl<'a> kernel::hil::symmetric_encryption::AES128<'a> for AesECB<'a> {
     }
 
     fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
-        if key.len() != symmetric_encryption::AES128_KEY_SIZE {
-            Err(ErrorCode::INVAL)
-        } else {
-            for (i, c) in key.iter().enumerate() {
-                unsafe {
-                    ECB_DATA[i] = *c;
-                }
-            }
-            Ok(())
-        }
+        unsafe { copy_exact(&mut ECB_DATA[KEY_START..=KEY_END], key) }
     }
 
     fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
-        if iv.len() != symmetric_encryption::AES128_BLOCK_SIZE {
-            Err(ErrorCode::INVAL)
-        } else {
-            for (i, c) in iv.iter().enumerate() {
-                unsafe {
-                    ECB_DATA[i + PLAINTEXT_START] = *c;
-                }
-            }
-            Ok(())
-        }
+        let mut new_iv = [0; symmetric_encryption::AES128_BLOCK_SIZE];
+        copy_exact(&mut new_iv, iv)?;
+        self.iv.set(new_iv);
+        self.counter.set(new_iv);
+        Ok(())
     }
 
-    // not needed by NRF5x
     fn start_message(&self) {
-        ()
+        // Don't disturb the counter of an operation in progress.
+        if self.reset_keystream().is_ok() {
+            self.counter.set(self.iv.get());
+        }
+    }
+
+    fn max_crypt_len(&self) -> usize {
+        N
+    }
+
+    // The CCM trait implementation is a placeholder, so it is not reported.
+    fn supported_modes(&self) -> symmetric_encryption::Modes {
+        symmetric_encryption::Modes::ECB
+            | symmetric_encryption::Modes::CTR
+            | symmetric_encryption::Modes::CBC
     }
 
     // start_index and stop_index not used!!!
@@ -304,51 +533,103 @@ impl<'a> kernel::hil::symmetric_encryption::AES128<'a> for AesECB<'a> {
         Option<&'static mut [u8]>,
         &'static mut [u8],
     )> {
-        match source {
-            None => Some((Err(ErrorCode::INVAL), source, dest)),
-            Some(src) => {
-                if stop_index - start_index <= MAX_LENGTH {
-                    // replace buffers
-                    self.input.replace(src);
-                    self.output.replace(dest);
-
-                    // configure buffer offsets
-                    self.current_idx.set(0);
-                    self.start_idx.set(start_index);
-                    self.end_idx.set(stop_index);
-
-                    // start crypt
-                    self.crypt();
-                    None
-                } else {
-                    Some((Err(ErrorCode::SIZE), Some(src), dest))
-                }
+        // Every mode needs the range to lie within `dest`, and a source at
+        // least as long as the range.
+        let len = match stop_index.checked_sub(start_index) {
+            Some(len) if stop_index <= dest.len() => len,
+            _ => return Some((Err(ErrorCode::INVAL), source, dest)),
+        };
+        // Without a source the input is `dest[start_index..stop_index]`.
+        let in_place = source.is_none();
+        let input_len = source.as_ref().map_or(len, |src| src.len());
+        if let Err(e) = self.check_mode() {
+            Some((Err(e), source, dest))
+        } else if input_len < len {
+            Some((Err(ErrorCode::INVAL), source, dest))
+        } else if self.mode.get() != Mode::Ctr && len % symmetric_encryption::AES128_BLOCK_SIZE != 0
+        {
+            Some((Err(ErrorCode::INVAL), source, dest))
+        } else if len <= N {
+            // replace buffers
+            if let Some(src) = source {
+                self.input.replace(src);
             }
+            self.output.replace(dest);
+            self.in_place.set(in_place);
+
+            // configure buffer offsets
+            self.current_idx.set(0);
+            self.start_idx.set(start_index);
+            self.end_idx.set(stop_index);
+
+            // start crypt
+            self.load_block();
+            self.crypt();
+            None
+        } else {
+            Some((Err(ErrorCode::SIZE), source, dest))
         }
     }
 }
 
-impl kernel::hil::symmetric_encryption::AES128ECB for AesECB<'_> {
-    // not needed by NRF5x (the configuration is the same for encryption and decryption)
-    fn set_mode_aes128ecb(&self, _encrypting: bool) -> Result<(), ErrorCode> {
-        Ok(())
+impl<const N: usize> kernel::hil::symmetric_encryption::AES128ECB for AesECB<'_, N> {
+    // ECB decryption needs the inverse cipher, which the hardware lacks
+    fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.mode.set(Mode::Ecb);
+        self.encrypting.set(encrypting);
+        self.check_mode()
     }
 }
 
-impl kernel::hil::symmetric_encryption::AES128Ctr for AesECB<'_> {
-    // not needed by NRF5x (the configuration is the same for encryption and decryption)
-    fn set_mode_aes128ctr(&self, _encrypting: bool) -> Result<(), ErrorCode> {
-        Ok(())
+impl<const N: usize> kernel::hil::symmetric_encryption::AES128Ctr for AesECB<'_, N> {
+    // the keystream is the same for encryption and decryption
+    fn set_mode_aes128ctr(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.mode.set(Mode::Ctr);
+        self.encrypting.set(encrypting);
+        self.check_mode()
     }
 }
 
-impl kernel::hil::symmetric_encryption::AES128CBC for AesECB<'_> {
-    fn set_mode_aes128cbc(&self, _encrypting: bool) -> Result<(), ErrorCode> {
+impl<const N: usize> kernel::hil::symmetric_encryption::AES128Stream for AesECB<'_, N> {
+    fn init(&self) -> Result<(), ErrorCode> {
+        if self.mode.get() != Mode::Ctr {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.reset_keystream()?;
+        self.counter.set(self.iv.get());
         Ok(())
     }
+
+    fn update(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.start_update(buf, len)? {
+            self.load_ctr();
+            self.crypt();
+        } else {
+            // The chunk fit in the keystream left from the previous one.
+            self.deferred_call.set();
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<(), ErrorCode> {
+        self.reset_keystream()
+    }
+}
+
+impl<const N: usize> kernel::hil::symmetric_encryption::AES128CBC for AesECB<'_, N> {
+    // CBC decryption needs the inverse cipher, which the hardware lacks
+    fn set_mode_aes128cbc(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.mode.set(Mode::Cbc);
+        self.encrypting.set(encrypting);
+        self.check_mode()
+    }
 }
 //TODO: replace this placeholder with a proper implementation of the AES system
-impl<'a> kernel::hil::symmetric_encryption::AES128CCM<'a> for AesECB<'a> {
+impl<'a, const N: usize> kernel::hil::symmetric_encryption::AES128CCM<'a> for AesECB<'a, N> {
     /// Set the client instance which will receive `crypt_done()` callbacks
     fn set_client(&'a self, _client: &'a dyn kernel::hil::symmetric_encryption::CCMClient) {}
 
@@ -376,3 +657,551 @@ impl<'a> kernel::hil::symmetric_encryption::AES128CCM<'a> for AesECB<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::symmetric_encryption::AES128;
+
+    #[test]
+    fn max_crypt_len() {
+        let aes: AesECB = AesECB::new();
+        assert_eq!(aes.max_crypt_len(), 128);
+    }
+
+    /// Keeps the buffer an operation hands back.
+    struct BufClient(TakeCell<'static, [u8]>);
+
+    impl<'a> symmetric_encryption::Client<'a> for BufClient {
+        fn crypt_done(
+            &'a self,
+            _source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            _len: usize,
+        ) {
+            self.0.replace(dest);
+        }
+    }
+
+    /// CTR-process the 500 bytes of `client`'s buffer in place, in calls of
+    /// at most `N` bytes. Returns the number of calls.
+    fn crypt_in_windows<const N: usize>(aes: &AesECB<N>, client: &BufClient) -> usize {
+        let mut calls = 0;
+        for start in (0..500).step_by(aes.max_crypt_len()) {
+            let stop = core::cmp::min(start + N, 500);
+            let buf = client.0.take().unwrap();
+            assert!(AES128::crypt(aes, None, buf, start, stop).is_none());
+            run_ctr(aes);
+            aes.handle_deferred_call();
+            calls += 1;
+        }
+        calls
+    }
+
+    /// Checks that a 500 byte payload round-trips through `expected_calls`
+    /// calls of at most `N` bytes, and that a call one byte longer is
+    /// rejected.
+    fn check_window<const N: usize>(expected_calls: usize) {
+        use kernel::hil::symmetric_encryption::AES128Ctr;
+
+        static mut REGS: [u32; REGISTER_WORDS] = [0; REGISTER_WORDS];
+        static mut SOURCE: [u8; 512] = [0; 512];
+        static mut PAYLOAD: [u8; 500] = [0; 500];
+        let plaintext: [u8; 500] = core::array::from_fn(|i| (i * 13 + 7) as u8);
+        let client = BufClient(TakeCell::empty());
+        let aes: AesECB<N> = with_registers(unsafe { &mut *core::ptr::addr_of_mut!(REGS) });
+        AES128::set_client(&aes, &client);
+        assert_eq!(aes.max_crypt_len(), N);
+        assert_eq!(aes.set_iv(&[0xa5; 16]), Ok(()));
+
+        unsafe {
+            PAYLOAD = plaintext;
+            client.0.replace(&mut *core::ptr::addr_of_mut!(PAYLOAD));
+        }
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+        assert_eq!(crypt_in_windows(&aes, &client), expected_calls);
+        client.0.map(|buf| assert_ne!(buf, &plaintext[..]));
+
+        // Decrypting from the IV again recovers the plaintext.
+        assert_eq!(aes.set_mode_aes128ctr(false), Ok(()));
+        aes.start_message();
+        assert_eq!(crypt_in_windows(&aes, &client), expected_calls);
+        client.0.map(|buf| assert_eq!(buf, &plaintext[..]));
+
+        let (source, dest) = unsafe {
+            (
+                &mut *core::ptr::addr_of_mut!(SOURCE),
+                client.0.take().unwrap(),
+            )
+        };
+        let res = AES128::crypt(&aes, Some(source), dest, 0, N + 1);
+        assert!(matches!(res, Some((Err(ErrorCode::SIZE), Some(_), _))));
+    }
+
+    #[test]
+    fn key_must_be_exact_length() {
+        let aes: AesECB = AesECB::new();
+        let key: [u8; 16] = core::array::from_fn(|i| i as u8);
+        assert_eq!(aes.set_key(&key), Ok(()));
+        assert_eq!(unsafe { &ECB_DATA[KEY_START..=KEY_END] }, key);
+
+        // Wrong lengths are rejected without touching the stored key.
+        assert_eq!(aes.set_key(&[0xff; 15]), Err(ErrorCode::INVAL));
+        assert_eq!(aes.set_key(&[0xff; 17]), Err(ErrorCode::INVAL));
+        assert_eq!(aes.set_key(&[]), Err(ErrorCode::INVAL));
+        assert_eq!(unsafe { &ECB_DATA[KEY_START..=KEY_END] }, key);
+    }
+
+    #[test]
+    fn iv_must_be_exact_length() {
+        let aes: AesECB = AesECB::new();
+        let iv = [0x5a; 16];
+        assert_eq!(aes.set_iv(&iv), Ok(()));
+        assert_eq!(aes.iv.get(), iv);
+        assert_eq!(aes.counter.get(), iv);
+
+        assert_eq!(aes.set_iv(&[1; 15]), Err(ErrorCode::INVAL));
+        assert_eq!(aes.set_iv(&[1; 17]), Err(ErrorCode::INVAL));
+        assert_eq!(aes.iv.get(), iv);
+        assert_eq!(aes.counter.get(), iv);
+    }
+
+    #[test]
+    fn keystream_window_sizes() {
+        // Both sizes share the static buffers, so run them in one test.
+        check_window::<16>(32);
+        check_window::<256>(2);
+    }
+
+    struct InPlaceClient(Cell<Option<bool>>);
+
+    impl<'a> symmetric_encryption::Client<'a> for InPlaceClient {
+        fn crypt_done(
+            &'a self,
+            source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            len: usize,
+        ) {
+            assert_eq!(len, 3);
+            // Only dest[1..4] is encrypted.
+            assert_eq!(dest, [0xaa, 0x01 ^ 0x10, 0x02 ^ 0x20, 0x03 ^ 0x30, 0xaa]);
+            self.0.set(Some(source.is_none()));
+        }
+    }
+
+    #[test]
+    fn ctr_in_place() {
+        static mut BUF: [u8; 5] = [0xaa, 0x01, 0x02, 0x03, 0xaa];
+        let client = InPlaceClient(Cell::new(None));
+        let aes: AesECB = AesECB::new();
+        symmetric_encryption::AES128::set_client(&aes, &client);
+
+        // Out of range for the destination buffer.
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(BUF) };
+        let res = AES128::crypt(&aes, None, buf, 1, 6);
+        let buf = match res {
+            Some((Err(ErrorCode::INVAL), None, buf)) => buf,
+            _ => panic!("in-place crypt past the buffer was accepted"),
+        };
+
+        // Finish an in-place operation as the interrupt handler would.
+        let mut ks = [0; DEFAULT_MAX_LENGTH];
+        ks[..3].copy_from_slice(&[0x10, 0x20, 0x30]);
+        aes.keystream.set(ks);
+        aes.start_idx.set(1);
+        aes.end_idx.set(4);
+        aes.in_place.set(true);
+        aes.output.replace(buf);
+        aes.handle_deferred_call();
+
+        assert_eq!(client.0.get(), Some(true));
+        assert!(!aes.in_place.get() && aes.output.is_none());
+    }
+
+    struct LenClient(Cell<usize>);
+
+    impl<'a> symmetric_encryption::Client<'a> for LenClient {
+        fn crypt_done(
+            &'a self,
+            _source: Option<&'static mut [u8]>,
+            _dest: &'static mut [u8],
+            len: usize,
+        ) {
+            self.0.set(len);
+        }
+    }
+
+    #[test]
+    fn reset_keystream_between_messages() {
+        static mut BUF: [u8; 4] = [0; 4];
+        let client = LenClient(Cell::new(0));
+        let aes: AesECB = AesECB::new();
+        symmetric_encryption::AES128::set_client(&aes, &client);
+
+        // A four byte message, finished as the interrupt handler would.
+        let mut ks = [0; DEFAULT_MAX_LENGTH];
+        ks[..4].copy_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+        aes.keystream.set(ks);
+        aes.current_idx.set(4);
+        aes.end_idx.set(4);
+        aes.in_place.set(true);
+        aes.output
+            .replace(unsafe { &mut *core::ptr::addr_of_mut!(BUF) });
+        // The keystream cannot be reset under an operation in progress.
+        assert_eq!(aes.reset_keystream(), Err(ErrorCode::BUSY));
+        aes.handle_deferred_call();
+        assert_eq!(client.0.get(), 4);
+
+        assert_eq!(aes.reset_keystream(), Ok(()));
+        assert!(aes.keystream.get().iter().all(|&b| b == 0));
+        assert_eq!(
+            (
+                aes.current_idx.get(),
+                aes.start_idx.get(),
+                aes.end_idx.get()
+            ),
+            (0, 0, 0)
+        );
+
+        // A two byte message only gets its own keystream; the bytes past it
+        // are not touched.
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(BUF) };
+        buf.copy_from_slice(&[0; 4]);
+        let mut ks = aes.keystream.get();
+        ks[..2].copy_from_slice(&[0x55, 0x66]);
+        aes.keystream.set(ks);
+        aes.current_idx.set(2);
+        aes.end_idx.set(2);
+        aes.in_place.set(true);
+        aes.output.replace(buf);
+        aes.handle_deferred_call();
+        assert_eq!(client.0.get(), 2);
+        assert_eq!(unsafe { BUF }, [0x55, 0x66, 0, 0]);
+        assert!(aes.keystream.get()[2..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn supported_modes_are_implemented() {
+        use kernel::hil::symmetric_encryption::{AES128Ctr, Modes, AES128CBC, AES128ECB};
+
+        let aes: AesECB = AesECB::new();
+        let modes = aes.supported_modes();
+        assert_eq!(modes, Modes::ECB | Modes::CTR | Modes::CBC);
+        // The CCM trait is only a stub, and there is no GCM.
+        assert!(!modes.contains(Modes::CCM));
+        assert!(!modes.contains(Modes::GCM));
+
+        assert_eq!(aes.set_mode_aes128ecb(true), Ok(()));
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+        assert_eq!(aes.set_mode_aes128cbc(true), Ok(()));
+    }
+
+    #[test]
+    fn ecb_decrypt_unsupported() {
+        use kernel::hil::symmetric_encryption::{AES128Ctr, AES128CBC, AES128ECB};
+
+        let aes: AesECB = AesECB::new();
+        assert_eq!(aes.set_mode_aes128ecb(false), Err(ErrorCode::NOSUPPORT));
+        assert_eq!(aes.set_mode_aes128cbc(false), Err(ErrorCode::NOSUPPORT));
+        assert_eq!(aes.set_mode_aes128ecb(true), Ok(()));
+        assert_eq!(aes.set_mode_aes128ctr(false), Ok(()));
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+    }
+
+    /// Stand-in for the ECB peripheral's block cipher.
+    fn fake_ecb(
+        block: [u8; symmetric_encryption::AES128_BLOCK_SIZE],
+    ) -> [u8; symmetric_encryption::AES128_BLOCK_SIZE] {
+        core::array::from_fn(|i| block[i].rotate_left(3) ^ (0x5a + i as u8))
+    }
+
+    /// Words of memory covering the peripheral's registers.
+    const REGISTER_WORDS: usize = core::mem::size_of::<AesEcbRegisters>() / 4;
+
+    /// An `AesECB` whose registers are `regs` rather than the peripheral's,
+    /// so that operations can be started on the host.
+    fn with_registers<'a, const N: usize>(
+        regs: &'static mut [u32; REGISTER_WORDS],
+    ) -> AesECB<'a, N> {
+        AesECB {
+            registers: unsafe { StaticRef::new(regs.as_ptr() as *const AesEcbRegisters) },
+            ..AesECB::new()
+        }
+    }
+
+    /// Generate the keystream of a CTR operation in progress as the hardware
+    /// and interrupt handler would, up to the deferred call finishing it.
+    fn run_ctr<const N: usize>(aes: &AesECB<N>) {
+        // The block the hardware is given is the current counter.
+        while aes.block_done(fake_ecb(aes.counter.get())) {}
+    }
+
+    /// Stream `buf` through `aes` in chunks of the given lengths, running
+    /// each `update()` as the hardware and interrupt handler would.
+    fn stream(aes: &AesECB, buf: &'static mut [u8], chunks: &[usize]) {
+        use kernel::hil::symmetric_encryption::AES128Stream;
+
+        assert_eq!(aes.init(), Ok(()));
+        let mut rest = buf;
+        for &len in chunks {
+            let (chunk, tail) = rest.split_at_mut(len);
+            rest = tail;
+            if matches!(aes.start_update(chunk, len), Ok(true)) {
+                run_ctr(aes);
+            }
+            aes.handle_deferred_call();
+        }
+        assert_eq!(aes.finalize(), Ok(()));
+    }
+
+    #[test]
+    fn stream_chunks_match_single_shot() {
+        use kernel::hil::symmetric_encryption::{AES128Ctr, AES128Stream};
+
+        static mut CHUNKED: [u8; 40] = [0; 40];
+        static mut WHOLE: [u8; 40] = [0; 40];
+        let message: [u8; 40] = core::array::from_fn(|i| (i * 7) as u8);
+        let iv = [0xff; symmetric_encryption::AES128_BLOCK_SIZE];
+        let (chunked, whole) = unsafe {
+            CHUNKED = message;
+            WHOLE = message;
+            (
+                &mut *core::ptr::addr_of_mut!(CHUNKED),
+                &mut *core::ptr::addr_of_mut!(WHOLE),
+            )
+        };
+
+        let client = LenClient(Cell::new(0));
+        let aes: AesECB = AesECB::new();
+        AES128::set_client(&aes, &client);
+        assert_eq!(aes.set_iv(&iv), Ok(()));
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+
+        // Three uneven chunks, the second fitting in what is left of the
+        // block the first started, then the whole message at once.
+        stream(&aes, chunked, &[5, 9, 26]);
+        assert_eq!(client.0.get(), 26);
+        stream(&aes, whole, &[40]);
+        assert_eq!(client.0.get(), 40);
+
+        // CTR over the fake cipher, with the counter wrapping from all ones.
+        let mut expected = message;
+        for (block, out) in expected.chunks_mut(16).enumerate() {
+            let counter = if block == 0 {
+                iv
+            } else {
+                let mut counter = [0; symmetric_encryption::AES128_BLOCK_SIZE];
+                counter[15] = block as u8 - 1;
+                counter
+            };
+            for (b, k) in out.iter_mut().zip(fake_ecb(counter)) {
+                *b ^= k;
+            }
+        }
+        unsafe {
+            assert_eq!(CHUNKED, WHOLE);
+            assert_eq!(CHUNKED, expected);
+        }
+
+        // Nothing can be fed outside CTR mode.
+        use kernel::hil::symmetric_encryption::AES128ECB;
+        assert_eq!(aes.set_mode_aes128ecb(true), Ok(()));
+        assert_eq!(aes.init(), Err(ErrorCode::NOSUPPORT));
+    }
+
+    #[test]
+    fn ecb_decrypt_rejected_by_crypt() {
+        use kernel::hil::symmetric_encryption::AES128ECB;
+
+        static mut SOURCE: [u8; 16] = [0; 16];
+        static mut DEST: [u8; 16] = [0; 16];
+        let aes: AesECB = AesECB::new();
+        let _ = aes.set_mode_aes128ecb(false);
+        let (source, dest) = unsafe {
+            (
+                &mut *core::ptr::addr_of_mut!(SOURCE),
+                &mut *core::ptr::addr_of_mut!(DEST),
+            )
+        };
+        let res = AES128::crypt(&aes, Some(source), dest, 0, 16);
+        assert!(matches!(res, Some((Err(ErrorCode::NOSUPPORT), Some(_), _))));
+        assert!(aes.input.is_none());
+    }
+
+    #[test]
+    fn ctr_counter_continues_across_operations() {
+        use kernel::hil::symmetric_encryption::AES128Ctr;
+
+        static mut REGS: [u32; REGISTER_WORDS] = [0; REGISTER_WORDS];
+        static mut FIRST: [u8; 16] = [0; 16];
+        static mut SECOND: [u8; 16] = [0; 16];
+        let client = LenClient(Cell::new(0));
+        let aes: AesECB = with_registers(unsafe { &mut *core::ptr::addr_of_mut!(REGS) });
+        AES128::set_client(&aes, &client);
+        let mut iv = [0; symmetric_encryption::AES128_BLOCK_SIZE];
+        iv[14..].copy_from_slice(&[0x01, 0xff]);
+        assert_eq!(aes.set_iv(&iv), Ok(()));
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+
+        // Two operations, with no `set_iv` in between.
+        let bufs = unsafe {
+            [
+                &mut *core::ptr::addr_of_mut!(FIRST),
+                &mut *core::ptr::addr_of_mut!(SECOND),
+            ]
+        };
+        for buf in bufs {
+            assert!(AES128::crypt(&aes, None, buf, 0, 16).is_none());
+            run_ctr(&aes);
+            aes.handle_deferred_call();
+            assert_eq!(client.0.get(), 16);
+        }
+
+        // Encrypting zeroes gives the keystream: the second operation used
+        // the counter after the first, carrying into the next byte.
+        let mut next = iv;
+        next[14..].copy_from_slice(&[0x02, 0x00]);
+        unsafe {
+            assert_eq!(FIRST, fake_ecb(iv));
+            assert_eq!(SECOND, fake_ecb(next));
+        }
+    }
+
+    #[test]
+    fn crypt_done_reports_requested_length() {
+        use kernel::hil::symmetric_encryption::AES128Ctr;
+
+        const DATA_LEN: usize = 21;
+        static mut REGS: [u32; REGISTER_WORDS] = [0; REGISTER_WORDS];
+        static mut SOURCE: [u8; DATA_LEN] = [0; DATA_LEN];
+        static mut DEST: [u8; 32] = [0; 32];
+        let client = LenClient(Cell::new(0));
+        let aes: AesECB = with_registers(unsafe { &mut *core::ptr::addr_of_mut!(REGS) });
+        AES128::set_client(&aes, &client);
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+
+        // Not a whole number of blocks, and not at the start of `dest`.
+        let (source, dest) = unsafe {
+            (
+                &mut *core::ptr::addr_of_mut!(SOURCE),
+                &mut *core::ptr::addr_of_mut!(DEST),
+            )
+        };
+        assert!(AES128::crypt(&aes, Some(source), dest, 4, 4 + DATA_LEN).is_none());
+        run_ctr(&aes);
+        aes.handle_deferred_call();
+        assert_eq!(client.0.get(), DATA_LEN);
+    }
+
+    #[test]
+    fn crypt_rejects_bad_ranges() {
+        use kernel::hil::symmetric_encryption::{AES128Ctr, AES128CBC};
+
+        static mut REGS: [u32; REGISTER_WORDS] = [0; REGISTER_WORDS];
+        static mut SOURCE: [u8; 8] = [0; 8];
+        static mut DEST: [u8; 32] = [0; 32];
+        let aes: AesECB = with_registers(unsafe { &mut *core::ptr::addr_of_mut!(REGS) });
+        let (mut source, mut dest): (Option<&'static mut [u8]>, &'static mut [u8]) = unsafe {
+            (
+                Some(&mut *core::ptr::addr_of_mut!(SOURCE)),
+                &mut *core::ptr::addr_of_mut!(DEST),
+            )
+        };
+
+        assert_eq!(aes.set_mode_aes128ctr(true), Ok(()));
+        for (with_source, start, stop) in [
+            // The range ends before it starts.
+            (true, 8, 4),
+            (false, 8, 4),
+            // The range runs past the end of `dest`.
+            (true, 28, 36),
+            (false, 28, 36),
+            // The source is shorter than the range.
+            (true, 0, 12),
+        ] {
+            let src = if with_source { source.take() } else { None };
+            match AES128::crypt(&aes, src, dest, start, stop) {
+                Some((Err(ErrorCode::INVAL), src, buf)) => {
+                    assert_eq!(src.is_some(), with_source);
+                    if src.is_some() {
+                        source = src;
+                    }
+                    dest = buf;
+                }
+                _ => panic!("crypt of {}..{} was accepted", start, stop),
+            }
+        }
+
+        // Block modes check the same bounds before the block size.
+        assert_eq!(aes.set_mode_aes128cbc(true), Ok(()));
+        let res = AES128::crypt(&aes, None, dest, 32, 16);
+        assert!(matches!(res, Some((Err(ErrorCode::INVAL), None, _))));
+        assert!(aes.output.is_none());
+    }
+
+    /// Starts another operation from within `crypt_done`, as a client with
+    /// queued work would.
+    struct ReissueClient<'a> {
+        aes: &'a AesECB<'a>,
+        next: TakeCell<'static, [u8]>,
+        in_callback: Cell<bool>,
+        done: Cell<usize>,
+    }
+
+    impl<'a> symmetric_encryption::Client<'a> for ReissueClient<'a> {
+        fn crypt_done(
+            &'a self,
+            _source: Option<&'static mut [u8]>,
+            _dest: &'static mut [u8],
+            len: usize,
+        ) {
+            assert!(!self.in_callback.get(), "crypt_done re-entered");
+            assert_eq!(len, 16);
+            self.in_callback.set(true);
+            self.done.set(self.done.get() + 1);
+            self.next.take().map(|buf| {
+                assert!(AES128::crypt(self.aes, None, buf, 0, 16).is_none());
+            });
+            self.in_callback.set(false);
+        }
+    }
+
+    #[test]
+    fn crypt_done_waits_for_deferred_call() {
+        static mut REGS: [u32; REGISTER_WORDS] = [0; REGISTER_WORDS];
+        static mut FIRST: [u8; 16] = [0; 16];
+        static mut SECOND: [u8; 16] = [0; 16];
+        let aes: AesECB = with_registers(unsafe { &mut *core::ptr::addr_of_mut!(REGS) });
+        let client = ReissueClient {
+            aes: &aes,
+            next: TakeCell::new(unsafe { &mut *core::ptr::addr_of_mut!(SECOND) }),
+            in_callback: Cell::new(false),
+            done: Cell::new(0),
+        };
+        AES128::set_client(&aes, &client);
+        // The hardware finishing a block, as seen by the interrupt handler.
+        let block_encrypted = || {
+            aes.registers.event_endecb.write(Event::READY::SET);
+            aes.handle_interrupt();
+        };
+
+        let first = unsafe { &mut *core::ptr::addr_of_mut!(FIRST) };
+        assert!(AES128::crypt(&aes, None, first, 0, 16).is_none());
+        block_encrypted();
+        // The interrupt handler leaves the client alone.
+        assert_eq!(client.done.get(), 0);
+
+        // The client starts the second operation from the deferred call, and
+        // it is only running when the callback returns.
+        aes.handle_deferred_call();
+        assert_eq!(client.done.get(), 1);
+        assert!(client.next.is_none() && aes.output.is_some());
+
+        // Which finishes in the next deferred call, not the interrupt.
+        block_encrypted();
+        assert_eq!(client.done.get(), 1);
+        aes.handle_deferred_call();
+        assert_eq!(client.done.get(), 2);
+        assert!(aes.output.is_none());
+    }
+}
@@ -13,6 +13,7 @@ use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::utilities::registers::{register_bitfields, ReadWrite};
 use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
 
 #[cfg(feature = "nrf51")]
 const NUM_GPIOTE: usize = 4;
@@ -408,6 +409,17 @@ impl hil::gpio::Configure for GPIOPin<'_> {
         }
     }
 
+    fn set_drive_strength(&self, strength: hil::gpio::DriveStrength) -> Result<(), ErrorCode> {
+        self.set_high_drive(strength == hil::gpio::DriveStrength::High);
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Option<hil::gpio::Capabilities> {
+        // Every pin has pulls and can interrupt, although only as many pins
+        // as there are GPIOTE channels can have interrupts enabled at once.
+        Some(hil::gpio::Capabilities::ALL)
+    }
+
     fn make_output(&self) -> hil::gpio::Configuration {
         self.gpio_registers.pin_cnf[self.pin as usize].modify(PinConfig::DIR::Output);
         hil::gpio::Configuration::Output
@@ -213,4 +213,11 @@ impl<'a> entropy::Entropy32<'a> for Trng<'a> {
     fn set_client(&'a self, client: &'a dyn entropy::Client32) {
         self.client.set(client);
     }
+
+    fn flush(&self) {
+        // Drop the bytes collected so far; any generation in progress starts
+        // a new word.
+        self.index.set(0);
+        self.randomness.set(0);
+    }
 }
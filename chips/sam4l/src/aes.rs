@@ -157,6 +157,9 @@ pub struct Aes<'a> {
     // An index into `dest`, marking how much data has been read back from the AESA
     read_index: Cell<usize>,
 
+    // The index of the first byte of `dest` that should receive encrypted output
+    start_index: Cell<usize>,
+
     // The index just after the last byte of `dest` that should receive encrypted output
     stop_index: Cell<usize>,
 }
@@ -170,6 +173,7 @@ impl<'a> Aes<'a> {
             dest: TakeCell::empty(),
             write_index: Cell::new(0),
             read_index: Cell::new(0),
+            start_index: Cell::new(0),
             stop_index: Cell::new(0),
         }
     }
@@ -244,6 +248,7 @@ impl<'a> Aes<'a> {
                         if self.dest.map_or(false, |dest| stop_index <= dest.len()) {
                             self.write_index.set(start_index);
                             self.read_index.set(start_index);
+                            self.start_index.set(start_index);
                             self.stop_index.set(stop_index);
                             true
                         } else {
@@ -261,6 +266,7 @@ impl<'a> Aes<'a> {
                             // We will start reading from the AES into `dest` at `start_index`,
                             // and continue until `stop_index`
                             self.read_index.set(start_index);
+                            self.start_index.set(start_index);
                             self.stop_index.set(stop_index);
                             true
                         } else {
@@ -391,7 +397,11 @@ impl<'a> Aes<'a> {
 
                 // Alert the client of the completion
                 self.client.map(|client| {
-                    client.crypt_done(self.source.take(), self.dest.take().unwrap());
+                    client.crypt_done(
+                        self.source.take(),
+                        self.dest.take().unwrap(),
+                        self.stop_index.get() - self.start_index.get(),
+                    );
                 });
             }
         }
@@ -467,6 +477,17 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
             .write(Control::NEWMSG.val(1) + Control::ENABLE.val(1));
     }
 
+    fn max_crypt_len(&self) -> usize {
+        // Data is streamed through the peripheral a block at a time.
+        usize::MAX
+    }
+
+    fn supported_modes(&self) -> hil::symmetric_encryption::Modes {
+        hil::symmetric_encryption::Modes::ECB
+            | hil::symmetric_encryption::Modes::CTR
+            | hil::symmetric_encryption::Modes::CBC
+    }
+
     fn crypt(
         &self,
         source: Option<&'static mut [u8]>,
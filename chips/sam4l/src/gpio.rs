@@ -500,6 +500,29 @@ impl<'a> gpio::Configure for GPIOPin<'a> {
         GPIOPin::disable(self);
     }
 
+    fn make_function(&self) -> Result<gpio::Configuration, kernel::ErrorCode> {
+        // Without GPIO control the pin is driven by the peripheral function
+        // its PMR bits select, as set up by `select_peripheral`.
+        GPIOPin::disable(self);
+        Ok(self.configuration())
+    }
+
+    fn capabilities(&self) -> Option<gpio::Capabilities> {
+        // Each pin of the GPIO controller has its own pull resistors and
+        // interrupt enable.
+        Some(gpio::Capabilities::ALL)
+    }
+
+    fn set_glitch_filter(&self, enable: bool) -> Result<(), kernel::ErrorCode> {
+        let port: &GpioRegisters = &*self.port;
+        if enable {
+            port.gfer.set.set(self.pin_mask);
+        } else {
+            port.gfer.clear.set(self.pin_mask);
+        }
+        Ok(())
+    }
+
     fn make_output(&self) -> gpio::Configuration {
         self.enable();
         GPIOPin::enable_output(self);
@@ -551,7 +574,7 @@ impl<'a> gpio::Configure for GPIOPin<'a> {
         let port: &GpioRegisters = &*self.port;
         let input = self.is_input();
         let output = self.is_output();
-        let gpio = (port.gper.val.get() & self.pin_mask) == 1;
+        let gpio = (port.gper.val.get() & self.pin_mask) != 0;
         let config = (gpio, input, output);
         match config {
             (false, _, _) => gpio::Configuration::Function,
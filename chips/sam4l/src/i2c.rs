@@ -16,7 +16,7 @@ use kernel::hil;
 use kernel::platform::chip::ClockInterface;
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::utilities::peripheral_management::{PeripheralManagement, PeripheralManager};
-use kernel::utilities::registers::interfaces::{Readable, Writeable};
+use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
 use kernel::utilities::registers::{
     register_bitfields, FieldValue, ReadOnly, ReadWrite, WriteOnly,
 };
@@ -557,6 +557,7 @@ pub struct I2CHw {
 
     slave_enabled: Cell<bool>,
     my_slave_address: Cell<u8>,
+    slave_nack: Cell<bool>,
     slave_read_buffer: TakeCell<'static, [u8]>,
     slave_read_buffer_len: Cell<usize>,
     slave_read_buffer_index: Cell<usize>,
@@ -651,6 +652,7 @@ impl I2CHw {
 
             slave_enabled: Cell::new(false),
             my_slave_address: Cell::new(0),
+            slave_nack: Cell::new(false),
             slave_read_buffer: TakeCell::empty(),
             slave_read_buffer_len: Cell::new(0),
             slave_read_buffer_index: Cell::new(0),
@@ -1325,6 +1327,15 @@ impl I2CHw {
         self.my_slave_address.set(address);
     }
 
+    /// The acknowledge driven for each data byte received from a master.
+    fn slave_ack_value(&self) -> FieldValue<u32, ControlSlave::Register> {
+        if self.slave_nack.get() {
+            ControlSlave::ACK::AckHigh
+        } else {
+            ControlSlave::ACK::AckLow
+        }
+    }
+
     fn slave_listen(&self) {
         if self.slave_mmio_address.is_some() {
             let twis = &TWISRegisterManager::new(&self);
@@ -1334,7 +1345,8 @@ impl I2CHw {
                 + ControlSlave::SOAM::Stretch
                 + ControlSlave::CUP::CountUp
                 + ControlSlave::STREN::Enable
-                + ControlSlave::SMATCH::AckSlaveAddress;
+                + ControlSlave::SMATCH::AckSlaveAddress
+                + self.slave_ack_value();
             twis.registers.cr.write(control);
 
             // Set this separately because that makes the HW happy.
@@ -1499,4 +1511,13 @@ impl hil::i2c::I2CSlave for I2CHw {
     fn listen(&self) {
         self.slave_listen();
     }
+
+    fn set_nack(&self, nack: bool) -> Result<(), hil::i2c::Error> {
+        self.slave_nack.set(nack);
+        if self.slave_enabled.get() && self.slave_mmio_address.is_some() {
+            let twis = &TWISRegisterManager::new(&self);
+            twis.registers.cr.modify(self.slave_ack_value());
+        }
+        Ok(())
+    }
 }
@@ -430,13 +430,50 @@ impl SpiHw {
         self.slave_client.map(|client| {
             if spi.registers.sr.is_set(Status::NSSR) {
                 // NSSR
-                client.chip_selected()
+                client.chip_selected();
+                // The master raised NSS, so a transfer it did not clock
+                // to the end is over, and so is the transaction.
+                self.end_slave_transfer(*client);
+                client.chip_deselected();
             }
             // TODO: Do we want to support byte-level interrupts too?
             // They currently conflict with DMA.
         });
     }
 
+    /// Finish a slave transfer that the master ended early by deasserting
+    /// NSS, reporting the bytes actually exchanged. The DMA transfer
+    /// counters hold the bytes not yet moved; the receive channel's is
+    /// exact, while the transmit channel runs a byte or two ahead of the
+    /// wire.
+    fn end_slave_transfer(&self, client: &dyn SpiSlaveClient) {
+        if self.transfers_in_progress.get() == 0 {
+            return;
+        }
+        let len = self.dma_length.get();
+        let remaining = match self.dma_read.map_or(false, |read| read.is_enabled()) {
+            true => self.dma_read.map_or(0, |read| read.transfer_counter()),
+            false => self.dma_write.map_or(0, |write| write.transfer_counter()),
+        };
+
+        let txbuf = self.dma_write.map_or(None, |dma| {
+            let buf = dma.abort_transfer();
+            dma.disable();
+            buf
+        });
+        let rxbuf = self.dma_read.map_or(None, |dma| {
+            let buf = dma.abort_transfer();
+            dma.disable();
+            buf
+        });
+        self.transfers_in_progress.set(0);
+        self.dma_length.set(0);
+
+        // Unlike `transfer_done`, do not wait for the TX register to drain
+        // in `disable`: with NSS high the master clocks out nothing more.
+        client.read_write_done(txbuf, rxbuf, len.saturating_sub(remaining), Ok(()));
+    }
+
     /// Asynchronous buffer read/write of SPI.
     ///
     /// Returns:
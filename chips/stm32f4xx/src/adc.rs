@@ -388,7 +388,9 @@ impl hil::adc::Adc for Adc<'_> {
         if self.status.get() == ADCStatus::Off {
             self.enable();
         }
-        if *channel as u32 == 18 {
+        // The temperature sensor and the internal reference are both
+        // enabled by TSVREFE.
+        if *channel == Channel::Channel18 || *channel == Channel::Channel17 {
             self.enable_temperature();
         }
         if self.status.get() == ADCStatus::Idle {
@@ -423,6 +425,14 @@ impl hil::adc::Adc for Adc<'_> {
         Some(3300)
     }
 
+    fn internal_temperature_channel(&self) -> Option<&Self::Channel> {
+        Some(&Channel::Channel18)
+    }
+
+    fn internal_reference_channel(&self) -> Option<&Self::Channel> {
+        Some(&Channel::Channel17)
+    }
+
     fn set_client(&self, client: &'static dyn hil::adc::Client) {
         self.client.set(client);
     }
@@ -24,6 +24,17 @@ impl<'a, T: Copy> RingBuffer<'a, T> {
         self.ring.len().saturating_sub(1 + queue::Queue::len(self))
     }
 
+    /// Returns the element `index` places behind the head of the queue, or
+    /// `None` if the queue holds `index` or fewer elements. `get(0)` is the
+    /// element `dequeue()` would return.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < queue::Queue::len(self) {
+            Some(&self.ring[(self.head + index) % self.ring.len()])
+        } else {
+            None
+        }
+    }
+
     /// Returns up to 2 slices that together form the contents of the ring buffer.
     ///
     /// Returns:
@@ -280,4 +291,25 @@ mod test {
         assert_eq!(buf.dequeue(), Some(9));
         assert_eq!(buf.dequeue(), None);
     }
+
+    #[test]
+    fn test_get() {
+        const LEN: usize = 10;
+        let mut ring = [0; LEN];
+        let mut buf = RingBuffer::new(&mut ring);
+
+        // Wrap the contents around the end of the backing slice.
+        move_head(&mut buf, LEN - 2);
+        for i in 0..5 {
+            assert!(buf.enqueue(i));
+        }
+
+        for i in 0..5 {
+            assert_eq!(buf.get(i), Some(&i));
+        }
+        assert_eq!(buf.get(5), None);
+        assert_eq!(buf.len(), 5);
+        assert_eq!(buf.dequeue(), Some(0));
+        assert_eq!(buf.get(0), Some(&1));
+    }
 }
@@ -76,6 +76,16 @@ pub(crate) struct Config {
     // credentials checking, e.g., whether elf2tab and tockloader are generating
     // properly formatted footers.
     pub(crate) debug_process_credentials: bool,
+
+    /// Whether the kernel should paint process memory when a process is
+    /// loaded or restarted.
+    ///
+    /// If enabled, the memory a process can grow into is filled with
+    /// `process::STACK_PAINT` before it starts, so that
+    /// `Process::debug_stack_usage()` can measure how deep its stack has
+    /// grown. Painting takes a pass over the process's memory each time it
+    /// starts.
+    pub(crate) debug_stack_paint: bool,
 }
 
 /// A unique instance of `Config` where compile-time configuration options are
@@ -88,4 +98,5 @@ pub(crate) const CONFIG: Config = Config {
     debug_load_processes: cfg!(feature = "debug_load_processes"),
     debug_panics: !cfg!(feature = "no_debug_panics"),
     debug_process_credentials: cfg!(feature = "debug_process_credentials"),
+    debug_stack_paint: cfg!(feature = "debug_stack_paint"),
 };
@@ -263,6 +263,67 @@ macro_rules! debug_gpio {
     }};
 }
 
+///////////////////////////////////////////////////////////////////
+// runtime debug verbosity
+
+/// Verbosity of debug output, which can be changed at runtime (for example
+/// from the process console). `debug!` and `debug_verbose!` print at the
+/// `Debug` level; other output paths that support filtering check the level
+/// of each message with `debug_level_enabled`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DebugLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl DebugLevel {
+    /// All levels, from least to most verbose.
+    pub const ALL: [DebugLevel; 5] = [
+        DebugLevel::Off,
+        DebugLevel::Error,
+        DebugLevel::Info,
+        DebugLevel::Debug,
+        DebugLevel::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugLevel::Off => "off",
+            DebugLevel::Error => "error",
+            DebugLevel::Info => "info",
+            DebugLevel::Debug => "debug",
+            DebugLevel::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DebugLevel> {
+        Self::ALL.into_iter().find(|level| level.name() == name)
+    }
+}
+
+/// The current verbosity. Everything up to `Debug` is shown by default, which
+/// matches the output before levels existed.
+static mut DEBUG_LEVEL: Cell<DebugLevel> = Cell::new(DebugLevel::Debug);
+
+pub fn set_debug_level(level: DebugLevel) {
+    // SAFETY: No accesses to DEBUG_LEVEL are via an &mut, and the Tock kernel
+    // is single-threaded so all accesses will occur from this thread.
+    unsafe { &DEBUG_LEVEL }.set(level);
+}
+
+pub fn debug_level() -> DebugLevel {
+    // SAFETY: see `set_debug_level`.
+    unsafe { &DEBUG_LEVEL }.get()
+}
+
+/// Whether messages of `level` should currently be output.
+pub fn debug_level_enabled(level: DebugLevel) -> bool {
+    level != DebugLevel::Off && level <= debug_level()
+}
+
 ///////////////////////////////////////////////////////////////////
 // debug_enqueue! support
 
@@ -541,6 +602,9 @@ impl Write for DebugWriterWrapper {
 }
 
 pub fn debug_print(args: Arguments) {
+    if !debug_level_enabled(DebugLevel::Debug) {
+        return;
+    }
     let writer = unsafe { get_debug_writer() };
 
     let _ = write(writer, args);
@@ -548,6 +612,9 @@ pub fn debug_print(args: Arguments) {
 }
 
 pub fn debug_println(args: Arguments) {
+    if !debug_level_enabled(DebugLevel::Debug) {
+        return;
+    }
     let writer = unsafe { get_debug_writer() };
 
     let _ = write(writer, args);
@@ -562,6 +629,9 @@ fn write_header(writer: &mut DebugWriterWrapper, (file, line): &(&'static str, u
 }
 
 pub fn debug_verbose_print(args: Arguments, file_line: &(&'static str, u32)) {
+    if !debug_level_enabled(DebugLevel::Debug) {
+        return;
+    }
     let writer = unsafe { get_debug_writer() };
 
     let _ = write_header(writer, file_line);
@@ -570,6 +640,9 @@ pub fn debug_verbose_print(args: Arguments, file_line: &(&'static str, u32)) {
 }
 
 pub fn debug_verbose_println(args: Arguments, file_line: &(&'static str, u32)) {
+    if !debug_level_enabled(DebugLevel::Debug) {
+        return;
+    }
     let writer = unsafe { get_debug_writer() };
 
     let _ = write_header(writer, file_line);
@@ -578,7 +651,8 @@ pub fn debug_verbose_println(args: Arguments, file_line: &(&'static str, u32)) {
     writer.publish_bytes();
 }
 
-/// In-kernel `println()` debugging.
+/// In-kernel `println()` debugging. Nothing is printed while the debug level
+/// is below `DebugLevel::Debug`.
 #[macro_export]
 macro_rules! debug {
     () => ({
@@ -593,7 +667,8 @@ macro_rules! debug {
     });
 }
 
-/// In-kernel `println()` debugging with filename and line numbers.
+/// In-kernel `println()` debugging with filename and line numbers. Filtered
+/// the same way as `debug!`.
 #[macro_export]
 macro_rules! debug_verbose {
     () => ({
@@ -696,3 +771,28 @@ pub unsafe fn flush<W: Write + IoWrite>(writer: &mut W) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_level_set_by_name() {
+        for level in DebugLevel::ALL {
+            set_debug_level(DebugLevel::from_name(level.name()).unwrap());
+            assert_eq!(debug_level(), level);
+        }
+        assert_eq!(DebugLevel::from_name("loud"), None);
+        assert!(debug_level_enabled(DebugLevel::Error));
+        assert!(!debug_level_enabled(DebugLevel::Off));
+        set_debug_level(DebugLevel::Off);
+        assert!(!debug_level_enabled(DebugLevel::Error));
+        // No debug writer is registered, so these would panic if they were
+        // not dropped.
+        debug!("dropped");
+        debug_verbose!("dropped");
+        set_debug_level(DebugLevel::Info);
+        debug!("dropped");
+        set_debug_level(DebugLevel::Debug);
+    }
+}
@@ -135,6 +135,7 @@ use crate::kernel::Kernel;
 use crate::process::{Error, Process, ProcessCustomGrantIdentifier, ProcessId};
 use crate::processbuffer::{ReadOnlyProcessBuffer, ReadWriteProcessBuffer};
 use crate::processbuffer::{ReadOnlyProcessBufferRef, ReadWriteProcessBufferRef};
+use crate::processbuffer::{ReadableProcessBuffer, ReadableProcessSlice};
 use crate::upcall::{Upcall, UpcallError, UpcallId};
 use crate::ErrorCode;
 
@@ -615,6 +616,30 @@ impl<'a> GrantKernelData<'a> {
         )
     }
 
+    /// Remove any upcalls for the specified `subscribe_num` that have been
+    /// scheduled but not yet delivered to the process, and whose arguments
+    /// `matching` returns true for.
+    ///
+    /// Returns the number of upcalls removed. Capsules can use this to
+    /// coalesce repeated events into a single upcall rather than queuing one
+    /// per event.
+    pub fn remove_pending_upcalls(
+        &self,
+        subscribe_num: usize,
+        matching: &dyn Fn((usize, usize, usize)) -> bool,
+    ) -> Result<usize, UpcallError> {
+        if subscribe_num >= self.upcalls.len() {
+            return Err(UpcallError::InvalidSubscribeNum);
+        }
+        Ok(self.process.remove_pending_upcalls(
+            UpcallId {
+                subscribe_num,
+                driver_num: self.driver_num,
+            },
+            matching,
+        ))
+    }
+
     /// Returns a lifetime limited reference to the requested
     /// `ReadOnlyProcessBuffer`.
     ///
@@ -918,6 +943,49 @@ pub(crate) fn allow_rw(
     }
 }
 
+/// Calls `fun` with the contents of the process buffer currently allowed to
+/// `driver_num` at `allow_num`, either read-write (`writeable`) or read-only.
+/// The buffer stays allowed to the driver. Returns `INVAL` if the driver has
+/// no such allow number.
+pub(crate) fn with_allowed_buffer<F, R>(
+    process: &dyn Process,
+    driver_num: usize,
+    allow_num: usize,
+    writeable: bool,
+    fun: F,
+) -> Result<R, ErrorCode>
+where
+    F: FnOnce(&ReadableProcessSlice) -> R,
+{
+    // Enter grant and keep it open until `layout` goes out of scope.
+    let mut layout = enter_grant_kernel_managed(process, driver_num)?;
+
+    // # Safety
+    //
+    // The saved pointers were validated to be within application memory
+    // before they were stored, and the buffers built from them are only used
+    // while the grant is held open. They are not consumed, so the allow
+    // stored in the grant is unchanged.
+    let res = if writeable {
+        let saved = layout
+            .get_allow_rw_slice()
+            .get(allow_num)
+            .map(|saved| (saved.ptr, saved.len))
+            .ok_or(ErrorCode::INVAL)?;
+        let buffer = unsafe { ReadWriteProcessBuffer::new(saved.0, saved.1, process.processid()) };
+        buffer.enter(fun)
+    } else {
+        let saved = layout
+            .get_allow_ro_slice()
+            .get(allow_num)
+            .map(|saved| (saved.ptr, saved.len))
+            .ok_or(ErrorCode::INVAL)?;
+        let buffer = unsafe { ReadOnlyProcessBuffer::new(saved.0, saved.1, process.processid()) };
+        buffer.enter(fun)
+    };
+    res.map_err(ErrorCode::from)
+}
+
 /// An instance of a grant allocated for a particular process.
 ///
 /// `ProcessGrant` is a handle to an instance of a grant that has been allocated
@@ -38,6 +38,53 @@ pub trait Adc {
     /// The returned reference voltage is in millivolts, or `None` if unknown.
     fn get_voltage_reference_mv(&self) -> Option<usize>;
 
+    /// Configure hardware oversampling, so that each sample returned by
+    /// `sample` is the average of `ratio` conversions. `ratio` is a power of
+    /// two, and a ratio of 1 turns oversampling off.
+    ///
+    /// Returns `NOSUPPORT` if the ADC cannot oversample in hardware or does
+    /// not support `ratio`, which is the default.
+    fn set_oversampling(&self, _ratio: usize) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    /// The channel connected to the chip's internal temperature sensor, or
+    /// `None` if there is none, which is the default. Its samples are raw
+    /// like those of any other channel: converting them to a temperature
+    /// needs the sensor's calibration from the chip's datasheet.
+    fn internal_temperature_channel(&self) -> Option<&Self::Channel> {
+        None
+    }
+
+    /// The channel connected to the chip's internal voltage reference
+    /// (bandgap), or `None` if there is none, which is the default.
+    fn internal_reference_channel(&self) -> Option<&Self::Channel> {
+        None
+    }
+
+    /// Start the ADC's self-calibration. The client's `calibration_done` is
+    /// called when it finishes.
+    ///
+    /// Returns `NOSUPPORT` if the ADC has no self-calibration, which is the
+    /// default.
+    fn calibrate(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    /// Request a single sample of the voltage of `positive` minus that of
+    /// `negative`. The client's `differential_sample_ready` is called with
+    /// the result.
+    ///
+    /// Returns `NOSUPPORT` if the ADC cannot sample differentially, which is
+    /// the default.
+    fn sample_differential(
+        &self,
+        _positive: &Self::Channel,
+        _negative: &Self::Channel,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
     fn set_client(&self, client: &'static dyn Client);
 }
 
@@ -45,6 +92,15 @@ pub trait Adc {
 pub trait Client {
     /// Called when a sample is ready.
     fn sample_ready(&self, sample: u16);
+
+    /// Called when a calibration started with `calibrate` finishes. On
+    /// success, carries the offset the calibration measured, in sample LSBs,
+    /// if the ADC reports one.
+    fn calibration_done(&self, _result: Result<Option<i32>, ErrorCode>) {}
+
+    /// Called when a sample requested with `sample_differential` is ready.
+    /// The sample is the raw signed difference left-justified in the i16.
+    fn differential_sample_ready(&self, _sample: i16) {}
 }
 
 // *** Interfaces for high-speed, buffered ADC sampling ***
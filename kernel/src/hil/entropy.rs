@@ -126,6 +126,11 @@ pub trait Entropy32<'a> {
 
     /// Set the client to receive `entropy_available` callbacks.
     fn set_client(&'a self, _: &'a dyn Client32);
+
+    /// Discard any entropy buffered by this source, so that the next `get` is
+    /// served only from freshly generated entropy. Sources that buffer nothing
+    /// can use the default, which does nothing.
+    fn flush(&self) {}
 }
 
 /// An [Entropy32](trait.Entropy32.html) client
@@ -187,6 +192,11 @@ pub trait Entropy8<'a> {
 
     /// Set the client to receive `entropy_available` callbacks.
     fn set_client(&'a self, _: &'a dyn Client8);
+
+    /// Discard any entropy buffered by this source, so that the next `get` is
+    /// served only from freshly generated entropy. Sources that buffer nothing
+    /// can use the default, which does nothing.
+    fn flush(&self) {}
 }
 
 /// An [Entropy8](trait.Entropy8.html) client
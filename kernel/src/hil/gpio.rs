@@ -13,6 +13,39 @@ pub enum FloatingState {
     PullNone,
 }
 
+/// Enum for selecting how much current an output pin can source and sink.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DriveStrength {
+    /// The chip's default drive.
+    Standard,
+    /// The chip's high drive, for example to drive an LED directly.
+    High,
+}
+
+/// Which optional features a pin supports, as reported by
+/// `Configure::capabilities`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// The pin can be an input.
+    pub input: bool,
+    /// The pin can be an output.
+    pub output: bool,
+    /// The pin can interrupt on its edges.
+    pub interrupt: bool,
+    /// The pin has pull-up and pull-down resistors.
+    pub pull: bool,
+}
+
+impl Capabilities {
+    /// A general-purpose pin with every feature.
+    pub const ALL: Capabilities = Capabilities {
+        input: true,
+        output: true,
+        interrupt: true,
+        pull: true,
+    };
+}
+
 /// Enum for selecting which edge to trigger interrupts on.
 #[derive(Clone, Copy, Debug)]
 pub enum InterruptEdge {
@@ -109,6 +142,37 @@ pub trait Configure {
     /// Return the current floating state of the pin.
     fn floating_state(&self) -> FloatingState;
 
+    /// Release the pin from GPIO control to the peripheral function it is
+    /// muxed to, returning the current configuration, which should be
+    /// `Configuration::Function`. Which function that is is chip-specific.
+    /// Returns `NOSUPPORT` if the pin has no alternate function.
+    fn make_function(&self) -> Result<Configuration, ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    /// Enable or disable the pin's hardware input glitch filter, which
+    /// ignores pulses shorter than a chip-specific length without involving
+    /// the CPU. Returns `NOSUPPORT` if the pin has no glitch filter.
+    fn set_glitch_filter(&self, _enable: bool) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    /// Set how strongly the pin drives its output. Returns `NOSUPPORT` if
+    /// the pin cannot use `strength`; by default only `Standard` is
+    /// supported.
+    fn set_drive_strength(&self, strength: DriveStrength) -> Result<(), ErrorCode> {
+        match strength {
+            DriveStrength::Standard => Ok(()),
+            DriveStrength::High => Err(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    /// Return which optional features the pin supports, or `None` if the
+    /// chip does not report them, which is the default.
+    fn capabilities(&self) -> Option<Capabilities> {
+        None
+    }
+
     /// Return whether the pin is an input (reading from
     /// the Input trait will return valid results). Returns
     /// true if the pin is in Configuration::Input or
@@ -349,6 +413,22 @@ impl<'a, IP: InterruptPin<'a>> Configure for InterruptValueWrapper<'a, IP> {
         self.source.floating_state()
     }
 
+    fn make_function(&self) -> Result<Configuration, ErrorCode> {
+        self.source.make_function()
+    }
+
+    fn set_drive_strength(&self, strength: DriveStrength) -> Result<(), ErrorCode> {
+        self.source.set_drive_strength(strength)
+    }
+
+    fn set_glitch_filter(&self, enable: bool) -> Result<(), ErrorCode> {
+        self.source.set_glitch_filter(enable)
+    }
+
+    fn capabilities(&self) -> Option<Capabilities> {
+        self.source.capabilities()
+    }
+
     fn is_input(&self) -> bool {
         self.source.is_input()
     }
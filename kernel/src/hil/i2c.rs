@@ -89,6 +89,41 @@ pub trait I2CMaster {
         buffer: &'static mut [u8],
         len: usize,
     ) -> Result<(), (Error, &'static mut [u8])>;
+
+    /// The largest number of bytes the hardware accepts in a single write or
+    /// read. Longer transfers must be split by the caller.
+    fn max_transfer_len(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Like `write`, but ends without a stop condition so that the next
+    /// command continues the transaction with a repeated start.
+    fn write_continued(
+        &self,
+        _addr: u8,
+        data: &'static mut [u8],
+        _len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        Err((Error::NotSupported, data))
+    }
+
+    /// Like `read`, but ends without a stop condition so that the next
+    /// command continues the transaction with a repeated start.
+    fn read_continued(
+        &self,
+        _addr: u8,
+        buffer: &'static mut [u8],
+        _len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        Err((Error::NotSupported, buffer))
+    }
+
+    /// After a write completes with `Error::DataNak`, the number of bytes the
+    /// device acknowledged before it NACKed one, or `None` if the hardware
+    /// does not report it, which is the default.
+    fn acked_len(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// Interface for an SMBus Master hardware driver.
@@ -171,6 +206,14 @@ pub trait I2CSlave {
         max_len: usize,
     ) -> Result<(), (Error, &'static mut [u8])>;
     fn listen(&self);
+
+    /// NACK (`true`) or ACK (`false`) the data bytes of writes from other
+    /// masters until changed, for example to push back while there is no
+    /// room for the data. Returns `NotSupported` if the hardware cannot
+    /// refuse data on demand.
+    fn set_nack(&self, _nack: bool) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
 }
 
 /// Convenience type for capsules that need hardware that supports both
@@ -132,6 +132,11 @@ pub trait Rng<'a> {
     ///     may or may not return an error code.
     fn cancel(&self) -> Result<(), ErrorCode>;
     fn set_client(&'a self, _: &'a dyn Client);
+
+    /// Discard any randomness buffered by this generator or the sources
+    /// beneath it, so that the next `get` is served only from fresh entropy.
+    /// Generators that buffer nothing can use the default, which does nothing.
+    fn flush(&self) {}
 }
 
 /// An [Rng](trait.Rng.html) client
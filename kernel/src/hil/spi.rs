@@ -189,6 +189,14 @@ pub trait SpiMaster {
     /// Return the current chip select's clock rate.
     fn get_rate(&self) -> u32;
 
+    /// Return the largest number of bytes a single `read_write_bytes` call
+    /// can transfer, for example because of the width of a DMA length
+    /// register, or `None` if it is only limited by the buffers passed in,
+    /// which is the default.
+    fn max_transfer_len(&self) -> Option<usize> {
+        None
+    }
+
     /// Set the bus polarity (whether idle is high or low) for the
     /// current chip select. Return values:
     ///   - Ok(()): the polarity was set.
@@ -277,6 +285,13 @@ pub trait SpiMasterDevice {
     /// Return the current chip select's clock rate.
     fn get_rate(&self) -> u32;
 
+    /// Return the largest number of bytes a single `read_write_bytes` call
+    /// can transfer, or `None` if it is only limited by the buffers passed
+    /// in, which is the default.
+    fn max_transfer_len(&self) -> Option<usize> {
+        None
+    }
+
     /// Set the bus polarity (whether idle is high or low) for this
     /// chip select. Return values:
     ///   - Ok(()): the polarity was set.
@@ -297,6 +312,18 @@ pub trait SpiMasterDevice {
 
     /// Get the current bus phase for the current chip select.
     fn get_phase(&self) -> ClockPhase;
+
+    /// Keep this device's chip select asserted between transfers, so that
+    /// several `read_write_bytes` calls form one logical transaction. While
+    /// held, the bus is not handed to other chip selects.
+    ///
+    /// The default does nothing, so each transfer remains a transaction of
+    /// its own.
+    fn hold_low(&self) {}
+
+    /// Stop holding the chip select. The line is raised once the current
+    /// transfer, if any, completes.
+    fn release_low(&self) {}
 }
 
 /// Trait for SPI peripherals (slaves) to receive callbacks when the
@@ -306,12 +333,16 @@ pub trait SpiMasterDevice {
 /// are written from and read into until the operation completes or one
 /// of them fills, at which point a `SpiSlaveClient::read_write_done`
 /// callback is called. If the client needs to read/write more it
-/// can call `SpiSlave::read_write_bytes` again. Note that there is
-/// no notification when the chip select line goes high.
+/// can call `SpiSlave::read_write_bytes` again. Hardware that can detect
+/// the chip select line going high reports it with `chip_deselected`.
 pub trait SpiSlaveClient {
     /// Notification that the chip select has been brought low.
     fn chip_selected(&self);
 
+    /// Notification that the chip select has been brought high, ending the
+    /// controller's transaction. Not all hardware reports this.
+    fn chip_deselected(&self) {}
+
     /// Callback issued when the controller completes an SPI operation
     /// to this peripheral. `write_buffer` and `read_buffer` are
     /// the values passed in the previous call to
@@ -416,6 +447,11 @@ pub trait SpiSlaveDevice {
     /// Setup the SPI settings and speed of the bus.
     fn configure(&self, cpol: ClockPolarity, cpal: ClockPhase) -> Result<(), ErrorCode>;
 
+    /// Set the byte the device sends when the controller clocks
+    /// data while no write buffer byte is available, such as past the
+    /// end of a `read_write_bytes` operation.
+    fn set_write_byte(&self, write_byte: u8);
+
     /// Provide buffers for the peripheral to write from and read
     /// into when a controller performs a `read_write_bytes` operation.
     /// The device will issue a callback when one of four things occurs:
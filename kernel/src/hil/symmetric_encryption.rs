@@ -7,7 +7,15 @@ use crate::ErrorCode;
 /// Implement this trait and use `set_client()` in order to receive callbacks from an `AES128`
 /// instance.
 pub trait Client<'a> {
-    fn crypt_done(&'a self, source: Option<&'static mut [u8]>, dest: &'static mut [u8]);
+    /// Called when a `crypt()` operation completes. `source` and `dest` are
+    /// the buffers passed to `crypt()`, and `length` is the number of bytes
+    /// that were encrypted or decrypted (`stop_index - start_index`).
+    fn crypt_done(
+        &'a self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        length: usize,
+    );
 }
 
 /// The number of bytes used for AES block operations.  Keys and IVs must have this length,
@@ -15,6 +23,45 @@ pub trait Client<'a> {
 pub const AES128_BLOCK_SIZE: usize = 16;
 pub const AES128_KEY_SIZE: usize = 16;
 
+/// A set of AES modes of operation, as reported by
+/// `AES128::supported_modes()`. Sets are combined with `|` and `&`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Modes(u8);
+
+impl Modes {
+    pub const NONE: Modes = Modes(0);
+    pub const ECB: Modes = Modes(1 << 0);
+    pub const CTR: Modes = Modes(1 << 1);
+    pub const CBC: Modes = Modes(1 << 2);
+    pub const CCM: Modes = Modes(1 << 3);
+    pub const GCM: Modes = Modes(1 << 4);
+
+    /// Whether every mode in `other` is also in this set.
+    pub const fn contains(self, other: Modes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for Modes {
+    type Output = Modes;
+
+    fn bitor(self, rhs: Modes) -> Modes {
+        Modes(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for Modes {
+    type Output = Modes;
+
+    fn bitand(self, rhs: Modes) -> Modes {
+        Modes(self.0 & rhs.0)
+    }
+}
+
 pub trait AES128<'a> {
     /// Enable the AES hardware.
     /// Must be called before any other methods
@@ -43,6 +90,21 @@ pub trait AES128<'a> {
     /// has no effect.
     fn start_message(&self);
 
+    /// Return the largest `stop_index - start_index` that a single call to
+    /// `crypt()` accepts. Longer messages must be split across several
+    /// calls.
+    fn max_crypt_len(&self) -> usize;
+
+    /// Return the modes this implementation actually provides, through the
+    /// `AES128Ctr`, `AES128CBC` and `AES128ECB` traits or `AES128CCM` and
+    /// `AES128GCM`. A mode is listed if it can at least encrypt; a direction
+    /// the hardware lacks is reported by the `set_mode_*` call. Placeholder
+    /// implementations that do nothing must not be listed. The default
+    /// reports no modes.
+    fn supported_modes(&self) -> Modes {
+        Modes::NONE
+    }
+
     /// Request an encryption/decryption
     ///
     /// If the source buffer is not `None`, the encryption input
@@ -105,6 +167,41 @@ pub trait AES128ECB {
     fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode>;
 }
 
+/// Optional incremental interface for stream modes such as AES128Ctr, for
+/// messages whose data arrives a piece at a time. Chunks can have any length:
+/// the cipher state, including keystream left over from a partial block,
+/// carries over from one `update()` to the next, so the output is the same as
+/// encrypting the whole message with one `AES128::crypt()`.
+///
+/// The key, IV and mode are set through `AES128` and the `set_mode_*()`
+/// traits, and each `update()` completes with a call to the `AES128`
+/// client's `crypt_done()`, with a `None` source.
+pub trait AES128Stream {
+    /// Begin a new message at the configured IV.
+    ///
+    /// Returns `BUSY` if an `update()` is in progress, or `NOSUPPORT` if the
+    /// current mode is not a stream mode.
+    fn init(&self) -> Result<(), ErrorCode>;
+
+    /// Encrypt or decrypt `buf[..len]` in place as the next part of the
+    /// message.
+    ///
+    /// If `Err` is returned, no callback will be made and `buf` is handed
+    /// back: `SIZE` if `len` is larger than `AES128::max_crypt_len()`, `INVAL`
+    /// if `len` is zero or larger than `buf`, `BUSY` if an operation is in
+    /// progress, and `NOSUPPORT` if the current mode is not a stream mode.
+    fn update(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// End the message, discarding any unused keystream so that none of it
+    /// can be applied to later data. Returns `BUSY` if an `update()` is in
+    /// progress.
+    fn finalize(&self) -> Result<(), ErrorCode>;
+}
+
 pub trait CCMClient {
     /// `res` is Ok(()) if the encryption/decryption process succeeded. This
     /// does not mean that the message has been verified in the case of
@@ -176,3 +273,52 @@ pub trait AES128GCM<'a> {
         encrypting: bool,
     ) -> Result<(), (ErrorCode, &'static mut [u8])>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Software stand-in that only accepts two blocks per call.
+    struct StubAes;
+
+    impl<'a> AES128<'a> for StubAes {
+        fn enable(&self) {}
+        fn disable(&self) {}
+        fn set_client(&'a self, _client: &'a dyn Client<'a>) {}
+        fn set_key(&self, _key: &[u8]) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_iv(&self, _iv: &[u8]) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn start_message(&self) {}
+        fn max_crypt_len(&self) -> usize {
+            2 * AES128_BLOCK_SIZE
+        }
+        fn crypt(
+            &self,
+            source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            _start_index: usize,
+            _stop_index: usize,
+        ) -> Option<(
+            Result<(), ErrorCode>,
+            Option<&'static mut [u8]>,
+            &'static mut [u8],
+        )> {
+            Some((Err(ErrorCode::NOSUPPORT), source, dest))
+        }
+    }
+
+    // Generic code sizing its chunks from the driver instead of a constant.
+    fn calls_needed<'a, A: AES128<'a>>(aes: &A, len: usize) -> usize {
+        (len + aes.max_crypt_len() - 1) / aes.max_crypt_len()
+    }
+
+    #[test]
+    fn stub_reports_own_limit() {
+        let aes = StubAes;
+        assert_eq!(aes.max_crypt_len(), 32);
+        assert_eq!(calls_needed(&aes, 80), 3);
+    }
+}
@@ -31,6 +31,16 @@ pub trait Ticks: Clone + Copy + From<u32> + fmt::Debug + Ord + PartialOrd + Eq {
     /// are 32 bits.
     fn into_u32(self) -> u32;
 
+    /// Converts the type into a `u64`, filling the higher bits with 0 if it is
+    /// smaller than `u64`. Unlike `into_u32`, this never loses bits, so
+    /// `from_or_max` turns the result back into the same value.
+    ///
+    /// The default widens `into_u32`, which is exact for types of up to 32
+    /// bits. Wider types must override it.
+    fn into_u64(self) -> u64 {
+        self.into_u32() as u64
+    }
+
     /// Add two values, wrapping around on overflow using standard
     /// unsigned arithmetic.
     fn wrapping_add(self, other: Self) -> Self;
@@ -668,6 +678,10 @@ impl Ticks for Ticks64 {
         self.0 as u32
     }
 
+    fn into_u64(self) -> u64 {
+        self.0
+    }
+
     fn wrapping_add(self, other: Self) -> Self {
         Ticks64(self.0.wrapping_add(other.0))
     }
@@ -153,6 +153,23 @@ pub trait Transmit<'a> {
     ///    not be synchronously cancelled. A callback will be made on the
     ///    client indicating whether the call was successfully cancelled.
     fn transmit_abort(&self) -> Result<(), ErrorCode>;
+
+    /// Hold the transmit line in the break condition (low) for at least
+    /// `duration_us` microseconds, then return it to idle. If the
+    /// `Result<(), ErrorCode>` is Ok(()), `transmitted_break` will be called
+    /// on the `TransmitClient` once the line has been released.
+    /// Other valid `ErrorCode` values are:
+    ///  - OFF: The underlying hardware is not available.
+    ///  - BUSY: the UART is transmitting and has not made a transmission
+    ///           callback yet.
+    ///  - INVAL: `duration_us` is 0.
+    ///  - NOSUPPORT: the UART cannot generate a break. This is the default.
+    /// If the `Result<(), ErrorCode>` is not Ok(()), no callback will be made.
+    /// Calling `transmit_buffer` or `transmit_word` before the callback will
+    /// return BUSY.
+    fn transmit_break(&self, _duration_us: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
 }
 
 pub trait Receive<'a> {
@@ -226,6 +243,14 @@ pub trait TransmitClient {
     ///   - FAIL if the transmission failed in some way.
     fn transmitted_word(&self, _rval: Result<(), ErrorCode>) {}
 
+    /// A call to `Transmit::transmit_break` completed and the line is idle
+    /// again. A call to `transmit_word`, `transmit_buffer` or
+    /// `transmit_break` made within this callback SHOULD NOT return BUSY.
+    ///
+    /// `rval` is Ok(()) if the break was held for the requested duration, or
+    ///   - FAIL if the break could not be generated.
+    fn transmitted_break(&self, _rval: Result<(), ErrorCode>) {}
+
     /// A call to `Transmit::transmit_buffer` completed. The `Result<(), ErrorCode>`
     /// indicates whether the buffer was successfully transmitted. A call
     /// to `transmit_word` or `transmit_buffer` made within this callback
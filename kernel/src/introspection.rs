@@ -13,10 +13,13 @@
 use core::cell::Cell;
 
 use crate::capabilities::ProcessManagementCapability;
+use crate::grant;
 use crate::kernel::Kernel;
 use crate::process;
 use crate::process::ProcessId;
+use crate::processbuffer::ReadableProcessSlice;
 use crate::utilities::cells::NumericCellExt;
+use crate::ErrorCode;
 
 /// This struct provides the inspection functions.
 pub struct KernelInfo {
@@ -143,6 +146,29 @@ impl KernelInfo {
         (used, number_of_grants)
     }
 
+    /// Calls `fun` with the contents of the buffer that `app` has allowed to
+    /// the driver `driver_num` with allow number `allow_num`, read-write if
+    /// `writeable` is set and read-only otherwise. Returns `INVAL` if there is
+    /// no such process or allow number, and `NOMEM` if the process has not
+    /// used the driver.
+    pub fn with_allowed_buffer<F, R>(
+        &self,
+        app: ProcessId,
+        driver_num: usize,
+        allow_num: usize,
+        writeable: bool,
+        _capability: &dyn ProcessManagementCapability,
+        fun: F,
+    ) -> Result<R, ErrorCode>
+    where
+        F: FnOnce(&ReadableProcessSlice) -> R,
+    {
+        self.kernel
+            .process_map_or(Err(ErrorCode::INVAL), app, |process| {
+                grant::with_allowed_buffer(process, driver_num, allow_num, writeable, fun)
+            })
+    }
+
     /// Returns the total number of times all processes have exceeded
     /// their timeslices.
     pub fn timeslice_expirations(&self, _capability: &dyn ProcessManagementCapability) -> usize {
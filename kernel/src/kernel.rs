@@ -1006,7 +1006,7 @@ impl Kernel {
                             // Only one upcall should exist per tuple. To ensure that
                             // there are no pending upcalls with the same identifier but
                             // with the old function pointer, we clear them now.
-                            process.remove_pending_upcalls(upcall_id);
+                            process.remove_pending_upcalls(upcall_id, &|_| true);
                         }
 
                         if config::CONFIG.trace_syscalls {
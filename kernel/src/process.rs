@@ -298,9 +298,19 @@ pub trait Process {
     /// return `None` when called.
     fn pending_tasks(&self) -> usize;
 
-    /// Remove all scheduled upcalls for a given upcall id from the task
-    /// queue.
-    fn remove_pending_upcalls(&self, upcall_id: UpcallId);
+    /// Returns a copy of the `Task` `index` places from the front of the
+    /// queue, without removing it, or `None` if fewer tasks are pending.
+    /// `peek_task(0)` is the task `dequeue_task()` would return.
+    fn peek_task(&self, index: usize) -> Option<Task>;
+
+    /// Remove the scheduled upcalls for a given upcall id whose arguments
+    /// `matching` returns true for from the task queue. Returns the number of
+    /// upcalls that were removed.
+    fn remove_pending_upcalls(
+        &self,
+        upcall_id: UpcallId,
+        matching: &dyn Fn((usize, usize, usize)) -> bool,
+    ) -> usize;
 
     /// Returns the current state the process is in. Common states are "running"
     /// or "yielded".
@@ -709,6 +719,18 @@ pub trait Process {
     /// Return the last syscall the process called. Returns `None` if the
     /// process has not called any syscalls or the information is unknown.
     fn debug_syscall_last(&self) -> Option<Syscall>;
+
+    /// Return how much of its stack the process has used. Returns `None` if
+    /// the kernel did not paint process memory when the process started
+    /// (the `debug_stack_paint` kernel feature), or if the process has not
+    /// told the kernel where its stack starts.
+    fn debug_stack_usage(&self) -> Option<StackUsage>;
+
+    /// Copy the process memory starting at `addr` into `buf`, so that it can
+    /// be inspected for debugging. Returns `INVAL` if any of the `buf.len()`
+    /// bytes lies outside the memory the process can access, between the
+    /// start of its RAM and its application break.
+    fn debug_read_memory(&self, addr: usize, buf: &mut [u8]) -> Result<(), ErrorCode>;
 }
 
 /// Opaque identifier for custom grants allocated dynamically from a process's
@@ -990,6 +1012,37 @@ pub struct ProcessAddresses {
     pub sram_stack_bottom: Option<usize>,
 }
 
+/// The byte the kernel fills process memory with before the process starts,
+/// if the `debug_stack_paint` kernel feature is enabled.
+pub const STACK_PAINT: u8 = 0xa5;
+
+/// How much of its stack a process has used, measured from the paint left in
+/// process memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackUsage {
+    /// The size of the stack region in bytes: from the start of the
+    /// process's memory to the top of its stack.
+    pub total: usize,
+    /// The number of bytes below the top of the stack that the process has
+    /// written, i.e. the deepest the stack has been.
+    pub used: usize,
+}
+
+impl StackUsage {
+    /// Measure the usage of `stack`, the painted stack region from its lowest
+    /// address up to the top of the stack. The stack grows down, so the
+    /// lowest byte that no longer holds `STACK_PAINT` marks its deepest
+    /// point. Stack slots the process happened to fill with the paint value
+    /// are not noticed, so this can be a slight underestimate.
+    pub fn measure(stack: &[u8]) -> StackUsage {
+        let painted = stack.iter().take_while(|b| **b == STACK_PAINT).count();
+        StackUsage {
+            total: stack.len(),
+            used: stack.len() - painted,
+        }
+    }
+}
+
 /// Collection of process state related to the size in memory of various process
 /// structures.
 pub struct ProcessSizes {
@@ -20,7 +20,7 @@ use crate::platform::chip::Chip;
 use crate::platform::mpu::{self, MPU};
 use crate::process::{Error, FunctionCall, FunctionCallSource, Process, State, Task};
 use crate::process::{FaultAction, ProcessCustomGrantIdentifier, ProcessId};
-use crate::process::{ProcessAddresses, ProcessSizes, ShortID};
+use crate::process::{ProcessAddresses, ProcessSizes, ShortID, StackUsage, STACK_PAINT};
 use crate::process_loading::ProcessLoadError;
 use crate::process_policies::ProcessFaultPolicy;
 use crate::processbuffer::{ReadOnlyProcessBuffer, ReadWriteProcessBuffer};
@@ -342,20 +342,31 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
             || self.state.get() == State::CredentialsApproved
     }
 
-    fn remove_pending_upcalls(&self, upcall_id: UpcallId) {
-        self.tasks.map(|tasks| {
+    fn remove_pending_upcalls(
+        &self,
+        upcall_id: UpcallId,
+        matching: &dyn Fn((usize, usize, usize)) -> bool,
+    ) -> usize {
+        self.tasks.map_or(0, |tasks| {
             let count_before = tasks.len();
             tasks.retain(|task| match task {
                 // Remove only tasks that are function calls with an id equal
-                // to `upcall_id`.
+                // to `upcall_id` and matching arguments.
                 Task::FunctionCall(function_call) => match function_call.source {
                     FunctionCallSource::Kernel => true,
-                    FunctionCallSource::Driver(id) => id != upcall_id,
+                    FunctionCallSource::Driver(id) => {
+                        id != upcall_id
+                            || !matching((
+                                function_call.argument0,
+                                function_call.argument1,
+                                function_call.argument2,
+                            ))
+                    }
                 },
                 _ => true,
             });
+            let count_after = tasks.len();
             if config::CONFIG.trace_syscalls {
-                let count_after = tasks.len();
                 debug!(
                     "[{:?}] remove_pending_upcalls[{:#x}:{}] = {} upcall(s) removed",
                     self.processid(),
@@ -364,7 +375,8 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
                     count_before - count_after,
                 );
             }
-        });
+            count_before - count_after
+        })
     }
 
     fn is_running(&self) -> bool {
@@ -506,6 +518,10 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         self.tasks.map_or(0, |tasks| tasks.len())
     }
 
+    fn peek_task(&self, index: usize) -> Option<Task> {
+        self.tasks.map_or(None, |tasks| tasks.get(index).copied())
+    }
+
     fn get_command_permissions(&self, driver_num: usize, offset: usize) -> CommandPermissions {
         self.header.get_command_permissions(driver_num, offset)
     }
@@ -1192,6 +1208,37 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         self.debug.map_or(None, |debug| debug.last_syscall)
     }
 
+    fn debug_stack_usage(&self) -> Option<StackUsage> {
+        if !config::CONFIG.debug_stack_paint {
+            return None;
+        }
+        let stack_top = self
+            .debug
+            .map_or(None, |debug| debug.app_stack_start_pointer)?;
+        // Only the memory the process can access can hold its stack.
+        let top = cmp::min(stack_top, self.app_memory_break());
+        let len = (top as usize).checked_sub(self.mem_start() as usize)?;
+
+        // Safety: `[mem_start, app_break)` is this process's memory, and the
+        // process is not running while the kernel reads it.
+        let stack = unsafe { slice::from_raw_parts(self.mem_start(), len) };
+        Some(StackUsage::measure(stack))
+    }
+
+    fn debug_read_memory(&self, addr: usize, buf: &mut [u8]) -> Result<(), ErrorCode> {
+        let start = addr as *const u8;
+        if !self.in_app_owned_memory(start, buf.len()) {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Safety: `in_app_owned_memory` checked that the range is within this
+        // process's memory, and the process is not running while the kernel
+        // reads it.
+        let memory = unsafe { slice::from_raw_parts(start, buf.len()) };
+        buf.copy_from_slice(memory);
+        Ok(())
+    }
+
     fn get_addresses(&self) -> ProcessAddresses {
         ProcessAddresses {
             flash_start: self.flash_start() as usize,
@@ -1652,6 +1699,13 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         // since no `allow` calls have been made yet.
         let initial_allow_high_water_mark = app_memory.as_ptr();
 
+        // Paint the memory the process can grow into, so its stack usage can
+        // be measured later.
+        if config::CONFIG.debug_stack_paint {
+            let app_len = app_memory.len() - initial_kernel_memory_size;
+            app_memory[..app_len].fill(STACK_PAINT);
+        }
+
         // Set up initial grant region.
         let mut kernel_memory_break = app_memory.as_mut_ptr().add(app_memory.len());
 
@@ -1907,6 +1961,20 @@ impl<C: 'static + Chip> ProcessStandard<'_, C> {
         // process's memory region.
         self.allow_high_water_mark.set(app_mpu_mem_start);
 
+        // Paint the memory the restarted process can grow into, as on load.
+        if config::CONFIG.debug_stack_paint {
+            // Safety: `[app_mpu_mem_start, kernel_brk)` is this process's
+            // memory. The process is not running while it restarts, and the
+            // grants that were allocated there have been dropped.
+            unsafe {
+                ptr::write_bytes(
+                    app_mpu_mem_start as *mut u8,
+                    STACK_PAINT,
+                    kernel_brk as usize - app_mpu_mem_start as usize,
+                );
+            }
+        }
+
         // Drop the old config and use the clean one
         self.mpu_config.replace(mpu_config);
 
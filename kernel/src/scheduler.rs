@@ -6,7 +6,7 @@ pub mod priority;
 pub mod round_robin;
 
 use crate::deferred_call::DeferredCall;
-use crate::kernel::StoppedExecutingReason;
+pub use crate::kernel::StoppedExecutingReason;
 use crate::platform::chip::Chip;
 use crate::process::ProcessId;
 
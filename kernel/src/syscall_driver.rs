@@ -102,6 +102,11 @@ impl CommandReturn {
         self.0
     }
 
+    /// Returns true if this `CommandReturn` is any success type.
+    pub fn is_success(&self) -> bool {
+        self.0.is_success()
+    }
+
     /// Command error
     pub fn failure(rc: ErrorCode) -> Self {
         CommandReturn(SyscallReturn::Failure(rc))